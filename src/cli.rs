@@ -0,0 +1,628 @@
+//! Command-line front end to the plugin engine, for machines without Stata.
+//!
+//! Every command goes through the same modules as the `pq` subcommands
+//! (scanning, type mapping, `if()` compilation, writers and the embedded
+//! Stata metadata), so a file prepared here matches one written by
+//! `pq convert`/`pq save`. Messages the engine would send to the Stata
+//! console go to stdout instead (see `stata_interface::in_stata`).
+
+use std::collections::HashMap;
+
+use polars::prelude::*;
+
+use crate::convert::{convert_file, ConvertOptions};
+use crate::csv_dialect::{CsvDialect, CsvWriteOptions};
+use crate::decompress::{remove_spooled_inputs, resolve_input_path};
+use crate::describe::file_summary;
+use crate::downcast::DecimalMode;
+use crate::fast_cache::resolve_varlist;
+use crate::mapping::map_polars_to_stata;
+use crate::nested::NestedOptions;
+use crate::read::{data_exists, format_from_extension, scan_lazyframe_with_options, source_metadata, InputFormat};
+use crate::stata_if;
+
+pub const USAGE: &str = "\
+usage: stata_parquet_io <command> [arguments] [options]
+
+commands:
+  describe <file>              columns, Polars types and the Stata types pq use would create
+      [--format F] [--columns LIST] [--drop LIST] [--if EXPR] [--sql SQL] [--detailed]
+      [--stata-missing] [CSV-INPUT]
+  head <file>                  print the first rows
+      [-n ROWS] [--format F] [--columns LIST] [--drop LIST] [--if EXPR] [--sql SQL]
+      [--stata-missing] [CSV-INPUT]
+  schema-diff <file1> <file2>  compare column names and types (exit code 1 if they differ)
+      [--format1 F] [--format2 F] [CSV-INPUT]
+  metadata <file>              dump the Stata metadata (labels, formats, notes) as JSON
+      [--format F]
+  convert <source> <target>    convert a file in batches, as pq convert
+      [--from F] [--to F] [--columns LIST] [--drop LIST] [--if EXPR] [--sql SQL]
+      [--cast JSON] [--lax] [--compress] [--compress-strings] [--partition-by LIST]
+      [--compression C] [--compression-level N] [--no-metadata] [--xpt-version 5|8]
+      [--relaxed] [--asterisk-to-variable NAME] [--preserve-order] [--replace] [--quiet]
+      [--stata-missing] [CSV-INPUT with a --from- prefix] [CSV-OUTPUT]
+
+CSV-INPUT, as pq use's CSV options:
+      [--delimiter C] [--quote C] [--no-quote] [--no-header] [--skip-rows N]
+      [--comment PREFIX] [--null-values LIST] [--decimal-comma] [--encoding E]
+CSV-OUTPUT, as pq save's CSV options:
+      [--delimiter C] [--quote-style S] [--line-terminator lf|crlf] [--null-value TEXT]
+      [--float-precision N] [--scientific] [--no-scientific] [--date-format FMT]
+      [--datetime-format FMT] [--bom]
+
+LIST is a space-separated list of names or Stata-style patterns (\"id wage*\").
+--if takes a Stata expression, as in pq's if(); --sql takes a SQL predicate.
+--stata-missing orders missing values in --if as Stata does (pq's stata_missing).
+Formats are inferred from the file extensions unless given.";
+
+/// Options of the CSV input dialect, without the leading "--" and any
+/// prefix (convert takes them as --from-delimiter, ...).
+const CSV_READ_VALUES: [&str; 6] = ["delimiter", "quote", "skip-rows", "comment", "null-values", "encoding"];
+const CSV_READ_FLAGS: [&str; 3] = ["no-quote", "no-header", "decimal-comma"];
+/// Options of the CSV output, as convert takes them.
+const CSV_WRITE_VALUES: [&str; 7] = [
+    "--delimiter",
+    "--quote-style",
+    "--line-terminator",
+    "--null-value",
+    "--float-precision",
+    "--date-format",
+    "--datetime-format",
+];
+const CSV_WRITE_FLAGS: [&str; 3] = ["--scientific", "--no-scientific", "--bom"];
+
+/// Options and positional arguments of one command.
+#[derive(Debug, Default)]
+struct ParsedArgs {
+    positional: Vec<String>,
+    values: HashMap<String, String>,
+    flags: Vec<String>,
+}
+
+impl ParsedArgs {
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+}
+
+fn parse_args(args: &[String], value_options: &[&str], flag_options: &[&str]) -> Result<ParsedArgs, String> {
+    let mut parsed = ParsedArgs::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            parsed.positional.push(arg.clone());
+            continue;
+        }
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        if value_options.contains(&name) {
+            let value = match inline_value {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("{} expects a value", name))?,
+            };
+            parsed.values.insert(name.to_string(), value);
+        } else if flag_options.contains(&name) && inline_value.is_none() {
+            parsed.flags.push(name.to_string());
+        } else {
+            return Err(format!("unknown option {}", arg));
+        }
+    }
+    Ok(parsed)
+}
+
+fn input_format(path: &str, explicit: Option<&str>) -> Result<InputFormat, String> {
+    let name = explicit.unwrap_or_else(|| format_from_extension(path));
+    InputFormat::from_str(name).ok_or_else(|| format!("Unsupported input format: {}", name))
+}
+
+/// The filter from --if (Stata syntax) or --sql, as `sql_if` holds it.
+fn sql_filter(args: &ParsedArgs) -> Result<Option<String>, String> {
+    match (args.value("--if"), args.value("--sql")) {
+        (Some(_), Some(_)) => Err("--if and --sql may not be combined".to_string()),
+        (Some(stata_if), None) => stata_if::encode(stata_if, args.flag("--stata-missing"))
+            .map(Some)
+            .map_err(|e| format!("Error in --if: {}", e.render(stata_if))),
+        (None, Some(sql)) => Ok(Some(sql.to_string())),
+        (None, None) => Ok(None),
+    }
+}
+
+/// `names` as option names, with the leading "--" and `prefix`.
+fn prefixed(names: &[&str], prefix: &str) -> Vec<String> {
+    names.iter().map(|name| format!("--{}{}", prefix, name)).collect()
+}
+
+/// `options` followed by `extra`, as the option lists parse_args takes.
+fn with_options<'a>(options: &[&'a str], extra: &'a [String]) -> Vec<&'a str> {
+    options.iter().copied().chain(extra.iter().map(String::as_str)).collect()
+}
+
+/// The CSV input dialect from the CSV-INPUT options, each given as
+/// --<prefix><name>.
+fn csv_dialect(args: &ParsedArgs, prefix: &str) -> Result<CsvDialect, String> {
+    let value = |name: &str| args.value(&format!("--{}{}", prefix, name)).map(str::to_string);
+    let flag = |name: &str| args.flag(&format!("--{}{}", prefix, name));
+    if value("quote").is_some() && flag("no-quote") {
+        return Err(format!("--{0}quote may not be combined with --{0}no-quote", prefix));
+    }
+    let skip_rows = match value("skip-rows") {
+        Some(n) => n
+            .parse::<usize>()
+            .map_err(|_| format!("--{}skip-rows expects a row count, got {}", prefix, n))?,
+        None => 0,
+    };
+    CsvDialect {
+        separator: value("delimiter"),
+        quote: if flag("no-quote") { Some(String::new()) } else { value("quote") },
+        no_header: flag("no-header"),
+        skip_rows,
+        comment_prefix: value("comment"),
+        null_values: value("null-values")
+            .map(|list| list.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default(),
+        decimal_comma: flag("decimal-comma"),
+        encoding: value("encoding"),
+    }
+    .validated()
+}
+
+/// The CSV output options from the CSV-OUTPUT options.
+fn csv_write_options(args: &ParsedArgs) -> Result<CsvWriteOptions, String> {
+    let value = |name: &str| args.value(name).map(str::to_string);
+    if args.flag("--scientific") && args.flag("--no-scientific") {
+        return Err("--scientific may not be combined with --no-scientific".to_string());
+    }
+    let float_precision = value("--float-precision")
+        .map(|n| {
+            n.parse::<usize>()
+                .map_err(|_| format!("--float-precision expects a number of digits, got {}", n))
+        })
+        .transpose()?;
+    CsvWriteOptions {
+        separator: value("--delimiter"),
+        quote_style: value("--quote-style"),
+        line_terminator: value("--line-terminator"),
+        null_value: value("--null-value"),
+        float_precision,
+        float_scientific: match (args.flag("--scientific"), args.flag("--no-scientific")) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        date_format: value("--date-format"),
+        datetime_format: value("--datetime-format"),
+        bom: args.flag("--bom"),
+    }
+    .validated()
+}
+
+/// The path to scan (see `resolve_input_path`), after checking the file exists.
+fn existing_input(path: &str, format: InputFormat) -> Result<String, String> {
+    if !data_exists(path) {
+        return Err(format!("File does not exist ({})", path));
+    }
+    resolve_input_path(path, format)
+}
+
+fn scan(
+    path: &str,
+    format: InputFormat,
+    relaxed: bool,
+    asterisk_to_variable: Option<&str>,
+    csv_dialect: &CsvDialect,
+) -> Result<LazyFrame, String> {
+    let infer_schema_length = matches!(format, InputFormat::Csv | InputFormat::Ndjson).then_some(10_000);
+    scan_lazyframe_with_options(
+        path,
+        relaxed,
+        asterisk_to_variable,
+        format,
+        false,
+        infer_schema_length,
+        false,
+        None,
+        csv_dialect,
+    )
+    .map_err(|e| format!("Error scanning {}: {}", path, e))
+}
+
+/// Runs one command; returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let Some((command, rest)) = args.split_first() else {
+        println!("{}", USAGE);
+        return 2;
+    };
+    let result = match command.as_str() {
+        "describe" => describe(rest),
+        "head" => head(rest),
+        "schema-diff" => schema_diff(rest),
+        "metadata" => metadata(rest),
+        "convert" => convert(rest),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        other => Err(format!("unknown command {}\n\n{}", other, USAGE)),
+    };
+    remove_spooled_inputs();
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn one_path(args: &ParsedArgs, command: &str) -> Result<String, String> {
+    match args.positional.as_slice() {
+        [path] => Ok(path.clone()),
+        _ => Err(format!("{} expects one file", command)),
+    }
+}
+
+fn describe(args: &[String]) -> Result<i32, String> {
+    let (csv_values, csv_flags) = (prefixed(&CSV_READ_VALUES, ""), prefixed(&CSV_READ_FLAGS, ""));
+    let args = parse_args(
+        args,
+        &with_options(&["--format", "--columns", "--drop", "--if", "--sql"], &csv_values),
+        &with_options(&["--detailed", "--stata-missing"], &csv_flags),
+    )?;
+    let path = one_path(&args, "describe")?;
+    let format = input_format(&path, args.value("--format"))?;
+    let input_path = existing_input(&path, format)?;
+    let sql_if = sql_filter(&args)?;
+    let dialect = csv_dialect(&args, "")?;
+
+    let rc = file_summary(
+        &input_path,
+        false,
+        args.flag("--detailed"),
+        sql_if.as_deref(),
+        false,
+        None,
+        false,
+        false,
+        format,
+        10_000,
+        false,
+        false,
+        0,
+        args.value("--columns").unwrap_or(""),
+        args.value("--drop").unwrap_or(""),
+        "",
+        false,
+        true,
+        false,
+        NestedOptions::default(),
+        DecimalMode::Auto,
+        false,
+        &dialect,
+    );
+    Ok(if rc == 0 { 0 } else { 1 })
+}
+
+fn head(args: &[String]) -> Result<i32, String> {
+    let (csv_values, csv_flags) = (prefixed(&CSV_READ_VALUES, ""), prefixed(&CSV_READ_FLAGS, ""));
+    let args = parse_args(
+        args,
+        &with_options(&["-n", "--format", "--columns", "--drop", "--if", "--sql"], &csv_values),
+        &with_options(&["--stata-missing"], &csv_flags),
+    )?;
+    let path = one_path(&args, "head")?;
+    let n_rows = match args.value("-n") {
+        Some(n) => n.parse::<u32>().map_err(|_| format!("-n expects a row count, got {}", n))?,
+        None => 10,
+    };
+    let format = input_format(&path, args.value("--format"))?;
+    let input_path = existing_input(&path, format)?;
+
+    let mut lf = scan(&input_path, format, false, None, &csv_dialect(&args, "")?)?;
+    if let Some(sql) = sql_filter(&args)? {
+        lf = stata_if::filter_lazy(lf, &sql).map_err(|e| format!("Error in SQL if statement: {}", e))?;
+    }
+    let schema = lf.collect_schema().map_err(|e| e.to_string())?;
+    let names: Vec<&str> = schema.iter_names().map(|s| s.as_str()).collect();
+    let columns = resolve_varlist(args.value("--columns").unwrap_or(""), &names, args.value("--drop").unwrap_or(""))?;
+    let df = lf
+        .select(columns.iter().map(|c| col(c.as_str())).collect::<Vec<_>>())
+        .limit(n_rows)
+        .collect()
+        .map_err(|e| format!("Error reading {}: {}", path, e))?;
+    println!("{}", df);
+    Ok(0)
+}
+
+/// One line per column that is missing from a file or whose type differs.
+fn schema_differences(first: &Schema, second: &Schema) -> Vec<String> {
+    let describe_type = |dtype: &DataType| format!("{} ({})", dtype, map_polars_to_stata(dtype, 0).to_string());
+    let mut differences = Vec::new();
+    for (name, dtype) in first.iter() {
+        match second.get(name) {
+            None => differences.push(format!("{:<32} {:<28} {}", name, describe_type(dtype), "-")),
+            Some(other) if other != dtype => {
+                differences.push(format!("{:<32} {:<28} {}", name, describe_type(dtype), describe_type(other)))
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, dtype) in second.iter() {
+        if first.get(name).is_none() {
+            differences.push(format!("{:<32} {:<28} {}", name, "-", describe_type(dtype)));
+        }
+    }
+    differences
+}
+
+fn schema_diff(args: &[String]) -> Result<i32, String> {
+    let (csv_values, csv_flags) = (prefixed(&CSV_READ_VALUES, ""), prefixed(&CSV_READ_FLAGS, ""));
+    let args = parse_args(args, &with_options(&["--format1", "--format2"], &csv_values), &with_options(&[], &csv_flags))?;
+    let [first, second] = args.positional.as_slice() else {
+        return Err("schema-diff expects two files".to_string());
+    };
+    let dialect = csv_dialect(&args, "")?;
+    let mut schemas = Vec::new();
+    for (path, explicit) in [(first, args.value("--format1")), (second, args.value("--format2"))] {
+        let format = input_format(path, explicit)?;
+        let input_path = existing_input(path, format)?;
+        let schema = scan(&input_path, format, false, None, &dialect)?
+            .collect_schema()
+            .map_err(|e| format!("Error reading the schema of {}: {}", path, e))?;
+        schemas.push(schema);
+    }
+
+    let differences = schema_differences(&schemas[0], &schemas[1]);
+    if differences.is_empty() {
+        println!("Schemas match ({} columns)", schemas[0].len());
+        return Ok(0);
+    }
+    println!("{:<32} {:<28} {}", "Column", first, second);
+    println!("{}", "-".repeat(90));
+    for line in differences {
+        println!("{}", line);
+    }
+    Ok(1)
+}
+
+fn metadata(args: &[String]) -> Result<i32, String> {
+    let args = parse_args(args, &["--format"], &[])?;
+    let path = one_path(&args, "metadata")?;
+    let format = input_format(&path, args.value("--format"))?;
+    let input_path = existing_input(&path, format)?;
+    match source_metadata(&input_path, format)? {
+        Some(envelope) => {
+            let json = serde_json::to_string_pretty(&envelope).map_err(|e| e.to_string())?;
+            println!("{}", json);
+        }
+        None => println!("null"),
+    }
+    Ok(0)
+}
+
+fn convert(args: &[String]) -> Result<i32, String> {
+    let csv_values: Vec<String> = prefixed(&CSV_READ_VALUES, "from-")
+        .into_iter()
+        .chain(CSV_WRITE_VALUES.iter().map(|name| name.to_string()))
+        .collect();
+    let csv_flags: Vec<String> = prefixed(&CSV_READ_FLAGS, "from-")
+        .into_iter()
+        .chain(CSV_WRITE_FLAGS.iter().map(|name| name.to_string()))
+        .collect();
+    let args = parse_args(
+        args,
+        &with_options(&[
+            "--from",
+            "--to",
+            "--columns",
+            "--drop",
+            "--if",
+            "--sql",
+            "--cast",
+            "--partition-by",
+            "--compression",
+            "--compression-level",
+            "--xpt-version",
+            "--asterisk-to-variable",
+        ], &csv_values),
+        &with_options(&[
+            "--lax",
+            "--compress",
+            "--compress-strings",
+            "--no-metadata",
+            "--relaxed",
+            "--preserve-order",
+            "--replace",
+            "--stata-missing",
+            "--quiet",
+        ], &csv_flags),
+    )?;
+    let [source, target] = args.positional.as_slice() else {
+        return Err("convert expects a source and a target file".to_string());
+    };
+    let format = input_format(source, args.value("--from"))?;
+    let output_format = args.value("--to").unwrap_or_else(|| format_from_extension(target));
+    if !["parquet", "spss", "csv", "ipc", "xpt"].contains(&output_format) {
+        return Err(format!(
+            "Unsupported output format: {} (expected parquet, spss, csv, ipc, or xpt)",
+            output_format
+        ));
+    }
+    if std::path::Path::new(target).exists() && !args.flag("--replace") {
+        return Err(format!("File exists: {} (pass --replace to overwrite it)", target));
+    }
+    let input_path = existing_input(source, format)?;
+    if input_path == *target || source == target {
+        return Err("convert cannot write over its own source file".to_string());
+    }
+    let sql_if = sql_filter(&args)?;
+    let compression_level = args
+        .value("--compression-level")
+        .map(|level| {
+            level
+                .parse::<usize>()
+                .map_err(|_| format!("--compression-level expects a number, got {}", level))
+        })
+        .transpose()?;
+    let xpt_version = match args.value("--xpt-version").unwrap_or("5") {
+        "5" => 5,
+        "8" => 8,
+        other => return Err(format!("--xpt-version must be 5 or 8, got {}", other)),
+    };
+
+    let options = ConvertOptions {
+        input_format: format,
+        csv_dialect: csv_dialect(&args, "from-")?,
+        output_format,
+        columns: args.value("--columns").unwrap_or(""),
+        drop: args.value("--drop").unwrap_or(""),
+        sql_if: sql_if.as_deref(),
+        cast_json: args.value("--cast").unwrap_or(""),
+        cast_strict: !args.flag("--lax"),
+        compress: args.flag("--compress"),
+        compress_string: args.flag("--compress-strings"),
+        partition_by: args
+            .value("--partition-by")
+            .unwrap_or("")
+            .split_whitespace()
+            .map(PlSmallStr::from)
+            .collect(),
+        compression: args.value("--compression").unwrap_or(""),
+        compression_level,
+        metadata: !args.flag("--no-metadata"),
+        xpt_version,
+        csv_options: csv_write_options(&args)?,
+        safe_relaxed: args.flag("--relaxed"),
+        asterisk_to_variable: args.value("--asterisk-to-variable"),
+        preserve_order: args.flag("--preserve-order"),
+        quietly: args.flag("--quiet"),
+    };
+    match convert_file(&input_path, target, &options) {
+        Ok(0) => Ok(0),
+        Ok(_) => Ok(1),
+        Err(e) => Err(format!("Error converting {}: {}", source, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_values_flags_and_positionals() {
+        let args = parse_args(
+            &strings(&["in.sav", "--columns", "id wage*", "--lax", "out.parquet", "--to=csv"]),
+            &["--columns", "--to"],
+            &["--lax"],
+        )
+        .unwrap();
+        assert_eq!(args.positional, ["in.sav", "out.parquet"]);
+        assert_eq!(args.value("--columns"), Some("id wage*"));
+        assert_eq!(args.value("--to"), Some("csv"));
+        assert!(args.flag("--lax"));
+
+        assert!(parse_args(&strings(&["--bogus"]), &[], &[]).is_err());
+        assert!(parse_args(&strings(&["--columns"]), &["--columns"], &[]).is_err());
+    }
+
+    #[test]
+    fn formats_follow_the_ado_inference() {
+        assert_eq!(format_from_extension("a/b/survey.SAS7BDAT"), "sas");
+        assert_eq!(format_from_extension("waves.csv.gz"), "csv");
+        assert_eq!(format_from_extension("out.zsav"), "spss");
+        assert_eq!(format_from_extension("adsl.xpt"), "xpt");
+        assert_eq!(format_from_extension("/data/partitioned"), "parquet");
+    }
+
+    #[test]
+    fn schema_differences_list_missing_and_changed_columns() {
+        let first = Schema::from_iter([
+            Field::new("id".into(), DataType::Int64),
+            Field::new("wage".into(), DataType::Float64),
+            Field::new("state".into(), DataType::String),
+        ]);
+        let second = Schema::from_iter([
+            Field::new("id".into(), DataType::Int64),
+            Field::new("wage".into(), DataType::Float32),
+            Field::new("year".into(), DataType::Int16),
+        ]);
+        let differences = schema_differences(&first, &second);
+        assert_eq!(differences.len(), 3);
+        assert!(differences[0].starts_with("wage") && differences[0].contains("(float)"));
+        assert!(differences[1].starts_with("state"));
+        assert!(differences[2].starts_with("year"));
+        assert!(schema_differences(&first, &first).is_empty());
+    }
+
+    #[test]
+    fn run_converts_a_file() {
+        let dir = std::env::temp_dir().join(format!("pq_cli_rs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.parquet").to_string_lossy().to_string();
+        let target = dir.join("target.csv").to_string_lossy().to_string();
+        let mut df = df!("id" => [1i64, 2, 3], "wage" => [10.5, 11.0, 12.25]).unwrap();
+        ParquetWriter::new(std::fs::File::create(&source).unwrap())
+            .finish(&mut df)
+            .unwrap();
+
+        let code = run(&strings(&["convert", &source, &target, "--if", "id >= 2", "--quiet"]));
+        let written = std::fs::read_to_string(&target).unwrap();
+        let exists_code = run(&strings(&["convert", &source, &target, "--quiet"]));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(code, 0);
+        assert_eq!(written, "id,wage\n2,11.0\n3,12.25\n");
+        assert_eq!(exists_code, 1);
+    }
+
+    #[test]
+    fn csv_flags_set_the_input_dialect_and_output_options() {
+        let dir = std::env::temp_dir().join(format!("pq_cli_csv_rs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.csv").to_string_lossy().to_string();
+        let target = dir.join("target.csv").to_string_lossy().to_string();
+        std::fs::write(&source, "# export\nid;wage\n1;10,5\n2;NA\n").unwrap();
+
+        let code = run(&strings(&[
+            "convert",
+            &source,
+            &target,
+            "--from-delimiter",
+            ";",
+            "--from-decimal-comma",
+            "--from-comment",
+            "#",
+            "--from-null-values",
+            "NA",
+            "--delimiter",
+            "|",
+            "--null-value",
+            ".",
+            "--quote-style",
+            "always",
+            "--quiet",
+        ]));
+        let written = std::fs::read_to_string(&target).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(code, 0);
+        assert_eq!(written, "\"id\"|\"wage\"\n\"1\"|\"10.5\"\n\"2\"|\".\"\n");
+
+        let args = parse_args(&strings(&["--quote", "'", "--no-quote"]), &["--quote"], &["--no-quote"]).unwrap();
+        assert!(csv_dialect(&args, "").is_err());
+        let args = parse_args(&strings(&["--quote-style", "sometimes"]), &CSV_WRITE_VALUES, &[]).unwrap();
+        assert!(csv_write_options(&args).is_err());
+    }
+}
@@ -0,0 +1,589 @@
+//! `pq collapse`: Stata's collapse computed on the file. The statistics
+//! become a `group_by().agg()` on the scan, so only the aggregated rows are
+//! written for pq.ado to load.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+
+use polars::prelude::*;
+use crate::stata_if;
+
+use crate::convert::metadata_for_columns;
+use crate::csv_dialect::CsvDialect;
+use crate::fast_cache::resolve_varlist;
+use crate::read::{scan_lazyframe_with_options, source_metadata, InputFormat};
+use crate::stata_interface::display;
+use crate::stata_metadata::{self, StataMetadataEnvelope, VariableMetadata};
+use crate::utilities::{DAY_SHIFT_SAS_STATA, SEC_SHIFT_SAS_STATA};
+use crate::write::parquet_options;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Statistic {
+    Mean,
+    Sum,
+    RawSum,
+    Count,
+    Min,
+    Max,
+    /// p1-p99; median is p50.
+    Percentile(u8),
+    Sd,
+    First,
+    Last,
+    FirstNm,
+    LastNm,
+}
+
+impl Statistic {
+    pub fn parse(value: &str) -> Option<Self> {
+        let stat = match value {
+            "mean" => Self::Mean,
+            "sum" => Self::Sum,
+            "rawsum" => Self::RawSum,
+            "count" => Self::Count,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "median" => Self::Percentile(50),
+            "sd" => Self::Sd,
+            "first" => Self::First,
+            "last" => Self::Last,
+            "firstnm" => Self::FirstNm,
+            "lastnm" => Self::LastNm,
+            _ => {
+                let p = value.strip_prefix('p')?.parse::<u8>().ok()?;
+                return (1..=99).contains(&p).then_some(Self::Percentile(p));
+            }
+        };
+        Some(stat)
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Self::Mean => "mean".to_string(),
+            Self::Sum => "sum".to_string(),
+            Self::RawSum => "rawsum".to_string(),
+            Self::Count => "count".to_string(),
+            Self::Min => "min".to_string(),
+            Self::Max => "max".to_string(),
+            Self::Percentile(50) => "median".to_string(),
+            Self::Percentile(p) => format!("p{}", p),
+            Self::Sd => "sd".to_string(),
+            Self::First => "first".to_string(),
+            Self::Last => "last".to_string(),
+            Self::FirstNm => "firstnm".to_string(),
+            Self::LastNm => "lastnm".to_string(),
+        }
+    }
+
+    /// Statistics that keep the source's values (and so its type) rather
+    /// than computing new numbers.
+    fn keeps_values(&self) -> bool {
+        matches!(self, Self::Min | Self::Max | Self::First | Self::Last | Self::FirstNm | Self::LastNm)
+    }
+
+    fn allows_strings(&self) -> bool {
+        matches!(self, Self::Count | Self::First | Self::Last | Self::FirstNm | Self::LastNm)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WeightKind {
+    Analytic,
+    Frequency,
+    Importance,
+    Probability,
+}
+
+impl WeightKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "aweight" | "aw" => Some(Self::Analytic),
+            "fweight" | "fw" => Some(Self::Frequency),
+            "iweight" | "iw" => Some(Self::Importance),
+            "pweight" | "pw" => Some(Self::Probability),
+            _ => None,
+        }
+    }
+
+    /// aweights and pweights are rescaled to sum to the number of
+    /// observations in each group.
+    fn normalized(&self) -> bool {
+        matches!(self, Self::Analytic | Self::Probability)
+    }
+}
+
+/// One entry of the collapse list: `(stat) source` or `(stat) name = source`.
+/// Without a name, the source may be a pattern (x*) naming several columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollapseTarget {
+    pub stat: Statistic,
+    pub name: Option<String>,
+    pub source: String,
+}
+
+/// Parses the collapse list pq.ado stages: `stat name source` entries
+/// separated by semicolons, with `.` for no name.
+pub fn parse_spec(spec: &str) -> Result<Vec<CollapseTarget>, String> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let parts: Vec<&str> = entry.split_whitespace().collect();
+            let [stat, name, source] = parts[..] else {
+                return Err(format!("Invalid collapse entry: {}", entry));
+            };
+            let stat = Statistic::parse(stat).ok_or_else(|| format!("unknown statistic ({})", stat))?;
+            Ok(CollapseTarget {
+                stat,
+                name: (name != ".").then(|| name.to_string()),
+                source: source.to_string(),
+            })
+        })
+        .collect()
+}
+
+pub struct CollapseOptions<'a> {
+    pub input_format: InputFormat,
+    pub targets: Vec<CollapseTarget>,
+    pub by: Vec<String>,
+    pub weight: Option<(WeightKind, String)>,
+    /// Drop rows with a missing value in any source column (cw).
+    pub casewise: bool,
+    pub sql_if: Option<&'a str>,
+    pub safe_relaxed: bool,
+    pub asterisk_to_variable: Option<&'a str>,
+    pub infer_schema_length: Option<usize>,
+    pub parse_dates: bool,
+    pub csv_dialect: CsvDialect,
+}
+
+/// A collapse list entry resolved against the file's schema.
+pub struct ResolvedTarget {
+    pub stat: Statistic,
+    pub name: String,
+    pub source: String,
+}
+
+/// The column as the number Stata would hold: dates and datetimes on the
+/// 1960 epoch. None for strings and other non-numeric types.
+fn stata_number(name: &str, dtype: &DataType) -> Option<Expr> {
+    let column = col(name);
+    match dtype {
+        DataType::Date => Some(column.cast(DataType::Int32).cast(DataType::Float64) + lit(DAY_SHIFT_SAS_STATA as f64)),
+        DataType::Datetime(_, _) => Some(
+            column
+                .cast(DataType::Datetime(TimeUnit::Milliseconds, None))
+                .cast(DataType::Int64)
+                .cast(DataType::Float64)
+                + lit((SEC_SHIFT_SAS_STATA * 1000) as f64),
+        ),
+        dtype if dtype.is_primitive_numeric() || dtype.is_bool() => Some(column.cast(DataType::Float64)),
+        _ => None,
+    }
+}
+
+/// Stata's percentile: with cumulative weights W_i over the sorted values
+/// and P = p/100 * W_n, the first x_i with W_i >= P, averaged with the
+/// next value when W_i == P exactly.
+fn percentile(x: Expr, weights: Expr, p: u8) -> Expr {
+    let valid = x.clone().is_not_null().and(weights.clone().is_not_null());
+    let values = x.clone().filter(valid.clone());
+    let sorted = values.clone().sort(SortOptions::default());
+    let cumulative = weights
+        .filter(valid)
+        .sort_by([values], SortMultipleOptions::default())
+        .cum_sum(false);
+    // Divide last: p / 100 is inexact (0.14), and the product must land on
+    // whole positions exactly for the averaging branch below.
+    let target = cumulative.clone().last() * lit(p as f64) / lit(100.0);
+    let at = sorted.clone().filter(cumulative.clone().gt_eq(target.clone())).first();
+    let above = sorted.filter(cumulative.clone().gt(target.clone())).first();
+    let exact = cumulative.clone().filter(cumulative.gt_eq(target.clone())).first().eq(target);
+    when(exact).then((at.clone() + above) / lit(2.0)).otherwise(at)
+}
+
+/// The aggregation for one target. `x` is the source as a Stata number
+/// (or the raw column for statistics that keep values).
+fn aggregation(stat: Statistic, x: Expr, weight: Option<(WeightKind, Expr)>) -> Expr {
+    let n = x.clone().count().cast(DataType::Float64);
+    match (stat, weight) {
+        (Statistic::First, _) => x.first(),
+        (Statistic::Last, _) => x.last(),
+        (Statistic::FirstNm, _) => x.drop_nulls().first(),
+        (Statistic::LastNm, _) => x.drop_nulls().last(),
+        (Statistic::Min, _) => x.min(),
+        (Statistic::Max, _) => x.max(),
+        (Statistic::RawSum, _) => x.sum(),
+        (Statistic::Count, None) => x.count().cast(DataType::Int64),
+        (Statistic::Mean, None) => x.mean(),
+        (Statistic::Sum, None) => x.sum(),
+        (Statistic::Sd, None) => x.std(1),
+        (Statistic::Percentile(p), None) => {
+            let ones = x.clone().is_not_null().cast(DataType::Float64);
+            percentile(x, ones, p)
+        }
+        (stat, Some((kind, w))) => {
+            // Weights of the observations where x is not missing
+            let w = when(x.clone().is_not_null()).then(w).otherwise(lit(NULL));
+            let total = w.clone().sum();
+            let weighted_sum = (x.clone() * w.clone()).sum();
+            match stat {
+                Statistic::Count if kind.normalized() => x.count().cast(DataType::Int64),
+                Statistic::Count => total,
+                Statistic::Mean => weighted_sum / total,
+                Statistic::Sum if kind.normalized() => when(total.clone().gt(lit(0.0)))
+                    .then(weighted_sum * n / total)
+                    .otherwise(lit(0.0)),
+                Statistic::Sum => weighted_sum,
+                Statistic::Sd => {
+                    let mean = weighted_sum / total.clone();
+                    let squares = ((x - mean).pow(2) * w).sum();
+                    let variance = if kind.normalized() {
+                        squares / total * n.clone() / (n - lit(1.0))
+                    } else {
+                        squares / (total - lit(1.0))
+                    };
+                    variance.sqrt()
+                }
+                Statistic::Percentile(p) => percentile(x, w, p),
+                _ => unreachable!("statistics that ignore weights are handled above"),
+            }
+        }
+    }
+}
+
+/// The collapsed frame: one row per by() group, sorted by the groups.
+/// Errors carry a Stata return code.
+pub fn collapse_lazy(
+    mut lf: LazyFrame,
+    options: &CollapseOptions,
+) -> Result<(LazyFrame, Vec<ResolvedTarget>), (i32, String)> {
+    let polars_error = |e: PolarsError| (198, format!("Error collapsing: {}", e));
+    let schema = lf.collect_schema().map_err(polars_error)?;
+    let names: Vec<&str> = schema.iter_names().map(|s| s.as_str()).collect();
+    let find = |name: &str| -> Result<DataType, (i32, String)> {
+        schema
+            .get(name)
+            .cloned()
+            .ok_or_else(|| (111, format!("variable {} not found", name)))
+    };
+
+    // Sources named by pattern expand to one target per column
+    let mut targets: Vec<ResolvedTarget> = Vec::new();
+    for target in &options.targets {
+        match &target.name {
+            Some(name) => {
+                find(&target.source)?;
+                targets.push(ResolvedTarget {
+                    stat: target.stat,
+                    name: name.clone(),
+                    source: target.source.clone(),
+                });
+            }
+            None => {
+                let sources = resolve_varlist(&target.source, &names, "").map_err(|e| (111, e))?;
+                targets.extend(sources.into_iter().map(|source| ResolvedTarget {
+                    stat: target.stat,
+                    name: source.clone(),
+                    source,
+                }));
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Err((198, "no statistics to compute".to_string()));
+    }
+    for (i, target) in targets.iter().enumerate() {
+        if options.by.contains(&target.name) || targets[..i].iter().any(|t| t.name == target.name) {
+            return Err((198, format!("{} defined more than once", target.name)));
+        }
+    }
+    for by in &options.by {
+        find(by)?;
+    }
+
+    if options.casewise {
+        let sources: Vec<Expr> = targets.iter().map(|t| col(t.source.as_str()).is_not_null()).collect();
+        lf = lf.filter(all_horizontal(sources).map_err(polars_error)?);
+    }
+
+    let weight = match &options.weight {
+        Some((kind, name)) => {
+            let dtype = find(name)?;
+            let Some(w) = stata_number(name, &dtype) else {
+                return Err((109, format!("weight {} is not numeric", name)));
+            };
+            if *kind == WeightKind::Probability && targets.iter().any(|t| t.stat == Statistic::Sd) {
+                return Err((135, "sd not allowed with pweights".to_string()));
+            }
+            // Observations with a missing weight are not in the sample
+            lf = lf.filter(col(name.as_str()).is_not_null());
+            let checks = lf
+                .clone()
+                .select([
+                    w.clone().min().alias("min"),
+                    (w.clone() - w.clone().floor()).abs().max().alias("fraction"),
+                ])
+                .collect()
+                .map_err(polars_error)?;
+            let value = |column: &str| {
+                checks
+                    .column(column)
+                    .ok()
+                    .and_then(|c| c.get(0).ok())
+                    .and_then(|v| v.extract::<f64>())
+                    .unwrap_or(0.0)
+            };
+            if *kind != WeightKind::Importance && value("min") < 0.0 {
+                return Err((402, "negative weights encountered".to_string()));
+            }
+            if *kind == WeightKind::Frequency && value("fraction") > 0.0 {
+                return Err((401, "may not use noninteger frequency weights".to_string()));
+            }
+            Some((*kind, w))
+        }
+        None => None,
+    };
+
+    let mut aggregations = Vec::with_capacity(targets.len());
+    for target in &targets {
+        let dtype = find(&target.source)?;
+        let keeps_numbers = target.stat.keeps_values() && stata_number(&target.source, &dtype).is_some();
+        let x = if keeps_numbers || target.stat.allows_strings() {
+            col(target.source.as_str())
+        } else {
+            match stata_number(&target.source, &dtype) {
+                Some(x) => x,
+                None => {
+                    return Err((
+                        109,
+                        format!("type mismatch: ({}) of {}, which is {}", target.stat.name(), target.source, dtype),
+                    ))
+                }
+            }
+        };
+        let agg = aggregation(target.stat, x, weight.clone());
+        // 0/0 (an empty group) is missing, not NaN
+        let agg = if target.stat.keeps_values() || target.stat == Statistic::Count {
+            agg
+        } else {
+            agg.fill_nan(lit(NULL))
+        };
+        aggregations.push(agg.alias(target.name.as_str()));
+    }
+
+    let lf = if options.by.is_empty() {
+        lf.select(aggregations)
+    } else {
+        let by: Vec<Expr> = options.by.iter().map(|b| col(b.as_str())).collect();
+        lf.group_by(by).agg(aggregations).sort(
+            options.by.iter().map(PlSmallStr::from).collect::<Vec<_>>(),
+            SortMultipleOptions::default().with_nulls_last(true).with_maintain_order(true),
+        )
+    };
+    Ok((lf, targets))
+}
+
+/// The labels and formats of the collapsed file: by() variables keep
+/// theirs, and each statistic is labelled "(stat) source label" as
+/// collapse does.
+fn collapsed_metadata(
+    source: Option<StataMetadataEnvelope>,
+    by: &[String],
+    targets: &[ResolvedTarget],
+) -> Option<StataMetadataEnvelope> {
+    let source_variables: BTreeMap<String, VariableMetadata> =
+        source.as_ref().map(|s| s.variables.clone()).unwrap_or_default();
+    let mut envelope = source
+        .and_then(|s| metadata_for_columns(s, by))
+        .unwrap_or_else(|| StataMetadataEnvelope {
+            version: stata_metadata::STATA_METADATA_VERSION,
+            ..Default::default()
+        });
+    for ResolvedTarget { stat, name, source } in targets {
+        let source_variable = source_variables.get(source);
+        let label = source_variable
+            .and_then(|v| v.label.clone())
+            .filter(|l| !l.is_empty())
+            .unwrap_or_else(|| source.clone());
+        let keeps_format = !matches!(stat, Statistic::Count | Statistic::Sum | Statistic::RawSum | Statistic::Sd);
+        envelope.variables.insert(
+            name.clone(),
+            VariableMetadata {
+                label: Some(format!("({}) {}", stat.name(), label)),
+                format: source_variable.and_then(|v| v.format.clone()).filter(|_| keeps_format),
+                ..Default::default()
+            },
+        );
+    }
+    Some(envelope)
+}
+
+/// Collapses `using` and writes the result to `target` for pq use to load.
+/// Returns a Stata return code; errors have been displayed.
+pub fn collapse_to_parquet(using: &str, target: &str, options: &CollapseOptions) -> i32 {
+    match collapse_file(using, target, options) {
+        Ok(()) => 0,
+        Err((rc, message)) => {
+            display(&message);
+            rc
+        }
+    }
+}
+
+fn collapse_file(using: &str, target: &str, options: &CollapseOptions) -> Result<(), (i32, String)> {
+    let mut lf = scan_lazyframe_with_options(
+        using,
+        options.safe_relaxed,
+        options.asterisk_to_variable,
+        options.input_format,
+        false,
+        options.infer_schema_length,
+        options.parse_dates,
+        None,
+        &options.csv_dialect,
+    )
+    .map_err(|e| (198, format!("Error scanning {}: {}", using, e)))?;
+    if let Some(sql) = options.sql_if.filter(|s| !s.trim().is_empty()) {
+        lf = stata_if::filter_lazy(lf, sql).map_err(|e| (198, format!("Error in SQL if statement: {}", e)))?;
+    }
+
+    let (lf, targets) = collapse_lazy(lf, options)?;
+    let mut result = lf
+        .collect()
+        .map_err(|e| (198, format!("Error collapsing {}: {}", using, e)))?;
+
+    let key_value_metadata = collapsed_metadata(
+        source_metadata(using, options.input_format).ok().flatten(),
+        &options.by,
+        &targets,
+    )
+    .as_ref()
+    .and_then(stata_metadata::build_key_value_metadata);
+    let file = File::create(target).map_err(|e| (198, format!("Error writing collapse result: {}", e)))?;
+    parquet_options("", None, key_value_metadata)
+        .to_writer(file)
+        .finish(&mut result)
+        .map_err(|e| (198, format!("Error writing collapse result: {}", e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(spec: &str, by: &[&str], weight: Option<(WeightKind, &str)>) -> CollapseOptions<'static> {
+        CollapseOptions {
+            input_format: InputFormat::Parquet,
+            targets: parse_spec(spec).unwrap(),
+            by: by.iter().map(|b| b.to_string()).collect(),
+            weight: weight.map(|(kind, name)| (kind, name.to_string())),
+            casewise: false,
+            sql_if: None,
+            safe_relaxed: false,
+            asterisk_to_variable: None,
+            infer_schema_length: None,
+            parse_dates: false,
+            csv_dialect: CsvDialect::default(),
+        }
+    }
+
+    fn panel() -> LazyFrame {
+        df!(
+            "state" => ["ny", "ny", "ny", "ny", "ca", "ca"],
+            "x" => [Some(1.0), Some(2.0), Some(3.0), Some(4.0), None, Some(10.0)],
+            "w" => [1.0, 1.0, 2.0, 4.0, 1.0, 3.0],
+            "name" => [None, Some("a"), Some("b"), None, Some("c"), None]
+        )
+        .unwrap()
+        .lazy()
+    }
+
+    fn error_code(lf: LazyFrame, options: &CollapseOptions) -> i32 {
+        match collapse_lazy(lf, options) {
+            Ok(_) => 0,
+            Err((rc, _)) => rc,
+        }
+    }
+
+    fn floats(df: &DataFrame, name: &str) -> Vec<Option<f64>> {
+        df.column(name).unwrap().cast(&DataType::Float64).unwrap().f64().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn parses_statistics_and_entries() {
+        let targets = parse_spec("mean . x*; p25 q x;median . y").unwrap();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[1].stat, Statistic::Percentile(25));
+        assert_eq!(targets[1].name.as_deref(), Some("q"));
+        assert_eq!(targets[2].stat, Statistic::Percentile(50));
+        assert!(parse_spec("p100 . x").is_err());
+        assert!(parse_spec("mode . x").is_err());
+    }
+
+    #[test]
+    fn percentile_on_a_whole_position_averages_the_neighbours() {
+        let values = |n: i32| df!("x" => (1..=n).map(f64::from).collect::<Vec<_>>()).unwrap().lazy();
+        let (lf, _) = collapse_lazy(values(50), &options("p14 . x", &[], None)).unwrap();
+        assert_eq!(floats(&lf.collect().unwrap(), "x"), [Some(7.5)]);
+        let (lf, _) = collapse_lazy(values(25), &options("p28 . x", &[], None)).unwrap();
+        assert_eq!(floats(&lf.collect().unwrap(), "x"), [Some(7.5)]);
+    }
+
+    #[test]
+    fn unweighted_statistics_by_group() {
+        let opts = options(
+            "mean . x; sum total x; count n x; median med x; p25 q x; sd s x; \
+             first f name; firstnm fnm name; lastnm lnm name",
+            &["state"],
+            None,
+        );
+        let (lf, _) = collapse_lazy(panel(), &opts).unwrap();
+        let result = lf.collect().unwrap();
+        // Sorted by state: ca, ny
+        assert_eq!(floats(&result, "x"), [Some(10.0), Some(2.5)]);
+        assert_eq!(floats(&result, "total"), [Some(10.0), Some(10.0)]);
+        assert_eq!(floats(&result, "n"), [Some(1.0), Some(4.0)]);
+        assert_eq!(floats(&result, "med"), [Some(10.0), Some(2.5)]);
+        assert_eq!(floats(&result, "q"), [Some(10.0), Some(1.5)]);
+        let sd = floats(&result, "s");
+        assert_eq!(sd[0], None);
+        assert!((sd[1].unwrap() - 1.2909944).abs() < 1e-6);
+        let strings = |name: &str| -> Vec<Option<String>> {
+            result.column(name).unwrap().str().unwrap().into_iter().map(|s| s.map(String::from)).collect()
+        };
+        assert_eq!(strings("f"), [Some("c".to_string()), None]);
+        assert_eq!(strings("fnm"), [Some("c".to_string()), Some("a".to_string())]);
+        assert_eq!(strings("lnm"), [Some("c".to_string()), Some("b".to_string())]);
+    }
+
+    #[test]
+    fn weighted_statistics_follow_stata() {
+        let ny = |lf: LazyFrame| lf.filter(col("state").eq(lit("ny")));
+        let fw = options("mean . x; sum s x; count n x; median m x; sd sd x", &[], Some((WeightKind::Frequency, "w")));
+        let result = collapse_lazy(ny(panel()), &fw).unwrap().0.collect().unwrap();
+        // Values 1, 2, 3, 3, 4, 4, 4, 4
+        assert_eq!(floats(&result, "x"), [Some(25.0 / 8.0)]);
+        assert_eq!(floats(&result, "s"), [Some(25.0)]);
+        assert_eq!(floats(&result, "n"), [Some(8.0)]);
+        assert_eq!(floats(&result, "m"), [Some(3.5)]);
+        assert!((floats(&result, "sd")[0].unwrap() - 1.1259916).abs() < 1e-6);
+
+        let aw = options("sum s x; count n x", &[], Some((WeightKind::Analytic, "w")));
+        let result = collapse_lazy(ny(panel()), &aw).unwrap().0.collect().unwrap();
+        assert_eq!(floats(&result, "s"), [Some(12.5)]);
+        assert_eq!(floats(&result, "n"), [Some(4.0)]);
+
+        let frac = df!("x" => [1.0], "w" => [0.5]).unwrap().lazy();
+        assert_eq!(error_code(frac, &options("sum . x", &[], Some((WeightKind::Frequency, "w")))), 401);
+    }
+
+    #[test]
+    fn rejects_strings_duplicates_and_unknown_columns() {
+        assert_eq!(error_code(panel(), &options("mean . name", &[], None)), 109);
+        assert_eq!(error_code(panel(), &options("mean . x; sum . x", &[], None)), 198);
+        assert_eq!(error_code(panel(), &options("mean . x", &["x"], None)), 198);
+        assert_eq!(error_code(panel(), &options("mean . y", &[], None)), 111);
+        assert_eq!(error_code(panel(), &options("mean . x", &["region"], None)), 111);
+    }
+}
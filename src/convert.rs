@@ -0,0 +1,781 @@
+//! `pq convert`: file-to-file conversion without loading the data into Stata.
+//!
+//! The source is read in batches, by readstat's batch iterator for a single
+//! SAS/SPSS/dta/xpt file and by the Polars streaming engine otherwise. The
+//! engine runs on its own thread and hands batches over a short queue, so
+//! every write (and every Stata display) happens on the calling thread.
+//! Parquet (plain or partitioned), CSV and IPC output is written batch by
+//! batch. SPSS and XPORT output is assembled in memory first, because those
+//! writers size string columns and the file header from the whole frame.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::{self, JoinHandle};
+
+use polars::io::parquet::write::BatchedWriter as ParquetBatchedWriter;
+use polars::prelude::*;
+use polars_arrow::io::ipc::write::{FileWriter as IpcFileWriter, WriteOptions as IpcWriteOptions};
+use polars_readstat_rs::{readstat_batch_iter, ReadstatBatchIter};
+use crate::stata_if;
+
+use crate::csv_dialect::{CsvDialect, CsvWriteOptions};
+use crate::decompress::Compression;
+use crate::downcast::{apply_user_cast, intelligent_downcast, DowncastConfig};
+use crate::fast_cache::resolve_varlist;
+use crate::read::{
+    apply_sql_filter_to_batch,
+    projected_readstat_columns,
+    readstat_format_for_input,
+    readstat_scan_options,
+    scan_lazyframe_with_options,
+    source_metadata,
+    InputFormat,
+};
+use crate::stata_interface::display;
+use crate::stata_metadata::{self, StataMetadataEnvelope};
+use crate::write::{
+    check_csv_compression_level,
+    check_parquet_compression_level,
+    csv_output_compression,
+    delete_existing_files,
+    delete_existing_non_parquet,
+    display_xpt_adjustments,
+    ipc_compression,
+    parquet_options,
+    save_partitioned_sequential,
+    PartitionedWriteOptions,
+};
+use crate::{sav, xpt};
+
+/// Rows per batch from the readstat batch iterator.
+const CONVERT_BATCH_ROWS: usize = 250_000;
+/// Batches the streaming engine may run ahead of the writer.
+const CONVERT_QUEUE_DEPTH: usize = 2;
+
+pub struct ConvertOptions<'a> {
+    pub input_format: InputFormat,
+    /// Dialect of CSV input (delimiter, encoding, ...).
+    pub csv_dialect: CsvDialect,
+    pub output_format: &'a str,
+    /// Columns to keep (names or Stata-style patterns; empty keeps all)
+    /// and to drop.
+    pub columns: &'a str,
+    pub drop: &'a str,
+    pub sql_if: Option<&'a str>,
+    /// cast() JSON, {"col":"int32",...}.
+    pub cast_json: &'a str,
+    pub cast_strict: bool,
+    pub compress: bool,
+    pub compress_string: bool,
+    pub partition_by: Vec<PlSmallStr>,
+    pub compression: &'a str,
+    pub compression_level: Option<usize>,
+    /// Carry the source's labels, formats and notes over to the output.
+    pub metadata: bool,
+    pub xpt_version: u8,
+    pub csv_options: CsvWriteOptions,
+    pub safe_relaxed: bool,
+    pub asterisk_to_variable: Option<&'a str>,
+    pub preserve_order: bool,
+    pub quietly: bool,
+}
+
+pub fn convert_file(source: &str, target: &str, options: &ConvertOptions) -> Result<i32, Box<dyn Error>> {
+    let output_format = options.output_format.to_ascii_lowercase();
+    if !options.partition_by.is_empty() && output_format != "parquet" {
+        display("partition_by() is only supported for parquet output");
+        return Ok(198);
+    }
+    if output_format == "parquet" {
+        if let Err(e) = check_parquet_compression_level(options.compression, options.compression_level) {
+            display(&e);
+            return Ok(198);
+        }
+    }
+
+    let scan_infer_schema_length = matches!(options.input_format, InputFormat::Csv | InputFormat::Ndjson)
+        .then_some(10_000);
+    let mut lf = match scan_lazyframe_with_options(
+        source,
+        options.safe_relaxed,
+        options.asterisk_to_variable,
+        options.input_format,
+        options.preserve_order,
+        scan_infer_schema_length,
+        false,
+        None,
+        &options.csv_dialect,
+    ) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&format!("Error scanning {}: {}", source, e));
+            return Ok(198);
+        }
+    };
+
+    let source_schema = match lf.collect_schema() {
+        Ok(schema) => schema,
+        Err(e) => {
+            display(&format!("Error reading the schema of {}: {}", source, e));
+            return Ok(198);
+        }
+    };
+    let source_names: Vec<&str> = source_schema.iter_names().map(|s| s.as_str()).collect();
+    let columns = match resolve_varlist(options.columns, &source_names, options.drop) {
+        Ok(columns) if columns.is_empty() => {
+            display("No columns left to convert");
+            return Ok(198);
+        }
+        Ok(columns) => columns,
+        Err(e) => {
+            display(&e);
+            return Ok(198);
+        }
+    };
+    for name in &options.partition_by {
+        if !columns.iter().any(|c| c == name.as_str()) {
+            display(&format!("partition_by() column {} is not among the converted columns", name));
+            return Ok(198);
+        }
+    }
+
+    let envelope = if options.metadata {
+        match source_metadata(source, options.input_format) {
+            Ok(envelope) => envelope.and_then(|e| carried_metadata(e, &columns, options)),
+            Err(e) => {
+                display(&e);
+                return Ok(198);
+            }
+        }
+    } else {
+        None
+    };
+
+    let sql_if = options.sql_if.filter(|s| !s.trim().is_empty());
+    lf = match apply_user_cast(lf, options.cast_json, options.cast_strict) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&format!("cast failed: {}", e));
+            return Ok(198);
+        }
+    };
+    if let Some(sql) = sql_if {
+        let period_columns = envelope.as_ref().map(stata_metadata::period_columns).unwrap_or_default();
+        lf = match stata_if::filter_lazy_with_periods(lf, sql, &period_columns) {
+            Ok(lf) => lf,
+            Err(e) => {
+                display(&format!("Error in SQL if statement: {}", e));
+                return Ok(198);
+            }
+        };
+    }
+    lf = lf.select(columns.iter().map(|c| col(c.as_str())).collect::<Vec<_>>());
+    if options.compress || options.compress_string {
+        let down_config = DowncastConfig {
+            check_strings: options.compress_string,
+            prefer_int_over_float: options.compress,
+        };
+        lf = match intelligent_downcast(lf, None, Some(options.partition_by.iter().map(|p| p.to_string()).collect()), down_config) {
+            Ok(lf) => lf,
+            Err(e) => {
+                display(&format!("Downcast/compress error: {}", e));
+                return Ok(198);
+            }
+        };
+    }
+    let schema = match lf.collect_schema() {
+        Ok(schema) => schema,
+        Err(e) => {
+            display(&format!("Error resolving the output schema: {}", e));
+            return Ok(198);
+        }
+    };
+
+    let delete_error = if output_format == "parquet" {
+        delete_existing_files(target, true)
+    } else {
+        delete_existing_non_parquet(target)
+    };
+    if delete_error > 0 {
+        return Ok(delete_error);
+    }
+
+    let mut writer = match BatchWriter::create(target, &output_format, &schema, envelope.as_ref(), options) {
+        Ok(writer) => writer,
+        Err(e) => {
+            display(&e);
+            return Ok(198);
+        }
+    };
+
+    // Compression needs the whole column to choose a type, so it always
+    // goes through the lazy plan.
+    let has_glob = source.contains('*') || source.contains('?') || source.contains('[');
+    let batch_format = readstat_format_for_input(options.input_format)
+        .filter(|_| !has_glob && !options.compress && !options.compress_string);
+    let batches = match batch_format {
+        Some(format) => {
+            let projected = projected_readstat_columns(&columns, sql_if);
+            match readstat_batch_iter(
+                source,
+                Some(readstat_scan_options(format)),
+                Some(format),
+                projected,
+                None,
+                Some(CONVERT_BATCH_ROWS),
+            ) {
+                Ok(iter) => Batches::Readstat {
+                    iter,
+                    columns: columns.clone(),
+                    sql_if: sql_if.map(str::to_string),
+                    cast_json: options.cast_json.to_string(),
+                    cast_strict: options.cast_strict,
+                },
+                Err(e) => {
+                    display(&format!("Error creating readstat batch iterator: {}", e));
+                    return Ok(198);
+                }
+            }
+        }
+        None => Batches::streaming(lf),
+    };
+
+    let mut n_rows = 0usize;
+    for batch in batches {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                display(&format!("Error reading {}: {}", source, e));
+                return Ok(198);
+            }
+        };
+        n_rows += batch.height();
+        if let Err(e) = writer.write_batch(batch, target, options) {
+            display(&format!("Error writing {}: {}", target, e));
+            return Ok(198);
+        }
+    }
+
+    match writer.finish(target, &schema, envelope.as_ref(), options) {
+        Ok(Some(adjustments)) => display_xpt_adjustments(&adjustments, options.xpt_version),
+        Ok(None) => {}
+        Err(e) => {
+            display(&format!("Error writing {}: {}", target, e));
+            return Ok(198);
+        }
+    }
+
+    if !options.quietly {
+        display(&format!("Converted {} rows from {} to {}", n_rows, source, target));
+    }
+    Ok(0)
+}
+
+/// The source's metadata restricted to the converted columns. A column
+/// whose type cast() or compress may change loses its recorded Stata type.
+fn carried_metadata(
+    mut envelope: StataMetadataEnvelope,
+    columns: &[String],
+    options: &ConvertOptions,
+) -> Option<StataMetadataEnvelope> {
+    let cast_columns: Vec<String> = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(options.cast_json)
+        .map(|m| m.keys().cloned().collect())
+        .unwrap_or_default();
+    for (name, variable) in envelope.variables.iter_mut() {
+        if options.compress || options.compress_string || cast_columns.contains(name) {
+            variable.stata_type = None;
+        }
+    }
+    metadata_for_columns(envelope, columns)
+}
+
+/// The source metadata restricted to `columns` and the value labels they
+/// use (an extended-missing companion column that was dropped is no longer
+/// referenced); None when nothing is left to carry.
+pub fn metadata_for_columns(
+    mut envelope: StataMetadataEnvelope,
+    columns: &[String],
+) -> Option<StataMetadataEnvelope> {
+    envelope.variables.retain(|name, _| columns.contains(name));
+    for variable in envelope.variables.values_mut() {
+        if variable.extended_missing.as_ref().is_some_and(|c| !columns.contains(c)) {
+            variable.extended_missing = None;
+        }
+    }
+    let used_labels: Vec<String> = envelope
+        .variables
+        .values()
+        .filter_map(|v| v.value_label.clone())
+        .collect();
+    envelope.value_labels.retain(|name, _| used_labels.contains(name));
+
+    let empty = envelope.variables.is_empty()
+        && envelope.dataset_label.as_deref().unwrap_or("").is_empty()
+        && envelope.dataset_notes.is_empty();
+    (!empty).then_some(envelope)
+}
+
+/// Batches of the converted frame, in source order.
+enum Batches {
+    Readstat {
+        iter: ReadstatBatchIter,
+        columns: Vec<String>,
+        sql_if: Option<String>,
+        cast_json: String,
+        cast_strict: bool,
+    },
+    Streaming {
+        receiver: Receiver<DataFrame>,
+        engine: Option<JoinHandle<PolarsResult<()>>>,
+    },
+}
+
+impl Batches {
+    fn streaming(lf: LazyFrame) -> Self {
+        let (sender, receiver) = sync_channel::<DataFrame>(CONVERT_QUEUE_DEPTH);
+        let engine = thread::spawn(move || {
+            let callback = PlanCallback::new(move |df: DataFrame| {
+                // A closed queue means the writer failed and stopped reading.
+                sender
+                    .send(df)
+                    .map_err(|_| polars_err!(ComputeError: "conversion stopped"))?;
+                Ok(false)
+            });
+            lf.sink_batches(callback, true, None)?.collect().map(|_| ())
+        });
+        Batches::Streaming {
+            receiver,
+            engine: Some(engine),
+        }
+    }
+
+    fn next_readstat_batch(
+        iter: &mut ReadstatBatchIter,
+        columns: &[String],
+        sql_if: Option<&str>,
+        cast_json: &str,
+        cast_strict: bool,
+    ) -> Option<PolarsResult<DataFrame>> {
+        let batch = iter.next()?;
+        Some(batch.and_then(|batch| {
+            let batch = apply_user_cast(batch.lazy(), cast_json, cast_strict)?.collect()?;
+            apply_sql_filter_to_batch(batch, sql_if)?.select(columns.iter().map(|c| c.as_str()))
+        }))
+    }
+}
+
+impl Iterator for Batches {
+    type Item = PolarsResult<DataFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Batches::Readstat {
+                iter,
+                columns,
+                sql_if,
+                cast_json,
+                cast_strict,
+            } => Self::next_readstat_batch(iter, columns, sql_if.as_deref(), cast_json, *cast_strict),
+            Batches::Streaming { receiver, engine } => match receiver.recv() {
+                Ok(df) => Some(Ok(df)),
+                // The engine has finished (or failed) once the queue closes.
+                Err(_) => match engine.take()?.join() {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(Err(e)),
+                    Err(_) => Some(Err(polars_err!(ComputeError: "the streaming engine panicked"))),
+                },
+            },
+        }
+    }
+}
+
+/// CSV output, plain or through a gzip/zstd encoder.
+enum CsvSink {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<File>),
+    Zstd(zstd::stream::write::Encoder<'static, File>),
+}
+
+impl Write for CsvSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CsvSink::Plain(w) => w.write(buf),
+            CsvSink::Gzip(w) => w.write(buf),
+            CsvSink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CsvSink::Plain(w) => w.flush(),
+            CsvSink::Gzip(w) => w.flush(),
+            CsvSink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl CsvSink {
+    /// Flushes the file, writing the encoder's trailer.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            CsvSink::Plain(mut w) => w.flush(),
+            CsvSink::Gzip(w) => w.finish().map(|_| ()),
+            CsvSink::Zstd(w) => w.finish().map(|_| ()),
+        }
+    }
+}
+
+enum BatchWriter {
+    Parquet(Box<ParquetBatchedWriter<File>>),
+    /// Each batch is split into the hive directories as it arrives.
+    Partitioned(Option<KeyValueMetadata>),
+    Csv {
+        sink: CsvSink,
+        header_written: bool,
+    },
+    Ipc(Box<IpcFileWriter<File>>),
+    InMemory(Vec<DataFrame>),
+}
+
+impl BatchWriter {
+    fn create(
+        target: &str,
+        output_format: &str,
+        schema: &Schema,
+        envelope: Option<&StataMetadataEnvelope>,
+        options: &ConvertOptions,
+    ) -> Result<Self, String> {
+        let key_value_metadata = envelope.and_then(stata_metadata::build_key_value_metadata);
+        match output_format {
+            "parquet" if !options.partition_by.is_empty() => Ok(BatchWriter::Partitioned(key_value_metadata)),
+            "parquet" => {
+                let file = File::create(target).map_err(|e| format!("Parquet file create error: {}", e))?;
+                parquet_options(options.compression, options.compression_level, key_value_metadata)
+                    .to_writer(file)
+                    .batched(schema)
+                    .map(|writer| BatchWriter::Parquet(Box::new(writer)))
+                    .map_err(|e| format!("Parquet writer error: {}", e))
+            }
+            "csv" => {
+                let compression = csv_output_compression(target, options.compression)?;
+                check_csv_compression_level(compression, options.compression_level)?;
+                let file = File::create(target).map_err(|e| format!("CSV file create error: {}", e))?;
+                let sink = match compression {
+                    None => CsvSink::Plain(BufWriter::new(file)),
+                    Some(Compression::Gzip) => CsvSink::Gzip(flate2::write::GzEncoder::new(
+                        file,
+                        flate2::Compression::new(options.compression_level.unwrap_or(6) as u32),
+                    )),
+                    Some(_) => CsvSink::Zstd(
+                        zstd::stream::write::Encoder::new(file, options.compression_level.unwrap_or(3) as i32)
+                            .map_err(|e| format!("CSV file create error: {}", e))?,
+                    ),
+                };
+                Ok(BatchWriter::Csv {
+                    sink,
+                    header_written: false,
+                })
+            }
+            "ipc" => {
+                let compression = ipc_compression(options.compression)?;
+                let file = File::create(target).map_err(|e| format!("IPC file create error: {}", e))?;
+                let arrow_schema = schema.to_arrow(CompatLevel::newest());
+                let mut writer = IpcFileWriter::new(
+                    file,
+                    Arc::new(arrow_schema),
+                    None,
+                    IpcWriteOptions {
+                        compression: compression.map(Into::into),
+                    },
+                );
+                // The schema metadata is only written with the footer.
+                if let Some(schema_metadata) = envelope.and_then(stata_metadata::build_ipc_schema_metadata) {
+                    writer.set_custom_schema_metadata(Arc::new(schema_metadata));
+                }
+                writer.start().map_err(|e| format!("IPC writer error: {}", e))?;
+                Ok(BatchWriter::Ipc(Box::new(writer)))
+            }
+            "xpt" if !matches!(options.compression.to_ascii_lowercase().as_str(), "" | "uncompressed") => {
+                Err("compression() is not available for xpt output".to_string())
+            }
+            "spss" | "xpt" => {
+                if !options.quietly {
+                    display(&format!(
+                        "note: {} output is assembled in memory before it is written; the whole converted dataset must fit in memory",
+                        if output_format == "spss" { "SPSS" } else { "XPORT" }
+                    ));
+                }
+                Ok(BatchWriter::InMemory(Vec::new()))
+            }
+            other => Err(format!("Unsupported output format: {}", other)),
+        }
+    }
+
+    fn write_batch(&mut self, mut batch: DataFrame, target: &str, options: &ConvertOptions) -> Result<(), Box<dyn Error>> {
+        match self {
+            BatchWriter::Parquet(writer) => writer.write_batch(&batch)?,
+            BatchWriter::Partitioned(key_value_metadata) => {
+                if batch.height() > 0 {
+                    let rc = save_partitioned_sequential(
+                        target,
+                        batch.lazy(),
+                        &PartitionedWriteOptions {
+                            compression: options.compression,
+                            compression_level: options.compression_level,
+                            partition_by: &options.partition_by,
+                            compress: false,
+                            compress_string: false,
+                            quietly: true,
+                            append_to_partition: true,
+                        },
+                        key_value_metadata.clone(),
+                    )?;
+                    if rc != 0 {
+                        return Err("partitioned write failed".into());
+                    }
+                }
+            }
+            BatchWriter::Csv { sink, header_written } => {
+                let first = !*header_written;
+                options
+                    .csv_options
+                    .apply(CsvWriter::new(&mut *sink))
+                    .include_header(first)
+                    .include_bom(first && options.csv_options.bom)
+                    .finish(&mut batch)?;
+                *header_written = true;
+            }
+            BatchWriter::Ipc(writer) => {
+                batch.align_chunks();
+                for chunk in batch.iter_chunks(CompatLevel::newest(), true) {
+                    writer.write(&chunk, None)?;
+                }
+            }
+            BatchWriter::InMemory(frames) => frames.push(batch),
+        }
+        Ok(())
+    }
+
+    /// Completes the file. XPORT output returns the renames and cut labels.
+    fn finish(
+        self,
+        target: &str,
+        schema: &Schema,
+        envelope: Option<&StataMetadataEnvelope>,
+        options: &ConvertOptions,
+    ) -> Result<Option<xpt::XptAdjustments>, Box<dyn Error>> {
+        match self {
+            BatchWriter::Parquet(writer) => {
+                writer.finish()?;
+            }
+            BatchWriter::Partitioned(_) => {}
+            BatchWriter::Ipc(mut writer) => writer.finish()?,
+            BatchWriter::Csv { mut sink, header_written } => {
+                if !header_written {
+                    options
+                        .csv_options
+                        .apply(CsvWriter::new(&mut sink))
+                        .finish(&mut DataFrame::empty_with_schema(schema))?;
+                }
+                sink.finish()?;
+            }
+            BatchWriter::InMemory(frames) => {
+                let mut frames = frames.into_iter();
+                let mut df = frames.next().unwrap_or_else(|| DataFrame::empty_with_schema(schema));
+                for frame in frames {
+                    df.vstack_mut_owned(frame)?;
+                }
+                df.rechunk_mut();
+                return write_in_memory(target, df, envelope, options);
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn write_in_memory(
+    target: &str,
+    df: DataFrame,
+    envelope: Option<&StataMetadataEnvelope>,
+    options: &ConvertOptions,
+) -> Result<Option<xpt::XptAdjustments>, Box<dyn Error>> {
+    match options.output_format.to_ascii_lowercase().as_str() {
+        "spss" => {
+            sav::write_sav(target, &df, envelope, &sav::ExtendedMissingCodes::new())?;
+            Ok(None)
+        }
+        _ => Ok(Some(xpt::write_xpt(target, df, envelope, options.xpt_version)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars_readstat_rs::{ReadStatFormat, SpssWriter};
+
+    fn options(output_format: &str) -> ConvertOptions<'_> {
+        ConvertOptions {
+            input_format: InputFormat::Parquet,
+            csv_dialect: CsvDialect::default(),
+            output_format,
+            columns: "",
+            drop: "",
+            sql_if: None,
+            cast_json: "",
+            cast_strict: true,
+            compress: false,
+            compress_string: false,
+            partition_by: Vec::new(),
+            compression: "",
+            compression_level: None,
+            metadata: true,
+            xpt_version: 5,
+            csv_options: CsvWriteOptions::default(),
+            safe_relaxed: false,
+            asterisk_to_variable: None,
+            preserve_order: false,
+            quietly: true,
+        }
+    }
+
+    fn sample() -> DataFrame {
+        df!(
+            "id" => (1..=1000).collect::<Vec<i64>>(),
+            "wage" => (1..=1000).map(|i| i as f64 * 0.5).collect::<Vec<f64>>(),
+            "state" => (1..=1000).map(|i| ["ny", "ca", "tx"][i % 3]).collect::<Vec<&str>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn streaming_batches_cover_every_row_in_order() {
+        let lf = sample().lazy().filter(col("id").gt(lit(10)));
+        let frames: Vec<DataFrame> = Batches::streaming(lf).collect::<PolarsResult<_>>().unwrap();
+        let ids: Vec<i64> = frames
+            .iter()
+            .flat_map(|f| f.column("id").unwrap().i64().unwrap().into_no_null_iter().collect::<Vec<_>>())
+            .collect();
+        assert_eq!(ids, (11..=1000).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn readstat_batches_apply_cast_filter_and_projection() {
+        let dir = std::env::temp_dir().join(format!("pq_convert_rs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("source.sav");
+        SpssWriter::new(&path).write_df(&sample()).unwrap();
+
+        let iter = readstat_batch_iter(
+            &path,
+            Some(readstat_scan_options(ReadStatFormat::Spss)),
+            Some(ReadStatFormat::Spss),
+            None,
+            None,
+            Some(300),
+        )
+        .unwrap();
+        let batches = Batches::Readstat {
+            iter,
+            columns: vec!["wage".to_string(), "id".to_string()],
+            sql_if: Some("state = 'ca'".to_string()),
+            cast_json: r#"{"id":"int32"}"#.to_string(),
+            cast_strict: true,
+        };
+        let frames: Vec<DataFrame> = batches.collect::<PolarsResult<_>>().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|f| f.get_column_names() == ["wage", "id"]));
+        assert_eq!(frames[0].column("id").unwrap().dtype(), &DataType::Int32);
+        assert_eq!(frames.iter().map(|f| f.height()).sum::<usize>(), 334);
+    }
+
+    #[test]
+    fn metadata_is_limited_to_converted_columns() {
+        let mut envelope = StataMetadataEnvelope {
+            version: 1,
+            ..Default::default()
+        };
+        for (name, label) in [("id", "Person"), ("wage", "Hourly wage")] {
+            envelope.variables.insert(
+                name.to_string(),
+                stata_metadata::VariableMetadata {
+                    label: Some(label.to_string()),
+                    value_label: (name == "id").then(|| "idl".to_string()),
+                    stata_type: Some("double".to_string()),
+                    extended_missing: (name == "wage").then(|| "wage_stata_missing".to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+        envelope.value_labels.insert("idl".to_string(), Default::default());
+
+        let mut opts = options("parquet");
+        opts.cast_json = r#"{"wage":"float32"}"#;
+        let carried = carried_metadata(envelope.clone(), &["wage".to_string()], &opts).unwrap();
+        assert_eq!(carried.variables.len(), 1);
+        assert!(carried.value_labels.is_empty());
+        assert_eq!(carried.variables["wage"].stata_type, None);
+        assert_eq!(carried.variables["wage"].extended_missing, None);
+
+        let columns = ["wage".to_string(), "wage_stata_missing".to_string()];
+        let carried = carried_metadata(envelope.clone(), &columns, &opts).unwrap();
+        assert_eq!(carried.variables["wage"].extended_missing.as_deref(), Some("wage_stata_missing"));
+
+        assert!(carried_metadata(envelope, &["state".to_string()], &opts).is_none());
+    }
+
+    #[test]
+    fn ipc_output_is_written_in_batches_with_metadata() {
+        let dir = std::env::temp_dir().join(format!("pq_convert_ipc_rs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.parquet");
+        let target = dir.join("target.arrow");
+        let mut envelope = StataMetadataEnvelope {
+            version: 1,
+            ..Default::default()
+        };
+        envelope.variables.insert(
+            "wage".to_string(),
+            stata_metadata::VariableMetadata {
+                label: Some("Hourly wage".to_string()),
+                ..Default::default()
+            },
+        );
+        parquet_options("", None, stata_metadata::build_key_value_metadata(&envelope))
+            .to_writer(File::create(&source).unwrap())
+            .finish(&mut sample())
+            .unwrap();
+
+        let rc = convert_file(source.to_str().unwrap(), target.to_str().unwrap(), &options("ipc")).unwrap();
+        let df = IpcReader::new(File::open(&target).unwrap()).finish().unwrap();
+        let carried = stata_metadata::read_metadata_validated(target.to_str().unwrap()).unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(rc, 0);
+        assert!(df.equals(&sample()));
+        assert_eq!(carried.variables["wage"].label.as_deref(), Some("Hourly wage"));
+    }
+
+    #[test]
+    fn csv_input_is_read_with_the_given_dialect() {
+        let dir = std::env::temp_dir().join(format!("pq_convert_csv_rs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.csv");
+        let target = dir.join("target.parquet");
+        std::fs::write(&source, "id;wage\n1;2,5\n2;3,0\n").unwrap();
+
+        let mut opts = options("parquet");
+        opts.input_format = InputFormat::Csv;
+        opts.csv_dialect = CsvDialect::from_json(r#"{"separator":";","decimal_comma":true}"#).unwrap();
+        let rc = convert_file(source.to_str().unwrap(), target.to_str().unwrap(), &opts).unwrap();
+        let df = ParquetReader::new(File::open(&target).unwrap()).finish().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(rc, 0);
+        assert_eq!(df.get_column_names(), ["id", "wage"]);
+        assert_eq!(df.column("wage").unwrap().f64().unwrap().get(0), Some(2.5));
+    }
+}
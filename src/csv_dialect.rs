@@ -0,0 +1,464 @@
+//! CSV dialect options (separator, quoting, header, skipped rows, comments,
+//! null tokens, decimal comma, encoding). pq.ado stages them as JSON in the
+//! pq_csv_opts local, and describe, read and write_overflow_dta all scan
+//! through `CsvDialect::apply` so the three passes parse a file the same way.
+//!
+//! `CsvWriteOptions` is the pq save counterpart, staged the same way.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::decompress::{open_decoded, strip_compression_extension, track_spooled_dir};
+use crate::stata_interface::get_macro;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvDialect {
+    /// Single-byte field separator; "tab" and "\t" are accepted for a tab.
+    pub separator: Option<String>,
+    /// Quote character; an empty string turns quoting off.
+    pub quote: Option<String>,
+    /// The first row is data; columns are named column_1, column_2, ...
+    pub no_header: bool,
+    /// Lines skipped before the header (or the first data row).
+    pub skip_rows: usize,
+    pub comment_prefix: Option<String>,
+    /// Values read as missing in every column, e.g. "NA" or "-9".
+    pub null_values: Vec<String>,
+    pub decimal_comma: bool,
+    /// utf8 (default), utf8-lossy, latin1 or windows-1252.
+    pub encoding: Option<String>,
+}
+
+/// Encodings Polars can't read directly; these files are transcoded to a
+/// UTF-8 copy first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SingleByteEncoding {
+    Latin1,
+    Windows1252,
+}
+
+impl CsvDialect {
+    /// Parses and validates the pq_csv_opts JSON. Empty means all defaults.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        if json.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let dialect: Self = serde_json::from_str(json)
+            .map_err(|e| format!("Invalid CSV options: {}", e))?;
+        dialect.validated()
+    }
+
+    /// The dialect, if its options are usable together; also used for the
+    /// command-line flags, which build the struct directly.
+    pub fn validated(self) -> Result<Self, String> {
+        self.separator_byte()?;
+        self.quote_byte()?;
+        self.single_byte_encoding()?;
+        if self.comment_prefix.as_deref() == Some("") {
+            return Err("comment() may not be empty".to_string());
+        }
+        if self.decimal_comma && self.separator_byte()? == b',' {
+            return Err("decimal_comma requires a separator() other than a comma".to_string());
+        }
+        Ok(self)
+    }
+
+    /// Plugin argument form: "pq_csv_opts" is a sentinel naming the local
+    /// the ado staged the JSON in, since the JSON itself contains quotes.
+    /// pq convert, which also takes CSV output options, stages the input
+    /// dialect in "pq_csv_read_opts".
+    pub fn from_arg(arg: Option<&str>) -> Result<Self, String> {
+        match arg {
+            Some(name @ ("pq_csv_opts" | "pq_csv_read_opts")) => {
+                Self::from_json(&get_macro(name, false, Some(1024 * 1024)))
+            }
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Stable text form for cache keys.
+    pub fn as_key(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    fn separator_byte(&self) -> Result<u8, String> {
+        separator_byte(self.separator.as_deref())
+    }
+
+    fn quote_byte(&self) -> Result<Option<u8>, String> {
+        match self.quote.as_deref() {
+            None => Ok(Some(b'"')),
+            Some("") => Ok(None),
+            Some(s) if s.len() == 1 => Ok(Some(s.as_bytes()[0])),
+            Some(s) => Err(format!("quote() must be a single character, passed \"{}\"", s)),
+        }
+    }
+
+    fn single_byte_encoding(&self) -> Result<Option<SingleByteEncoding>, String> {
+        match self.encoding.as_deref().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("") | Some("utf8") | Some("utf-8") | Some("utf8-lossy") => Ok(None),
+            Some("latin1") | Some("iso-8859-1") => Ok(Some(SingleByteEncoding::Latin1)),
+            Some("windows-1252") | Some("cp1252") => Ok(Some(SingleByteEncoding::Windows1252)),
+            Some(other) => Err(format!(
+                "encoding({}) is not supported; use utf8, utf8-lossy, latin1, or windows-1252",
+                other
+            )),
+        }
+    }
+
+    /// Applies the dialect to a CSV reader. Options were validated in
+    /// from_json, so invalid values fall back to the defaults here.
+    pub fn apply(&self, reader: LazyCsvReader) -> LazyCsvReader {
+        let reader = reader
+            .with_separator(self.separator_byte().unwrap_or(b','))
+            .with_quote_char(self.quote_byte().unwrap_or(Some(b'"')))
+            .with_has_header(!self.no_header)
+            .with_skip_rows(self.skip_rows)
+            .with_comment_prefix(self.comment_prefix.as_deref().map(PlSmallStr::from))
+            .with_decimal_comma(self.decimal_comma);
+
+        let reader = if self.null_values.is_empty() {
+            reader
+        } else {
+            reader.with_null_values(Some(NullValues::AllColumns(
+                self.null_values.iter().map(|v| PlSmallStr::from(v.as_str())).collect(),
+            )))
+        };
+
+        if self.encoding.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("utf8-lossy")) {
+            reader.with_encoding(CsvEncoding::LossyUtf8)
+        } else {
+            reader
+        }
+    }
+
+    /// The path to scan: `path` itself for UTF-8 input, otherwise a glob over
+    /// UTF-8 copies of every matching file, each under its own name in a
+    /// numbered subdirectory. The copies live in a temp
+    /// directory keyed on the files' names, sizes and modification times, so
+    /// the describe, read and overflow passes transcode once and share them;
+    /// the directory is removed with the decompressed copies once the command
+    /// finishes (see `decompress::remove_spooled_inputs`).
+    pub fn source_path(&self, path: &str) -> Result<String, PolarsError> {
+        let Some(encoding) = self.single_byte_encoding().map_err(|e| PolarsError::ComputeError(e.into()))? else {
+            return Ok(path.to_string());
+        };
+
+        let normalized = path.replace('\\', "/").replace("**.", "**/*.");
+        let mut files: Vec<PathBuf> = glob(&normalized)
+            .map_err(|e| PolarsError::ComputeError(format!("Invalid glob pattern: {}", e).into()))?
+            .filter_map(Result::ok)
+            .filter(|p| p.is_file())
+            .collect();
+        if files.is_empty() {
+            return Err(PolarsError::ComputeError(
+                format!("No files found matching pattern: {}", normalized).into(),
+            ));
+        }
+        files.sort();
+
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}", encoding).hash(&mut hasher);
+        for file in &files {
+            file.hash(&mut hasher);
+            if let Ok(meta) = fs::metadata(file) {
+                meta.len().hash(&mut hasher);
+                meta.modified().ok().hash(&mut hasher);
+            }
+        }
+        let dir = std::env::temp_dir().join(format!("pq_csv_utf8_{:016x}", hasher.finish()));
+        track_spooled_dir(&dir);
+        let done_marker = dir.join(".complete");
+
+        if !done_marker.exists() {
+            fs::create_dir_all(&dir).map_err(|e| PolarsError::ComputeError(e.to_string().into()))?;
+            for (i, file) in files.iter().enumerate() {
+                let target = dir.join(transcoded_name(i, file));
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(|e| PolarsError::ComputeError(e.to_string().into()))?;
+                }
+                transcode_file(file, &target, encoding)
+                    .map_err(|e| PolarsError::ComputeError(format!("Error transcoding {}: {}", file.display(), e).into()))?;
+            }
+            fs::write(&done_marker, b"").map_err(|e| PolarsError::ComputeError(e.to_string().into()))?;
+        }
+
+        Ok(format!("{}/*/*", dir.to_string_lossy().replace('\\', "/")))
+    }
+}
+
+fn separator_byte(separator: Option<&str>) -> Result<u8, String> {
+    match separator {
+        None | Some("") => Ok(b','),
+        Some("tab") | Some("\\t") | Some("\t") => Ok(b'\t'),
+        Some(s) if s.len() == 1 => Ok(s.as_bytes()[0]),
+        Some(s) => Err(format!("delimiter() must be a single character, passed \"{}\"", s)),
+    }
+}
+
+/// CSV output options for pq save. Value labels are handled in the ado
+/// (the label option decodes before the plugin sees the data).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvWriteOptions {
+    pub separator: Option<String>,
+    /// always, necessary (default), non_numeric or never.
+    pub quote_style: Option<String>,
+    /// lf (default) or crlf.
+    pub line_terminator: Option<String>,
+    /// Text written for missing values; empty by default.
+    pub null_value: Option<String>,
+    /// Digits after the decimal point for float/double columns.
+    pub float_precision: Option<usize>,
+    /// Some(true) forces scientific notation, Some(false) rules it out.
+    pub float_scientific: Option<bool>,
+    /// chrono strftime formats, e.g. "%d/%m/%Y".
+    pub date_format: Option<String>,
+    pub datetime_format: Option<String>,
+    /// Start the file with a UTF-8 byte order mark (for Excel).
+    pub bom: bool,
+}
+
+impl CsvWriteOptions {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        if json.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let options: Self = serde_json::from_str(json)
+            .map_err(|e| format!("Invalid CSV options: {}", e))?;
+        options.validated()
+    }
+
+    /// As `CsvDialect::validated`.
+    pub fn validated(self) -> Result<Self, String> {
+        separator_byte(self.separator.as_deref())?;
+        self.quote_style()?;
+        self.line_terminator()?;
+        Ok(self)
+    }
+
+    /// Same "pq_csv_opts" sentinel as `CsvDialect::from_arg`.
+    pub fn from_arg(arg: Option<&str>) -> Result<Self, String> {
+        match arg {
+            Some("pq_csv_opts") => Self::from_json(&get_macro("pq_csv_opts", false, Some(1024 * 1024))),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    fn quote_style(&self) -> Result<QuoteStyle, String> {
+        match self.quote_style.as_deref().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("") | Some("necessary") => Ok(QuoteStyle::Necessary),
+            Some("always") => Ok(QuoteStyle::Always),
+            Some("non_numeric") => Ok(QuoteStyle::NonNumeric),
+            Some("never") => Ok(QuoteStyle::Never),
+            Some(other) => Err(format!(
+                "quote_style({}) is not supported; use always, necessary, non_numeric, or never",
+                other
+            )),
+        }
+    }
+
+    fn line_terminator(&self) -> Result<&'static str, String> {
+        match self.line_terminator.as_deref().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("") | Some("lf") => Ok("\n"),
+            Some("crlf") => Ok("\r\n"),
+            Some(other) => Err(format!("line_terminator({}) is not supported; use lf or crlf", other)),
+        }
+    }
+
+    /// Options were validated in from_json, so invalid values fall back to
+    /// the defaults here.
+    pub fn apply<W: Write>(&self, writer: CsvWriter<W>) -> CsvWriter<W> {
+        let writer = writer
+            .include_header(true)
+            .include_bom(self.bom)
+            .with_separator(separator_byte(self.separator.as_deref()).unwrap_or(b','))
+            .with_quote_style(self.quote_style().unwrap_or_default())
+            .with_line_terminator(PlSmallStr::from(self.line_terminator().unwrap_or("\n")))
+            .with_float_precision(self.float_precision)
+            .with_float_scientific(self.float_scientific)
+            .with_date_format(self.date_format.as_deref().map(PlSmallStr::from))
+            .with_datetime_format(self.datetime_format.as_deref().map(PlSmallStr::from));
+
+        match self.null_value.as_deref() {
+            Some(null_value) => writer.with_null_value(PlSmallStr::from(null_value)),
+            None => writer,
+        }
+    }
+}
+
+/// The copy's path within the temp directory: the file's own name (less a
+/// compression suffix) in a subdirectory zero-padded so the copies glob back
+/// in the original file order.
+fn transcoded_name(index: usize, file: &Path) -> PathBuf {
+    let name = file.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    Path::new(&format!("{:06}", index)).join(strip_compression_extension(&name))
+}
+
+/// Windows-1252 differs from Latin-1 only in 0x80-0x9F; the five undefined
+/// bytes there keep their Latin-1 (C1 control) meaning.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// Streams `source` (decoded first if it is gzip/zstd) into a UTF-8 copy
+/// at `target`. Every byte is one character, so chunks decode on their own.
+fn transcode_file(source: &Path, target: &Path, encoding: SingleByteEncoding) -> io::Result<()> {
+    let mut input = BufReader::new(open_decoded(source)?);
+    let mut output = BufWriter::new(File::create(target)?);
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(decode_single_byte(&chunk[..n], encoding).as_bytes())?;
+    }
+    output.flush()
+}
+
+fn decode_single_byte(bytes: &[u8], encoding: SingleByteEncoding) -> String {
+    bytes
+        .iter()
+        .map(|&b| match (encoding, b) {
+            (SingleByteEncoding::Windows1252, 0x80..=0x9F) => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(dir: &Path, name: &str, contents: &[u8], dialect: &CsvDialect) -> DataFrame {
+        let file = dir.join(name);
+        fs::write(&file, contents).unwrap();
+        let path = dialect.source_path(file.to_str().unwrap()).unwrap();
+        dialect
+            .apply(LazyCsvReader::new(PlRefPath::new(path.as_str())).with_glob(true))
+            .finish()
+            .unwrap()
+            .collect()
+            .unwrap()
+    }
+
+    #[test]
+    fn semicolon_file_with_preamble_nulls_and_decimal_comma() {
+        let dir = std::env::temp_dir().join(format!("pq_csv_dialect_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dialect = CsvDialect::from_json(
+            r##"{"separator": ";", "skip_rows": 2, "comment_prefix": "#",
+                "null_values": ["NA", "-9"], "decimal_comma": true}"##,
+        )
+        .unwrap();
+        let df = scan(
+            &dir,
+            "semi.csv",
+            b"exported 2026-01-01\nsource: api\nid;wage;city\n1;12,5;Oslo\n# dropped\n2;NA;-9\n",
+            &dialect,
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(df.height(), 2);
+        assert_eq!(df.column("wage").unwrap().f64().unwrap().get(0), Some(12.5));
+        assert_eq!(df.column("wage").unwrap().null_count(), 1);
+        assert_eq!(df.column("city").unwrap().null_count(), 1);
+    }
+
+    #[test]
+    fn headerless_tab_file_gets_generated_names() {
+        let dir = std::env::temp_dir().join(format!("pq_csv_noheader_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dialect = CsvDialect::from_json(r#"{"separator": "tab", "no_header": true, "quote": ""}"#).unwrap();
+        let df = scan(&dir, "tab.tsv", b"1\t\"a\"\n2\tb\n", &dialect);
+        let _ = fs::remove_dir_all(&dir);
+
+        let names: Vec<&str> = df.get_column_names().iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["column_1", "column_2"]);
+        assert_eq!(df.column("column_2").unwrap().str().unwrap().get(0), Some("\"a\""));
+    }
+
+    #[test]
+    fn windows_1252_is_transcoded() {
+        assert_eq!(decode_single_byte(b"caf\xe9 \x80", SingleByteEncoding::Windows1252), "café €");
+        assert_eq!(decode_single_byte(b"caf\xe9 \x80", SingleByteEncoding::Latin1), "café \u{80}");
+    }
+
+    #[test]
+    fn compressed_latin1_file_is_transcoded_to_a_tracked_copy() {
+        let _guard = crate::decompress::SPOOL_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("pq_csv_latin1_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dialect = CsvDialect::from_json(r#"{"encoding": "latin1"}"#).unwrap();
+        let df = scan(&dir, "latin.csv.gz", &gzip(b"city\nK\xf8benhavn\n"), &dialect);
+        assert_eq!(df.column("city").unwrap().str().unwrap().get(0), Some("København"));
+
+        let copies = dialect.source_path(dir.join("latin.csv.gz").to_str().unwrap()).unwrap();
+        let copy_dir = Path::new(&copies).parent().and_then(Path::parent).unwrap().to_path_buf();
+        assert!(copy_dir.exists());
+        crate::decompress::remove_spooled_inputs();
+        assert!(!copy_dir.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn write_options_shape_the_output() {
+        let mut df = df!(
+            "id" => [1i32, 2],
+            "x" => [Some(1.23456f64), None],
+            "d" => [19783i32, 20088], // 2024-03-01, 2024-12-31
+            "s" => ["a;b", "c"],
+        )
+        .unwrap()
+        .lazy()
+        .with_column(col("d").cast(DataType::Date))
+        .collect()
+        .unwrap();
+        let options = CsvWriteOptions::from_json(
+            r#"{"separator": ";", "quote_style": "non_numeric", "line_terminator": "crlf",
+                "null_value": "NA", "float_precision": 2, "date_format": "%d/%m/%Y", "bom": true}"#,
+        )
+        .unwrap();
+
+        let mut buf: Vec<u8> = Vec::new();
+        options.apply(CsvWriter::new(&mut buf)).finish(&mut df).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            text,
+            "\u{FEFF}\"id\";\"x\";\"d\";\"s\"\r\n1;1.23;\"01/03/2024\";\"a;b\"\r\n2;NA;\"31/12/2024\";\"c\"\r\n"
+        );
+
+        assert!(CsvWriteOptions::from_json(r#"{"quote_style": "sometimes"}"#).is_err());
+        assert!(CsvWriteOptions::from_json(r#"{"line_terminator": "cr"}"#).is_err());
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(CsvDialect::from_json(r#"{"separator": "||"}"#).is_err());
+        assert!(CsvDialect::from_json(r#"{"decimal_comma": true}"#).is_err());
+        assert!(CsvDialect::from_json(r#"{"encoding": "ebcdic"}"#).is_err());
+        assert!(CsvDialect::from_json("").unwrap().is_default());
+    }
+}
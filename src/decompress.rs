@@ -0,0 +1,384 @@
+//! Transparent decompression of gzip/zstd/bzip2/xz input files.
+//!
+//! Polars decodes gzip and zstd CSV/NDJSON itself as it reads, so those are
+//! scanned in place. Every other reader (readstat for SAS/SPSS/dta, Polars
+//! for Parquet/IPC) needs a plain file it can seek in, so compressed inputs
+//! are streamed through the decoder into a temp directory and the scan is
+//! pointed there instead; bzip2/xz CSV/NDJSON are recompressed to zstd there
+//! rather than spooled out in full. Uncompressed files matched by the same
+//! glob are hard-linked (or copied) alongside, keeping their place relative
+//! to the glob's base directory so the rewritten pattern matches the same
+//! files and asterisk_to_variable still extracts the same values.
+//!
+//! The temp directories outlive a single plugin call (describe, read and
+//! the overflow passes share them) and are removed by
+//! `remove_spooled_inputs`, which pq.ado calls through the "cleanup"
+//! subfunction once each pq command finishes.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use glob::glob;
+
+use crate::read::InputFormat;
+
+/// Temp directories created (or reused) since the last cleanup.
+static SPOOLED_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// `remove_spooled_inputs` clears every tracked directory, so tests that
+/// spool files take turns.
+#[cfg(test)]
+pub static SPOOL_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    const EXTENSIONS: [(&'static str, Compression); 6] = [
+        (".gz", Compression::Gzip),
+        (".gzip", Compression::Gzip),
+        (".zst", Compression::Zstd),
+        (".zstd", Compression::Zstd),
+        (".bz2", Compression::Bzip2),
+        (".xz", Compression::Xz),
+    ];
+
+    /// The compression a file's leading magic bytes identify.
+    fn sniff(header: &[u8]) -> Option<Self> {
+        match header {
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Compression::Zstd),
+            [b'B', b'Z', b'h', ..] => Some(Compression::Bzip2),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    /// The compression implied by a file name's final extension.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let lower = path.to_ascii_lowercase();
+        Self::EXTENSIONS
+            .iter()
+            .find(|(ext, _)| lower.ends_with(ext))
+            .map(|(_, c)| *c)
+    }
+}
+
+/// `path` with a trailing compression extension removed
+/// ("wave_*.csv.gz" -> "wave_*.csv").
+pub fn strip_compression_extension(path: &str) -> &str {
+    let lower = path.to_ascii_lowercase();
+    for (ext, _) in Compression::EXTENSIONS {
+        if lower.ends_with(ext) {
+            return &path[..path.len() - ext.len()];
+        }
+    }
+    path
+}
+
+/// Returns the path to scan in place of `path`: unchanged when no matching
+/// file needs a temp copy, otherwise the equivalent pattern over the copies.
+/// Copies are cached in a directory keyed on the matched files' names, sizes
+/// and modification times, so the describe, read and overflow passes
+/// decompress once between them.
+pub fn resolve_input_path(path: &str, input_format: InputFormat) -> Result<String, String> {
+    let normalized = path.replace('\\', "/");
+    let files: Vec<PathBuf> = if has_wildcard(&normalized) {
+        let mut files: Vec<PathBuf> = glob(&normalized.replace("**.", "**/*."))
+            .map_err(|e| format!("Invalid glob pattern: {}", e))?
+            .filter_map(Result::ok)
+            .filter(|p| p.is_file())
+            .collect();
+        files.sort();
+        files
+    } else if Path::new(&normalized).is_file() {
+        vec![PathBuf::from(&normalized)]
+    } else {
+        // Directories (hive datasets) and missing paths are left to the reader.
+        vec![]
+    };
+
+    let streams_in_place = |compression: Compression| {
+        matches!(input_format, InputFormat::Csv | InputFormat::Ndjson)
+            && matches!(compression, Compression::Gzip | Compression::Zstd)
+    };
+    if !files.iter().any(|f| {
+        Compression::from_path(&f.to_string_lossy()).is_some_and(|c| !streams_in_place(c))
+    }) {
+        return Ok(path.to_string());
+    }
+
+    let base = glob_base_dir(&normalized);
+    let mut hasher = DefaultHasher::new();
+    normalized.hash(&mut hasher);
+    input_format.as_str().hash(&mut hasher);
+    for file in &files {
+        file.hash(&mut hasher);
+        if let Ok(meta) = fs::metadata(file) {
+            meta.len().hash(&mut hasher);
+            meta.modified().ok().hash(&mut hasher);
+        }
+    }
+    let dir = std::env::temp_dir().join(format!("pq_decompressed_{:016x}", hasher.finish()));
+    track_spooled_dir(&dir);
+    let done_marker = dir.join(".complete");
+
+    if !done_marker.exists() {
+        for file in &files {
+            let relative = file.strip_prefix(base).unwrap_or(file.as_path());
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let target = dir.join(strip_compression_extension(&relative));
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            let spooled = match Compression::from_path(&relative) {
+                Some(compression) if streams_in_place(compression) => link_or_copy(file, &target),
+                // Polars reads the zstd copy as it would the original; only
+                // the formats that need to seek get a decompressed copy.
+                Some(compression) if matches!(input_format, InputFormat::Csv | InputFormat::Ndjson) => {
+                    recompress_to_zstd(file, &target, compression)
+                }
+                Some(compression) => decompress_file(file, &target, compression),
+                None => link_or_copy(file, &target),
+            };
+            spooled.map_err(|e| format!("Error decompressing {}: {}", file.display(), e))?;
+        }
+        fs::write(&done_marker, b"").map_err(|e| e.to_string())?;
+    }
+
+    let remainder = normalized[base.len()..].trim_start_matches('/');
+    Ok(format!(
+        "{}/{}",
+        dir.to_string_lossy().replace('\\', "/"),
+        strip_compression_extension(remainder)
+    ))
+}
+
+/// Records a temp directory for `remove_spooled_inputs`.
+pub fn track_spooled_dir(dir: &Path) {
+    if let Ok(mut dirs) = SPOOLED_DIRS.lock() {
+        if !dirs.iter().any(|d| d == dir) {
+            dirs.push(dir.to_path_buf());
+        }
+    }
+}
+
+/// Deletes every temp copy made since the last call.
+pub fn remove_spooled_inputs() {
+    let dirs = match SPOOLED_DIRS.lock() {
+        Ok(mut dirs) => std::mem::take(&mut *dirs),
+        Err(_) => return,
+    };
+    for dir in dirs {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+/// Opens `path` for reading, decoding it on the fly when its leading bytes
+/// mark it as gzip or zstd (the compressions CSV/NDJSON inputs still carry
+/// after `resolve_input_path`).
+pub fn open_decoded(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let compression = Compression::sniff(input.fill_buf()?);
+    Ok(match compression {
+        Some(Compression::Gzip) => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+        Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::with_buffer(input)?),
+        Some(other) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} input should have been recompressed before reading", other),
+            ))
+        }
+        None => Box::new(input),
+    })
+}
+
+fn has_wildcard(path: &str) -> bool {
+    path.contains('*') || path.contains('?') || path.contains('[')
+}
+
+/// The directory part of `pattern` before the first wildcard component
+/// (the file's own directory for a plain path).
+fn glob_base_dir(pattern: &str) -> &str {
+    let first_wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+    match pattern[..first_wildcard].rfind('/') {
+        Some(i) => &pattern[..i],
+        None => "",
+    }
+}
+
+/// Streams `source` through the decoder into `target`; the whole file is
+/// never held in memory.
+fn decompress_file(source: &Path, target: &Path, compression: Compression) -> io::Result<()> {
+    let mut output = BufWriter::new(File::create(target)?);
+    decompress_into(source, &mut output, compression)?;
+    output.flush()
+}
+
+fn decompress_into<W: Write>(source: &Path, output: &mut W, compression: Compression) -> io::Result<()> {
+    let input = BufReader::new(File::open(source)?);
+    match compression {
+        Compression::Gzip => copy_from(flate2::read::MultiGzDecoder::new(input), output),
+        Compression::Zstd => copy_from(zstd::stream::read::Decoder::with_buffer(input)?, output),
+        Compression::Bzip2 => copy_from(bzip2::read::MultiBzDecoder::new(input), output),
+        Compression::Xz => {
+            let mut input = input;
+            lzma_rs::xz_decompress(&mut input, output)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+        }
+    }
+}
+
+/// Streams `source` through its decoder and a zstd encoder into `target`,
+/// so the copy stays close to the compressed size.
+fn recompress_to_zstd(source: &Path, target: &Path, compression: Compression) -> io::Result<()> {
+    let output = BufWriter::new(File::create(target)?);
+    let mut encoder = zstd::stream::write::Encoder::new(output, 1)?;
+    decompress_into(source, &mut encoder, compression)?;
+    encoder.finish()?.flush()
+}
+
+fn link_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(target);
+    if fs::hard_link(source, target).is_err() {
+        fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+fn copy_from<R: Read, W: Write>(mut reader: R, writer: &mut W) -> io::Result<()> {
+    io::copy(&mut reader, writer).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixed_glob_is_rewritten_over_decompressed_copies() {
+        let _guard = SPOOL_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("pq_decompress_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_compressed_parts(&dir, "arrow");
+
+        let pattern = format!("{}/part_*", dir.to_string_lossy().replace('\\', "/"));
+        let resolved = resolve_input_path(&pattern, InputFormat::Ipc).unwrap();
+        assert!(resolved.ends_with("/part_*"));
+        assert_ne!(resolved, pattern);
+
+        let mut contents: Vec<String> = glob(&resolved)
+            .unwrap()
+            .filter_map(Result::ok)
+            .map(|p| fs::read_to_string(p).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents.len(), 5);
+        assert_eq!(contents[4], "id,x\n5,e\n");
+
+        // Resolving again reuses the cached copies.
+        assert_eq!(resolve_input_path(&pattern, InputFormat::Ipc).unwrap(), resolved);
+
+        // Plain inputs pass through untouched.
+        let plain_path = format!("{}/part_1.arrow", dir.to_string_lossy());
+        assert_eq!(resolve_input_path(&plain_path, InputFormat::Ipc).unwrap(), plain_path);
+
+        let _ = fs::remove_dir_all(&dir);
+        remove_spooled_inputs();
+        assert!(!Path::new(&resolved).parent().unwrap().exists());
+    }
+
+    #[test]
+    fn csv_inputs_are_decoded_as_they_are_read() {
+        let _guard = SPOOL_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("pq_decompress_csv_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_compressed_parts(&dir, "csv");
+        let dir_path = dir.to_string_lossy().replace('\\', "/");
+
+        // gzip and zstd need no copy at all.
+        let gz_path = format!("{}/part_2.csv.gz", dir_path);
+        assert_eq!(resolve_input_path(&gz_path, InputFormat::Csv).unwrap(), gz_path);
+        let df = crate::read::scan_lazyframe(&gz_path, false, None, InputFormat::Csv)
+            .unwrap()
+            .collect()
+            .unwrap();
+        assert_eq!(df.column("x").unwrap().str().unwrap().get(0), Some("b"));
+
+        let ndjson_path = format!("{}/rows.jsonl.zst", dir_path);
+        fs::write(&ndjson_path, zstd::encode_all(&b"{\"id\":1}\n{\"id\":2}\n"[..], 0).unwrap()).unwrap();
+        assert_eq!(resolve_input_path(&ndjson_path, InputFormat::Ndjson).unwrap(), ndjson_path);
+        let df = crate::read::scan_lazyframe(&ndjson_path, false, None, InputFormat::Ndjson)
+            .unwrap()
+            .collect()
+            .unwrap();
+        assert_eq!(df.height(), 2);
+
+        // bzip2/xz copies are zstd, not the full decompressed text.
+        let pattern = format!("{}/part_*", dir_path);
+        let resolved = resolve_input_path(&pattern, InputFormat::Csv).unwrap();
+        assert_ne!(resolved, pattern);
+        let xz_copy = Path::new(&resolved).parent().unwrap().join("part_5.csv");
+        assert_eq!(Compression::sniff(&fs::read(&xz_copy).unwrap()), Some(Compression::Zstd));
+
+        let mut contents: Vec<String> = glob(&resolved)
+            .unwrap()
+            .filter_map(Result::ok)
+            .map(|p| {
+                let mut text = String::new();
+                open_decoded(&p).unwrap().read_to_string(&mut text).unwrap();
+                text
+            })
+            .collect();
+        contents.sort();
+        assert_eq!(contents.len(), 5);
+        assert_eq!(contents[4], "id,x\n5,e\n");
+
+        let df = crate::read::scan_lazyframe(&resolved, false, None, InputFormat::Csv)
+            .unwrap()
+            .collect()
+            .unwrap();
+        assert_eq!(df.height(), 5);
+
+        let _ = fs::remove_dir_all(&dir);
+        remove_spooled_inputs();
+        assert!(!Path::new(&resolved).parent().unwrap().exists());
+    }
+
+    /// part_1 plain, then gzip, zstd, bzip2 and xz, each holding one row.
+    fn write_compressed_parts(dir: &Path, extension: &str) {
+        fs::write(dir.join(format!("part_1.{}", extension)), b"id,x\n1,a\n").unwrap();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"id,x\n2,b\n").unwrap();
+        fs::write(dir.join(format!("part_2.{}.gz", extension)), gz.finish().unwrap()).unwrap();
+        fs::write(
+            dir.join(format!("part_3.{}.zst", extension)),
+            zstd::encode_all(&b"id,x\n3,c\n"[..], 0).unwrap(),
+        )
+        .unwrap();
+
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(b"id,x\n4,d\n").unwrap();
+        fs::write(dir.join(format!("part_4.{}.bz2", extension)), bz.finish().unwrap()).unwrap();
+
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &b"id,x\n5,e\n"[..], &mut xz).unwrap();
+        fs::write(dir.join(format!("part_5.{}.xz", extension)), xz).unwrap();
+    }
+
+    #[test]
+    fn compression_extension_is_stripped() {
+        assert_eq!(strip_compression_extension("wave_*.sas7bdat.GZ"), "wave_*.sas7bdat");
+        assert_eq!(strip_compression_extension("a.csv"), "a.csv");
+        assert_eq!(Compression::from_path("x.parquet.zst"), Some(Compression::Zstd));
+    }
+}
@@ -1,5 +1,5 @@
 use polars::prelude::*;
-use polars_sql::SQLContext;
+use crate::stata_if;
 use polars_readstat_rs::{readstat_metadata_json, ReadStatFormat};
 use serde_json::Value;
 use std::collections::HashMap;
@@ -7,7 +7,9 @@ use std::time::{Duration, Instant};
 use glob::glob;
 
 use crate::fast_cache::{self, FastCacheKey, resolve_varlist};
-use crate::mapping::{is_string_type, schema_with_stata_types, widen_with_recorded_type, StataType};
+use crate::csv_dialect::CsvDialect;
+use crate::nested::{flatten_nested_columns, unhandled_nested_columns, widths_to_json, NestedOptions};
+use crate::mapping::{generate_rename_map, is_string_type, schema_with_stata_types, widen_with_recorded_type, StataType};
 use crate::stata_interface::{
     ST_retcode,
     display,
@@ -20,13 +22,19 @@ use crate::utilities::{ms, normalize_path_separators, profile_timing_enabled};
 use crate::read::{
     InputFormat,
     cast_catenum_to_string,
+    categorical_dictionaries,
+    durations_as_numbers,
+    encode_catenum_columns,
     filtered_row_count_readstat_with_sql,
+    localize_time_zones,
     scan_lazyframe_with_options,
 };
 
 use crate::downcast::{
     apply_user_cast,
     find_optimal_integer_type,
+    DecimalMode,
+    DECIMAL_SCALED_CAST,
     intelligent_downcast,
     polars_type_to_stata_type,
     validate_user_type,
@@ -53,6 +61,10 @@ pub fn file_summary(
     binary_to_string: bool,
     cast_strict: bool,
     safe_int64: bool,
+    nested: NestedOptions,
+    decimal_mode: DecimalMode,
+    encode: bool,
+    csv_dialect: &CsvDialect,
 ) -> i32 {
     let prof = profile_timing_enabled();
     let t_total = Instant::now();
@@ -76,14 +88,20 @@ pub fn file_summary(
     // factor so that a 25 MB parquet file counts as ~100 MB of estimated RAM.
     // CSV, SAS, and SPSS are roughly 1:1 (on-disk ≈ in-memory).
     const PARQUET_RAM_EXPANSION: u64 = 4;
-    let file_bytes = total_file_size_bytes(path);
+    let file_bytes = match input_format {
+        // A pq sql query is sized by the files it reads.
+        InputFormat::Sql => crate::sql_query::rewrite_file_references(path)
+            .map(|(_, tables)| tables.iter().map(|t| total_file_size_bytes(&t.path)).sum())
+            .unwrap_or(0),
+        _ => total_file_size_bytes(path),
+    };
     let estimated_ram_mb = match input_format {
         InputFormat::Parquet => (file_bytes / (1024 * 1024)).saturating_mul(PARQUET_RAM_EXPANSION),
         _ => file_bytes / (1024 * 1024),
     };
     let effective_fast = fast || estimated_ram_mb < auto_fast_limit_mb;
 
-    let csv_infer_schema_length = if matches!(input_format, InputFormat::Csv) {
+    let scan_infer_schema_length = if matches!(input_format, InputFormat::Csv | InputFormat::Ndjson) {
         if infer_schema_length == 0 {
             None
         } else {
@@ -101,9 +119,10 @@ pub fn file_summary(
         asterisk_to_variable_name,
         input_format,
         false,
-        csv_infer_schema_length,
+        scan_infer_schema_length,
         csv_try_parse_dates,
         None,
+        csv_dialect,
     ) {
         Ok(df) => df,
         Err(e) => {
@@ -115,10 +134,35 @@ pub fn file_summary(
         t_scan += t0.elapsed();
     }
 
+    // Flatten Struct/List columns first, so every later step (cast, varlist
+    // matching, if(), the schema macros) sees the same columns read_to_stata
+    // will load. The spread widths are handed to read through pq_list_widths
+    // rather than re-measured there, so the two passes cannot disagree.
+    let mut spread_widths: HashMap<String, usize> = HashMap::new();
+    df = match flatten_nested_columns(df, &nested, &mut spread_widths) {
+        Ok((lf, truncated)) => {
+            for (name, longest) in truncated {
+                display(&format!(
+                    "note: {} has lists of up to {} elements; only the first {} are loaded",
+                    name,
+                    longest,
+                    spread_widths.get(&name).copied().unwrap_or(0)
+                ));
+            }
+            lf
+        }
+        Err(e) => {
+            display(&format!("Error flattening nested columns: {:?}", e));
+            return 198;
+        }
+    };
+    set_macro("pq_list_widths", &widths_to_json(&spread_widths), false);
+
     set_macro("cast_json", "", false);
     set_macro("pq_user_cast_json", "", false);
     set_macro("pq_cast_strict", if cast_strict { "1" } else { "0" }, false);
     set_macro("pq_cast_error", "", false);
+    set_macro("pq_cat_labels", "", false);
 
     // Apply user cast (binary_to_string + cast option) BEFORE compress and schema computation
     // so that string lengths, types, and the fast cache all reflect the cast types.
@@ -132,6 +176,16 @@ pub fn file_summary(
         }
     };
 
+    let unhandled_nested = unhandled_nested_columns(&scan_schema, &nested);
+    if !unhandled_nested.is_empty() {
+        display(&format!(
+            "note: {} nested (Struct/List) column(s) cannot be loaded as-is: {}. \
+             Use unnest and/or list(explode|spread [#]) to flatten them.",
+            unhandled_nested.len(),
+            unhandled_nested.join(", ")
+        ));
+    }
+
     let mut cast_map: HashMap<String, String> = HashMap::new();
 
     if binary_to_string {
@@ -230,6 +284,98 @@ pub fn file_summary(
         }
     }
 
+    // Decimal(p,s) is not a Stata type, so every Decimal column the user
+    // didn't cast lands in cast_map. A double holds the value exactly only
+    // while the unscaled integer (value * 10^s) is within +/-2^53, which any
+    // precision <= 15 guarantees; wider columns are checked against the data.
+    // Only columns in the varlist are checked, so an unrequested column can't
+    // raise the error (an unresolvable varlist is reported further down).
+    let mut decimal_type_overrides: HashMap<String, StataType> = HashMap::new();
+    let scan_col_strs: Vec<&str> = scan_schema.iter_names().map(|s| s.as_str()).collect();
+    let requested_cols = resolve_varlist(columns_varlist, &scan_col_strs, drop_list).unwrap_or_default();
+    let decimal_candidates: Vec<(PlSmallStr, usize, usize)> = scan_schema
+        .iter()
+        .filter_map(|(name, dtype)| match dtype {
+            DataType::Decimal(precision, scale)
+                if !cast_map.contains_key(name.as_str())
+                    && requested_cols.iter().any(|c| c == name.as_str()) =>
+            {
+                Some((name.clone(), *precision, *scale))
+            }
+            _ => None,
+        })
+        .collect();
+
+    if !decimal_candidates.is_empty() {
+        let to_check: Vec<PlSmallStr> = decimal_candidates
+            .iter()
+            .filter(|(_, precision, _)| {
+                decimal_mode == DecimalMode::Scaled || *precision > MAX_EXACT_DECIMAL_PRECISION
+            })
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        let ranges = match decimal_unscaled_ranges(&df, &to_check) {
+            Ok(r) => r,
+            Err(e) => {
+                display(&format!("Error checking Decimal precision range: {:?}", e));
+                return 198;
+            }
+        };
+        let overflow_cols: Vec<&str> = decimal_candidates
+            .iter()
+            .filter(|(name, _, _)| {
+                ranges
+                    .get(name.as_str())
+                    .map(|(min_val, max_val)| decimal_range_overflows(*min_val, *max_val))
+                    .unwrap_or(false)
+            })
+            .map(|(name, _, _)| name.as_str())
+            .collect();
+
+        if !overflow_cols.is_empty() && matches!(decimal_mode, DecimalMode::Auto | DecimalMode::Scaled) {
+            let msg = format!(
+                "Column(s) {} contain Decimal values whose unscaled digits fall outside \
+                 +/-2^53 (9,007,199,254,740,992). These values would silently lose precision \
+                 as a Stata double{}. Pass decimal(string) to load the affected column(s) as \
+                 strings, or decimal(double) to accept the loss.",
+                overflow_cols.join(", "),
+                if decimal_mode == DecimalMode::Scaled { ", even as scaled integers" } else { "" }
+            );
+            display(&msg);
+            set_macro("pq_cast_error", &msg, false);
+            return 198;
+        }
+        if !overflow_cols.is_empty() && decimal_mode == DecimalMode::Double {
+            display(&format!(
+                "note: {} loaded as double with lost precision (decimal(double))",
+                overflow_cols.join(", ")
+            ));
+        }
+
+        for (name, _, scale) in &decimal_candidates {
+            let overflows = overflow_cols.contains(&name.as_str());
+            let cast_type = match decimal_mode {
+                DecimalMode::Scaled => {
+                    if let Some((min_val, max_val)) = ranges.get(name.as_str()) {
+                        if *min_val >= STATA_LONG_MIN && *max_val <= STATA_LONG_MAX {
+                            decimal_type_overrides.insert(name.to_string(), StataType::Long);
+                        }
+                    }
+                    if *scale > 0 {
+                        display(&format!(
+                            "note: {} loaded as an integer scaled by 10^{} (decimal(scaled))",
+                            name, scale
+                        ));
+                    }
+                    DECIMAL_SCALED_CAST
+                }
+                DecimalMode::String if overflows => "string",
+                _ => "float64",
+            };
+            cast_map.insert(name.to_string(), cast_type.to_string());
+        }
+    }
+
     if !cast_map.is_empty() {
         let combined_json = serde_json::to_string(&cast_map).unwrap_or_default();
         df = match apply_user_cast(df, &combined_json, cast_strict) {
@@ -302,7 +448,7 @@ pub fn file_summary(
     matched_cols_sorted.sort();
 
     // Build a filtered schema with only matched columns (preserves file order for macros).
-    let matched_schema: Schema = Schema::from_iter(
+    let mut matched_schema: Schema = Schema::from_iter(
         matched_cols.iter()
             .filter_map(|name| {
                 schema.get(name.as_str())
@@ -310,16 +456,36 @@ pub fn file_summary(
             })
     );
 
+    // encode: dictionaries come from the unfiltered columns (before if()),
+    // labelled with the Stata variable name each column will load as.
+    let cat_dictionaries = if encode {
+        let rename_map = generate_rename_map(&matched_schema);
+        match categorical_dictionaries(&df, &matched_schema, &matched_cols, &rename_map) {
+            Ok(d) => d,
+            Err(e) => {
+                display(&format!("Error reading categories for encode: {:?}", e));
+                return 198;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    // timezone() and duration(): as in read, ahead of if() so both passes
+    // keep the same rows
+    df = match localize_time_zones(df).and_then(durations_as_numbers) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&e);
+            return 198;
+        }
+    };
+
     //  display(&format!("schema: {:?}", schema));
     let sql_filter = sql_if.filter(|s| !s.trim().is_empty());
     if let Some(sql) = sql_filter {
         let t0 = Instant::now();
-        let mut ctx = SQLContext::new();
-        ctx.register("df", df);
-        
-
-
-        df = match ctx.execute(&format!("select * from df where {}", sql)) {
+        df = match stata_if::filter_lazy(df, sql) {
             Ok(lazyframe) => lazyframe,
             Err(e) => {
                 display(&format!("Error in SQL if statement: {}", e));
@@ -341,6 +507,25 @@ pub fn file_summary(
 
     let t0 = Instant::now();
     df = cast_catenum_to_string(&df).unwrap();
+    let mut cat_type_overrides: HashMap<String, StataType> = HashMap::new();
+    if !cat_dictionaries.is_empty() {
+        df = match encode_catenum_columns(df, &cat_dictionaries) {
+            Ok(lf) => lf,
+            Err(e) => {
+                display(&format!("Error encoding categorical columns: {:?}", e));
+                return 198;
+            }
+        };
+        for dict in &cat_dictionaries {
+            matched_schema.set_dtype(dict.column.as_str(), DataType::Int32);
+            cat_type_overrides.insert(dict.column.clone(), smallest_code_type(dict.categories.len()));
+        }
+        set_macro(
+            "pq_cat_labels",
+            &serde_json::to_string(&cat_dictionaries).unwrap_or_default(),
+            false,
+        );
+    }
     if prof {
         t_cat_cast += t0.elapsed();
     }
@@ -367,6 +552,8 @@ pub fn file_summary(
             format: input_format.as_str().to_string(),
             parse_dates,
             infer_schema_length,
+            nested: nested.as_key(),
+            csv_dialect: csv_dialect.as_key(),
         };
         fast_cache::store(cache_key, cache_df);
         stats
@@ -382,7 +569,7 @@ pub fn file_summary(
             }
         } else {
             let n_rows = if let Some(sql) = sql_filter {
-                if matches!(input_format, InputFormat::Sas | InputFormat::Spss) {
+                if input_format.is_readstat() {
                     filtered_row_count_readstat_with_sql(path, input_format, sql)
                         .unwrap_or_else(|| get_row_count(&df).unwrap())
                 } else {
@@ -406,13 +593,17 @@ pub fn file_summary(
     // narrowing was silently re-widened one level by that same default
     // mapping when schema_with_stata_types ran on the already-narrowed
     // schema. Parquet without `compress` uses the cheap footer-stats path.
-    let type_overrides = if compress {
+    let mut type_overrides = if compress {
         direct_integer_type_overrides(&matched_schema, &cast_map)
     } else if matches!(input_format, InputFormat::Parquet) {
         safe_integer_type_overrides(path, &matched_schema, &cast_map)
     } else {
         HashMap::new()
     };
+    // Scaled decimals whose observed range fits a Stata long; the Int64
+    // default would otherwise map them to double.
+    type_overrides.extend(decimal_type_overrides);
+    type_overrides.extend(cat_type_overrides);
 
     let t0 = Instant::now();
     schema_with_stata_types(
@@ -575,8 +766,6 @@ fn find_int64_precision_overflow_columns(
     df: &LazyFrame,
     candidates: &[PlSmallStr],
 ) -> Result<Vec<PlSmallStr>, PolarsError> {
-    const MAX_SAFE_INT_AS_DOUBLE: f64 = 9_007_199_254_740_992.0; // 2^53
-
     let stats_exprs: Vec<Expr> = candidates
         .iter()
         .flat_map(|name| {
@@ -603,6 +792,68 @@ fn find_int64_precision_overflow_columns(
     Ok(overflow_cols)
 }
 
+/// Smallest Stata integer type that holds codes 1..=n_categories without
+/// reaching its reserved missing-value range.
+fn smallest_code_type(n_categories: usize) -> StataType {
+    if n_categories <= 100 {
+        StataType::Byte
+    } else if n_categories <= 32_740 {
+        StataType::Int
+    } else {
+        StataType::Long
+    }
+}
+
+const MAX_SAFE_INT_AS_DOUBLE: f64 = 9_007_199_254_740_992.0; // 2^53
+
+/// Decimal precision at or below which every unscaled value (< 10^15) is
+/// within 2^53, so no data check is needed.
+const MAX_EXACT_DECIMAL_PRECISION: usize = 15;
+
+/// Stata long range, excluding the reserved missing-value codes at the top.
+const STATA_LONG_MIN: f64 = -2_147_483_647.0;
+const STATA_LONG_MAX: f64 = 2_147_483_620.0;
+
+/// Min and max unscaled value (the physical Int128, i.e. value * 10^scale)
+/// of each Decimal column in `candidates`, as f64, in a single aggregation.
+/// All-null columns are absent from the result.
+fn decimal_unscaled_ranges(
+    df: &LazyFrame,
+    candidates: &[PlSmallStr],
+) -> Result<HashMap<String, (f64, f64)>, PolarsError> {
+    if candidates.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let stats_exprs: Vec<Expr> = candidates
+        .iter()
+        .flat_map(|name| {
+            let unscaled = col(name.as_str()).to_physical().cast(DataType::Float64);
+            vec![
+                unscaled.clone().min().alias(format!("{}_min", name)),
+                unscaled.max().alias(format!("{}_max", name)),
+            ]
+        })
+        .collect();
+
+    let stats_df = df.clone().select(stats_exprs).collect()?;
+
+    let mut ranges = HashMap::with_capacity(candidates.len());
+    for name in candidates {
+        let min_val = stats_df.column(&format!("{}_min", name))?.f64()?.get(0);
+        let max_val = stats_df.column(&format!("{}_max", name))?.f64()?.get(0);
+        if let (Some(min_val), Some(max_val)) = (min_val, max_val) {
+            ranges.insert(name.to_string(), (min_val, max_val));
+        }
+    }
+
+    Ok(ranges)
+}
+
+fn decimal_range_overflows(min_val: f64, max_val: f64) -> bool {
+    min_val < -MAX_SAFE_INT_AS_DOUBLE || max_val > MAX_SAFE_INT_AS_DOUBLE
+}
+
 fn collect_row_count_and_string_lengths(
     df: &LazyFrame,
     schema: &Schema,
@@ -653,6 +904,8 @@ fn readstat_format_for_input(input_format: InputFormat) -> Option<ReadStatFormat
     match input_format {
         InputFormat::Sas => Some(ReadStatFormat::Sas),
         InputFormat::Spss => Some(ReadStatFormat::Spss),
+        InputFormat::Dta => Some(ReadStatFormat::Stata),
+        InputFormat::Xpt => Some(ReadStatFormat::SasXpt),
         _ => None,
     }
 }
@@ -731,3 +984,47 @@ fn total_file_size_bytes(path: &str) -> u64 {
     // Fallback: single-file stat (handles non-glob paths that didn't match above)
     std::fs::metadata(path).map(|m| m.len()).unwrap_or(u64::MAX)
 }
+
+#[cfg(test)]
+mod decimal_tests {
+    use super::*;
+
+    fn decimal_frame() -> LazyFrame {
+        let small = Series::new("small".into(), [Some(123.45f64), Some(-0.5), None])
+            .cast(&DataType::Decimal(10, 2))
+            .unwrap();
+        let big = Series::new("big".into(), ["12345678901234567.89", "1.00", "2.50"])
+            .cast(&DataType::Decimal(20, 2))
+            .unwrap();
+        DataFrame::new_infer_height(vec![small.into(), big.into()]).unwrap().lazy()
+    }
+
+    #[test]
+    fn decimal_ranges_use_unscaled_values() {
+        let ranges = decimal_unscaled_ranges(
+            &decimal_frame(),
+            &[PlSmallStr::from("small"), PlSmallStr::from("big")],
+        )
+        .unwrap();
+        assert_eq!(ranges["small"], (-50.0, 12345.0));
+        assert!(!decimal_range_overflows(ranges["small"].0, ranges["small"].1));
+        assert!(decimal_range_overflows(ranges["big"].0, ranges["big"].1));
+    }
+
+    #[test]
+    fn scaled_cast_loads_unscaled_integers() {
+        let df = apply_user_cast(decimal_frame(), r#"{"small":"decimal_scaled"}"#, true)
+            .unwrap()
+            .collect()
+            .unwrap();
+        let values: Vec<Option<i64>> = df.column("small").unwrap().i64().unwrap().into_iter().collect();
+        assert_eq!(values, vec![Some(12345), Some(-50), None]);
+    }
+
+    #[test]
+    fn decimal_mode_parses_option() {
+        assert_eq!(DecimalMode::from_arg("").unwrap(), DecimalMode::Auto);
+        assert_eq!(DecimalMode::from_arg("Scaled").unwrap(), DecimalMode::Scaled);
+        assert!(DecimalMode::from_arg("long").is_err());
+    }
+}
@@ -473,6 +473,40 @@ pub fn apply_cast(
     }
 }
 
+/// Cast-map type used internally (never accepted from `cast()`) for Decimal
+/// columns loaded as unscaled integers with `decimal(scaled)`.
+pub const DECIMAL_SCALED_CAST: &str = "decimal_scaled";
+
+/// How Decimal(p,s) columns that a double cannot hold exactly are loaded.
+/// Columns whose unscaled values fit in +/-2^53 are always loaded as double,
+/// except under `Scaled`, which applies to every Decimal column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecimalMode {
+    /// Error, naming the affected columns (the default)
+    Auto,
+    /// Load as double anyway, accepting the lost digits
+    Double,
+    /// Load every Decimal column as its unscaled integer (value * 10^scale)
+    Scaled,
+    /// Load the affected columns as strings
+    String,
+}
+
+impl DecimalMode {
+    pub fn from_arg(arg: &str) -> Result<Self, String> {
+        match arg.trim().to_lowercase().as_str() {
+            "" => Ok(DecimalMode::Auto),
+            "double" => Ok(DecimalMode::Double),
+            "scaled" => Ok(DecimalMode::Scaled),
+            "string" => Ok(DecimalMode::String),
+            other => Err(format!(
+                "decimal({}): expected double, scaled, or string",
+                other
+            )),
+        }
+    }
+}
+
 /// Apply user-specified casts from column->type JSON: {"col1":"int32","col2":"string"}
 /// strict=true uses strict_cast (errors on invalid values); strict=false uses lenient cast (nulls).
 /// Errors surface at LazyFrame collect time for value-level failures.
@@ -503,6 +537,18 @@ pub fn apply_user_cast(
         if schema.get(col_name.as_str()).is_none() {
             continue;
         }
+        // Decimal columns loaded as scaled integers: the unscaled (physical
+        // Int128) value, e.g. 123.45 in a Decimal(p,2) becomes 12345.
+        if type_str == DECIMAL_SCALED_CAST {
+            let unscaled = col(col_name.as_str()).to_physical();
+            let expr = if strict {
+                unscaled.strict_cast(DataType::Int64)
+            } else {
+                unscaled.cast(DataType::Int64)
+            };
+            cast_exprs.push(expr.alias(col_name.as_str()));
+            continue;
+        }
         let target_type = parse_data_type(type_str)?;
         let expr = if strict {
             col(col_name.as_str()).strict_cast(target_type).alias(col_name.as_str())
@@ -9,6 +9,8 @@ pub struct FastCacheKey {
     pub format: String,
     pub parse_dates: bool,
     pub infer_schema_length: usize,
+    pub nested: String,         // NestedOptions::as_key()
+    pub csv_dialect: String,    // CsvDialect::as_key()
 }
 
 struct FastCache {
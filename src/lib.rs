@@ -10,11 +10,22 @@ pub mod mapping;
 pub mod stata_interface;
 pub mod stata_metadata;
 pub mod describe;
-pub mod sql_from_if;
+pub mod stata_if;
+pub mod time_zone;
 pub mod utilities;
 pub mod downcast;
 pub mod fast_cache;
 pub mod parquet_stats;
+pub mod nested;
+pub mod readstat_metadata;
+pub mod csv_dialect;
+pub mod decompress;
+pub mod xpt;
+pub mod sav;
+pub mod convert;
+pub mod sql_query;
+pub mod merge;
+pub mod collapse;
 
 use std::ptr;
 
@@ -23,6 +34,9 @@ use stata_interface::{
     ST_retcode,
 };
 use describe::file_summary;
+use nested::NestedOptions;
+use csv_dialect::{CsvDialect, CsvWriteOptions};
+use downcast::DecimalMode;
 use read::{
     InputFormat,
     data_exists,
@@ -33,7 +47,7 @@ use read::{
 
 #[no_mangle]
 pub static mut _stata_: *mut stata_sys::ST_plugin = ptr::null_mut();
-
+// Set the first time any subfunction other than "setup_check"/"set_threads"/"cleanup" runs.
 // Set the first time any subfunction other than "setup_check"/"set_threads" runs.
 // Polars' global rayon thread pool (and our own, in utilities::get_thread_pool) is
 // lazily built on first use and reads POLARS_MAX_THREADS exactly once, so
@@ -90,7 +104,7 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
         let subfunction_name = args[0];
         let subfunction_args = &args[1..];
 
-        if subfunction_name != "setup_check" && subfunction_name != "set_threads" {
+        if subfunction_name != "setup_check" && subfunction_name != "set_threads" && subfunction_name != "cleanup" {
             POLARS_TOUCHED.store(true, Ordering::SeqCst);
         }
 
@@ -132,7 +146,7 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 return 0 as ST_retcode;
             }
             "read" => {
-                if !data_exists(&subfunction_args[0]) {
+                if subfunction_args[0] != sql_query::QUERY_SENTINEL && !data_exists(subfunction_args[0]) {
                     stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
                     return 601 as ST_retcode;
                 }
@@ -147,6 +161,8 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 // [10]=random_share [11]=random_seed [12]=batch_size
                 // [13]=strl_col_names [14]=strl_dta_path [15]=format
                 // [16]=preserve_order [17]=infer_schema_length [18]=parse_dates
+                // [19]=columns_varlist [20]=skip_metadata [21]=unnest [22]=list mode
+                // [23]="pq_csv_opts" when CSV dialect options were given
                 let asterisk_to_variable_name = if subfunction_args[7].is_empty() {
                     None
                 } else {
@@ -179,6 +195,23 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 };
                 let columns_varlist = if subfunction_args.len() > 19 { subfunction_args[19] } else { "" };
                 let skip_metadata = subfunction_args.get(20).map(|s| *s == "1").unwrap_or(false);
+                let nested = match NestedOptions::from_args(
+                    subfunction_args.get(21).copied().unwrap_or("0"),
+                    subfunction_args.get(22).copied().unwrap_or(""),
+                ) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let csv_dialect = match CsvDialect::from_arg(subfunction_args.get(23).copied()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
                 let input_format = match InputFormat::from_str(format_arg) {
                     Some(f) => f,
                     None => {
@@ -186,9 +219,18 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                         return 198 as ST_retcode;
                     }
                 };
+                // Compressed inputs (.gz/.zst/.bz2/.xz) are decoded as read or from temp
+                // copies; a pq sql query is read by name.
+                let input_path = match sql_query::resolve_source(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
 
                 let read_result = read_to_stata(
-                    subfunction_args[0],
+                    &input_path,
                     subfunction_args[1],
                     subfunction_args[2].parse::<usize>().unwrap_or(0),
                     subfunction_args[3].parse::<usize>().unwrap_or(0),
@@ -209,6 +251,8 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                     parse_dates,
                     columns_varlist,
                     skip_metadata,
+                    nested,
+                    &csv_dialect,
                 );
         
                 // Use match to handle the Result
@@ -227,7 +271,7 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
 
             },
             "describe" => {
-                if !data_exists(&subfunction_args[0]) {
+                if subfunction_args[0] != sql_query::QUERY_SENTINEL && !data_exists(subfunction_args[0]) {
                     stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
                     return 601 as ST_retcode;
                 }
@@ -276,6 +320,15 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                         return 198 as ST_retcode;
                     }
                 };
+                // Compressed inputs (.gz/.zst/.bz2/.xz) are decoded as read or from temp
+                // copies; a pq sql query is read by name.
+                let input_path = match sql_query::resolve_source(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
                 let cast_buf_arg = if subfunction_args.len() > 14 { subfunction_args[14] } else { "" };
                 let user_cast_json_owned: String;
                 let user_cast_json: &str = if cast_buf_arg == "pq_cast_buf" {
@@ -287,8 +340,32 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 let binary_to_string = if subfunction_args.len() > 15 { subfunction_args[15] == "1" } else { false };
                 let cast_strict = if subfunction_args.len() > 16 { subfunction_args[16] != "0" } else { true };
                 let safe_int64 = if subfunction_args.len() > 17 { subfunction_args[17] == "1" } else { false };
+                let unnest_arg = if subfunction_args.len() > 18 { subfunction_args[18] } else { "0" };
+                let list_mode_arg = if subfunction_args.len() > 19 { subfunction_args[19] } else { "" };
+                let nested = match NestedOptions::from_args(unnest_arg, list_mode_arg) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let decimal_mode = match DecimalMode::from_arg(subfunction_args.get(20).copied().unwrap_or("")) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let encode = subfunction_args.get(21).map(|s| *s == "1").unwrap_or(false);
+                let csv_dialect = match CsvDialect::from_arg(subfunction_args.get(22).copied()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
                 return file_summary(
-                        subfunction_args[0],
+                        &input_path,
                         subfunction_args[1].parse::<u8>().unwrap_or(0) != 0,
                         subfunction_args[2].parse::<u8>().unwrap_or(0) != 0,
                         Some(subfunction_args[3].as_ref()),
@@ -307,6 +384,10 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                         binary_to_string,
                         cast_strict,
                         safe_int64,
+                        nested,
+                        decimal_mode,
+                        encode,
+                        &csv_dialect,
                     ) as ST_retcode;
             },
             "save" => {
@@ -318,14 +399,14 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 let mapping = subfunction_args[5];
                 let partition_by = subfunction_args[6];
                 let compression = subfunction_args[7];
-                let compression_level_passed = subfunction_args[8].parse::<i32>().unwrap_or(-1);
                 let overwrite_partition = subfunction_args[9].parse::<i32>().unwrap_or(0) == 1;
 
-                
-                let compression_level = if compression_level_passed == -1 {
-                    None
-                } else {
-                    Some(compression_level_passed as usize)
+                let compression_level = match write::parse_compression_level(subfunction_args[8]) {
+                    Ok(level) => level,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
                 };
 
 
@@ -334,6 +415,21 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 let quietly = subfunction_args[12].parse::<u8>().unwrap_or(0) != 0;
                 let append_to_partition = subfunction_args[13].parse::<u8>().unwrap_or(0) != 0;
                 let output_format = if subfunction_args.len() > 14 { subfunction_args[14] } else { "parquet" };
+                let csv_options = match CsvWriteOptions::from_arg(subfunction_args.get(15).copied()) {
+                    Ok(o) => o,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let xpt_version = subfunction_args.get(16).and_then(|s| s.parse::<u8>().ok()).unwrap_or(5);
+                let spss_missing = match sav::missing_codes_from_arg(subfunction_args.get(17).copied()) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
                 
                 let output = match write::write_from_stata(
                     path,
@@ -351,18 +447,264 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                     quietly,
                     append_to_partition,
                     output_format,
+                    &csv_options,
+                    xpt_version,
+                    &spss_missing,
                 ) {
-                    Ok(_) => 0 as i32,
+                    // Non-zero Ok means an error was already displayed
+                    Ok(rc) => rc,
                     Err(_e) => 198 as i32
                 };
                 return output as ST_retcode;
             },
+            "convert" => {
+                if !data_exists(subfunction_args[0]) {
+                    stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
+                    return 601 as ST_retcode;
+                }
+                let input_format = match InputFormat::from_str(subfunction_args[2]) {
+                    Some(f) => f,
+                    None => {
+                        display(&format!("Unsupported input format: {}", subfunction_args[2]));
+                        return 198 as ST_retcode;
+                    }
+                };
+                // Compressed inputs (.gz/.zst/.bz2/.xz) are decoded as read or from temp copies.
+                let input_path = match decompress::resolve_input_path(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let target = subfunction_args[1];
+                if input_path == target || subfunction_args[0] == target {
+                    display("pq convert cannot write over its own source file");
+                    return 198 as ST_retcode;
+                }
+                // Column list and cast() JSON are read by name, as for pq use.
+                let columns = if subfunction_args[5] == "pq_namelist_buf" {
+                    stata_interface::get_macro("pq_namelist_buf", false, Some(1024 * 1024 * 10))
+                } else {
+                    subfunction_args[5].to_string()
+                };
+                let cast_json = if subfunction_args[7] == "pq_cast_buf" {
+                    stata_interface::get_macro("pq_cast_buf", false, Some(1024 * 1024))
+                } else {
+                    String::new()
+                };
+                let csv_options = match CsvWriteOptions::from_arg(subfunction_args.get(20).copied()) {
+                    Ok(o) => o,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let csv_dialect = match CsvDialect::from_arg(subfunction_args.get(21).copied()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let partition_by: Vec<polars::prelude::PlSmallStr> = subfunction_args[11]
+                    .split_whitespace()
+                    .map(polars::prelude::PlSmallStr::from)
+                    .collect();
+                let compression_level = match write::parse_compression_level(subfunction_args[13]) {
+                    Ok(level) => level,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+
+                let options = convert::ConvertOptions {
+                    input_format,
+                    csv_dialect,
+                    output_format: subfunction_args[3],
+                    columns: &columns,
+                    drop: subfunction_args[6],
+                    sql_if: Some(subfunction_args[4]),
+                    cast_json: &cast_json,
+                    cast_strict: subfunction_args[8] != "0",
+                    compress: subfunction_args[9] == "1",
+                    compress_string: subfunction_args[10] == "1",
+                    partition_by,
+                    compression: subfunction_args[12],
+                    compression_level,
+                    metadata: subfunction_args[14] == "1",
+                    xpt_version: subfunction_args[15].parse::<u8>().unwrap_or(5),
+                    csv_options,
+                    safe_relaxed: subfunction_args[16] == "1",
+                    asterisk_to_variable: Some(subfunction_args[17]).filter(|s| !s.is_empty()),
+                    preserve_order: subfunction_args[18] == "1",
+                    quietly: subfunction_args[19] == "1",
+                };
+                return match convert::convert_file(&input_path, target, &options) {
+                    Ok(rc) => rc as ST_retcode,
+                    Err(e) => {
+                        display(&format!("Error converting {}: {}", subfunction_args[0], e));
+                        198 as ST_retcode
+                    }
+                };
+            },
+            "merge" => {
+                // args: [0]=using [1]=result path [2]=1:1/m:1/1:m [3]=format [4]=sql_if
+                // [5]="pq_namelist_buf" (keepusing) [6]=drop [7]=keep codes [8]=assert codes
+                // [9]=row number variable [10]=safe_relaxed [11]=asterisk
+                // [12]=infer_schema_length [13]=parse_dates [14]="pq_csv_opts"
+                // The key variables are staged as for pq save (var_count, name_#, ...).
+                if !data_exists(subfunction_args[0]) {
+                    stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
+                    return 601 as ST_retcode;
+                }
+                let kind = match merge::MergeKind::parse(subfunction_args[2]) {
+                    Some(k) => k,
+                    None => {
+                        display(&format!("Unsupported merge type: {}", subfunction_args[2]));
+                        return 198 as ST_retcode;
+                    }
+                };
+                let input_format = match InputFormat::from_str(subfunction_args[3]) {
+                    Some(f) => f,
+                    None => {
+                        display(&format!("Unsupported input format: {}", subfunction_args[3]));
+                        return 198 as ST_retcode;
+                    }
+                };
+                let input_path = match decompress::resolve_input_path(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let keepusing = if subfunction_args[5] == "pq_namelist_buf" {
+                    stata_interface::get_macro("pq_namelist_buf", false, Some(1024 * 1024 * 10))
+                } else {
+                    subfunction_args[5].to_string()
+                };
+                let csv_dialect = match CsvDialect::from_arg(subfunction_args.get(14).copied()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let codes = |arg: &str| -> Vec<u8> {
+                    arg.split_whitespace().filter_map(|c| c.parse::<u8>().ok()).collect()
+                };
+                let master_keys = match merge::master_keys_from_stata() {
+                    Ok(df) => df,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+
+                let options = merge::MergeOptions {
+                    kind,
+                    input_format,
+                    keepusing: &keepusing,
+                    drop: subfunction_args[6],
+                    sql_if: Some(subfunction_args[4]),
+                    keep: codes(subfunction_args[7]),
+                    assert: codes(subfunction_args[8]),
+                    row_column: subfunction_args[9],
+                    safe_relaxed: subfunction_args[10] == "1",
+                    asterisk_to_variable: Some(subfunction_args[11]).filter(|s| !s.is_empty()),
+                    infer_schema_length: subfunction_args[12].parse::<usize>().ok(),
+                    parse_dates: subfunction_args[13] == "1",
+                    csv_dialect,
+                };
+                return merge::merge_to_parquet(&input_path, subfunction_args[1], master_keys, &options) as ST_retcode;
+            },
+            "collapse" => {
+                // args: [0]=using [1]=result path [2]=format [3]=sql_if
+                // [4]="pq_collapse_spec" [5]=by [6]=weight type [7]=weight variable
+                // [8]=cw [9]=safe_relaxed [10]=asterisk [11]=infer_schema_length
+                // [12]=parse_dates [13]="pq_csv_opts"
+                if !data_exists(subfunction_args[0]) {
+                    stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
+                    return 601 as ST_retcode;
+                }
+                let input_format = match InputFormat::from_str(subfunction_args[2]) {
+                    Some(f) => f,
+                    None => {
+                        display(&format!("Unsupported input format: {}", subfunction_args[2]));
+                        return 198 as ST_retcode;
+                    }
+                };
+                let input_path = match decompress::resolve_input_path(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                // The collapse list is read by name, as the column lists are.
+                let spec = if subfunction_args[4] == "pq_collapse_spec" {
+                    stata_interface::get_macro("pq_collapse_spec", false, Some(1024 * 1024))
+                } else {
+                    subfunction_args[4].to_string()
+                };
+                let targets = match collapse::parse_spec(&spec) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let weight = match subfunction_args[6] {
+                    "" => None,
+                    kind => match collapse::WeightKind::parse(kind) {
+                        Some(k) => Some((k, subfunction_args[7].to_string())),
+                        None => {
+                            display(&format!("Unsupported weight type: {}", kind));
+                            return 198 as ST_retcode;
+                        }
+                    },
+                };
+                let csv_dialect = match CsvDialect::from_arg(subfunction_args.get(13).copied()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+
+                let options = collapse::CollapseOptions {
+                    input_format,
+                    targets,
+                    by: subfunction_args[5].split_whitespace().map(String::from).collect(),
+                    weight,
+                    casewise: subfunction_args[8] == "1",
+                    sql_if: Some(subfunction_args[3]),
+                    safe_relaxed: subfunction_args[9] == "1",
+                    asterisk_to_variable: Some(subfunction_args[10]).filter(|s| !s.is_empty()),
+                    infer_schema_length: subfunction_args[11].parse::<usize>().ok(),
+                    parse_dates: subfunction_args[12] == "1",
+                    csv_dialect,
+                };
+                return collapse::collapse_to_parquet(&input_path, subfunction_args[1], &options) as ST_retcode;
+            },
             "describe_stata_metadata" => {
                 if !data_exists(&subfunction_args[0]) {
                     stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
                     return 601 as ST_retcode;
                 }
-                let path = subfunction_args[0];
+                // Compressed inputs (.gz/.zst/.bz2/.xz) are read from temp copies.
+                let input_format = InputFormat::from_str(read::format_from_extension(subfunction_args[0]))
+                    .unwrap_or(InputFormat::Parquet);
+                let input_path = match decompress::resolve_input_path(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let path = input_path.as_str();
                 let quietly = subfunction_args.get(1).map(|s| *s == "1").unwrap_or(false);
                 if let Err(e) = stata_metadata::describe_metadata(path, quietly) {
                     display(&format!("Error reading embedded Stata metadata: {e}"));
@@ -371,7 +713,7 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 return 0 as ST_retcode;
             },
             "write_overflow_dta" => {
-                if !data_exists(&subfunction_args[0]) {
+                if subfunction_args[0] != sql_query::QUERY_SENTINEL && !data_exists(subfunction_args[0]) {
                     stata_interface::display(&format!("File does not exist ({})",subfunction_args[0]));
                     return 601 as ST_retcode;
                 }
@@ -398,6 +740,39 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 } else {
                     false
                 };
+                let nested = match NestedOptions::from_args(
+                    subfunction_args.get(13).copied().unwrap_or("0"),
+                    subfunction_args.get(14).copied().unwrap_or(""),
+                ) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
+                let list_widths_json = subfunction_args.get(15).copied().unwrap_or("");
+                // Casts resolved by describe (cast(), safe_int64, decimal()), passed
+                // by name like describe's "pq_cast_buf" since the JSON has quotes.
+                let cast_buf_arg = subfunction_args.get(16).copied().unwrap_or("");
+                let user_cast_json = if cast_buf_arg == "pq_cast_buf" {
+                    stata_interface::get_macro("pq_cast_buf", false, Some(1024 * 1024))
+                } else {
+                    cast_buf_arg.to_string()
+                };
+                let cast_strict = subfunction_args.get(17).map(|s| *s != "0").unwrap_or(true);
+                // encode dictionaries from describe, also read by name.
+                let cat_labels_json = if subfunction_args.get(18).copied() == Some("pq_cat_labels") {
+                    stata_interface::get_macro("pq_cat_labels", false, Some(1024 * 1024 * 10))
+                } else {
+                    String::new()
+                };
+                let csv_dialect = match CsvDialect::from_arg(subfunction_args.get(19).copied()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
                 let input_format = match InputFormat::from_str(format_arg) {
                     Some(f) => f,
                     None => {
@@ -405,6 +780,15 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                         return 198 as ST_retcode;
                     }
                 };
+                // Compressed inputs (.gz/.zst/.bz2/.xz) are decoded as read or from temp
+                // copies; a pq sql query is read by name.
+                let input_path = match sql_query::resolve_source(subfunction_args[0], input_format) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        display(&e);
+                        return 198 as ST_retcode;
+                    }
+                };
 
                 // Handle columns parameter (may be empty)
                 let columns = if subfunction_args[2].is_empty() {
@@ -414,7 +798,7 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 };
 
                 let result = write_overflow_batch_to_dta(
-                    subfunction_args[0],  // parquet path
+                    &input_path,  // source path
                     subfunction_args[1],  // dta output path
                     columns,  // column names (space-separated, optional)
                     subfunction_args[3].parse::<usize>().unwrap_or(0),  // n_rows
@@ -427,6 +811,12 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                     input_format,
                     infer_schema_length,
                     parse_dates,
+                    nested,
+                    list_widths_json,
+                    &user_cast_json,
+                    cast_strict,
+                    &cat_labels_json,
+                    &csv_dialect,
                 );
 
                 match result {
@@ -437,6 +827,11 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                     }
                 }
             },
+            "cleanup" => {
+                // Called by pq.ado once a command finishes: drops the temp
+                // copies of compressed or re-encoded inputs.
+                decompress::remove_spooled_inputs();
+            },
             "clean_path" => {
                 let path = subfunction_args[0];
                 let create_dir = subfunction_args[1].parse::<i32>().unwrap_or(0) == 1;
@@ -466,9 +861,16 @@ pub extern "C" fn stata_call(argc: c_int, argv: *const *const c_char) -> ST_retc
                 return output as ST_retcode;
             },
             "if" => {
-                let sql_if = sql_from_if::stata_to_sql(subfunction_args[0] as &str);
-                stata_interface::set_macro("sql_if", &sql_if, false);
-
+                let strict = subfunction_args.len() > 1 && subfunction_args[1] == "1";
+                match stata_if::encode(subfunction_args[0], strict) {
+                    Ok(sql_if) => {
+                        stata_interface::set_macro("sql_if", &sql_if, false);
+                    }
+                    Err(e) => {
+                        stata_interface::display(&format!("Error in if(): {}", e.render(subfunction_args[0])));
+                        return 198 as ST_retcode;
+                    }
+                }
             },
             _ => {
                 stata_interface::display(&format!("Error: Unknown subfunction '{}'", subfunction_name));
@@ -4,12 +4,17 @@
 
 //  use log::{debug, info, warn, error};
 
+//  Command-line tool over the plugin's modules: describe, head, schema-diff,
+//  metadata and convert without a Stata session (see cli.rs).
+
 #[cfg(debug_assertions)]
 use env_logger::Builder;
 
 #[cfg(debug_assertions)]
 use std::io::Write;
 
+use std::ptr;
+
 
 pub mod read;
 pub mod write;
@@ -21,114 +26,42 @@ pub mod utilities;
 pub mod downcast;
 pub mod fast_cache;
 pub mod parquet_stats;
+pub mod nested;
+pub mod readstat_metadata;
+pub mod csv_dialect;
+pub mod decompress;
+pub mod xpt;
+pub mod sav;
+pub mod convert;
+pub mod sql_query;
+pub mod merge;
+pub mod collapse;
+pub mod stata_if;
+pub mod time_zone;
+pub mod cli;
+
+
+//  Stays null: there is no Stata session, so stata_interface prints to
+//  stdout rather than calling into Stata (lib.rs sets it in pginit).
+#[no_mangle]
+pub static mut _stata_: *mut stata_sys::ST_plugin = ptr::null_mut();
 
-#[cfg(debug_assertions)]
-mod sql_from_if;
-
-#[cfg(debug_assertions)]
-use crate::read::data_exists;
- 
-
-
-
-#[cfg(not(debug_assertions))]
-fn main() {
-    //  Do nothing
-}
-
-
-
-#[cfg(debug_assertions)]
-struct ReadParams {
-    path:String,
-    variables_as_str:String,
-    n_rows:usize,
-    offset:usize,
-    sql_if:Option<String>,
-    mapping:String,
-}
-
-#[cfg(debug_assertions)]
-impl ReadParams {
-    pub fn new(
-        path:String,
-        variables_as_str:String,
-        n_rows:usize,
-        offset:usize,
-        sql_if:Option<String>,
-        mapping:String,
-    ) -> Self {
-        ReadParams {
-            path:path,
-            variables_as_str: variables_as_str, 
-            n_rows: n_rows,
-            offset: offset,
-            sql_if: sql_if,
-            mapping: mapping,
-        }
-    }
-}
 
-#[cfg(debug_assertions)]
 fn main() {
-    //  env_logger::init();
+    #[cfg(debug_assertions)]
     Builder::from_default_env()
         .format(|buf, record| {
-            writeln!(buf, "[{}] {}", 
+            writeln!(buf, "[{}] {}",
                 record.level(),
                 record.args()
             )
         })
         .init();
 
-    _ = test_glob_path();
-}
-
-
-#[cfg(debug_assertions)]
-fn test_glob_path() {
-    let valid1 = data_exists("C:/Users/jonro/Downloads/random_types.parquet");
-    let valid2 = data_exists("C:/Users/jonro/Downloads/random_types_*.parquet");
-    let valid3 = data_exists("C:/Users/jonro/Downloads/random_types_partitioned.parquet");
-    let valid4 = data_exists("C:/Users/jonro/Downloads/random_types_partitioned.parquet/**.parquet");
+    polars::datatypes::extension::set_unknown_extension_type_behavior(
+        polars::datatypes::extension::UnknownExtensionTypeBehavior::LoadAsStorage,
+    );
 
-    let invalid1 = data_exists("C:/Users/jonro/Downloads/random_types_wrong.parquet");
-    let invalid2 = data_exists("C:/Users/jonro/Downloads/random_types_*_test.parquet");
-    let invalid3 = data_exists("C:/Users/jonro/Downloads/random_types__test.parquet/**.parquet");
-
-    println!("valid1 = {:?}",valid1);
-    println!("valid2 = {:?}",valid2);
-    println!("valid3 = {:?}",valid3);
-    println!("valid4 = {:?}",valid4);
-    println!("invalid1 = {:?}",invalid1);
-    println!("invalid2 = {:?}",invalid2);
-    println!("invalid3 = {:?}",invalid3);
-    
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(cli::run(&args));
 }
-
-
-#[cfg(debug_assertions)]
-fn test_stata_if_to_sql() {
-    let test1 = sql_from_if::stata_to_sql("age > 30");
-    let test2 = sql_from_if::stata_to_sql("age > 30 & gender == \"male\"");
-    let test3 = sql_from_if::stata_to_sql("inrange(income, 1000, 5000)");
-    let test4 = sql_from_if::stata_to_sql("inlist(country, \"USA\", \"Canada\")");
-    let test5 = sql_from_if::stata_to_sql("missing(value)");
-    let test6 = sql_from_if::stata_to_sql("inrange(age, 18, 65) & !missing(income) | status == \"active\"");
-    let test7 = sql_from_if::stata_to_sql("a == 5 & (b == 10 | c >= 72)");
-    let test8 = sql_from_if::stata_to_sql("a == 5 & ((b == 10 | c >= 72) == 1)");
-
-    let test9 = sql_from_if::stata_to_sql("age > -30");
-    
-    println!("test1 = {:?}",test1);
-    println!("test2 = {:?}",test2);
-    println!("test3 = {:?}",test3);
-    println!("test4 = {:?}",test4);
-    println!("test5 = {:?}",test5);
-    println!("test6 = {:?}",test6);
-    println!("test7 = {:?}",test7);
-    println!("test8 = {:?}",test8);
-    println!("test9 = {:?}",test9);
-
-
-}
\ No newline at end of file
@@ -9,6 +9,8 @@ use crate::stata_interface::{
     display,
     set_macro,
 };
+use crate::time_zone;
+use crate::utilities::parse_duration_record;
 
 
 // Enum representing Stata data types
@@ -123,6 +125,7 @@ pub fn map_polars_to_stata(
         DataType::Date => StataType::Date,
         DataType::Time => StataType::Time,
         DataType::Datetime(_, _) => StataType::DateTime,
+        DataType::Duration(_) => StataType::Double,  // a count of duration()'s unit
         DataType::String 
         | DataType::Categorical(_,_)
         | DataType::Enum(_,_) => {
@@ -186,6 +189,8 @@ pub struct StataColumnInfo {
     pub str_length: usize,
     #[serde(default)]
     pub stata_col: usize,  // 1-based position in the Stata dataset; 0 = unset (use enumerate index)
+    #[serde(default)]
+    pub duration: String,  // _pq_duration characteristic ("s Duration(Microseconds)"); "" = not a Duration
 }
 
 /// Resolves a Stata dtype string (as staged by pq.ado: "String"/"StrL"/
@@ -214,8 +219,12 @@ pub fn stata_column_info_to_schema(
     let fields: Vec<Field> = column_info.iter().map(|col| {
         let stata_type = resolve_stata_type(&col.dtype, &col.format);
 
-        // Map StataType to Polars DataType
-        let polars_dtype = map_stata_to_polars(&stata_type);
+        // Map StataType to Polars DataType; a variable loaded from a
+        // Duration column goes back as one
+        let polars_dtype = match parse_duration_record(&col.duration) {
+            Some((_, time_unit)) => DataType::Duration(time_unit),
+            None => map_stata_to_polars(&stata_type),
+        };
 
         // Create a Field with the column name and data type
         Field::new(PlSmallStr::from(&col.name), polars_dtype)
@@ -231,9 +240,26 @@ pub fn find_str_length_by_name(columns: &Vec<StataColumnInfo>, target_name: &str
         .map(|col| col.str_length)
 }
 
+/// The _pq_duration record of a column, or "" if it isn't in `columns`.
+pub fn find_duration_by_name(columns: &[StataColumnInfo], target_name: &str) -> String {
+    columns.iter()
+        .find(|col| col.name == target_name)
+        .map(|col| col.duration.clone())
+        .unwrap_or_default()
+}
+
+/// The Stata display format of a column, or "" if it isn't in `columns`.
+pub fn find_format_by_name(columns: &[StataColumnInfo], target_name: &str) -> String {
+    columns.iter()
+        .find(|col| col.name == target_name)
+        .map(|col| col.format.clone())
+        .unwrap_or_default()
+}
+
 fn match_var_format_stata(format_str: &str) -> Option<StataType> {
-    // Convert to lowercase for case-insensitive matching
-    let format_lower = format_str.to_lowercase();
+    // Convert to lowercase for case-insensitive matching; a left-justified
+    // format (%-tm) is the same type as %tm
+    let format_lower = format_str.to_lowercase().replacen("%-", "%", 1);
     
     // 1. Check for TIME formats first (most specific)
     if format_lower.contains("hh:mm:ss") || format_lower.contains("hh:mm") {
@@ -271,8 +297,8 @@ pub fn schema_with_stata_types(
 ) {
 
     if !quietly {
-        display(&String::from("Variable Name                    | Polars Type                      | Stata Type"));
-        display(&String::from("-------------------------------- | -------------------------------- | -------------------- "));
+        display(&String::from("Variable Name                    | Polars Type                      | Stata Type           | Time zone"));
+        display(&String::from("-------------------------------- | -------------------------------- | -------------------- | --------------------"));
     }
 
     let hash_strings = if detailed {
@@ -310,10 +336,11 @@ pub fn schema_with_stata_types(
 
         all_columns.push(column_info);
         if !quietly {
-            let msg = format!("{:<32} | {:<32} | {}", 
+            let msg = format!("{:<32} | {:<32} | {:<20} | {}",
                                     name, 
                                     format!("{:?}", dtype), 
-                                    stata_type.to_string());
+                                    stata_type.to_string(),
+                                    time_zone::zone_of(dtype));
             display(&msg);
         }
 
@@ -340,6 +367,13 @@ pub fn schema_with_stata_types(
             false
         );
 
+        //      Time zone of a zone-aware datetime (if applicable)
+        let _ = set_macro(
+            &format!("timezone_{}",i+1),
+            &time_zone::zone_of(dtype),
+            false
+        );
+
         //      String length (if applicable)
         let _ = set_macro(
             &format!("string_length_{}",i+1),
@@ -378,7 +412,7 @@ pub fn schema_with_stata_types(
 
 
 
-fn generate_rename_map(schema: &Schema) -> HashMap<String, String> {
+pub fn generate_rename_map(schema: &Schema) -> HashMap<String, String> {
     let mut rename_map: HashMap<String, String> = HashMap::new();
     
     let reserved_words: HashSet<&str> = [
@@ -0,0 +1,530 @@
+//! `pq merge` without loading the using file into Stata.
+//!
+//! The master key variables are read from Stata, and the using file is
+//! joined to them lazily. When keep() drops using-only observations the
+//! using file is first reduced to the rows whose keys appear in the master
+//! data, so only those rows are ever collected. The result holds the using
+//! rows that will end up in the data (matched or using-only), their
+//! keepusing() columns, and the master observation each one belongs to;
+//! pq.ado loads it and attaches it to the data by that row number, so the
+//! _merge codes it gets are the ones computed here.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use polars::prelude::*;
+use crate::stata_if;
+
+use crate::convert::metadata_for_columns;
+use crate::csv_dialect::CsvDialect;
+use crate::fast_cache::resolve_varlist;
+use crate::read::{scan_lazyframe_with_options, source_metadata, InputFormat};
+use crate::stata_interface::{display, get_macro};
+use crate::stata_metadata;
+use crate::utilities::{DAY_SHIFT_SAS_STATA, SEC_SHIFT_SAS_STATA};
+use crate::write::{column_info_from_macros, parquet_options, StataDataScan};
+
+const MERGE_MASTER: u8 = 1;
+const MERGE_USING: u8 = 2;
+const MERGE_MATCH: u8 = 3;
+
+/// Marks using rows through the join; null afterwards means master only.
+const USING_MARKER: &str = "__pq_in_using";
+const MERGE_CODE: &str = "__pq_merge";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeKind {
+    OneToOne,
+    ManyToOne,
+    OneToMany,
+}
+
+impl MergeKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "1:1" => Some(Self::OneToOne),
+            "m:1" => Some(Self::ManyToOne),
+            "1:m" => Some(Self::OneToMany),
+            _ => None,
+        }
+    }
+
+    fn unique_master(&self) -> bool {
+        matches!(self, Self::OneToOne | Self::OneToMany)
+    }
+
+    fn unique_using(&self) -> bool {
+        matches!(self, Self::OneToOne | Self::ManyToOne)
+    }
+}
+
+pub struct MergeOptions<'a> {
+    pub kind: MergeKind,
+    pub input_format: InputFormat,
+    /// Using columns to bring in besides the keys (names or patterns;
+    /// empty brings all).
+    pub keepusing: &'a str,
+    pub drop: &'a str,
+    pub sql_if: Option<&'a str>,
+    /// _merge codes to keep and to assert, as 1/2/3; empty means all.
+    pub keep: Vec<u8>,
+    pub assert: Vec<u8>,
+    /// Name of the master row number column (1-based, _n) in the result.
+    pub row_column: &'a str,
+    pub safe_relaxed: bool,
+    pub asterisk_to_variable: Option<&'a str>,
+    pub infer_schema_length: Option<usize>,
+    pub parse_dates: bool,
+    pub csv_dialect: CsvDialect,
+}
+
+/// Observations in the merged data by _merge code.
+#[derive(Debug, Default, PartialEq)]
+pub struct MergeCounts {
+    pub master: usize,
+    pub using: usize,
+    pub matched: usize,
+}
+
+impl MergeCounts {
+    fn count(&self, code: u8) -> usize {
+        match code {
+            MERGE_MASTER => self.master,
+            MERGE_USING => self.using,
+            _ => self.matched,
+        }
+    }
+}
+
+/// The key variables staged by pq.ado (var_count, name_#, dtype_#, ...)
+/// read from the data in memory.
+pub fn master_keys_from_stata() -> Result<DataFrame, String> {
+    let n_vars = get_macro("var_count", false, None).parse::<usize>().unwrap_or(0);
+    let column_info = column_info_from_macros(n_vars, HashMap::new());
+    let names: Vec<PlSmallStr> = column_info.iter().map(|c| PlSmallStr::from(c.name.as_str())).collect();
+    let scan = StataDataScan::new(column_info, names, Some(100_000), 0, 0, None);
+    LazyFrame::anonymous_scan(Arc::new(scan), ScanArgsAnonymous::default())
+        .and_then(|lf| lf.collect())
+        .map_err(|e| format!("Error reading the key variables: {}", e))
+}
+
+/// A key as the number Stata compares (dates on the 1960 epoch) or as a
+/// string. None for a type merge cannot compare with a Stata variable.
+fn stata_key_expr(name: &str, dtype: &DataType) -> Option<(Expr, bool)> {
+    let column = col(name);
+    match dtype {
+        DataType::String => Some((column, true)),
+        DataType::Categorical(_, _) | DataType::Enum(_, _) => Some((column.cast(DataType::String), true)),
+        DataType::Date => Some((
+            column.cast(DataType::Int32).cast(DataType::Float64) + lit(DAY_SHIFT_SAS_STATA as f64),
+            false,
+        )),
+        DataType::Datetime(_, _) => Some((
+            column
+                .cast(DataType::Datetime(TimeUnit::Milliseconds, None))
+                .cast(DataType::Int64)
+                .cast(DataType::Float64)
+                + lit((SEC_SHIFT_SAS_STATA * 1000) as f64),
+            false,
+        )),
+        dtype if dtype.is_primitive_numeric() || dtype.is_bool() || *dtype == DataType::Time => {
+            Some((column.cast(DataType::Float64), false))
+        }
+        _ => None,
+    }
+}
+
+fn join_key(i: usize) -> String {
+    format!("__pq_key_{}", i + 1)
+}
+
+fn has_duplicate_keys(lf: LazyFrame, keys: &[String]) -> PolarsResult<bool> {
+    let key_exprs: Vec<Expr> = keys.iter().map(|k| col(k.as_str())).collect();
+    let duplicates = lf
+        .group_by(key_exprs)
+        .agg([len().alias("__pq_n")])
+        .filter(col("__pq_n").gt(lit(1)))
+        .limit(1)
+        .collect()?;
+    Ok(duplicates.height() > 0)
+}
+
+fn code_names(codes: &[u8]) -> String {
+    let names: Vec<&str> = codes
+        .iter()
+        .map(|code| match *code {
+            MERGE_MASTER => "master",
+            MERGE_USING => "using",
+            _ => "match",
+        })
+        .collect();
+    names.join(" ")
+}
+
+/// Joins `master_keys` to the using file and writes the rows that will end
+/// up in the data to `target`. Returns a Stata return code; errors have
+/// been displayed.
+pub fn merge_to_parquet(using: &str, target: &str, master_keys: DataFrame, options: &MergeOptions) -> i32 {
+    match merge_inner(using, target, master_keys, options) {
+        Ok(_) => 0,
+        Err((rc, message)) => {
+            display(&message);
+            rc
+        }
+    }
+}
+
+fn merge_inner(
+    using: &str,
+    target: &str,
+    master_keys: DataFrame,
+    options: &MergeOptions,
+) -> Result<MergeCounts, (i32, String)> {
+    let polars_error = |e: PolarsError| (198, format!("Error merging {}: {}", using, e));
+    let keys: Vec<String> = master_keys.get_column_names().iter().map(|s| s.to_string()).collect();
+    let n_master = master_keys.height();
+
+    let mut using_lf = scan_lazyframe_with_options(
+        using,
+        options.safe_relaxed,
+        options.asterisk_to_variable,
+        options.input_format,
+        false,
+        options.infer_schema_length,
+        options.parse_dates,
+        None,
+        &options.csv_dialect,
+    )
+    .map_err(|e| (198, format!("Error scanning {}: {}", using, e)))?;
+    if let Some(sql) = options.sql_if.filter(|s| !s.trim().is_empty()) {
+        using_lf = stata_if::filter_lazy(using_lf, sql).map_err(|e| (198, format!("Error in SQL if statement: {}", e)))?;
+    }
+
+    let using_schema = using_lf.collect_schema().map_err(polars_error)?;
+    for key in &keys {
+        if using_schema.get(key).is_none() {
+            return Err((111, format!("variable {} not found in using data", key)));
+        }
+    }
+    let using_names: Vec<&str> = using_schema.iter_names().map(|s| s.as_str()).collect();
+    let mut using_columns = resolve_varlist(options.keepusing, &using_names, options.drop).map_err(|e| (111, e))?;
+    using_columns.retain(|c| !keys.contains(c));
+    let output_columns: Vec<String> = keys.iter().cloned().chain(using_columns.iter().cloned()).collect();
+
+    // Keys in the form Stata compares them, under shared names for the join
+    let mut master_key_exprs = Vec::with_capacity(keys.len());
+    let mut using_key_exprs = Vec::with_capacity(keys.len());
+    for (i, key) in keys.iter().enumerate() {
+        let master_dtype = master_keys.column(key).map_err(polars_error)?.dtype().clone();
+        let using_dtype = using_schema.get(key).cloned().unwrap_or(DataType::Null);
+        let (Some((master_expr, master_string)), Some((using_expr, using_string))) =
+            (stata_key_expr(key, &master_dtype), stata_key_expr(key, &using_dtype))
+        else {
+            return Err((106, format!("key variable {} has type {} in the using data, which cannot be merged on", key, using_dtype)));
+        };
+        if master_string != using_string {
+            let describe = |is_string: bool| if is_string { "string" } else { "numeric" };
+            return Err((
+                106,
+                format!(
+                    "key variable {} is {} in master but {} in using data",
+                    key,
+                    describe(master_string),
+                    describe(using_string)
+                ),
+            ));
+        }
+        master_key_exprs.push(master_expr.alias(join_key(i)));
+        using_key_exprs.push(using_expr.alias(join_key(i)));
+    }
+    let join_keys: Vec<String> = (0..keys.len()).map(join_key).collect();
+    let join_exprs: Vec<Expr> = join_keys.iter().map(|k| col(k.as_str())).collect();
+
+    let master = master_keys
+        .lazy()
+        .with_row_index(options.row_column, Some(1))
+        .select(
+            std::iter::once(col(options.row_column).cast(DataType::Int64))
+                .chain(master_key_exprs)
+                .collect::<Vec<_>>(),
+        );
+    let using_keyed = using_lf.select(
+        output_columns
+            .iter()
+            .map(|c| col(c.as_str()))
+            .chain(using_key_exprs)
+            .chain(std::iter::once(lit(true).alias(USING_MARKER)))
+            .collect::<Vec<_>>(),
+    );
+
+    if options.kind.unique_master() && has_duplicate_keys(master.clone(), &join_keys).map_err(polars_error)? {
+        return Err((459, format!("variables {} do not uniquely identify observations in the master data", keys.join(" "))));
+    }
+    if options.kind.unique_using() && has_duplicate_keys(using_keyed.clone(), &join_keys).map_err(polars_error)? {
+        return Err((459, format!("variables {} do not uniquely identify observations in the using data", keys.join(" "))));
+    }
+
+    let keeps = |code: u8| options.keep.is_empty() || options.keep.contains(&code);
+    let master_distinct = master.clone().select(join_exprs.clone()).unique(None, UniqueKeepStrategy::Any);
+    let joined = if keeps(MERGE_USING) {
+        master
+            .join_builder()
+            .with(using_keyed.clone())
+            .how(JoinType::Full)
+            .left_on(join_exprs.clone())
+            .right_on(join_exprs.clone())
+            .join_nulls(true)
+            .coalesce(JoinCoalesce::CoalesceColumns)
+            .finish()
+    } else {
+        // Using-only rows are dropped anyway: reduce the using file to the
+        // master's keys before anything is held in memory.
+        let matching_using = using_keyed
+            .clone()
+            .join_builder()
+            .with(master_distinct.clone())
+            .how(JoinType::Inner)
+            .left_on(join_exprs.clone())
+            .right_on(join_exprs.clone())
+            .join_nulls(true)
+            .finish();
+        master
+            .join_builder()
+            .with(matching_using)
+            .how(JoinType::Left)
+            .left_on(join_exprs.clone())
+            .right_on(join_exprs.clone())
+            .join_nulls(true)
+            .coalesce(JoinCoalesce::CoalesceColumns)
+            .finish()
+    };
+    let joined = joined
+        .with_column(
+            when(col(options.row_column).is_null())
+                .then(lit(MERGE_USING as i32))
+                .when(col(USING_MARKER).is_null())
+                .then(lit(MERGE_MASTER as i32))
+                .otherwise(lit(MERGE_MATCH as i32))
+                .alias(MERGE_CODE),
+        )
+        .collect()
+        .map_err(polars_error)?;
+
+    let codes = joined.column(MERGE_CODE).map_err(polars_error)?.i32().map_err(polars_error)?.clone();
+    let mut counts = MergeCounts::default();
+    for code in codes.into_no_null_iter() {
+        match code as u8 {
+            MERGE_MASTER => counts.master += 1,
+            MERGE_USING => counts.using += 1,
+            _ => counts.matched += 1,
+        }
+    }
+    if !keeps(MERGE_USING) && !options.assert.is_empty() && !options.assert.contains(&MERGE_USING) {
+        // Only the key columns of the using file are read for this count.
+        let unmatched = using_keyed
+            .select(join_exprs.clone())
+            .join_builder()
+            .with(master_distinct.with_column(lit(true).alias(USING_MARKER)))
+            .how(JoinType::Left)
+            .left_on(join_exprs.clone())
+            .right_on(join_exprs.clone())
+            .join_nulls(true)
+            .finish()
+            .filter(col(USING_MARKER).is_null())
+            .select([len()])
+            .collect()
+            .map_err(polars_error)?;
+        counts.using = unmatched
+            .column("len")
+            .ok()
+            .and_then(|c| c.get(0).ok())
+            .and_then(|v| v.extract::<usize>())
+            .unwrap_or(0);
+    }
+
+    if !options.assert.is_empty() {
+        let violated = [MERGE_MASTER, MERGE_USING, MERGE_MATCH]
+            .into_iter()
+            .any(|code| !options.assert.contains(&code) && counts.count(code) > 0);
+        if violated {
+            return Err((
+                9,
+                format!(
+                    "merge: after merge, not all observations from {}\n       (merge assumption assert({}) not met: {} master only, {} using only, {} matched)",
+                    code_names(&options.assert),
+                    code_names(&options.assert),
+                    counts.master,
+                    counts.using,
+                    counts.matched
+                ),
+            ));
+        }
+    }
+
+    // Rows that bring using data into Stata; using-only rows are numbered
+    // after the master observations.
+    let mut result = joined
+        .lazy()
+        .filter(col(MERGE_CODE).neq(lit(MERGE_MASTER as i32)))
+        .filter(if keeps(MERGE_MATCH) { lit(true) } else { col(MERGE_CODE).neq(lit(MERGE_MATCH as i32)) })
+        .with_column(
+            when(col(options.row_column).is_null())
+                .then(
+                    lit(n_master as i64)
+                        + col(options.row_column).is_null().cast(DataType::Int64).cum_sum(false),
+                )
+                .otherwise(col(options.row_column))
+                .alias(options.row_column),
+        )
+        .select(
+            std::iter::once(col(options.row_column))
+                .chain(output_columns.iter().map(|c| col(c.as_str())))
+                .collect::<Vec<_>>(),
+        )
+        .collect()
+        .map_err(polars_error)?;
+
+    // Labels and formats of the using variables come along, as with pq use.
+    let key_value_metadata = source_metadata(using, options.input_format)
+        .ok()
+        .flatten()
+        .and_then(|envelope| metadata_for_columns(envelope, &output_columns))
+        .as_ref()
+        .and_then(stata_metadata::build_key_value_metadata);
+    let file = File::create(target).map_err(|e| (198, format!("Error writing merge result: {}", e)))?;
+    parquet_options("", None, key_value_metadata)
+        .to_writer(file)
+        .finish(&mut result)
+        .map_err(polars_error)?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(kind: MergeKind) -> MergeOptions<'static> {
+        MergeOptions {
+            kind,
+            input_format: InputFormat::Parquet,
+            keepusing: "",
+            drop: "",
+            sql_if: None,
+            keep: Vec::new(),
+            assert: Vec::new(),
+            row_column: "__row",
+            safe_relaxed: false,
+            asterisk_to_variable: None,
+            infer_schema_length: None,
+            parse_dates: false,
+            csv_dialect: CsvDialect::default(),
+        }
+    }
+
+    struct Files {
+        dir: std::path::PathBuf,
+        using: String,
+        result: String,
+    }
+
+    impl Files {
+        fn new(tag: &str, mut using: DataFrame) -> Self {
+            let dir = std::env::temp_dir().join(format!("pq_merge_rs_{}_{}", tag, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("using.parquet").to_string_lossy().to_string();
+            ParquetWriter::new(File::create(&path).unwrap()).finish(&mut using).unwrap();
+            let result = dir.join("result.parquet").to_string_lossy().to_string();
+            Files { dir, using: path, result }
+        }
+
+        fn result(&self) -> DataFrame {
+            ParquetReader::new(File::open(&self.result).unwrap()).finish().unwrap()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn ints(df: &DataFrame, name: &str) -> Vec<Option<i64>> {
+        df.column(name).unwrap().cast(&DataType::Int64).unwrap().i64().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn many_to_one_numbers_rows_and_counts_codes() {
+        let files = Files::new(
+            "m1",
+            df!("id" => [1i64, 2, 5], "region" => ["n", "s", "w"], "pop" => [10i64, 20, 50]).unwrap(),
+        );
+        // Stata long keys arrive as Int32 and must match the Int64 using keys.
+        let master = df!("id" => [2i32, 1, 2, 4]).unwrap();
+        let mut opts = options(MergeKind::ManyToOne);
+        opts.keepusing = "region";
+        let counts = merge_inner(&files.using, &files.result, master, &opts).unwrap();
+        assert_eq!(counts, MergeCounts { master: 1, using: 1, matched: 3 });
+
+        let result = files.result().sort(["__row"], Default::default()).unwrap();
+        assert_eq!(result.get_column_names(), ["__row", "id", "region"]);
+        assert_eq!(ints(&result, "__row"), [Some(1), Some(2), Some(3), Some(5)]);
+        assert_eq!(ints(&result, "id"), [Some(2), Some(1), Some(2), Some(5)]);
+    }
+
+    #[test]
+    fn keep_match_only_and_one_to_many_duplicates_master_rows() {
+        let files = Files::new(
+            "1m",
+            df!("id" => [1i64, 1, 2, 9], "visit" => [1i64, 2, 1, 1]).unwrap(),
+        );
+        let master = df!("id" => [1.0f64, 2.0, 3.0]).unwrap();
+        let mut opts = options(MergeKind::OneToMany);
+        opts.keep = vec![MERGE_MATCH];
+        let counts = merge_inner(&files.using, &files.result, master, &opts).unwrap();
+        assert_eq!(counts.matched, 3);
+        let result = files.result().sort(["__row", "visit"], Default::default()).unwrap();
+        assert_eq!(ints(&result, "__row"), [Some(1), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn uniqueness_types_and_assert_are_checked() {
+        let files = Files::new("err", df!("id" => [1i64, 1], "name" => ["a", "b"]).unwrap());
+        let master = || df!("id" => [1i32, 2]).unwrap();
+
+        let err = merge_inner(&files.using, &files.result, master(), &options(MergeKind::OneToOne)).unwrap_err();
+        assert_eq!(err.0, 459);
+        assert!(err.1.contains("using data"));
+
+        let mut opts = options(MergeKind::OneToMany);
+        opts.assert = vec![MERGE_MATCH];
+        assert_eq!(merge_inner(&files.using, &files.result, master(), &opts).unwrap_err().0, 9);
+
+        let string_master = df!("id" => ["1", "2"]).unwrap();
+        assert_eq!(
+            merge_inner(&files.using, &files.result, string_master, &options(MergeKind::OneToMany)).unwrap_err().0,
+            106
+        );
+        let other_key = df!("code" => [1i32]).unwrap();
+        assert_eq!(
+            merge_inner(&files.using, &files.result, other_key, &options(MergeKind::OneToMany)).unwrap_err().0,
+            111
+        );
+    }
+
+    #[test]
+    fn date_keys_compare_on_the_stata_epoch() {
+        // 05jan2020: 18266 days after 01jan1970, 21919 after 01jan1960
+        let using = df!("day" => [18266i32], "temp" => [3.5])
+            .unwrap()
+            .lazy()
+            .with_column(col("day").cast(DataType::Date))
+            .collect()
+            .unwrap();
+        let files = Files::new("date", using);
+        // A plain long holding a Stata date
+        let master = df!("day" => [21919i32]).unwrap();
+        let counts = merge_inner(&files.using, &files.result, master, &options(MergeKind::OneToOne)).unwrap();
+        assert_eq!(counts.matched, 1);
+    }
+}
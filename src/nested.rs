@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use polars::prelude::*;
+
+/// How List/Array columns are laid out for Stata, which has no nested types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListMode {
+    /// Leave list columns as they are (they cannot be loaded).
+    Keep,
+    /// One row per list element, plus a 1-based `<name>_index` column.
+    Explode,
+    /// One column per element position (`<name>_1`, `<name>_2`, ...), up to
+    /// the longest list in the data or the optional user cap, whichever is
+    /// smaller.
+    Spread(Option<usize>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NestedOptions {
+    pub unnest: bool,
+    pub list_mode: ListMode,
+}
+
+impl Default for NestedOptions {
+    fn default() -> Self {
+        Self {
+            unnest: false,
+            list_mode: ListMode::Keep,
+        }
+    }
+}
+
+impl NestedOptions {
+    /// Parses the `unnest` flag ("0"/"1") and `list()` option text
+    /// ("", "explode", "spread" or "spread #") as passed by pq.ado.
+    pub fn from_args(unnest: &str, list_mode: &str) -> Result<Self, String> {
+        let mut tokens = list_mode.split_whitespace();
+        let mode = tokens.next().map(|s| s.to_ascii_lowercase());
+        let width = tokens.next();
+        if tokens.next().is_some() {
+            return Err(format!("list({}): expected explode or spread [#]", list_mode.trim()));
+        }
+
+        let list_mode = match (mode.as_deref(), width) {
+            (None, _) | (Some("keep"), None) => ListMode::Keep,
+            (Some("explode"), None) => ListMode::Explode,
+            (Some("spread"), None) => ListMode::Spread(None),
+            (Some("spread"), Some(w)) => match w.parse::<usize>() {
+                Ok(n) if n >= 1 => ListMode::Spread(Some(n)),
+                _ => {
+                    return Err(format!(
+                        "list(spread {}): maximum width must be a positive integer",
+                        w
+                    ))
+                }
+            },
+            _ => {
+                return Err(format!("list({}): expected explode or spread [#]", list_mode.trim()));
+            }
+        };
+
+        Ok(Self {
+            unnest: unnest.trim() == "1",
+            list_mode,
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.unnest || self.list_mode != ListMode::Keep
+    }
+
+    /// Stable text form, used in the fast cache key so a frame cached with one
+    /// layout is never handed to a read that asked for another.
+    pub fn as_key(&self) -> String {
+        let list = match self.list_mode {
+            ListMode::Keep => "keep".to_string(),
+            ListMode::Explode => "explode".to_string(),
+            ListMode::Spread(None) => "spread".to_string(),
+            ListMode::Spread(Some(n)) => format!("spread {}", n),
+        };
+        format!("unnest={}|list={}", self.unnest, list)
+    }
+}
+
+fn is_list_like(dtype: &DataType) -> bool {
+    matches!(dtype, DataType::List(_) | DataType::Array(_, _))
+}
+
+/// Names of columns that would still be nested after flattening with `opts`,
+/// i.e. the ones Stata cannot load as-is.
+pub fn unhandled_nested_columns(schema: &Schema, opts: &NestedOptions) -> Vec<String> {
+    schema
+        .iter()
+        .filter_map(|(name, dtype)| {
+            let unhandled = match dtype {
+                DataType::Struct(_) => !opts.unnest,
+                dt if is_list_like(dt) => opts.list_mode == ListMode::Keep,
+                _ => false,
+            };
+            unhandled.then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Flattens Struct columns into `<parent>_<field>` columns and lays List/Array
+/// columns out per `opts.list_mode`, repeating until nothing nested is left to
+/// handle (a list of structs is exploded/spread first, then unnested on the
+/// next pass). Column order is kept: flattened columns take the place of their
+/// parent.
+///
+/// `spread_widths` carries the number of spread columns per list column
+/// (keyed by the list column's name at the pass it was spread). Widths already
+/// present are used as-is, so the read pass reproduces exactly the columns
+/// describe reported; missing widths are measured from the data and added.
+/// Returns the lists whose longest observed length exceeded the spread cap,
+/// with that length, so describe can report the truncation.
+pub fn flatten_nested_columns(
+    lf: LazyFrame,
+    opts: &NestedOptions,
+    spread_widths: &mut HashMap<String, usize>,
+) -> PolarsResult<(LazyFrame, Vec<(String, usize)>)> {
+    // Guards against pathological self-similar schemas; real data nests a
+    // handful of levels at most.
+    const MAX_NESTING_DEPTH: usize = 32;
+
+    let mut lf = lf;
+    let mut truncated: Vec<(String, usize)> = Vec::new();
+    if !opts.is_active() {
+        return Ok((lf, truncated));
+    }
+
+    for _ in 0..MAX_NESTING_DEPTH {
+        let schema = lf.collect_schema()?;
+
+        if let ListMode::Spread(cap) = opts.list_mode {
+            let unmeasured: Vec<(PlSmallStr, DataType)> = schema
+                .iter()
+                .filter(|(name, dtype)| is_list_like(dtype) && !spread_widths.contains_key(name.as_str()))
+                .map(|(name, dtype)| (name.clone(), dtype.clone()))
+                .collect();
+            let longest = observed_list_lengths(&lf, &unmeasured)?;
+            for (name, observed) in longest {
+                let width = match cap {
+                    Some(cap) if observed > cap => {
+                        truncated.push((name.clone(), observed));
+                        cap
+                    }
+                    // An all-empty list column still gets one (missing)
+                    // variable rather than vanishing from the load.
+                    _ => observed.max(1),
+                };
+                spread_widths.insert(name, width);
+            }
+        }
+
+        let mut exprs: Vec<Expr> = Vec::with_capacity(schema.len());
+        let mut explode_pairs: Vec<(PlSmallStr, PlSmallStr)> = Vec::new();
+        let mut changed = false;
+
+        for (name, dtype) in schema.iter() {
+            match dtype {
+                DataType::Struct(fields) if opts.unnest => {
+                    for field in fields {
+                        exprs.push(
+                            col(name.clone())
+                                .struct_()
+                                .field_by_name(field.name().as_str())
+                                .alias(format!("{}_{}", name, field.name())),
+                        );
+                    }
+                    changed = true;
+                }
+                dt if is_list_like(dt) && opts.list_mode != ListMode::Keep => {
+                    let list_expr = as_list_expr(name, dt);
+                    match opts.list_mode {
+                        ListMode::Explode => {
+                            let index_name = PlSmallStr::from(format!("{}_index", name));
+                            exprs.push(list_expr.clone().alias(name.clone()));
+                            exprs.push(
+                                int_ranges(
+                                    lit(1i32),
+                                    list_expr.list().len().cast(DataType::Int32) + lit(1i32),
+                                    lit(1i32),
+                                    DataType::Int32,
+                                )
+                                .alias(index_name.clone()),
+                            );
+                            explode_pairs.push((name.clone(), index_name));
+                        }
+                        ListMode::Spread(_) => {
+                            let width = spread_widths.get(name.as_str()).copied().unwrap_or(1);
+                            for i in 0..width {
+                                exprs.push(
+                                    list_expr
+                                        .clone()
+                                        .list()
+                                        .get(lit(i as i64), true)
+                                        .alias(format!("{}_{}", name, i + 1)),
+                                );
+                            }
+                        }
+                        ListMode::Keep => unreachable!(),
+                    }
+                    changed = true;
+                }
+                _ => exprs.push(col(name.clone())),
+            }
+        }
+
+        if !changed {
+            break;
+        }
+
+        lf = lf.select(exprs);
+        // Each list is exploded on its own (its index alongside it), so two
+        // list columns in the same row produce every combination of their
+        // elements rather than requiring equal lengths.
+        for (list_name, index_name) in explode_pairs {
+            lf = lf.explode(
+                cols([list_name, index_name]),
+                ExplodeOptions {
+                    empty_as_null: true,
+                    keep_nulls: true,
+                },
+            );
+        }
+    }
+
+    Ok((lf, truncated))
+}
+
+/// Fixed-size Array columns are handled as Lists so one code path covers both.
+fn as_list_expr(name: &PlSmallStr, dtype: &DataType) -> Expr {
+    match dtype {
+        DataType::Array(inner, _) => col(name.clone()).cast(DataType::List(inner.clone())),
+        _ => col(name.clone()),
+    }
+}
+
+/// Longest list length per column, from a single aggregation over just those
+/// columns. Fixed-size Arrays need no scan - their width is in the dtype.
+fn observed_list_lengths(
+    lf: &LazyFrame,
+    columns: &[(PlSmallStr, DataType)],
+) -> PolarsResult<Vec<(String, usize)>> {
+    let mut out = Vec::with_capacity(columns.len());
+    let mut exprs: Vec<Expr> = Vec::new();
+    let mut scanned: Vec<PlSmallStr> = Vec::new();
+    for (name, dtype) in columns {
+        match dtype {
+            DataType::Array(_, size) => out.push((name.to_string(), *size)),
+            _ => {
+                exprs.push(col(name.clone()).list().len().max().cast(DataType::UInt64).alias(name.clone()));
+                scanned.push(name.clone());
+            }
+        }
+    }
+    if exprs.is_empty() {
+        return Ok(out);
+    }
+
+    let lengths = lf.clone().select(exprs).collect()?;
+    for name in scanned {
+        let longest = lengths.column(name.as_str())?.u64()?.get(0).unwrap_or(0);
+        out.push((name.to_string(), longest as usize));
+    }
+    Ok(out)
+}
+
+/// Serializes spread widths for the `pq_list_widths` macro that carries them
+/// from describe to read.
+pub fn widths_to_json(spread_widths: &HashMap<String, usize>) -> String {
+    if spread_widths.is_empty() {
+        String::new()
+    } else {
+        serde_json::to_string(spread_widths).unwrap_or_default()
+    }
+}
+
+pub fn widths_from_json(json: &str) -> HashMap<String, usize> {
+    if json.trim().is_empty() {
+        return HashMap::new();
+    }
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_frame() -> LazyFrame {
+        let point = StructChunked::from_series(
+            "point".into(),
+            2,
+            [
+                Series::new("x".into(), [1.0f64, 2.0]),
+                Series::new("y".into(), [3.0f64, 4.0]),
+            ]
+            .iter(),
+        )
+        .unwrap()
+        .into_series();
+        let tags = Series::new(
+            "tags".into(),
+            [
+                Series::new("".into(), [10i64, 20, 30]),
+                Series::new("".into(), [40i64]),
+            ],
+        );
+        DataFrame::new_infer_height(vec![
+            Series::new("id".into(), [1i32, 2]).into_column(),
+            point.into_column(),
+            tags.into_column(),
+        ])
+        .unwrap()
+        .lazy()
+    }
+
+    #[test]
+    fn parses_list_option_text() {
+        assert_eq!(
+            NestedOptions::from_args("1", "").unwrap(),
+            NestedOptions { unnest: true, list_mode: ListMode::Keep }
+        );
+        assert_eq!(NestedOptions::from_args("0", "explode").unwrap().list_mode, ListMode::Explode);
+        assert_eq!(NestedOptions::from_args("0", "spread").unwrap().list_mode, ListMode::Spread(None));
+        assert_eq!(NestedOptions::from_args("0", "Spread 4").unwrap().list_mode, ListMode::Spread(Some(4)));
+        assert!(NestedOptions::from_args("0", "spread 0").is_err());
+        assert!(NestedOptions::from_args("0", "wide").is_err());
+    }
+
+    #[test]
+    fn unnests_structs_in_place() {
+        let opts = NestedOptions { unnest: true, list_mode: ListMode::Keep };
+        let (mut lf, _) = flatten_nested_columns(nested_frame(), &opts, &mut HashMap::new()).unwrap();
+        let names: Vec<String> = lf.collect_schema().unwrap().iter_names().map(|s| s.to_string()).collect();
+        assert_eq!(names, vec!["id", "point_x", "point_y", "tags"]);
+    }
+
+    #[test]
+    fn spreads_lists_to_longest_observed_length() {
+        let opts = NestedOptions { unnest: false, list_mode: ListMode::Spread(None) };
+        let mut widths = HashMap::new();
+        let (lf, truncated) = flatten_nested_columns(nested_frame(), &opts, &mut widths).unwrap();
+        let df = lf.collect().unwrap();
+        assert!(truncated.is_empty());
+        assert_eq!(widths.get("tags"), Some(&3));
+        assert_eq!(df.column("tags_3").unwrap().i64().unwrap().get(0), Some(30));
+        assert_eq!(df.column("tags_2").unwrap().i64().unwrap().get(1), None);
+    }
+
+    #[test]
+    fn spread_cap_reports_truncation() {
+        let opts = NestedOptions { unnest: false, list_mode: ListMode::Spread(Some(2)) };
+        let mut widths = HashMap::new();
+        let (_, truncated) = flatten_nested_columns(nested_frame(), &opts, &mut widths).unwrap();
+        assert_eq!(widths.get("tags"), Some(&2));
+        assert_eq!(truncated, vec![("tags".to_string(), 3)]);
+    }
+
+    #[test]
+    fn explodes_lists_with_element_index() {
+        let opts = NestedOptions { unnest: true, list_mode: ListMode::Explode };
+        let (lf, _) = flatten_nested_columns(nested_frame(), &opts, &mut HashMap::new()).unwrap();
+        let df = lf.collect().unwrap();
+        assert_eq!(df.height(), 4);
+        let index: Vec<Option<i32>> = df.column("tags_index").unwrap().i32().unwrap().into_iter().collect();
+        assert_eq!(index, vec![Some(1), Some(2), Some(3), Some(1)]);
+        let ids: Vec<Option<i32>> = df.column("id").unwrap().i32().unwrap().into_iter().collect();
+        assert_eq!(ids, vec![Some(1), Some(1), Some(1), Some(2)]);
+    }
+}
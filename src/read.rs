@@ -8,17 +8,18 @@ use std::time::{Duration, Instant};
 use rayon::prelude::*;
 use polars::error::ErrString;
 use polars::prelude::*;
-use polars_sql::SQLContext;
+use crate::{stata_if, time_zone};
 use polars::datatypes::{AnyValue, TimeUnit};
 use std::error::Error;
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use glob::glob;
 use regex::Regex;
 use polars_readstat_rs::{
     readstat_batch_iter,
     readstat_metadata_json,
     readstat_scan,
+    scan_por,
     ReadStatFormat,
     ScanOptions as ReadStatScanOptions,
 };
@@ -28,6 +29,16 @@ use sqlparser::parser::Parser;
 
 use crate::fast_cache::{self, FastCacheKey, parse_varlist};
 use crate::mapping::ColumnInfo;
+use crate::nested::{flatten_nested_columns, widths_from_json, NestedOptions};
+use crate::csv_dialect::CsvDialect;
+use crate::readstat_metadata::{metadata_from_por, metadata_from_readstat};
+use crate::stata_metadata::{
+    dictionaries_from_json,
+    recorded_duration_units,
+    with_categorical_value_labels,
+    CategoricalDictionary,
+    StataMetadataEnvelope,
+};
 use crate::stata_interface::{
     display,
     set_macro,
@@ -41,6 +52,7 @@ use crate::utilities::{
     get_thread_pool,
     ms,
     profile_timing_enabled,
+    DurationUnit,
     DAY_SHIFT_SAS_STATA,
     SEC_MICROSECOND,
     SEC_MILLISECOND,
@@ -101,6 +113,13 @@ pub enum InputFormat {
     Sas,
     Spss,
     Csv,
+    Dta,
+    Ipc,
+    Ndjson,
+    Xpt,
+    Por,
+    /// A `pq sql` query over files; the "path" is the query itself.
+    Sql,
 }
 
 impl InputFormat {
@@ -110,6 +129,14 @@ impl InputFormat {
             "sas" | "sas7bdat" => Some(Self::Sas),
             "spss" | "sav" | "zsav" => Some(Self::Spss),
             "csv" => Some(Self::Csv),
+            "dta" | "stata" => Some(Self::Dta),
+            "ipc" | "arrow" | "feather" => Some(Self::Ipc),
+            // Not "json": a .json file is usually one JSON array, which the
+            // line reader can't parse.
+            "ndjson" | "jsonl" => Some(Self::Ndjson),
+            "xpt" | "xport" => Some(Self::Xpt),
+            "por" => Some(Self::Por),
+            "sql" => Some(Self::Sql),
             _ => None,
         }
     }
@@ -120,8 +147,39 @@ impl InputFormat {
             Self::Sas => "sas",
             Self::Spss => "spss",
             Self::Csv => "csv",
+            Self::Dta => "dta",
+            Self::Ipc => "ipc",
+            Self::Ndjson => "ndjson",
+            Self::Xpt => "xpt",
+            Self::Por => "por",
+            Self::Sql => "sql",
         }
     }
+
+    /// Formats read through polars_readstat_rs: labels and formats come from
+    /// its metadata, and scans are projected to the columns a read needs.
+    pub fn is_readstat(&self) -> bool {
+        matches!(self, Self::Sas | Self::Spss | Self::Dta | Self::Xpt | Self::Por)
+    }
+}
+
+/// Format name from a path's extension (compression suffixes ignored), as
+/// pq_infer_format does in pq.ado.
+pub fn format_from_extension(path: &str) -> &'static str {
+    let lower = path.to_ascii_lowercase();
+    let stripped = crate::decompress::strip_compression_extension(&lower);
+    let extension = stripped.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("");
+    match extension {
+        "sas7bdat" => "sas",
+        "sav" | "zsav" => "spss",
+        "csv" => "csv",
+        "dta" => "dta",
+        "arrow" | "feather" | "ipc" => "ipc",
+        "jsonl" | "ndjson" => "ndjson",
+        "xpt" => "xpt",
+        "por" => "por",
+        _ => "parquet",
+    }
 }
 
 fn parse_polars_debug_dtype(dtype: &str) -> Option<DataType> {
@@ -324,6 +382,7 @@ pub fn scan_lazyframe(
         None,
         false,
         None,
+        &CsvDialect::default(),
     )
 }
 
@@ -333,15 +392,70 @@ pub fn scan_lazyframe_with_options(
     asterisk_to_variable_name: Option<&str>,
     input_format: InputFormat,
     preserve_order: bool,
-    csv_infer_schema_length: Option<usize>,
+    scan_infer_schema_length: Option<usize>,
     csv_try_parse_dates: bool,
     csv_schema: Option<SchemaRef>,
+    csv_dialect: &CsvDialect,
 ) -> Result<LazyFrame, PolarsError> {
     match input_format {
         InputFormat::Parquet => scan_lazyframe_parquet(path, safe_relaxed, asterisk_to_variable_name),
         InputFormat::Sas => scan_lazyframe_readstat(path, ReadStatFormat::Sas, preserve_order),
         InputFormat::Spss => scan_lazyframe_readstat(path, ReadStatFormat::Spss, preserve_order),
-        InputFormat::Csv => scan_lazyframe_csv(path, csv_infer_schema_length, csv_try_parse_dates, csv_schema),
+        InputFormat::Xpt => scan_lazyframe_readstat(path, ReadStatFormat::SasXpt, preserve_order),
+        // SPSS portable files have no batch reader; each file is read whole.
+        InputFormat::Por => scan_lazyframe_files(path, |file| scan_por(file, readstat_scan_options(ReadStatFormat::Spss))),
+        InputFormat::Dta => match asterisk_to_variable_name {
+            Some(var_name) => scan_with_filename_extraction(path, var_name, |file| {
+                let mut options = readstat_scan_options(ReadStatFormat::Stata);
+                if preserve_order {
+                    options.preserve_order = Some(true);
+                }
+                readstat_scan(file, Some(options), Some(ReadStatFormat::Stata))
+            }),
+            None => scan_lazyframe_readstat(path, ReadStatFormat::Stata, preserve_order),
+        },
+        InputFormat::Csv => scan_lazyframe_csv(
+            path,
+            asterisk_to_variable_name,
+            scan_infer_schema_length,
+            csv_try_parse_dates,
+            csv_schema,
+            csv_dialect,
+        ),
+        InputFormat::Ipc => scan_lazyframe_ipc(path, safe_relaxed, asterisk_to_variable_name),
+        InputFormat::Ndjson => scan_lazyframe_ndjson(path, scan_infer_schema_length),
+        InputFormat::Sql => crate::sql_query::file_query(path, csv_dialect)
+            .map_err(|e| PolarsError::ComputeError(e.into())),
+    }
+}
+
+fn scan_lazyframe_ipc(
+    path: &str,
+    safe_relaxed: bool,
+    asterisk_to_variable_name: Option<&str>,
+) -> Result<LazyFrame, PolarsError> {
+    if Path::new(path).is_dir() {
+        return Err(PolarsError::ComputeError(
+            "Directory inputs are not supported for this input format".into(),
+        ));
+    }
+
+    match (safe_relaxed, asterisk_to_variable_name) {
+        (_, Some(var_name)) => scan_with_filename_extraction(path, var_name, scan_ipc_file),
+        (true, _) => scan_with_diagonal_relaxed(path, scan_ipc_file),
+        _ => {
+            let mut normalized_pattern = if cfg!(windows) {
+                path.replace('\\', "/")
+            } else {
+                path.to_string()
+            };
+
+            // Fix "**.ext" to "**/*.ext"
+            if normalized_pattern.contains("**.") {
+                normalized_pattern = normalized_pattern.replace("**.", "**/*.");
+            }
+            scan_ipc_file(&normalized_pattern)
+        }
     }
 }
 
@@ -359,8 +473,8 @@ fn scan_lazyframe_parquet(
     
     // Handle glob patterns with special options
     match (safe_relaxed, asterisk_to_variable_name) {
-        (_, Some(var_name)) => scan_with_filename_extraction(path, var_name),
-        (true, _) => scan_with_diagonal_relaxed(path),
+        (_, Some(var_name)) => scan_with_filename_extraction(path, var_name, scan_parquet_file),
+        (true, _) => scan_with_diagonal_relaxed(path, scan_parquet_file),
         _ => {
             // Default behavior - direct scan_parquet on glob (with pattern normalization)
             let mut normalized_pattern = if cfg!(windows) {
@@ -384,11 +498,46 @@ fn scan_lazyframe_parquet(
     
 }
 
+/// Scan options shared by every SAS/SPSS/.dta read. Labelled SAS/SPSS
+/// variables load as their label text, unless value_labels asked for the
+/// numeric codes; .dta variables always keep their codes, as `use` does.
+/// Where codes are kept, the value labels are applied in Stata from the
+/// readstat metadata (see readstat_metadata.rs).
+pub fn readstat_scan_options(format: ReadStatFormat) -> ReadStatScanOptions {
+    ReadStatScanOptions {
+        value_labels_as_strings: Some(!value_labels_as_codes(format)),
+        ..Default::default()
+    }
+}
+
+/// Whether labelled variables of `format` load as codes: always for .dta,
+/// and for SAS/SPSS when value_labels was passed (staged by pq.ado in
+/// pq_value_labels and read by name, so describe, read and the overflow
+/// writer agree).
+pub fn value_labels_as_codes(format: ReadStatFormat) -> bool {
+    format == ReadStatFormat::Stata || get_macro("pq_value_labels", false, None) == "1"
+}
+
 fn scan_lazyframe_readstat(
     path: &str,
     format: ReadStatFormat,
     preserve_order: bool,
 ) -> Result<LazyFrame, PolarsError> {
+    scan_lazyframe_files(path, |file| {
+        let mut options = readstat_scan_options(format);
+        if preserve_order {
+            options.preserve_order = Some(true);
+        }
+        readstat_scan(file, Some(options), Some(format))
+    })
+}
+
+/// Scans a single file or every file matching a glob with `scan_file`,
+/// stacking glob matches diagonally in sorted path order.
+fn scan_lazyframe_files<F>(path: &str, scan_file: F) -> Result<LazyFrame, PolarsError>
+where
+    F: Fn(&Path) -> Result<LazyFrame, PolarsError>,
+{
     if Path::new(path).is_dir() {
         return Err(PolarsError::ComputeError(
             "Directory inputs are not supported for this input format".into(),
@@ -425,11 +574,7 @@ fn scan_lazyframe_readstat(
         file_paths.sort();
         let mut frames = Vec::with_capacity(file_paths.len());
         for file_path in file_paths {
-            let mut options = ReadStatScanOptions::default();
-            if preserve_order {
-                options.preserve_order = Some(true);
-            }
-            frames.push(readstat_scan(&file_path, Some(options), Some(format))?);
+            frames.push(scan_file(&file_path)?);
         }
 
         return concat(
@@ -446,27 +591,49 @@ fn scan_lazyframe_readstat(
         );
     }
 
-    let mut options = ReadStatScanOptions::default();
-    if preserve_order {
-        options.preserve_order = Some(true);
-    }
-    readstat_scan(path, Some(options), Some(format))
+    scan_file(Path::new(path))
 }
 
 fn scan_lazyframe_csv(
+    path: &str,
+    asterisk_to_variable_name: Option<&str>,
+    infer_schema_length: Option<usize>,
+    try_parse_dates: bool,
+    schema: Option<SchemaRef>,
+    dialect: &CsvDialect,
+) -> Result<LazyFrame, PolarsError> {
+    match asterisk_to_variable_name {
+        // The value comes from each source file's own name, so each file is
+        // scanned (and transcoded, if need be) on its own.
+        Some(var_name) => {
+            let schema = schema.map(|schema| {
+                let mut schema = schema.as_ref().clone();
+                schema.shift_remove(var_name);
+                Arc::new(schema)
+            });
+            scan_with_filename_extraction(path, var_name, |file| {
+                scan_csv_source(file, infer_schema_length, try_parse_dates, schema.clone(), dialect)
+            })
+        }
+        None => scan_csv_source(path, infer_schema_length, try_parse_dates, schema, dialect),
+    }
+}
+
+fn scan_csv_source(
     path: &str,
     infer_schema_length: Option<usize>,
     try_parse_dates: bool,
     schema: Option<SchemaRef>,
+    dialect: &CsvDialect,
 ) -> Result<LazyFrame, PolarsError> {
+    let source_path = dialect.source_path(path)?;
     let normalized_path = if cfg!(windows) {
-        path.replace('\\', "/")
+        source_path.replace('\\', "/")
     } else {
-        path.to_string()
+        source_path
     };
 
-    let reader = LazyCsvReader::new(PlRefPath::new(normalized_path.as_str()))
-        .with_has_header(true)
+    let reader = dialect.apply(LazyCsvReader::new(PlRefPath::new(normalized_path.as_str())))
         .with_glob(true)
         .with_cache(false)
         .with_try_parse_dates(try_parse_dates)
@@ -481,6 +648,23 @@ fn scan_lazyframe_csv(
     reader.finish()
 }
 
+/// Newline-delimited JSON, one object per line. Nested objects come back
+/// as Struct columns for nested.rs to flatten, like any other Struct.
+fn scan_lazyframe_ndjson(
+    path: &str,
+    infer_schema_length: Option<usize>,
+) -> Result<LazyFrame, PolarsError> {
+    let normalized_path = if cfg!(windows) {
+        path.replace('\\', "/")
+    } else {
+        path.to_string()
+    };
+
+    LazyJsonLineReader::new(PlRefPath::new(normalized_path.as_str()))
+        .with_infer_schema_length(infer_schema_length.and_then(NonZeroUsize::new))
+        .finish()
+}
+
 fn scan_hive_partitioned(dir_path: &str, safe_relaxed: bool) -> Result<LazyFrame, PolarsError> {
     // Detect hive partitioning structure and create appropriate glob
     let mut glob_pattern = String::from(dir_path);
@@ -508,7 +692,7 @@ fn scan_hive_partitioned(dir_path: &str, safe_relaxed: bool) -> Result<LazyFrame
             let files: Vec<_> = paths.filter_map(Result::ok).collect();
             if !files.is_empty() {
                 if safe_relaxed {
-                    return scan_with_diagonal_relaxed(&pattern);
+                    return scan_with_diagonal_relaxed(&pattern, scan_parquet_file);
                 }
                 let mut scan_args = ScanArgsParquet::default();
                 scan_args.allow_missing_columns = true;
@@ -521,7 +705,27 @@ fn scan_hive_partitioned(dir_path: &str, safe_relaxed: bool) -> Result<LazyFrame
     Err(PolarsError::ComputeError(format!("No parquet files found in hive partitioned structure: {}", dir_path).into()))
 }
 
-fn scan_with_diagonal_relaxed(glob_path: &str) -> Result<LazyFrame, PolarsError> {
+fn scan_parquet_file(file: &str) -> Result<LazyFrame, PolarsError> {
+    let scan_args = ScanArgsParquet {
+        allow_missing_columns: true,
+        cache: false,
+        ..Default::default()
+    };
+    LazyFrame::scan_parquet(file.into(), scan_args)
+}
+
+fn scan_ipc_file(file: &str) -> Result<LazyFrame, PolarsError> {
+    let scan_args = UnifiedScanArgs {
+        missing_columns_policy: MissingColumnsPolicy::Insert,
+        ..Default::default()
+    };
+    LazyFrame::scan_ipc(file.into(), IpcScanOptions::default(), scan_args)
+}
+
+fn scan_with_diagonal_relaxed(
+    glob_path: &str,
+    scan_file: impl Fn(&str) -> Result<LazyFrame, PolarsError>,
+) -> Result<LazyFrame, PolarsError> {
     // Normalize pattern for Windows and fix recursive wildcards
     let mut normalized_pattern = if cfg!(windows) {
         glob_path.replace('\\', "/")
@@ -547,17 +751,9 @@ fn scan_with_diagonal_relaxed(glob_path: &str) -> Result<LazyFrame, PolarsError>
     }
     
     // Create individual lazy frames for each file
-    let mut scan_args = ScanArgsParquet::default();
-    scan_args.allow_missing_columns = true;
-    scan_args.cache = false;
     let lazy_frames: Result<Vec<LazyFrame>, PolarsError> = file_paths
         .iter()
-        .map(|path| {
-            LazyFrame::scan_parquet(
-                path.to_string_lossy().as_ref().into(), 
-                scan_args.clone(),
-            )
-        })
+        .map(|path| scan_file(path.to_string_lossy().as_ref()))
         .collect();
     
     let lazy_frames = lazy_frames?;
@@ -579,7 +775,8 @@ fn scan_with_diagonal_relaxed(glob_path: &str) -> Result<LazyFrame, PolarsError>
 
 fn scan_with_filename_extraction(
     glob_path: &str, 
-    variable_name: &str
+    variable_name: &str,
+    scan_file: impl Fn(&str) -> Result<LazyFrame, PolarsError>,
 ) -> Result<LazyFrame, PolarsError> {
     // Normalize pattern for Windows and fix recursive wildcards
     let mut normalized_pattern = if cfg!(windows) {
@@ -640,13 +837,7 @@ fn scan_with_filename_extraction(
                 .unwrap_or("unknown");
             
             // Create lazy frame with extracted column
-            let mut scan_args = ScanArgsParquet::default();
-            scan_args.allow_missing_columns = true;
-            scan_args.cache = false;
-            LazyFrame::scan_parquet(
-                path_str.as_ref().into(), 
-                scan_args.clone()
-            )
+            scan_file(path_str.as_ref())
             .map(|lf| {
                 //  display(&format!("Matched, {}: {}", variable_name, extracted_value));
                 lf.with_columns([
@@ -691,10 +882,12 @@ fn smart_lit(value: &str) -> Expr {
     lit(value)
 }
 
-fn readstat_format_for_input(input_format: InputFormat) -> Option<ReadStatFormat> {
+pub fn readstat_format_for_input(input_format: InputFormat) -> Option<ReadStatFormat> {
     match input_format {
         InputFormat::Sas => Some(ReadStatFormat::Sas),
         InputFormat::Spss => Some(ReadStatFormat::Spss),
+        InputFormat::Dta => Some(ReadStatFormat::Stata),
+        InputFormat::Xpt => Some(ReadStatFormat::SasXpt),
         _ => None,
     }
 }
@@ -778,12 +971,12 @@ fn push_unique_column(columns: &mut Vec<String>, seen: &mut HashSet<String>, nam
     }
 }
 
-fn projected_readstat_columns(
+pub fn projected_readstat_columns(
     selected_columns_ordered: &[String],
     sql_filter: Option<&str>,
 ) -> Option<Vec<String>> {
     if let Some(sql_filter) = sql_filter {
-        let filter_columns = extract_sql_if_columns(sql_filter).ok()?;
+        let filter_columns = stata_if::filter_columns(sql_filter).ok()?;
         let mut projected = Vec::new();
         let mut seen = HashSet::new();
 
@@ -816,13 +1009,13 @@ pub fn filtered_row_count_readstat_with_sql(
     }
 
     let readstat_format = readstat_format_for_input(input_format)?;
-    let selected_cols = match extract_sql_if_columns(sql_if) {
+    let selected_cols = match stata_if::filter_columns(sql_if) {
         Ok(cols) if cols.is_empty() => None,
         Ok(cols) => Some(cols),
         Err(_) => None,
     };
 
-    let mut scan_opts = ReadStatScanOptions::default();
+    let mut scan_opts = readstat_scan_options(readstat_format);
     scan_opts.preserve_order = Some(true);
 
     let mut iter = readstat_batch_iter(
@@ -895,14 +1088,280 @@ mod sql_if_projection_tests {
     }
 }
 
-fn apply_sql_filter_to_batch(batch: DataFrame, sql_if: Option<&str>) -> PolarsResult<DataFrame> {
+#[cfg(test)]
+mod ipc_scan_tests {
+    use super::*;
+
+    #[test]
+    fn ipc_glob_extracts_asterisk_and_unions_schemas() {
+        let dir = std::env::temp_dir().join(format!("pq_ipc_scan_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut first = df!("id" => [1i64, 2], "name" => ["a", "b"]).unwrap();
+        let mut second = df!("id" => [3i64]).unwrap();
+        for (part, df) in [("2019", &mut first), ("2020", &mut second)] {
+            let mut file = std::fs::File::create(dir.join(format!("part_{part}.arrow"))).unwrap();
+            IpcWriter::new(&mut file).finish(df).unwrap();
+        }
+
+        let pattern = format!("{}/part_*.arrow", dir.to_string_lossy());
+        let df = scan_lazyframe_ipc(&pattern, true, Some("year"))
+            .unwrap()
+            .sort(["id"], Default::default())
+            .collect()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(df.height(), 3);
+        let years = df.column("year").unwrap().cast(&DataType::Int64).unwrap();
+        let years: Vec<Option<i64>> = years.i64().unwrap().into_iter().collect();
+        assert_eq!(years, vec![Some(2019), Some(2019), Some(2020)]);
+        assert_eq!(df.column("name").unwrap().null_count(), 1);
+    }
+}
+
+#[cfg(test)]
+mod csv_scan_tests {
+    use super::*;
+
+    #[test]
+    fn transcoded_glob_extracts_asterisk_from_the_original_names() {
+        let _guard = crate::decompress::SPOOL_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("pq_csv_asterisk_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("wave_2019.csv"), b"id,city\n1,K\xf8benhavn\n").unwrap();
+        std::fs::write(dir.join("wave_2020.csv"), b"id,city\n2,Malm\xf6\n").unwrap();
+        let dialect = CsvDialect::from_json(r#"{"encoding": "latin1"}"#).unwrap();
+
+        let pattern = format!("{}/wave_*.csv", dir.to_string_lossy().replace('\\', "/"));
+        let df = scan_lazyframe_csv(&pattern, Some("year"), Some(100), false, None, &dialect)
+            .unwrap()
+            .sort(["id"], Default::default())
+            .collect()
+            .unwrap();
+        let copies: Vec<String> = glob(&dialect.source_path(&pattern).unwrap())
+            .unwrap()
+            .filter_map(Result::ok)
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        crate::decompress::remove_spooled_inputs();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let years = df.column("year").unwrap().cast(&DataType::Int64).unwrap();
+        let years: Vec<Option<i64>> = years.i64().unwrap().into_iter().collect();
+        assert_eq!(years, vec![Some(2019), Some(2020)]);
+        assert_eq!(df.column("city").unwrap().str().unwrap().get(1), Some("Malmö"));
+        assert_eq!(copies, ["wave_2019.csv", "wave_2020.csv"]);
+    }
+}
+
+#[cfg(test)]
+mod transport_scan_tests {
+    use super::*;
+    use polars_readstat_rs::{write_por, PorWriteOptions, XptWriter};
+
+    #[test]
+    fn xpt_and_por_globs_stack_with_labels() {
+        let dir = std::env::temp_dir().join(format!("pq_transport_scan_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = df!("ID" => [1.0f64, 2.0], "SITE" => ["a", "b"]).unwrap();
+        let second = df!("ID" => [3.0f64], "SITE" => ["c"]).unwrap();
+        let labels: HashMap<String, String> = [("ID".to_string(), "Subject id".to_string())].into();
+        for (part, df) in [("1", &first), ("2", &second)] {
+            XptWriter::new(dir.join(format!("wave_{part}.xpt")))
+                .with_variable_labels(labels.clone())
+                .write_df(df)
+                .unwrap();
+            let options = PorWriteOptions {
+                file_label: None,
+                variable_labels: Some(labels.clone()),
+            };
+            write_por(df, dir.join(format!("wave_{part}.por")), options).unwrap();
+        }
+
+        let dir_str = dir.to_string_lossy().replace('\\', "/");
+        for (format, ext) in [(InputFormat::Xpt, "xpt"), (InputFormat::Por, "por")] {
+            let pattern = format!("{}/wave_*.{}", dir_str, ext);
+            let df = scan_lazyframe_with_options(
+                &pattern, false, None, format, false, None, false, None, &CsvDialect::default(),
+            )
+            .unwrap()
+            .select([col("ID"), col("SITE")])
+            .filter(col("ID").gt(lit(1.0)))
+            .collect()
+            .unwrap();
+            assert_eq!(df.height(), 2, "{}", ext);
+            assert_eq!(df.column("SITE").unwrap().str().unwrap().get(1), Some("c"), "{}", ext);
+        }
+
+        let xpt_meta = metadata_from_readstat(&format!("{}/wave_*.xpt", dir_str), ReadStatFormat::SasXpt, false).unwrap();
+        assert_eq!(xpt_meta.variables["ID"].label.as_deref(), Some("Subject id"));
+        let por_meta = metadata_from_por(&format!("{}/wave_1.por", dir_str), false).unwrap();
+        assert_eq!(por_meta.variables["ID"].label.as_deref(), Some("Subject id"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+#[cfg(test)]
+mod ndjson_scan_tests {
+    use super::*;
+    use crate::nested::ListMode;
+
+    #[test]
+    fn ndjson_objects_flatten_like_structs() {
+        let path = std::env::temp_dir().join(format!("pq_ndjson_{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"id\": 1, \"person\": {\"age\": 30, \"name\": \"a\"}}\n\
+             {\"id\": 2, \"person\": {\"age\": 41}}\n",
+        )
+        .unwrap();
+
+        let lf = scan_lazyframe_ndjson(path.to_str().unwrap(), Some(100)).unwrap();
+        let opts = NestedOptions { unnest: true, list_mode: ListMode::Keep };
+        let (lf, _) = flatten_nested_columns(lf, &opts, &mut HashMap::new()).unwrap();
+        let df = lf.collect().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let names: Vec<&str> = df.get_column_names().iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["id", "person_age", "person_name"]);
+        assert_eq!(df.column("person_age").unwrap().i64().unwrap().get(1), Some(41));
+        assert_eq!(df.column("person_name").unwrap().null_count(), 1);
+    }
+
+    #[test]
+    fn only_line_delimited_json_is_a_format() {
+        assert_eq!(InputFormat::from_str("jsonl"), Some(InputFormat::Ndjson));
+        assert_eq!(InputFormat::from_str("NDJSON"), Some(InputFormat::Ndjson));
+        assert_eq!(InputFormat::from_str("json"), None);
+    }
+}
+
+#[cfg(test)]
+mod catenum_encode_tests {
+    use super::*;
+
+    fn codes(lf: LazyFrame, dictionaries: &[CategoricalDictionary], name: &str) -> Vec<Option<i32>> {
+        let df = encode_catenum_columns(lf, dictionaries).unwrap().collect().unwrap();
+        df.column(name).unwrap().i32().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn categorical_codes_follow_sorted_values() {
+        let s = Series::new("color".into(), [Some("red"), Some("blue"), None, Some("red")])
+            .cast(&DataType::from_categories(Categories::global()))
+            .unwrap();
+        let mut lf = DataFrame::new_infer_height(vec![s.into()]).unwrap().lazy();
+        let schema = lf.collect_schema().unwrap();
+        let dictionaries =
+            categorical_dictionaries(&lf, &schema, &["color".to_string()], &HashMap::new()).unwrap();
+        assert_eq!(dictionaries[0].categories, vec!["blue", "red"]);
+        assert_eq!(dictionaries[0].label_name, "color");
+        assert_eq!(codes(lf, &dictionaries, "color"), vec![Some(2), Some(1), None, Some(2)]);
+    }
+
+    #[test]
+    fn enum_codes_follow_declared_order() {
+        let fcats = FrozenCategories::new(["low", "mid", "high"]).unwrap();
+        let s = Series::new("level".into(), ["high", "low", "high"])
+            .cast(&DataType::from_frozen_categories(fcats))
+            .unwrap();
+        let mut lf = DataFrame::new_infer_height(vec![s.into()]).unwrap().lazy();
+        let schema = lf.collect_schema().unwrap();
+        let mut labels = HashMap::new();
+        labels.insert("level".to_string(), "level_lbl".to_string());
+        let dictionaries =
+            categorical_dictionaries(&lf, &schema, &["level".to_string()], &labels).unwrap();
+        assert_eq!(dictionaries[0].categories, vec!["low", "mid", "high"]);
+        assert_eq!(dictionaries[0].label_name, "level_lbl");
+        assert_eq!(codes(lf, &dictionaries, "level"), vec![Some(3), Some(1), Some(3)]);
+    }
+}
+
+/// Applies the zone the ado staged in pq_timezone (the timezone() option)
+/// to every zone-aware datetime column; unchanged when it is empty.
+pub fn localize_time_zones(lf: LazyFrame) -> Result<LazyFrame, String> {
+    match time_zone::parse(&get_macro("pq_timezone", false, None))? {
+        Some(zone) => time_zone::to_local_clock(lf, &zone).map_err(|e| format!("timezone(): {e}")),
+        None => Ok(lf),
+    }
+}
+
+/// Loads each Duration column as a count of the unit the ado staged in
+/// pq_duration (the duration() option) or, without it, of the unit pq save
+/// recorded for the column; milliseconds otherwise.
+pub fn durations_as_numbers(lf: LazyFrame) -> Result<LazyFrame, String> {
+    let chosen = get_macro("pq_duration", false, None);
+    let unit = DurationUnit::parse(&chosen)
+        .ok_or_else(|| format!("duration(): expected ms, s, or days, passed \"{}\"", chosen.trim()))?;
+    let recorded = if chosen.trim().is_empty() {
+        recorded_duration_units()
+    } else {
+        HashMap::new()
+    };
+    durations_in_units(lf, unit, &recorded).map_err(|e| format!("duration(): {e}"))
+}
+
+fn durations_in_units(
+    mut lf: LazyFrame,
+    unit: DurationUnit,
+    recorded: &HashMap<String, DurationUnit>,
+) -> PolarsResult<LazyFrame> {
+    let schema = lf.collect_schema()?;
+    let counts: Vec<Expr> = schema
+        .iter()
+        .filter_map(|(name, dtype)| match dtype {
+            DataType::Duration(time_unit) => {
+                let unit = recorded.get(name.as_str()).copied().unwrap_or(unit);
+                Some(
+                    col(name.clone()).cast(DataType::Int64).cast(DataType::Float64)
+                        / lit(unit.ticks(*time_unit)),
+                )
+            }
+            _ => None,
+        })
+        .collect();
+    if counts.is_empty() {
+        return Ok(lf);
+    }
+    Ok(lf.with_columns(counts))
+}
+
+#[cfg(test)]
+mod duration_tests {
+    use super::*;
+
+    #[test]
+    fn durations_load_as_counts_of_the_unit() {
+        // 90 s and 1.5 days in microseconds, 2 s in milliseconds
+        let lf = df!(
+            "wait" => [Some(90_000_000i64), Some(129_600_000_000), None],
+            "lag" => [2_000i64, 0, 500],
+            "n" => [1i32, 2, 3]
+        )
+        .unwrap()
+        .lazy()
+        .with_columns([
+            col("wait").cast(DataType::Duration(TimeUnit::Microseconds)),
+            col("lag").cast(DataType::Duration(TimeUnit::Milliseconds)),
+        ]);
+        let recorded = HashMap::from([("lag".to_string(), DurationUnit::Seconds)]);
+        let df = durations_in_units(lf, DurationUnit::Days, &recorded).unwrap().collect().unwrap();
+
+        let wait: Vec<Option<f64>> = df.column("wait").unwrap().f64().unwrap().into_iter().collect();
+        assert_eq!(wait, vec![Some(90.0 / 86_400.0), Some(1.5), None]);
+        let lag: Vec<Option<f64>> = df.column("lag").unwrap().f64().unwrap().into_iter().collect();
+        assert_eq!(lag, vec![Some(2.0), Some(0.0), Some(0.5)]);
+        assert_eq!(df.column("n").unwrap().dtype(), &DataType::Int32);
+    }
+}
+
+pub fn apply_sql_filter_to_batch(batch: DataFrame, sql_if: Option<&str>) -> PolarsResult<DataFrame> {
     let Some(sql_if) = sql_if.filter(|s| !s.trim().is_empty()) else {
         return Ok(batch);
     };
 
-    let mut ctx = SQLContext::new();
-    ctx.register("df", batch.lazy());
-    ctx.execute(&format!("SELECT * FROM df WHERE {}", sql_if))?.collect()
+    stata_if::filter_lazy(batch.lazy(), sql_if)?.collect()
 }
 
 fn apply_cast_to_batch(
@@ -961,7 +1420,7 @@ fn read_to_stata_from_readstat_batches(
         None
     };
 
-    let mut scan_opts = ReadStatScanOptions::default();
+    let mut scan_opts = readstat_scan_options(readstat_format);
     scan_opts.threads = Some(n_threads);
     scan_opts.chunk_size = Some(effective_batch_size);
     if preserve_order {
@@ -1070,6 +1529,24 @@ fn read_to_stata_from_readstat_batches(
     Ok(0)
 }
 
+/// Stata metadata carried by the source: the embedded footer of a Parquet or
+/// IPC file, or SAS/SPSS labels and formats from the readstat metadata in the
+/// same envelope shape.
+pub fn source_metadata(path: &str, input_format: InputFormat) -> Result<Option<StataMetadataEnvelope>, String> {
+    if let Some(format) = readstat_format_for_input(input_format) {
+        Ok(metadata_from_readstat(path, format, value_labels_as_codes(format)))
+    } else if input_format == InputFormat::Por {
+        Ok(metadata_from_por(path, value_labels_as_codes(ReadStatFormat::Spss)))
+    } else if input_format == InputFormat::Sql {
+        Ok(crate::sql_query::query_metadata(path))
+    } else if matches!(input_format, InputFormat::Parquet | InputFormat::Ipc) {
+        crate::stata_metadata::read_metadata_validated(path)
+            .map_err(|e| format!("Error reading embedded Stata metadata: {e}"))
+    } else {
+        Ok(None)
+    }
+}
+
 pub fn read_to_stata(
     path: &str,
     variables_as_str: &str,
@@ -1092,21 +1569,34 @@ pub fn read_to_stata(
     parse_dates: bool,
     columns_varlist: &str,
     skip_metadata: bool,
+    nested: NestedOptions,
+    csv_dialect: &CsvDialect,
 ) -> Result<i32, Box<dyn Error>> {
     // Clear any stale cast error from a previous call
     set_macro("pq_cast_error", "", false);
 
-    if skip_metadata || !matches!(input_format, InputFormat::Parquet) {
-        crate::stata_metadata::clear_metadata_macro();
+    // Categorical/Enum columns describe chose to encode (empty unless the
+    // encode option was passed). Their value labels ride along with the
+    // footer metadata, and are applied even with nostatametadata.
+    let cat_dictionaries = dictionaries_from_json(&get_macro("pq_cat_labels", false, Some(1024 * 1024 * 10)));
+
+    let footer_envelope = if skip_metadata {
+        None
     } else {
-        match crate::stata_metadata::read_metadata_validated(path) {
-            Ok(Some(envelope)) => crate::stata_metadata::push_metadata_to_macros(&envelope),
-            Ok(None) => crate::stata_metadata::clear_metadata_macro(),
+        match source_metadata(path, input_format) {
+            Ok(envelope) => envelope,
             Err(e) => {
-                display(&format!("Error reading embedded Stata metadata: {e}"));
+                display(&e);
                 return Ok(198);
             }
         }
+    };
+    let envelope = with_categorical_value_labels(footer_envelope, &cat_dictionaries);
+    // if() compares %tm & co. saved as dates as the periods they were
+    let period_columns = envelope.as_ref().map(crate::stata_metadata::period_columns).unwrap_or_default();
+    match envelope {
+        Some(envelope) => crate::stata_metadata::push_metadata_to_macros(&envelope),
+        None => crate::stata_metadata::clear_metadata_macro(),
     }
 
     let prof = profile_timing_enabled();
@@ -1163,7 +1653,7 @@ pub fn read_to_stata(
 
     let has_strl = !strl_col_names.is_empty() && !strl_dta_path.is_empty();
     let has_glob = path.contains('*') || path.contains('?') || path.contains('[');
-    let csv_infer_schema_length = if matches!(input_format, InputFormat::Csv) {
+    let scan_infer_schema_length = if matches!(input_format, InputFormat::Csv | InputFormat::Ndjson) {
         if infer_schema_length == 0 {
             None
         } else {
@@ -1189,6 +1679,8 @@ pub fn read_to_stata(
         format: input_format.as_str().to_string(),
         parse_dates,
         infer_schema_length,
+        nested: nested.as_key(),
+        csv_dialect: csv_dialect.as_key(),
     };
     let cached_lf: Option<LazyFrame> = fast_cache::take(&cache_key).map(|df: DataFrame| df.lazy());
     let loaded_from_cache = cached_lf.is_some();
@@ -1199,7 +1691,7 @@ pub fn read_to_stata(
         None
     };
     let can_use_readstat_batch_iter = cached_lf.is_none()
-        && matches!(input_format, InputFormat::Sas | InputFormat::Spss)
+        && readstat_format_for_input(input_format).is_some()
         && !has_strl
         && !has_glob
         && sort.is_empty()
@@ -1233,9 +1725,10 @@ pub fn read_to_stata(
         asterisk_to_variable_name,
         input_format,
         preserve_order,
-        csv_infer_schema_length,
+        scan_infer_schema_length,
         csv_try_parse_dates,
         csv_schema,
+        csv_dialect,
     ) {
         Ok(df) => df,
         Err(e) => {
@@ -1244,6 +1737,18 @@ pub fn read_to_stata(
         },
     }
     }; // end cached_lf else branch
+
+    // A cached frame was flattened by describe already.
+    if !loaded_from_cache && nested.is_active() {
+        let mut spread_widths = widths_from_json(&get_macro("pq_list_widths", false, None));
+        df = match flatten_nested_columns(df, &nested, &mut spread_widths) {
+            Ok((lf, _)) => lf,
+            Err(e) => {
+                display(&format!("Error flattening nested columns: {:?}", e));
+                return Ok(198);
+            }
+        };
+    }
     if prof {
         t_scan += t0.elapsed();
     }
@@ -1291,11 +1796,22 @@ pub fn read_to_stata(
         t_cast_cat += t0.elapsed();
     }
 
+    // timezone(): zone-aware datetimes become that zone's local clock, and
+    // duration(): durations become counts, ahead of if() so conditions
+    // compare the values Stata will show
+    df = match localize_time_zones(df).and_then(durations_as_numbers) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&e);
+            return Ok(198);
+        }
+    };
+
     let sql_filter = sql_if.filter(|s| !s.trim().is_empty());
 
     // For SAS/SPSS, project to requested columns + SQL predicate columns.
     // This enables projection pushdown on non-streaming paths too.
-    if !loaded_from_cache && matches!(input_format, InputFormat::Sas | InputFormat::Spss) {
+    if !loaded_from_cache && input_format.is_readstat() {
         if let Some(projected_columns) = projected_readstat_columns(&selected_columns_ordered, sql_filter) {
             let projection_exprs: Vec<Expr> = projected_columns
                 .iter()
@@ -1309,10 +1825,7 @@ pub fn read_to_stata(
     if !loaded_from_cache {
     if let Some(sql) = sql_filter {
         let t0 = Instant::now();
-        let mut ctx = SQLContext::new();
-        ctx.register("df", df);
-        
-        df = match ctx.execute(&format!("select * from df where {}", sql)) {
+        df = match stata_if::filter_lazy_with_periods(df, sql, &period_columns) {
             Ok(lazyframe) => lazyframe,
             Err(e) => {
                 display(&format!("Error in SQL if statement: {}", e));
@@ -1325,6 +1838,15 @@ pub fn read_to_stata(
     }
     }
 
+    // After the if() filter, which compares the category text.
+    df = match encode_catenum_columns(df, &cat_dictionaries) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&format!("Error encoding categorical columns: {:?}", e));
+            return Ok(198);
+        }
+    };
+
     let sample_share = random_share > 0.0;
     if sample_share {
         let t0 = Instant::now();
@@ -1392,7 +1914,7 @@ pub fn read_to_stata(
     //  display(&format!("columns: {:?}", columns));
     let effective_batch_size = if let Some(requested) = batch_size.filter(|v| *v > 0) {
         Some(adaptive_batch_size(requested, columns.len(), n_rows))
-    } else if matches!(input_format, InputFormat::Sas | InputFormat::Spss) {
+    } else if input_format.is_readstat() {
         Some(infer_default_batch_size(
             columns.len(),
             (n_rows > 0).then_some(n_rows),
@@ -1594,6 +2116,99 @@ pub fn cast_catenum_to_string(lf: &LazyFrame) -> Result<LazyFrame, PolarsError>
     }
 }
 
+/// Builds the `encode` dictionary for each Categorical/Enum column in
+/// `columns`: an Enum keeps its declared category order, a Categorical gets
+/// its distinct values sorted (as Stata's `encode` would). Categorical
+/// values are read from the whole column, not just the rows a later if()
+/// keeps, so the codes don't depend on the filter.
+pub fn categorical_dictionaries(
+    lf: &LazyFrame,
+    schema: &Schema,
+    columns: &[String],
+    label_names: &HashMap<String, String>,
+) -> PolarsResult<Vec<CategoricalDictionary>> {
+    let mut dictionaries = Vec::new();
+    let mut to_collect: Vec<&str> = Vec::new();
+    for name in columns {
+        match schema.get(name.as_str()) {
+            Some(DataType::Enum(fcats, _)) => {
+                dictionaries.push(CategoricalDictionary {
+                    column: name.clone(),
+                    label_name: label_names.get(name).cloned().unwrap_or_else(|| name.clone()),
+                    categories: fcats.categories().values_iter().map(|s| s.to_string()).collect(),
+                });
+            }
+            Some(DataType::Categorical(_, _)) => to_collect.push(name.as_str()),
+            _ => {}
+        }
+    }
+
+    if !to_collect.is_empty() {
+        let exprs: Vec<Expr> = to_collect
+            .iter()
+            .map(|name| {
+                col(*name)
+                    .cast(DataType::String)
+                    .drop_nulls()
+                    .unique()
+                    .sort(SortOptions::default())
+                    .implode()
+            })
+            .collect();
+        let values_df = lf.clone().select(exprs).collect()?;
+        for name in to_collect {
+            let list = values_df.column(name)?.list()?.get_as_series(0);
+            let categories: Vec<String> = match list {
+                Some(series) => series.str()?.into_no_null_iter().map(|s| s.to_string()).collect(),
+                None => Vec::new(),
+            };
+            dictionaries.push(CategoricalDictionary {
+                column: name.to_string(),
+                label_name: label_names.get(name).cloned().unwrap_or_else(|| name.to_string()),
+                categories,
+            });
+        }
+    }
+
+    Ok(dictionaries)
+}
+
+/// Replaces each dictionary column with its Int32 code (1-based position in
+/// the dictionary). Runs after `cast_catenum_to_string` and the if() filter,
+/// so the filter still compares text; columns that are already numeric
+/// (e.g. a frame describe encoded and cached) are left alone.
+pub fn encode_catenum_columns(
+    lf: LazyFrame,
+    dictionaries: &[CategoricalDictionary],
+) -> PolarsResult<LazyFrame> {
+    if dictionaries.is_empty() {
+        return Ok(lf);
+    }
+    let mut lf = lf;
+    let schema = lf.collect_schema()?;
+    let mut exprs: Vec<Expr> = Vec::with_capacity(dictionaries.len());
+    for dict in dictionaries {
+        match schema.get(dict.column.as_str()) {
+            Some(DataType::String | DataType::Categorical(_, _) | DataType::Enum(_, _)) => {}
+            _ => continue,
+        }
+        let fcats = FrozenCategories::new(dict.categories.iter().map(|s| s.as_str()))?;
+        exprs.push(
+            (col(dict.column.as_str())
+                .cast(DataType::String)
+                .cast(DataType::from_frozen_categories(fcats))
+                .to_physical()
+                .cast(DataType::Int32)
+                + lit(1i32))
+            .alias(dict.column.as_str()),
+        );
+    }
+    if exprs.is_empty() {
+        Ok(lf)
+    } else {
+        Ok(lf.with_columns(exprs))
+    }
+}
 
 // Create column info from Stata macros
 fn column_info_from_macros(n_vars: usize) -> Vec<ColumnInfo> {
@@ -1937,7 +2552,8 @@ fn process_numeric_column(
                 return Ok(());
             }
         }
-        "Float64" => {
+        // Duration columns arrive as Float64 counts (see durations_as_numbers)
+        dtype if dtype == "Float64" || dtype.starts_with("Duration(") => {
             if let Ok(ca) = col.f64() {
                 for row_idx in start_row..end_row {
                     write_number(row_idx, ca.get(row_idx));
@@ -1963,6 +2579,7 @@ fn process_numeric_column(
         "Float64" => |av| match av { AnyValue::Float64(v) => Some(*v), _ => None },
         "Date" => |av| match av { AnyValue::Date(v) => Some((*v + DAY_SHIFT_SAS_STATA) as f64), _ => None },
         "Time" => |av| match av { AnyValue::Time(v) => Some((*v / SEC_MICROSECOND) as f64), _ => None },
+        dtype if dtype.starts_with("Duration(") => |av| match av { AnyValue::Float64(v) => Some(*v), _ => None },
         _ => return Ok(()) // Skip unknown types
     };
 
@@ -1990,10 +2607,16 @@ pub fn write_overflow_batch_to_dta(
     input_format: InputFormat,
     infer_schema_length: usize,
     parse_dates: bool,
+    nested: NestedOptions,
+    list_widths_json: &str,
+    user_cast_json: &str,
+    cast_strict: bool,
+    cat_labels_json: &str,
+    csv_dialect: &CsvDialect,
 ) -> Result<i32, Box<dyn Error>> {
     use polars_readstat_rs::stata::writer::StataWriter;
 
-    let csv_infer_schema_length = if matches!(input_format, InputFormat::Csv) {
+    let scan_infer_schema_length = if matches!(input_format, InputFormat::Csv | InputFormat::Ndjson) {
         if infer_schema_length == 0 {
             None
         } else {
@@ -2011,9 +2634,10 @@ pub fn write_overflow_batch_to_dta(
         asterisk_to_variable_name,
         input_format,
         false,
-        csv_infer_schema_length,
+        scan_infer_schema_length,
         csv_try_parse_dates,
         None,
+        csv_dialect,
     ) {
         Ok(lf) => lf,
         Err(e) => {
@@ -2022,6 +2646,36 @@ pub fn write_overflow_batch_to_dta(
         }
     };
 
+    // Same flattening (and spread widths) as the describe pass that named
+    // the overflow columns.
+    if nested.is_active() {
+        let mut spread_widths = widths_from_json(list_widths_json);
+        df = match flatten_nested_columns(df, &nested, &mut spread_widths) {
+            Ok((lf, _)) => lf,
+            Err(e) => {
+                display(&format!("write_overflow_dta: error flattening nested columns: {:?}", e));
+                return Ok(198);
+            }
+        };
+    }
+
+    // Same casts as the in-memory rows, so the overflow .dta columns match
+    // the types describe resolved (e.g. Decimal -> double).
+    df = match apply_user_cast(df, user_cast_json, cast_strict) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&format!("write_overflow_dta: cast failed: {}", e));
+            return Ok(198);
+        }
+    };
+    df = match localize_time_zones(df).and_then(durations_as_numbers) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&format!("write_overflow_dta: {}", e));
+            return Ok(198);
+        }
+    };
+
     // Select columns if specified
     if let Some(col_names) = columns {
         if !col_names.is_empty() {
@@ -2034,9 +2688,7 @@ pub fn write_overflow_batch_to_dta(
     // Apply SQL if filter if provided
     if let Some(sql_filter) = sql_if {
         if !sql_filter.is_empty() {
-            let mut ctx = SQLContext::new();
-            ctx.register("df", df.clone());
-            df = match ctx.execute(&format!("SELECT * FROM df WHERE {}", sql_filter)) {
+            df = match stata_if::filter_lazy(df, sql_filter) {
                 Ok(lf) => lf,
                 Err(e) => {
                     display(&format!("write_overflow_dta: error in SQL filter: {:?}", e));
@@ -2046,6 +2698,14 @@ pub fn write_overflow_batch_to_dta(
         }
     }
 
+    df = match encode_catenum_columns(df, &dictionaries_from_json(cat_labels_json)) {
+        Ok(lf) => lf,
+        Err(e) => {
+            display(&format!("write_overflow_dta: error encoding categorical columns: {:?}", e));
+            return Ok(198);
+        }
+    };
+
     // Apply offset and limit (n_rows)
     if offset > 0 {
         df = df.slice(offset as i64, n_rows as u32);
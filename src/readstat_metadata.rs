@@ -0,0 +1,412 @@
+//! Translates the labels and display formats `readstat_metadata_json`
+//! reports for SAS (.sas7bdat/.xpt), SPSS (.sav/.por) and .dta files into a
+//! `StataMetadataEnvelope`, so
+//! read_to_stata can stage them through the same pq_meta_* macros it uses
+//! for a Parquet footer.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use polars_readstat_rs::{metadata_json_por, readstat_metadata_json, ReadStatFormat};
+use serde_json::Value;
+
+use crate::stata_metadata::{StataMetadataEnvelope, VariableMetadata, STATA_METADATA_VERSION};
+
+/// Stata caps variable and dataset labels at 80 characters.
+const MAX_LABEL_LEN: usize = 80;
+
+/// Stata value labels only cover integer codes; larger magnitudes than this
+/// are not valid label values.
+const MAX_VALUE_LABEL_CODE: f64 = 2_147_483_647.0;
+
+/// Reads the SAS/SPSS/.dta metadata for a `pq use` path. For a directory or
+/// glob, the first matching file stands in for the rest. None when the
+/// file has nothing to carry over or its metadata can't be read - labels
+/// are a convenience here, never a reason to fail the load.
+/// `value_labels_as_codes` matches the scan: when labelled variables load
+/// as their label text, their value labels are left out.
+pub fn metadata_from_readstat(
+    path: &str,
+    format: ReadStatFormat,
+    value_labels_as_codes: bool,
+) -> Option<StataMetadataEnvelope> {
+    let file = first_readstat_file(path)?;
+    let json = readstat_metadata_json(&file, Some(format)).ok()?;
+    let envelope = envelope_from_readstat_json(&json, format)?;
+    if value_labels_as_codes { Some(envelope) } else { without_value_labels(envelope) }
+}
+
+/// As `metadata_from_readstat`, for SPSS portable files. Their metadata has
+/// the .sav shape (without value labels), so it is translated the same way.
+pub fn metadata_from_por(path: &str, value_labels_as_codes: bool) -> Option<StataMetadataEnvelope> {
+    let file = first_readstat_file(path)?;
+    let json = metadata_json_por(&file).ok()?;
+    let envelope = envelope_from_readstat_json(&json, ReadStatFormat::Spss)?;
+    if value_labels_as_codes { Some(envelope) } else { without_value_labels(envelope) }
+}
+
+/// The envelope for a read that loads labelled variables as their label
+/// text: those variables keep their variable label but lose the value label
+/// and the numeric display format, which no longer suit a string.
+fn without_value_labels(mut envelope: StataMetadataEnvelope) -> Option<StataMetadataEnvelope> {
+    envelope.value_labels.clear();
+    envelope.variables.retain(|_, var| {
+        if var.value_label.take().is_some() {
+            var.format = None;
+        }
+        var.label.is_some() || var.format.is_some()
+    });
+    if envelope.variables.is_empty() && envelope.dataset_label.is_none() {
+        return None;
+    }
+    Some(envelope)
+}
+
+fn first_readstat_file(path: &str) -> Option<String> {
+    if Path::new(path).is_file() {
+        return Some(path.to_string());
+    }
+    glob::glob(&path.replace('\\', "/"))
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|p| p.is_file())
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+pub fn envelope_from_readstat_json(json: &str, format: ReadStatFormat) -> Option<StataMetadataEnvelope> {
+    let metadata: Value = serde_json::from_str(json).ok()?;
+    let mut envelope = StataMetadataEnvelope {
+        version: STATA_METADATA_VERSION,
+        ..Default::default()
+    };
+
+    match format {
+        ReadStatFormat::Spss => {
+            envelope.dataset_label = non_empty_str(metadata.get("file_label")).map(truncate_label);
+            for var in metadata.get("variables")?.as_array()? {
+                let Some(name) = var.get("name").and_then(Value::as_str) else { continue };
+                let numeric = var.get("type").and_then(Value::as_str) == Some("Numeric");
+
+                let value_label = if numeric {
+                    spss_value_labels(var).map(|defn| {
+                        let label_name = stata_label_name(
+                            var.get("value_label").and_then(Value::as_str).unwrap_or(name),
+                        );
+                        envelope.value_labels.insert(label_name.clone(), defn);
+                        label_name
+                    })
+                } else {
+                    None
+                };
+
+                let var_meta = VariableMetadata {
+                    label: non_empty_str(var.get("label")).map(truncate_label),
+                    value_label,
+                    format: if numeric { spss_format_to_stata(var) } else { None },
+                    ..Default::default()
+                };
+                if var_meta.label.is_some() || var_meta.value_label.is_some() || var_meta.format.is_some() {
+                    envelope.variables.insert(name.to_string(), var_meta);
+                }
+            }
+        }
+        ReadStatFormat::Stata => {
+            envelope.dataset_label = non_empty_str(metadata.get("data_label")).map(truncate_label);
+            for var in metadata.get("variables")?.as_array()? {
+                let Some(name) = var.get("name").and_then(Value::as_str) else { continue };
+                let numeric = var
+                    .get("type")
+                    .and_then(Value::as_str)
+                    .is_some_and(|t| t.starts_with("Numeric"));
+
+                let value_label = if numeric {
+                    non_empty_str(var.get("value_label_name")).and_then(|label_name| {
+                        let defn = dta_value_labels(var)?;
+                        envelope.value_labels.insert(label_name.clone(), defn);
+                        Some(label_name)
+                    })
+                } else {
+                    None
+                };
+
+                let var_meta = VariableMetadata {
+                    label: non_empty_str(var.get("label")),
+                    value_label,
+                    // Already a Stata format; the ado applies it under
+                    // capture in case it no longer suits the loaded type.
+                    format: non_empty_str(var.get("format")),
+                    ..Default::default()
+                };
+                if var_meta.label.is_some() || var_meta.value_label.is_some() || var_meta.format.is_some() {
+                    envelope.variables.insert(name.to_string(), var_meta);
+                }
+            }
+        }
+        _ => {
+            for column in metadata.get("columns")?.as_array()? {
+                let Some(name) = column.get("name").and_then(Value::as_str) else { continue };
+                let numeric = column.get("type").and_then(Value::as_str) == Some("Numeric");
+                let var_meta = VariableMetadata {
+                    label: non_empty_str(column.get("label")).map(truncate_label),
+                    format: if numeric {
+                        non_empty_str(column.get("format")).and_then(|f| sas_format_to_stata(&f))
+                    } else {
+                        None
+                    },
+                    ..Default::default()
+                };
+                if var_meta.label.is_some() || var_meta.format.is_some() {
+                    envelope.variables.insert(name.to_string(), var_meta);
+                }
+            }
+        }
+    }
+
+    if envelope.variables.is_empty() && envelope.dataset_label.is_none() {
+        return None;
+    }
+    Some(envelope)
+}
+
+fn non_empty_str(value: Option<&Value>) -> Option<String> {
+    value
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+fn truncate_label(label: String) -> String {
+    if label.len() <= MAX_LABEL_LEN {
+        return label;
+    }
+    let mut end = MAX_LABEL_LEN;
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label[..end].to_string()
+}
+
+/// SPSS value labels whose codes are all integers Stata can label. A set
+/// with any fractional or out-of-range code is dropped whole rather than
+/// loaded half-labelled.
+fn spss_value_labels(var: &Value) -> Option<BTreeMap<String, String>> {
+    let labels = var.get("value_labels")?.as_object()?;
+    let mut defn = BTreeMap::new();
+    for (code, text) in labels {
+        let value: f64 = code.parse().ok()?;
+        if value.fract() != 0.0 || value.abs() > MAX_VALUE_LABEL_CODE {
+            return None;
+        }
+        defn.insert((value as i64).to_string(), text.as_str().unwrap_or("").to_string());
+    }
+    if defn.is_empty() { None } else { Some(defn) }
+}
+
+/// A .dta value-label set. Codes for the extended missing values (.a-.z)
+/// are left out; the rest are integers already.
+fn dta_value_labels(var: &Value) -> Option<BTreeMap<String, String>> {
+    let labels = var.get("value_labels")?.as_object()?;
+    let defn: BTreeMap<String, String> = labels
+        .iter()
+        .filter_map(|(code, text)| {
+            let value: i64 = code.parse().ok()?;
+            Some((value.to_string(), text.as_str().unwrap_or("").to_string()))
+        })
+        .collect();
+    if defn.is_empty() { None } else { Some(defn) }
+}
+
+/// A valid Stata name for an SPSS value-label set: invalid characters
+/// become underscores, a leading digit gets an underscore prefix, and the
+/// result is capped at 32 characters.
+fn stata_label_name(name: &str) -> String {
+    let mut label: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if label.is_empty() || label.starts_with(|c: char| c.is_ascii_digit()) {
+        label.insert(0, '_');
+    }
+    label.truncate(32);
+    label
+}
+
+/// SPSS print formats for numeric variables (format type codes from the
+/// .sav dictionary). Date/time formats are left to the %td/%tc the Date
+/// and Datetime columns already get; unknown types keep Stata's default.
+fn spss_format_to_stata(var: &Value) -> Option<String> {
+    let format_type = var.get("format_type").and_then(Value::as_i64)?;
+    let width = var.get("format_width").and_then(Value::as_i64).unwrap_or(0);
+    let decimals = var.get("format_decimals").and_then(Value::as_i64).unwrap_or(0);
+    if width <= 0 || decimals < 0 || decimals >= width {
+        return None;
+    }
+    match format_type {
+        // F
+        5 => Some(format!("%{}.{}f", width, decimals)),
+        // COMMA, DOLLAR
+        3 | 4 => Some(format!("%{}.{}fc", width, decimals)),
+        // E
+        17 => Some(format!("%{}.{}e", width.max(decimals + 7), decimals)),
+        // N (restricted numeric with leading zeros)
+        16 => Some(format!("%0{}.0f", width)),
+        _ => None,
+    }
+}
+
+/// SAS formats as written in the file: "COMMA12.2", "Z8" or, from a
+/// .sas7bdat (which records only the name here), "DOLLAR". A format with
+/// no width takes SAS's default width for it and no decimals, as SAS
+/// itself displays it. Only the ones with a clear Stata counterpart are
+/// translated.
+fn sas_format_to_stata(format: &str) -> Option<String> {
+    let format = format.trim().to_uppercase();
+    let spec_start = format.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(format.len());
+    let (name, spec) = format.split_at(spec_start);
+    let (width, decimals) = spec.split_once('.').unwrap_or((spec, ""));
+    let default_width = match name {
+        "COMMA" | "DOLLAR" | "NLNUM" => 6,
+        "Z" => 1,
+        "E" | "F" => 12,
+        _ => return None,
+    };
+    let width: usize = if width.is_empty() { default_width } else { width.parse().ok()? };
+    let decimals: usize = if decimals.is_empty() { 0 } else { decimals.parse().ok()? };
+    if width == 0 || decimals >= width {
+        return None;
+    }
+    match name {
+        "COMMA" | "DOLLAR" | "NLNUM" => Some(format!("%{}.{}fc", width, decimals)),
+        "Z" => Some(format!("%0{}.{}f", width, decimals)),
+        "F" => Some(format!("%{}.{}f", width, decimals)),
+        // Ew. shows w-7 digits after the point ("1.235E+01" in E10.).
+        "E" => Some(format!("%{}.{}e", width, width.saturating_sub(7).max(decimals))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spss_labels_value_labels_and_formats() {
+        let json = r#"{
+            "file_label": "Survey 2020",
+            "variables": [
+                {"name": "sex", "type": "Numeric", "label": "Respondent sex",
+                 "value_label": "labels0", "value_labels": {"1": "Male", "2": "Female"},
+                 "format_type": 5, "format_width": 8, "format_decimals": 0},
+                {"name": "income", "type": "Numeric", "label": "",
+                 "format_type": 4, "format_width": 12, "format_decimals": 2},
+                {"name": "score", "type": "Numeric", "value_label": "half",
+                 "value_labels": {"1.5": "mid"}, "format_type": 5, "format_width": 8, "format_decimals": 2},
+                {"name": "city", "type": "Str", "label": "City",
+                 "value_labels": {"a": "A"}, "format_type": 1, "format_width": 20, "format_decimals": 0}
+            ]
+        }"#;
+        let envelope = envelope_from_readstat_json(json, ReadStatFormat::Spss).unwrap();
+        assert_eq!(envelope.dataset_label.as_deref(), Some("Survey 2020"));
+
+        let sex = &envelope.variables["sex"];
+        assert_eq!(sex.label.as_deref(), Some("Respondent sex"));
+        assert_eq!(sex.value_label.as_deref(), Some("labels0"));
+        assert_eq!(sex.format.as_deref(), Some("%8.0f"));
+        assert_eq!(envelope.value_labels["labels0"]["2"], "Female");
+
+        assert_eq!(envelope.variables["income"].format.as_deref(), Some("%12.2fc"));
+        // Fractional codes can't be a Stata value label.
+        assert!(envelope.variables["score"].value_label.is_none());
+        // String variables keep their label but no value label or format.
+        let city = &envelope.variables["city"];
+        assert_eq!(city.label.as_deref(), Some("City"));
+        assert!(city.value_label.is_none() && city.format.is_none());
+    }
+
+    #[test]
+    fn labelled_variables_loaded_as_text_drop_their_value_labels() {
+        let json = r#"{
+            "variables": [
+                {"name": "sex", "type": "Numeric", "label": "Respondent sex",
+                 "value_label": "labels0", "value_labels": {"1": "Male", "2": "Female"},
+                 "format_type": 5, "format_width": 8, "format_decimals": 0},
+                {"name": "grp", "type": "Numeric", "value_label": "grp",
+                 "value_labels": {"1": "A"}, "format_type": 5, "format_width": 8, "format_decimals": 0},
+                {"name": "income", "type": "Numeric",
+                 "format_type": 4, "format_width": 12, "format_decimals": 2}
+            ]
+        }"#;
+        let envelope =
+            without_value_labels(envelope_from_readstat_json(json, ReadStatFormat::Spss).unwrap()).unwrap();
+        assert!(envelope.value_labels.is_empty());
+        let sex = &envelope.variables["sex"];
+        assert_eq!(sex.label.as_deref(), Some("Respondent sex"));
+        assert!(sex.value_label.is_none() && sex.format.is_none());
+        assert!(!envelope.variables.contains_key("grp"));
+        assert_eq!(envelope.variables["income"].format.as_deref(), Some("%12.2fc"));
+    }
+
+    #[test]
+    fn sas_labels_and_formats() {
+        let json = r#"{
+            "columns": [
+                {"name": "amt", "type": "Numeric", "label": "Amount", "format": "DOLLAR"},
+                {"name": "id", "type": "Numeric", "label": null, "format": "BEST"},
+                {"name": "nm", "type": "Character", "label": "Name", "format": "$"}
+            ]
+        }"#;
+        let envelope = envelope_from_readstat_json(json, ReadStatFormat::Sas).unwrap();
+        assert_eq!(envelope.variables["amt"].label.as_deref(), Some("Amount"));
+        assert_eq!(envelope.variables["amt"].format.as_deref(), Some("%6.0fc"));
+        assert!(!envelope.variables.contains_key("id"));
+        assert!(envelope.variables["nm"].format.is_none());
+    }
+
+    #[test]
+    fn sas_formats_keep_their_width_and_decimals() {
+        assert_eq!(sas_format_to_stata("COMMA12.2").as_deref(), Some("%12.2fc"));
+        assert_eq!(sas_format_to_stata("dollar10").as_deref(), Some("%10.0fc"));
+        assert_eq!(sas_format_to_stata("Z8").as_deref(), Some("%08.0f"));
+        assert_eq!(sas_format_to_stata("Z9.3").as_deref(), Some("%09.3f"));
+        assert_eq!(sas_format_to_stata("E10").as_deref(), Some("%10.3e"));
+        assert_eq!(sas_format_to_stata("F8.2").as_deref(), Some("%8.2f"));
+        assert_eq!(sas_format_to_stata("Z").as_deref(), Some("%01.0f"));
+        assert_eq!(sas_format_to_stata("COMMA4.4"), None);
+        assert_eq!(sas_format_to_stata("BEST12"), None);
+        assert_eq!(sas_format_to_stata("DATE9"), None);
+    }
+
+    #[test]
+    fn dta_labels_value_labels_and_formats() {
+        let json = r#"{
+            "data_label": "Panel wave 3",
+            "variables": [
+                {"name": "region", "type": "Numeric(Byte)", "format": "%8.0g", "label": "Region",
+                 "value_label_name": "regionlbl", "value_labels": {"1": "North", "2": "South", ".a": "Refused"}},
+                {"name": "wage", "type": "Numeric(Double)", "format": "%10.2fc", "label": null,
+                 "value_label_name": null},
+                {"name": "nm", "type": "Str(12)", "format": "%12s", "label": "Name",
+                 "value_label_name": null}
+            ]
+        }"#;
+        let envelope = envelope_from_readstat_json(json, ReadStatFormat::Stata).unwrap();
+        assert_eq!(envelope.dataset_label.as_deref(), Some("Panel wave 3"));
+
+        let region = &envelope.variables["region"];
+        assert_eq!(region.label.as_deref(), Some("Region"));
+        assert_eq!(region.value_label.as_deref(), Some("regionlbl"));
+        assert_eq!(envelope.value_labels["regionlbl"].len(), 2);
+        assert_eq!(envelope.value_labels["regionlbl"]["2"], "South");
+
+        assert_eq!(envelope.variables["wage"].format.as_deref(), Some("%10.2fc"));
+        assert_eq!(envelope.variables["nm"].format.as_deref(), Some("%12s"));
+    }
+
+    #[test]
+    fn label_names_and_lengths_fit_stata() {
+        assert_eq!(stata_label_name("1st.choice"), "_1st_choice");
+        assert_eq!(stata_label_name(&"x".repeat(40)).len(), 32);
+        assert_eq!(truncate_label("é".repeat(50)).len(), 80);
+    }
+}
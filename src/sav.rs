@@ -0,0 +1,525 @@
+//! SPSS (.sav/.zsav) output for pq save.
+//!
+//! SpssWriter writes the data, variable labels, value labels and formats.
+//! What it has no option for is patched into its output afterwards: the file
+//! label, user-missing definitions for the codes the ado substituted for
+//! Stata's extended missing values (.a-.z), and the zlib-compressed data
+//! layout of .zsav files.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use flate2::write::ZlibEncoder;
+use polars::prelude::*;
+use polars_readstat_rs::{SpssValueLabelKey, SpssVariableFormat, SpssWriter};
+
+use crate::stata_interface::get_macro;
+use crate::stata_metadata::StataMetadataEnvelope;
+
+/// Variable -> extended missing value (".a") -> the numeric code written in
+/// its place.
+pub type ExtendedMissingCodes = BTreeMap<String, BTreeMap<String, f64>>;
+
+const SAV_HEADER_LEN: usize = 176;
+const SAV_FILE_LABEL: std::ops::Range<usize> = 109..173;
+const SAV_COMPRESSION: std::ops::Range<usize> = 72..76;
+const SAV_SYSMIS_BITS: u64 = 0xFFEFFFFFFFFFFFFF;
+const SAV_BIAS: f64 = 100.0;
+/// Bytes of bytecode-compressed data per zlib block, as SPSS writes them.
+const ZSAV_BLOCK_SIZE: usize = 0x3FF000;
+
+// SPSS print/write format types
+const SPSS_FORMAT_COMMA: u8 = 3;
+const SPSS_FORMAT_F: u8 = 5;
+const SPSS_FORMAT_E: u8 = 17;
+
+/// Reads the extended-missing code map the ado staged in `pq_spss_missing`.
+pub fn missing_codes_from_arg(arg: Option<&str>) -> Result<ExtendedMissingCodes, String> {
+    let json = match arg {
+        None | Some("") => return Ok(ExtendedMissingCodes::new()),
+        Some("pq_spss_missing") => get_macro("pq_spss_missing", false, Some(1024 * 1024)),
+        Some(json) => json.to_string(),
+    };
+    if json.trim().is_empty() {
+        return Ok(ExtendedMissingCodes::new());
+    }
+    serde_json::from_str(&json).map_err(|e| format!("Invalid SPSS missing-value map: {}", e))
+}
+
+/// SPSS print format for a Stata display format, where one exists. Dates,
+/// datetimes and strings keep the formats SpssWriter gives them.
+pub fn stata_format_to_spss(format: &str) -> Option<SpssVariableFormat> {
+    let re = regex::Regex::new(r"^%-?0?(\d+)\.(\d+)(f|fc|e)$").ok()?;
+    let caps = re.captures(format)?;
+    let width: u8 = caps[1].parse().ok().filter(|w| (1..=40).contains(w))?;
+    let decimals: u8 = caps[2].parse().ok().filter(|d| *d < width)?;
+    let format_type = match &caps[3] {
+        "f" => SPSS_FORMAT_F,
+        "fc" => SPSS_FORMAT_COMMA,
+        _ => SPSS_FORMAT_E,
+    };
+    Some(SpssVariableFormat {
+        format_type: Some(format_type),
+        width: Some(width),
+        decimals: Some(decimals),
+    })
+}
+
+/// Writes `df` as .sav, or .zsav when the path asks for it.
+pub fn write_sav(
+    path: &str,
+    df: &DataFrame,
+    envelope: Option<&StataMetadataEnvelope>,
+    missing_codes: &ExtendedMissingCodes,
+) -> Result<(), String> {
+    let mut variable_labels: HashMap<String, String> = HashMap::new();
+    let mut value_labels: HashMap<String, HashMap<SpssValueLabelKey, String>> = HashMap::new();
+    let mut formats: HashMap<String, SpssVariableFormat> = HashMap::new();
+
+    for column in df.columns() {
+        let name = column.name().to_string();
+        let numeric = column.dtype().is_primitive_numeric();
+        let codes = missing_codes.get(&name);
+        let meta = envelope.and_then(|e| e.variables.get(&name));
+
+        if let Some(label) = meta.and_then(|m| m.label.as_ref()).filter(|l| !l.is_empty()) {
+            variable_labels.insert(name.clone(), label.clone());
+        }
+        if !numeric {
+            continue;
+        }
+        if let Some(format) = meta.and_then(|m| m.format.as_deref()).and_then(stata_format_to_spss) {
+            formats.insert(name.clone(), format);
+        }
+
+        let mut labels: HashMap<SpssValueLabelKey, String> = HashMap::new();
+        let definition = meta
+            .and_then(|m| m.value_label.as_ref())
+            .and_then(|label_name| envelope?.value_labels.get(label_name));
+        for (code, text) in definition.into_iter().flatten() {
+            // Labels on .a-.z follow their codes; labels on plain "." have nowhere to go.
+            let value = match code.parse::<f64>() {
+                Ok(value) => Some(value),
+                Err(_) => codes.and_then(|c| c.get(code)).copied(),
+            };
+            if let Some(value) = value {
+                labels.insert(SpssValueLabelKey::from_f64(value), text.clone());
+            }
+        }
+        // Unlabelled codes keep their Stata name, so .a stays recognisable.
+        for (missing, code) in codes.into_iter().flatten() {
+            labels
+                .entry(SpssValueLabelKey::from_f64(*code))
+                .or_insert_with(|| missing.clone());
+        }
+        if !labels.is_empty() {
+            value_labels.insert(name, labels);
+        }
+    }
+
+    let file_label = envelope.and_then(|e| e.dataset_label.clone()).unwrap_or_default();
+    let zsav = path.to_ascii_lowercase().ends_with(".zsav");
+    let numeric_missing: Vec<Vec<f64>> = df
+        .columns()
+        .iter()
+        .filter(|c| c.dtype() != &DataType::String)
+        .map(|c| {
+            let mut codes: Vec<f64> = missing_codes
+                .get(c.name().as_str())
+                .map(|m| m.values().copied().collect())
+                .unwrap_or_default();
+            codes.sort_by(|a, b| a.total_cmp(b));
+            codes
+        })
+        .collect();
+    let needs_patch = zsav || !file_label.is_empty() || numeric_missing.iter().any(|m| !m.is_empty());
+
+    let raw_path = if needs_patch { format!("{}.pq_raw", path) } else { path.to_string() };
+    SpssWriter::new(&raw_path)
+        .with_variable_labels(variable_labels)
+        .with_value_labels(value_labels)
+        .with_variable_formats(formats)
+        .write_df(df)
+        .map_err(|e| e.to_string())?;
+
+    if needs_patch {
+        let result = finish_sav(&raw_path, path, &file_label, &numeric_missing, zsav)
+            .map_err(|e| format!("Error finishing {}: {}", path, e));
+        let _ = fs::remove_file(&raw_path);
+        result?;
+    }
+    Ok(())
+}
+
+/// Copies SpssWriter's uncompressed output at `source` to `target`, adding
+/// the file label and the user-missing values of each numeric variable (in
+/// dictionary order), and recompressing the data for .zsav.
+fn finish_sav(
+    source: &str,
+    target: &str,
+    file_label: &str,
+    numeric_missing: &[Vec<f64>],
+    zsav: bool,
+) -> io::Result<()> {
+    let mut input = BufReader::with_capacity(1 << 20, File::open(source)?);
+    let mut output = BufWriter::with_capacity(1 << 20, File::create(target)?);
+
+    let mut header = [0u8; SAV_HEADER_LEN];
+    input.read_exact(&mut header)?;
+    if !file_label.is_empty() {
+        let mut end = file_label.len().min(SAV_FILE_LABEL.len());
+        while !file_label.is_char_boundary(end) {
+            end -= 1;
+        }
+        header[SAV_FILE_LABEL].fill(b' ');
+        header[SAV_FILE_LABEL.start..SAV_FILE_LABEL.start + end].copy_from_slice(&file_label.as_bytes()[..end]);
+    }
+    if zsav {
+        header[SAV_COMPRESSION].copy_from_slice(&2i32.to_le_bytes());
+    }
+    output.write_all(&header)?;
+
+    // One entry per 8-byte case segment: true for numeric, false for string.
+    let mut segments: Vec<bool> = Vec::new();
+    let mut numeric_index = 0usize;
+    loop {
+        let rec_type = read_i32(&mut input)?;
+        write_i32(&mut output, rec_type)?;
+        match rec_type {
+            2 => {
+                let mut fields = [0u8; 28];
+                input.read_exact(&mut fields)?;
+                let typ = i32::from_le_bytes(fields[0..4].try_into().unwrap());
+                let has_label = i32::from_le_bytes(fields[4..8].try_into().unwrap());
+                let n_missing = i32::from_le_bytes(fields[8..12].try_into().unwrap());
+                segments.push(typ == 0);
+
+                let missing = if typ == 0 {
+                    numeric_index += 1;
+                    numeric_missing.get(numeric_index - 1).filter(|m| !m.is_empty())
+                } else {
+                    None
+                };
+                // Up to three discrete values, otherwise the range they span (n = -2).
+                let missing_values: Vec<f64> = match missing {
+                    Some(codes) if codes.len() <= 3 => {
+                        fields[8..12].copy_from_slice(&(codes.len() as i32).to_le_bytes());
+                        codes.clone()
+                    }
+                    Some(codes) => {
+                        fields[8..12].copy_from_slice(&(-2i32).to_le_bytes());
+                        vec![codes[0], codes[codes.len() - 1]]
+                    }
+                    None => vec![],
+                };
+                output.write_all(&fields)?;
+
+                if has_label == 1 {
+                    let len = read_i32(&mut input)?;
+                    write_i32(&mut output, len)?;
+                    copy_bytes(&mut input, &mut output, (len as usize).div_ceil(4) * 4)?;
+                }
+                if missing.is_some() {
+                    skip_bytes(&mut input, n_missing.unsigned_abs() as usize * 8)?;
+                    for value in &missing_values {
+                        output.write_all(&value.to_le_bytes())?;
+                    }
+                } else {
+                    copy_bytes(&mut input, &mut output, n_missing.unsigned_abs() as usize * 8)?;
+                }
+            }
+            3 => {
+                let count = read_i32(&mut input)?;
+                write_i32(&mut output, count)?;
+                for _ in 0..count {
+                    let mut value_and_len = [0u8; 9];
+                    input.read_exact(&mut value_and_len)?;
+                    output.write_all(&value_and_len)?;
+                    let len = value_and_len[8] as usize;
+                    copy_bytes(&mut input, &mut output, (len + 8) / 8 * 8 - 1)?;
+                }
+            }
+            4 => {
+                let count = read_i32(&mut input)?;
+                write_i32(&mut output, count)?;
+                copy_bytes(&mut input, &mut output, count as usize * 4)?;
+            }
+            6 => {
+                let lines = read_i32(&mut input)?;
+                write_i32(&mut output, lines)?;
+                copy_bytes(&mut input, &mut output, lines as usize * 80)?;
+            }
+            7 => {
+                let mut sizes = [0u8; 12];
+                input.read_exact(&mut sizes)?;
+                output.write_all(&sizes)?;
+                let size = i32::from_le_bytes(sizes[4..8].try_into().unwrap()) as usize;
+                let count = i32::from_le_bytes(sizes[8..12].try_into().unwrap()) as usize;
+                copy_bytes(&mut input, &mut output, size * count)?;
+            }
+            999 => {
+                copy_bytes(&mut input, &mut output, 4)?;
+                break;
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected dictionary record type {}", other),
+                ))
+            }
+        }
+    }
+
+    if zsav {
+        output.flush()?;
+        let mut file = output.into_inner().map_err(|e| e.into_error())?;
+        write_zsav_data(&mut input, &mut file, &segments)?;
+    } else {
+        io::copy(&mut input, &mut output)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Writes the cases as bytecode compressed in zlib blocks, followed by the
+/// block index, with the zheader in front pointing at it.
+fn write_zsav_data<R: Read>(input: &mut R, file: &mut File, segments: &[bool]) -> io::Result<()> {
+    let zheader_ofs = file.stream_position()?;
+    file.write_all(&[0u8; 24])?;
+
+    let mut blocks: Vec<(i64, i64, i32, i32)> = Vec::new();
+    let mut uncompressed_ofs = zheader_ofs as i64;
+    let mut compressed_ofs = zheader_ofs as i64 + 24;
+    let mut pending: Vec<u8> = Vec::with_capacity(ZSAV_BLOCK_SIZE + 72);
+    let mut flush_block = |pending: &mut Vec<u8>, file: &mut File, len: usize| -> io::Result<()> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&pending[..len])?;
+        let compressed = encoder.finish()?;
+        file.write_all(&compressed)?;
+        blocks.push((uncompressed_ofs, compressed_ofs, len as i32, compressed.len() as i32));
+        uncompressed_ofs += len as i64;
+        compressed_ofs += compressed.len() as i64;
+        pending.drain(..len);
+        Ok(())
+    };
+
+    let mut bytecode = Bytecode::default();
+    let mut case = vec![0u8; segments.len() * 8];
+    while !segments.is_empty() && read_case(input, &mut case)? {
+        for (segment, numeric) in case.chunks_exact(8).zip(segments) {
+            bytecode.push(segment, *numeric, &mut pending);
+        }
+        while pending.len() >= ZSAV_BLOCK_SIZE {
+            flush_block(&mut pending, file, ZSAV_BLOCK_SIZE)?;
+        }
+    }
+    bytecode.finish(&mut pending);
+    while !pending.is_empty() {
+        let len = pending.len().min(ZSAV_BLOCK_SIZE);
+        flush_block(&mut pending, file, len)?;
+    }
+
+    let ztrailer_ofs = file.stream_position()?;
+    let ztrailer_len = 24 + 24 * blocks.len() as u64;
+    file.write_all(&(-SAV_BIAS as i64).to_le_bytes())?;
+    file.write_all(&0i64.to_le_bytes())?;
+    file.write_all(&(ZSAV_BLOCK_SIZE as i32).to_le_bytes())?;
+    file.write_all(&(blocks.len() as i32).to_le_bytes())?;
+    for (uncompressed_ofs, compressed_ofs, uncompressed_size, compressed_size) in &blocks {
+        file.write_all(&uncompressed_ofs.to_le_bytes())?;
+        file.write_all(&compressed_ofs.to_le_bytes())?;
+        file.write_all(&uncompressed_size.to_le_bytes())?;
+        file.write_all(&compressed_size.to_le_bytes())?;
+    }
+
+    file.seek(SeekFrom::Start(zheader_ofs))?;
+    file.write_all(&zheader_ofs.to_le_bytes())?;
+    file.write_all(&ztrailer_ofs.to_le_bytes())?;
+    file.write_all(&ztrailer_len.to_le_bytes())?;
+    file.flush()
+}
+
+/// SPSS bytecode compression: groups of eight one-byte codes, each followed
+/// by the 8-byte values that codes 253 ("raw value follows") refer to.
+#[derive(Default)]
+struct Bytecode {
+    codes: Vec<u8>,
+    raw: Vec<u8>,
+}
+
+impl Bytecode {
+    fn push(&mut self, segment: &[u8], numeric: bool, out: &mut Vec<u8>) {
+        let code = if numeric {
+            let value = f64::from_le_bytes(segment.try_into().unwrap());
+            if value.to_bits() == SAV_SYSMIS_BITS {
+                255
+            } else if value.fract() == 0.0 && (1.0 - SAV_BIAS..=251.0 - SAV_BIAS).contains(&value) {
+                (value + SAV_BIAS) as u8
+            } else {
+                253
+            }
+        } else if segment.iter().all(|b| *b == b' ') {
+            254
+        } else {
+            253
+        };
+        self.codes.push(code);
+        if code == 253 {
+            self.raw.extend_from_slice(segment);
+        }
+        if self.codes.len() == 8 {
+            self.flush(out);
+        }
+    }
+
+    fn flush(&mut self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.codes);
+        out.extend_from_slice(&self.raw);
+        self.codes.clear();
+        self.raw.clear();
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if !self.codes.is_empty() {
+            self.codes.resize(8, 0);
+            self.flush(out);
+        }
+    }
+}
+
+fn read_case<R: Read>(input: &mut R, case: &mut [u8]) -> io::Result<bool> {
+    match input.read_exact(case) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_i32<R: Read>(input: &mut R) -> io::Result<i32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn write_i32<W: Write>(output: &mut W, value: i32) -> io::Result<()> {
+    output.write_all(&value.to_le_bytes())
+}
+
+fn copy_bytes<R: Read, W: Write>(input: &mut R, output: &mut W, n: usize) -> io::Result<()> {
+    let copied = io::copy(&mut input.take(n as u64), output)?;
+    if copied as usize != n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn skip_bytes<R: Read>(input: &mut R, n: usize) -> io::Result<()> {
+    copy_bytes(input, &mut io::sink(), n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stata_metadata::VariableMetadata;
+    use polars_readstat_rs::{readstat_metadata_json, readstat_scan, ReadStatFormat};
+    use serde_json::Value;
+
+    fn envelope() -> StataMetadataEnvelope {
+        let mut envelope = StataMetadataEnvelope {
+            version: 1,
+            dataset_label: Some("Panel wave 3".to_string()),
+            ..Default::default()
+        };
+        envelope.variables.insert(
+            "q1".to_string(),
+            VariableMetadata {
+                label: Some("Satisfaction".to_string()),
+                value_label: Some("agree".to_string()),
+                format: Some("%8.1f".to_string()),
+                ..Default::default()
+            },
+        );
+        envelope.value_labels.insert(
+            "agree".to_string(),
+            BTreeMap::from([
+                ("1".to_string(), "Disagree".to_string()),
+                ("2".to_string(), "Agree".to_string()),
+                (".a".to_string(), "Refused".to_string()),
+            ]),
+        );
+        envelope
+    }
+
+    fn write_and_read(ext: &str) -> (DataFrame, Value) {
+        let path = std::env::temp_dir().join(format!("pq_sav_test_{}.{}", std::process::id(), ext));
+        let path_str = path.to_string_lossy().to_string();
+        // .a and .b were replaced by -1 and -2 in Stata before the write.
+        let df = df!(
+            "q1" => [Some(1.0f64), Some(-1.0), Some(2.0), None, Some(-2.0)],
+            "name" => ["a", "b", "", "d", "a much longer string value"],
+            "x" => [0.5f64, 1e10, -3.0, 251.0, 7.0],
+        )
+        .unwrap();
+        let codes: ExtendedMissingCodes =
+            BTreeMap::from([("q1".to_string(), BTreeMap::from([(".a".to_string(), -1.0), (".b".to_string(), -2.0)]))]);
+
+        write_sav(&path_str, &df, Some(&envelope()), &codes).unwrap();
+        let back = readstat_scan(&path, None, Some(ReadStatFormat::Spss)).unwrap().collect().unwrap();
+        let meta: Value = serde_json::from_str(&readstat_metadata_json(&path, Some(ReadStatFormat::Spss)).unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        (back, meta)
+    }
+
+    #[test]
+    fn sav_carries_labels_formats_and_user_missing() {
+        let (back, meta) = write_and_read("sav");
+        assert_eq!(back.height(), 5);
+        assert_eq!(meta["file_label"].as_str().unwrap().trim(), "Panel wave 3");
+
+        let q1 = &meta["variables"][0];
+        assert_eq!(q1["label"], "Satisfaction");
+        assert_eq!(q1["format_type"], 5);
+        assert_eq!(q1["format_decimals"], 1);
+        assert_eq!(q1["missing_doubles"], serde_json::json!([-2.0, -1.0]));
+        let labels = q1["value_labels"].as_object().unwrap();
+        assert!(labels.values().any(|v| v == "Refused"));
+        assert!(labels.values().any(|v| v == ".b"));
+    }
+
+    #[test]
+    fn zsav_round_trips() {
+        let (back, _) = write_and_read("zsav");
+        let (plain, _) = write_and_read("sav");
+        assert!(back.equals_missing(&plain));
+        assert_eq!(back.column("x").unwrap().f64().unwrap().get(1), Some(1e10));
+        assert_eq!(back.column("name").unwrap().str().unwrap().get(4), Some("a much longer string value"));
+    }
+
+    #[test]
+    fn many_missing_codes_become_a_range() {
+        let path = std::env::temp_dir().join(format!("pq_sav_range_{}.sav", std::process::id()));
+        let path_str = path.to_string_lossy().to_string();
+        let df = df!("v" => [1.0f64, -1.0, -2.0, -3.0, -26.0]).unwrap();
+        let codes: ExtendedMissingCodes = BTreeMap::from([(
+            "v".to_string(),
+            [(".a", -1.0), (".b", -2.0), (".c", -3.0), (".z", -26.0)]
+                .iter()
+                .map(|(k, v)| (k.to_string(), *v))
+                .collect(),
+        )]);
+        write_sav(&path_str, &df, None, &codes).unwrap();
+        let meta: Value = serde_json::from_str(&readstat_metadata_json(&path, Some(ReadStatFormat::Spss)).unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(meta["variables"][0]["missing_range"], true);
+        assert_eq!(meta["variables"][0]["missing_doubles"], serde_json::json!([-26.0, -1.0]));
+    }
+
+    #[test]
+    fn stata_formats_map_to_spss() {
+        let f = stata_format_to_spss("%12.2fc").unwrap();
+        assert_eq!((f.format_type, f.width, f.decimals), (Some(SPSS_FORMAT_COMMA), Some(12), Some(2)));
+        assert!(stata_format_to_spss("%9.0g").is_none());
+        assert!(stata_format_to_spss("%td").is_none());
+    }
+}
@@ -1,271 +0,0 @@
-#![allow(dead_code)]
-use regex::Regex;
-
-pub struct StataToSqlRegexConverter {
-    replacements: Vec<(Regex, String)>,
-}
-
-impl StataToSqlRegexConverter {
-    pub fn new() -> Self {
-        let mut converter = StataToSqlRegexConverter {
-            replacements: Vec::new(),
-        };
-        
-        // Add only Stata-specific patterns - leave SQL syntax alone
-        converter.add_patterns();
-        converter
-    }
-    
-    fn add_patterns(&mut self) {
-        // Handle !missing(var) => var IS NOT NULL
-        self.add_replacement(
-            r"!missing\s*\(\s*([^)]+)\s*\)",
-            "$1 IS NOT NULL"
-        );
-        
-        // Handle missing(var) => var IS NULL
-        self.add_replacement(
-            r"missing\s*\(\s*([^)]+)\s*\)",
-            "$1 IS NULL"
-        );
-        
-        // Handle inrange(var, min, max) => var BETWEEN min AND max
-        self.add_replacement(
-            r"inrange\s*\(\s*([^,]+)\s*,\s*([^,]+)\s*,\s*([^)]+)\s*\)",
-            "$1 BETWEEN $2 AND $3"
-        );
-        
-        // Handle inlist(var, val1, val2, ...) => var IN (val1, val2, ...)
-        self.add_replacement(
-            r"inlist\s*\(\s*([^,]+)\s*,\s*([^)]+)\s*\)",
-            "$1 IN ($2)"
-        );
-        
-        // Handle mod(a, b) => a % b
-        self.add_replacement(
-            r"mod\s*\(\s*([^,]+)\s*,\s*([^)]+)\s*\)",
-            "($1 % $2)"
-        );
-        
-        // Handle ceil(x) => CEILING(x)
-        self.add_replacement(
-            r"ceil\s*\(\s*([^)]+)\s*\)",
-            "CEILING($1)"
-        );
-        
-        // Handle floor(x) => FLOOR(x)
-        self.add_replacement(
-            r"floor\s*\(\s*([^)]+)\s*\)",
-            "FLOOR($1)"
-        );
-        
-        // Handle round(x) => ROUND(x)
-        self.add_replacement(
-            r"round\s*\(\s*([^)]+)\s*\)",
-            "ROUND($1)"
-        );
-        
-        // Handle real(x) => CAST(x AS REAL)
-        self.add_replacement(
-            r"real\s*\(\s*([^)]+)\s*\)",
-            "CAST($1 AS REAL)"
-        );
-        
-        // Handle string(x) => CAST(x AS VARCHAR)
-        self.add_replacement(
-            r"string\s*\(\s*([^)]+)\s*\)",
-            "CAST($1 AS VARCHAR)"
-        );
-        
-        // Handle Stata logical operators (simple patterns since we handle quotes separately)
-        self.add_replacement(r"\s*&\s*", " AND ");
-        self.add_replacement(r"\s*\|\s*", " OR ");
-        self.add_replacement(r"==", "=");
-        
-        // Handle negation with parentheses
-        self.add_replacement(r"!\s*\(", "NOT (");
-    }
-    
-    fn add_replacement(&mut self, pattern: &str, replacement: &str) {
-        let regex = Regex::new(pattern).expect("Invalid regex pattern");
-        self.replacements.push((regex, replacement.to_string()));
-    }
-    
-    pub fn convert(&self, input: &str) -> String {
-        // Normalize common smart quotes to plain double quotes before processing.
-        let normalized = input
-            .replace('“', "\"")
-            .replace('”', "\"");
-
-        // Protect quoted string literals so replacements do not touch their contents.
-        let (mut processed, quoted_literals) = self.extract_quoted_literals(&normalized);
-
-        for (regex, replacement) in &self.replacements {
-            processed = regex.replace_all(&processed, replacement.as_str()).to_string();
-        }
-
-        let restored = self.restore_quoted_literals(&processed, &quoted_literals);
-        self.normalize_double_quoted_literals(&restored)
-    }
-
-    fn extract_quoted_literals(&self, input: &str) -> (String, Vec<String>) {
-        let mut protected = String::new();
-        let mut quoted_literals = Vec::new();
-        let mut current = String::new();
-        let mut in_quote = false;
-        let mut quote_char = None;
-        let chars = input.chars();
-        
-        for ch in chars {
-            match ch {
-                '"' | '\'' if !in_quote => {
-                    if !current.is_empty() {
-                        protected.push_str(&current);
-                        current.clear();
-                    }
-                    current.push(ch);
-                    in_quote = true;
-                    quote_char = Some(ch);
-                }
-                ch if in_quote && Some(ch) == quote_char => {
-                    current.push(ch);
-                    let token = format!("__Q{}__", quoted_literals.len());
-                    quoted_literals.push(current.clone());
-                    protected.push_str(&token);
-                    current.clear();
-                    in_quote = false;
-                    quote_char = None;
-                }
-                _ => {
-                    current.push(ch);
-                }
-            }
-        }
-        
-        // If quote is unclosed, keep trailing text as-is.
-        if in_quote {
-            protected.push_str(&current);
-        } else if !current.is_empty() {
-            protected.push_str(&current);
-        }
-
-        (protected, quoted_literals)
-    }
-
-    fn restore_quoted_literals(&self, input: &str, quoted_literals: &[String]) -> String {
-        let mut output = input.to_string();
-        for (idx, literal) in quoted_literals.iter().enumerate() {
-            let token = format!("__Q{}__", idx);
-            // Stata uses double-quoted string literals; SQL requires single quotes.
-            // Convert "..." -> '...' so the SQL engine treats them as values, not identifiers.
-            let sql_literal = if literal.starts_with('"') && literal.ends_with('"') && literal.len() >= 2 {
-                format!("'{}'", &literal[1..literal.len() - 1])
-            } else {
-                literal.clone()
-            };
-            output = output.replace(&token, &sql_literal);
-        }
-        output
-    }
-
-    fn normalize_double_quoted_literals(&self, input: &str) -> String {
-        // Fallback: if any double-quoted literals survived tokenization,
-        // normalize them to SQL single-quoted string literals.
-        let re = Regex::new(r#""([^"]*)""#).expect("Invalid regex pattern");
-        re.replace_all(input, "'$1'").to_string()
-    }
-}
-
-
-pub fn stata_to_sql(input: &str) -> String {
-    let converter = StataToSqlRegexConverter::new();
-    converter.convert(input)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_stata_functions_converted() {
-        assert_eq!(stata_to_sql("missing(age)"), "age IS NULL");
-        assert_eq!(stata_to_sql("!missing(age)"), "age IS NOT NULL");
-        assert_eq!(stata_to_sql("inrange(age, 18, 65)"), "age BETWEEN 18 AND 65");
-        assert_eq!(stata_to_sql("inlist(country, \"USA\", \"Canada\")"), "country IN ('USA', 'Canada')");
-        assert_eq!(stata_to_sql("ceil(value)"), "CEILING(value)");
-        assert_eq!(stata_to_sql("mod(x, 5)"), "(x % 5)");
-    }
-
-    #[test]
-    fn test_stata_operators_converted() {
-        assert_eq!(stata_to_sql("age > 30 & gender == \"male\""), "age > 30 AND gender = 'male'");
-        assert_eq!(stata_to_sql("status == 1 | status == 2"), "status = 1 OR status = 2");
-    }
-
-    #[test]
-    fn test_sql_syntax_unchanged() {
-        // Standard SQL should pass through unchanged
-        assert_eq!(stata_to_sql("age > 30 AND gender = 'male'"), "age > 30 AND gender = 'male'");
-        assert_eq!(stata_to_sql("status = 1 OR status = 2"), "status = 1 OR status = 2");
-        assert_eq!(stata_to_sql("value BETWEEN 1 AND 10"), "value BETWEEN 1 AND 10");
-        assert_eq!(stata_to_sql("country IN ('USA', 'Canada')"), "country IN ('USA', 'Canada')");
-        assert_eq!(stata_to_sql("name IS NULL"), "name IS NULL");
-        assert_eq!(stata_to_sql("name IS NOT NULL"), "name IS NOT NULL");
-        assert_eq!(stata_to_sql("CEILING(value)"), "CEILING(value)");
-        assert_eq!(stata_to_sql("FLOOR(value)"), "FLOOR(value)");
-        assert_eq!(stata_to_sql("CAST(x AS INTEGER)"), "CAST(x AS INTEGER)");
-    }
-
-    #[test]
-    fn test_mixed_syntax() {
-        // Mix of Stata and SQL should convert only Stata parts
-        assert_eq!(
-            stata_to_sql("inrange(age, 18, 65) AND status = 'active'"),
-            "age BETWEEN 18 AND 65 AND status = 'active'"
-        );
-        assert_eq!(
-            stata_to_sql("missing(income) | salary IS NOT NULL"),
-            "income IS NULL OR salary IS NOT NULL"
-        );
-    }
-
-    #[test]
-    fn test_complex_mixed_expression() {
-        let input = "inrange(age, 18, 65) & !missing(income) | status = 'exempt' AND CEILING(score) > 80";
-        let expected = "age BETWEEN 18 AND 65 AND income IS NOT NULL OR status = 'exempt' AND CEILING(score) > 80";
-        assert_eq!(stata_to_sql(input), expected);
-    }
-
-    #[test]
-    fn test_operators_in_strings_unchanged() {
-        // Operators inside strings should not be converted
-        assert_eq!(stata_to_sql("name = 'John & Jane'"), "name = 'John & Jane'");
-        assert_eq!(stata_to_sql("text = \"a | b\""), "text = 'a | b'");
-        assert_eq!(stata_to_sql("value = 'x == y'"), "value = 'x == y'");
-        assert_eq!(stata_to_sql("desc = \"missing(data)\""), "desc = 'missing(data)'");
-        assert_eq!(stata_to_sql("c_2 == \"A\""), "c_2 = 'A'");
-    }
-
-    #[test]
-    fn test_operator_spacing_variations() {
-        assert_eq!(stata_to_sql("a&b"), "a AND b");
-        assert_eq!(stata_to_sql("a & b"), "a AND b");
-        assert_eq!(stata_to_sql("a  &  b"), "a AND b");
-        assert_eq!(stata_to_sql("a|b"), "a OR b");
-        assert_eq!(stata_to_sql("a | b"), "a OR b");
-        assert_eq!(stata_to_sql("a  |  b"), "a OR b");
-    }
-
-    #[test]
-    fn test_mixed_quotes_and_operators() {
-        assert_eq!(
-            stata_to_sql("name = 'John & Jane' & age > 30"),
-            "name = 'John & Jane' AND age > 30"
-        );
-        assert_eq!(
-            stata_to_sql("missing(name) | desc = \"has | in text\""),
-            "name IS NULL OR desc = 'has | in text'"
-        );
-    }
-}
-
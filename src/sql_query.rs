@@ -0,0 +1,262 @@
+//! `pq sql`: a SQL query over files. Each file is named in the query as a
+//! quoted path after FROM or JOIN ('a.parquet', 'waves/*.parquet',
+//! 'survey.sav'), scanned lazily in any input format and registered under
+//! a generated table name before the query runs. The query is a source
+//! like any file (`InputFormat::Sql`), so `pq use` describes and loads its
+//! result in batches through `read_to_stata`.
+
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use polars::prelude::*;
+use polars_sql::SQLContext;
+use sqlparser::ast::{visit_relations_mut, Ident, ObjectName, ObjectNamePart};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+
+use crate::csv_dialect::CsvDialect;
+use crate::decompress::resolve_input_path;
+use crate::read::{data_exists, format_from_extension, scan_lazyframe_with_options, source_metadata, InputFormat};
+use crate::stata_interface::get_macro;
+use crate::stata_metadata::StataMetadataEnvelope;
+
+/// The local pq.ado stages the query in (it has quotes, and can be long),
+/// passed as the source path of the describe, read and overflow calls; the
+/// query's working directory is in pq_sql_cwd.
+pub const QUERY_SENTINEL: &str = "pq_sql_query";
+
+/// A file referenced in the query and the table name that replaced it.
+#[derive(Debug, PartialEq)]
+pub struct FileTable {
+    pub name: String,
+    pub path: String,
+}
+
+/// The source a describe/read/overflow call scans: for a query, the query
+/// read by name with its paths made absolute; otherwise the file, with
+/// compressed inputs resolved.
+pub fn resolve_source(arg: &str, input_format: InputFormat) -> Result<String, String> {
+    if input_format != InputFormat::Sql {
+        return resolve_input_path(arg, input_format);
+    }
+    let query = get_macro(QUERY_SENTINEL, false, Some(1024 * 1024));
+    let cwd = get_macro("pq_sql_cwd", false, None);
+    absolute_file_references(&query, &cwd)
+}
+
+/// Parses the query and replaces the name of every table factor written as
+/// a quoted path with the identifier `replace` returns for that path.
+fn map_file_references(query: &str, mut replace: impl FnMut(&str) -> Ident) -> Result<String, String> {
+    let mut statements =
+        Parser::parse_sql(&GenericDialect {}, query).map_err(|e| format!("Error in SQL query: {}", e))?;
+    let _ = visit_relations_mut(&mut statements, |name: &mut ObjectName| {
+        if let [ObjectNamePart::Identifier(ident)] = name.0.as_slice() {
+            if ident.quote_style == Some('\'') {
+                *name = ObjectName::from(vec![replace(&ident.value)]);
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    Ok(statements.iter().map(|s| s.to_string()).collect::<Vec<_>>().join("; "))
+}
+
+/// The query with each quoted path after FROM/JOIN replaced by a table name
+/// (pq_file_1, pq_file_2, ...; a path used twice is one table), and the
+/// files those names stand for.
+pub fn rewrite_file_references(query: &str) -> Result<(String, Vec<FileTable>), String> {
+    let mut tables: Vec<FileTable> = Vec::new();
+    let rewritten = map_file_references(query, |path| {
+        let name = match tables.iter().find(|t| t.path == path) {
+            Some(table) => table.name.clone(),
+            None => {
+                let name = format!("pq_file_{}", tables.len() + 1);
+                tables.push(FileTable {
+                    name: name.clone(),
+                    path: path.to_string(),
+                });
+                name
+            }
+        };
+        Ident::new(name)
+    })?;
+    Ok((rewritten, tables))
+}
+
+/// The query with relative paths resolved against `cwd` (Stata's working
+/// directory).
+pub fn absolute_file_references(query: &str, cwd: &str) -> Result<String, String> {
+    map_file_references(query, |path| {
+        let path = if Path::new(path).is_absolute() || cwd.is_empty() {
+            path.to_string()
+        } else {
+            Path::new(cwd).join(path).to_string_lossy().to_string()
+        };
+        Ident::with_quote('\'', path)
+    })
+}
+
+/// Each file's path, after compressed inputs are resolved, and format.
+fn table_sources(tables: &[FileTable]) -> Result<Vec<(String, InputFormat)>, String> {
+    tables
+        .iter()
+        .map(|table| {
+            if !data_exists(&table.path) {
+                return Err(format!("File does not exist ({})", table.path));
+            }
+            let input_format =
+                InputFormat::from_str(format_from_extension(&table.path)).unwrap_or(InputFormat::Parquet);
+            // Compressed inputs (.gz/.zst/.bz2/.xz) are decoded as read or from temp copies.
+            Ok((resolve_input_path(&table.path, input_format)?, input_format))
+        })
+        .collect()
+}
+
+/// The lazy result of `query`. CSV files are read with `csv_dialect`.
+pub fn file_query(query: &str, csv_dialect: &CsvDialect) -> Result<LazyFrame, String> {
+    let (rewritten, tables) = rewrite_file_references(query)?;
+    if tables.is_empty() {
+        return Err("The query names no files; refer to them as quoted paths, e.g. FROM 'data.parquet'".to_string());
+    }
+
+    let mut ctx = SQLContext::new();
+    for (table, (input_path, input_format)) in tables.iter().zip(table_sources(&tables)?) {
+        let infer_schema_length = matches!(input_format, InputFormat::Csv | InputFormat::Ndjson).then_some(10_000);
+        let lf = scan_lazyframe_with_options(
+            &input_path,
+            false,
+            None,
+            input_format,
+            false,
+            infer_schema_length,
+            false,
+            None,
+            csv_dialect,
+        )
+        .map_err(|e| format!("Error scanning {}: {}", table.path, e))?;
+        ctx.register(&table.name, lf);
+    }
+
+    ctx.execute(&rewritten).map_err(|e| format!("Error in SQL query: {}", e))
+}
+
+/// Labels, value labels, formats and notes the queried files record, for
+/// the result columns that keep a source column's name (the first file to
+/// name a column or value label wins). Storage types are left out, since
+/// the query may have changed them.
+pub fn query_metadata(query: &str) -> Option<StataMetadataEnvelope> {
+    let (_, tables) = rewrite_file_references(query).ok()?;
+    let mut merged: Option<StataMetadataEnvelope> = None;
+    for (input_path, input_format) in table_sources(&tables).ok()? {
+        let Some(envelope) = source_metadata(&input_path, input_format).ok().flatten() else {
+            continue;
+        };
+        let merged = merged.get_or_insert_with(|| StataMetadataEnvelope {
+            version: envelope.version,
+            ..Default::default()
+        });
+        // A dataset label only describes the result of a query over one file.
+        if tables.len() == 1 {
+            merged.dataset_label = envelope.dataset_label;
+            merged.dataset_notes = envelope.dataset_notes;
+        }
+        for (name, mut variable) in envelope.variables {
+            variable.stata_type = None;
+            merged.variables.entry(name).or_insert(variable);
+        }
+        for (name, labels) in envelope.value_labels {
+            merged.value_labels.entry(name).or_insert(labels);
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_paths_after_from_and_join_become_tables() {
+        let (query, tables) = rewrite_file_references(
+            "SELECT a.id, sum(b.x) FROM 'a.parquet' a JOIN 'b/*.parquet' b USING(id) \
+             WHERE a.name <> 'from' AND a.id IN (SELECT id FROM 'a.parquet') GROUP BY 1",
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            "SELECT a.id, sum(b.x) FROM pq_file_1 a JOIN pq_file_2 b USING(id) \
+             WHERE a.name <> 'from' AND a.id IN (SELECT id FROM pq_file_1) GROUP BY 1"
+        );
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[1].path, "b/*.parquet");
+
+        let (_, tables) = rewrite_file_references("select * from 'o''brien.csv'").unwrap();
+        assert_eq!(tables[0].path, "o'brien.csv");
+
+        let query = absolute_file_references("SELECT * FROM '/data/a.parquet' JOIN 'b.csv' USING(id)", "/work").unwrap();
+        assert_eq!(query, "SELECT * FROM '/data/a.parquet' JOIN '/work/b.csv' USING(id)");
+        assert!(rewrite_file_references("SELECT * FROM").is_err());
+    }
+
+    #[test]
+    fn joins_and_aggregates_across_files() {
+        let dir = std::env::temp_dir().join(format!("pq_sql_rs_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        let mut people = df!("id" => [1i64, 2, 3], "name" => ["ann", "bo", "cy"]).unwrap();
+        ParquetWriter::new(std::fs::File::create(dir.join("a.parquet")).unwrap())
+            .finish(&mut people)
+            .unwrap();
+        for (i, amounts) in [[1.0, 2.0], [4.0, 8.0]].iter().enumerate() {
+            let mut spend = df!("id" => [1i64, 2], "x" => amounts.to_vec()).unwrap();
+            ParquetWriter::new(std::fs::File::create(dir.join(format!("b/part_{i}.parquet"))).unwrap())
+                .finish(&mut spend)
+                .unwrap();
+        }
+        std::fs::write(dir.join("c.csv"), "id;bonus\n1;0,5\n2;1,5\n").unwrap();
+        let cwd = dir.to_string_lossy();
+
+        let result = absolute_file_references(
+            "SELECT a.name, sum(b.x) AS total FROM 'a.parquet' a JOIN 'b/*.parquet' b USING(id) GROUP BY 1 ORDER BY 1",
+            &cwd,
+        )
+        .and_then(|query| file_query(&query, &CsvDialect::default()))
+        .and_then(|lf| lf.collect().map_err(|e| e.to_string()));
+        let dialect = CsvDialect::from_json(r#"{"separator":";","decimal_comma":true}"#).unwrap();
+        let bonus = absolute_file_references("SELECT sum(bonus) AS bonus FROM 'c.csv'", &cwd)
+            .and_then(|query| file_query(&query, &dialect))
+            .and_then(|lf| lf.collect().map_err(|e| e.to_string()));
+        let missing = absolute_file_references("SELECT * FROM 'nope.parquet'", &cwd)
+            .and_then(|query| file_query(&query, &CsvDialect::default()))
+            .err();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let result = result.unwrap();
+        assert_eq!(result.height(), 2);
+        let totals: Vec<f64> = result.column("total").unwrap().f64().unwrap().into_no_null_iter().collect();
+        assert_eq!(totals, [5.0, 10.0]);
+        assert_eq!(bonus.unwrap().column("bonus").unwrap().f64().unwrap().get(0), Some(2.0));
+        assert!(missing.unwrap().starts_with("File does not exist"));
+    }
+
+    #[test]
+    fn labels_of_the_queried_files_are_carried() {
+        use polars_readstat_rs::SpssWriter;
+
+        let dir = std::env::temp_dir().join(format!("pq_sql_meta_rs_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("survey.sav");
+        let df = df!("id" => [1.0f64, 2.0], "age" => [30.0f64, 40.0]).unwrap();
+        SpssWriter::new(&path)
+            .with_variable_labels([("age".to_string(), "Age in years".to_string())].into_iter().collect())
+            .write_df(&df)
+            .unwrap();
+
+        let query = absolute_file_references("SELECT id, age FROM 'survey.sav' WHERE age > 35", &dir.to_string_lossy())
+            .unwrap();
+        let metadata = query_metadata(&query);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let metadata = metadata.unwrap();
+        assert_eq!(metadata.variables["age"].label.as_deref(), Some("Age in years"));
+        assert_eq!(metadata.variables["age"].stata_type, None);
+    }
+}
@@ -0,0 +1,2122 @@
+//! if() expressions in Stata syntax, parsed and compiled to a Polars `Expr`.
+//!
+//! pq.ado hands the expression to the plugin's "if" subfunction, which
+//! checks the syntax and stores it in the `sql_if` local behind a prefix
+//! (`stata:` or, with stata_missing, `stata_strict:`). Every reader then
+//! compiles it against the schema of what it scans with [`filter_lazy`];
+//! a filter without the prefix is Polars SQL, as the CLI's --sql passes.
+//!
+//! The SQL spellings the filters used to be translated to (AND, OR, NOT,
+//! =, <>, IS [NOT] NULL, BETWEEN, IN, [I]LIKE, 'text', DATE '2020-01-05',
+//! TIMESTAMP '2020-01-05 08:00:00', CAST(x AS type) and date(s, '%d%b%Y'))
+//! are accepted alongside Stata's operators. SQL that stops parsing as
+//! Stata at a SQL-only keyword (CASE WHEN, SIMILAR TO, ...) is passed on to
+//! Polars SQL unchanged, as every if() was before; other parse errors are
+//! reported.
+//!
+//! By default a comparison with a missing value is not true, as in SQL. In
+//! strict mode missing values are ordered as in Stata: a missing number is
+//! greater than every number (and equal to another missing), and a missing
+//! string is "". Comparisons with the literal `.` always follow Stata, as do
+//! `.a`-`.z` against a variable with an extended_missing codes column.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use polars::lazy::dsl::{self, dt};
+use polars::prelude::*;
+use polars_sql::SQLContext;
+
+use crate::utilities::{
+    clock_with_leap_seconds, clock_without_leap_seconds, days_from_civil, StataPeriod, DAY_SHIFT_SAS_STATA,
+    SEC_SHIFT_SAS_STATA,
+};
+
+pub const STATA_IF_PREFIX: &str = "stata:";
+pub const STATA_IF_STRICT_PREFIX: &str = "stata_strict:";
+
+/// A parse or compile error and the character position it points at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IfError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl IfError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        IfError {
+            message: message.into(),
+            position,
+        }
+    }
+
+    /// The message with the expression and a caret under the position.
+    pub fn render(&self, input: &str) -> String {
+        format!("{}\n    {}\n    {}^", self.message, input, " ".repeat(self.position))
+    }
+}
+
+impl fmt::Display for IfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at character {})", self.message, self.position + 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Number(f64),
+    /// `.`, or `.a`-`.z` with the code letter.
+    Missing(Option<char>),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    pos: usize,
+}
+
+const OPERATORS: [&str; 19] = [
+    "==", "!=", "~=", "<>", "<=", ">=", "=", "<", ">", "&", "|", "!", "~", "+", "-", "*", "/", "^", "%",
+];
+
+const DATE_LITERAL_FUNCTIONS: [&str; 8] = ["td", "tc", "tC", "tw", "tm", "tq", "th", "ty"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, IfError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            // td(01jan2020) and friends take their argument unquoted
+            let open = (i..chars.len()).find(|&j| !chars[j].is_whitespace());
+            if DATE_LITERAL_FUNCTIONS.contains(&name.as_str()) && open.is_some_and(|j| chars[j] == '(') {
+                let open = open.unwrap_or(i);
+                let close = (open..chars.len())
+                    .find(|&j| chars[j] == ')')
+                    .ok_or_else(|| IfError::new(format!("unmatched ( in {}()", name), open))?;
+                let text: String = chars[open + 1..close].iter().collect();
+                tokens.push(Token {
+                    tok: Tok::Ident(name),
+                    pos: start,
+                });
+                tokens.push(Token {
+                    tok: Tok::LParen,
+                    pos: open,
+                });
+                tokens.push(Token {
+                    tok: Tok::Str(text.trim().to_string()),
+                    pos: open + 1,
+                });
+                tokens.push(Token {
+                    tok: Tok::RParen,
+                    pos: close,
+                });
+                i = close + 1;
+                continue;
+            }
+            tokens.push(Token {
+                tok: Tok::Ident(name),
+                pos: start,
+            });
+            continue;
+        }
+        let digit_at = |j: usize| chars.get(j).is_some_and(|c| c.is_ascii_digit());
+        if c.is_ascii_digit() || (c == '.' && digit_at(i + 1)) {
+            while digit_at(i) {
+                i += 1;
+            }
+            if chars.get(i) == Some(&'.') {
+                i += 1;
+                while digit_at(i) {
+                    i += 1;
+                }
+            }
+            if matches!(chars.get(i), Some('e') | Some('E'))
+                && (digit_at(i + 1) || (matches!(chars.get(i + 1), Some('+') | Some('-')) && digit_at(i + 2)))
+            {
+                i += 2;
+                while digit_at(i) {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = text
+                .parse::<f64>()
+                .map_err(|_| IfError::new(format!("invalid number {}", text), start))?;
+            tokens.push(Token {
+                tok: Tok::Number(value),
+                pos: start,
+            });
+            continue;
+        }
+        if c == '.' {
+            // .a-.z, unless the letter starts a longer name
+            let code = chars
+                .get(i + 1)
+                .filter(|c| c.is_ascii_lowercase())
+                .filter(|_| !chars.get(i + 2).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_'))
+                .copied();
+            tokens.push(Token {
+                tok: Tok::Missing(code),
+                pos: start,
+            });
+            i += if code.is_some() { 2 } else { 1 };
+            continue;
+        }
+        // "text", 'text' ('' is a quote), “text” and compound `"text"'
+        let quote = match c {
+            '"' => Some(('"', 1, "\"")),
+            '\'' => Some(('\'', 1, "'")),
+            '“' => Some(('”', 1, "”")),
+            '`' if chars.get(i + 1) == Some(&'"') => Some(('"', 2, "\"'")),
+            _ => None,
+        };
+        if let Some((_, skip, close)) = quote {
+            let close: Vec<char> = close.chars().collect();
+            let mut j = i + skip;
+            let mut text = String::new();
+            loop {
+                if j >= chars.len() {
+                    return Err(IfError::new("unmatched quote", start));
+                }
+                if chars[j..].starts_with(&close) {
+                    if c == '\'' && chars.get(j + 1) == Some(&'\'') {
+                        text.push('\'');
+                        j += 2;
+                        continue;
+                    }
+                    j += close.len();
+                    break;
+                }
+                text.push(chars[j]);
+                j += 1;
+            }
+            tokens.push(Token {
+                tok: Tok::Str(text),
+                pos: start,
+            });
+            i = j;
+            continue;
+        }
+        let simple = match c {
+            '(' => Some(Tok::LParen),
+            ')' => Some(Tok::RParen),
+            ',' => Some(Tok::Comma),
+            _ => None,
+        };
+        if let Some(tok) = simple {
+            tokens.push(Token { tok, pos: start });
+            i += 1;
+            continue;
+        }
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            Some(op) => {
+                tokens.push(Token {
+                    tok: Tok::Op(op),
+                    pos: start,
+                });
+                i += op.chars().count();
+            }
+            None => return Err(IfError::new(format!("unexpected character {}", c), start)),
+        }
+    }
+    tokens.push(Token {
+        tok: Tok::End,
+        pos: chars.len(),
+    });
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Column(String),
+    Number(f64),
+    /// `.` (or NULL), or an extended missing value `.a`-`.z`.
+    Missing(Option<char>),
+    Str(String),
+    /// DATE 'yyyy-mm-dd', as days since 01jan1970.
+    Date(i32),
+    /// TIMESTAMP 'yyyy-mm-dd hh:mm:ss', as milliseconds since 01jan1970.
+    Timestamp(i64),
+    Not(Box<Ast>),
+    Negate(Box<Ast>),
+    Binary(BinaryOp, Box<Ast>, Box<Ast>),
+    Call(String, Vec<Ast>),
+    Cast(Box<Ast>, String),
+    IsNull(Box<Ast>, bool),
+    Between(Box<Ast>, Box<Ast>, Box<Ast>, bool),
+    In(Box<Ast>, Vec<Ast>, bool),
+    /// s [NOT] LIKE/ILIKE 'pattern': the pattern, case-insensitive, negated.
+    Like(Box<Ast>, String, bool, bool),
+}
+
+/// A parsed expression; `pos` is where it starts in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ast {
+    node: Node,
+    pos: usize,
+}
+
+impl Ast {
+    fn new(node: Node, pos: usize) -> Self {
+        Ast { node, pos }
+    }
+
+    fn boxed(self) -> Box<Ast> {
+        Box::new(self)
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.at]
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token {
+        &self.tokens[(self.at + offset).min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.at].clone();
+        if self.at < self.tokens.len() - 1 {
+            self.at += 1;
+        }
+        token
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(&self.peek().tok, Tok::Op(o) if *o == op)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.is_keyword_at(0, keyword)
+    }
+
+    fn is_keyword_at(&self, offset: usize, keyword: &str) -> bool {
+        matches!(&self.peek_at(offset).tok, Tok::Ident(s) if s.eq_ignore_ascii_case(keyword))
+    }
+
+    fn unexpected(&self) -> IfError {
+        let token = self.peek();
+        let message = match &token.tok {
+            Tok::End => "unexpected end of expression".to_string(),
+            Tok::Ident(s) => format!("unexpected {}", s),
+            Tok::Number(n) => format!("unexpected number {}", n),
+            Tok::Missing(None) => "unexpected .".to_string(),
+            Tok::Missing(Some(code)) => format!("unexpected .{}", code),
+            Tok::Str(s) => format!("unexpected string \"{}\"", s),
+            Tok::Op(o) => format!("unexpected {}", o),
+            Tok::LParen => "unexpected (".to_string(),
+            Tok::RParen => "unexpected )".to_string(),
+            Tok::Comma => "unexpected ,".to_string(),
+        };
+        IfError::new(message, token.pos)
+    }
+
+    fn expect(&mut self, tok: Tok, what: &str) -> Result<Token, IfError> {
+        if self.peek().tok == tok {
+            Ok(self.next())
+        } else {
+            let found = self.unexpected();
+            Err(IfError::new(format!("expected {}, {}", what, found.message.replace("unexpected ", "found ")), found.position))
+        }
+    }
+
+    fn or(&mut self) -> Result<Ast, IfError> {
+        let mut left = self.and()?;
+        while self.is_op("|") || self.is_keyword("or") {
+            let pos = self.next().pos;
+            let right = self.and()?;
+            left = Ast::new(Node::Binary(BinaryOp::Or, left.boxed(), right.boxed()), pos);
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Ast, IfError> {
+        let mut left = self.not()?;
+        while self.is_op("&") || self.is_keyword("and") {
+            let pos = self.next().pos;
+            let right = self.not()?;
+            left = Ast::new(Node::Binary(BinaryOp::And, left.boxed(), right.boxed()), pos);
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Ast, IfError> {
+        // SQL's NOT, below the comparisons (Stata's ! binds tighter)
+        let starts_operand = !matches!(self.peek_at(1).tok, Tok::Op(_) | Tok::End | Tok::RParen | Tok::Comma);
+        if self.is_keyword("not") && starts_operand {
+            let pos = self.next().pos;
+            let operand = self.not()?;
+            return Ok(Ast::new(Node::Not(operand.boxed()), pos));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Ast, IfError> {
+        let mut left = self.additive()?;
+        loop {
+            let pos = self.peek().pos;
+            let op = match &self.peek().tok {
+                Tok::Op("==") | Tok::Op("=") => Some(BinaryOp::Eq),
+                Tok::Op("!=") | Tok::Op("~=") | Tok::Op("<>") => Some(BinaryOp::Ne),
+                Tok::Op("<") => Some(BinaryOp::Lt),
+                Tok::Op("<=") => Some(BinaryOp::Le),
+                Tok::Op(">") => Some(BinaryOp::Gt),
+                Tok::Op(">=") => Some(BinaryOp::Ge),
+                _ => None,
+            };
+            if let Some(op) = op {
+                self.next();
+                let right = self.additive()?;
+                left = Ast::new(Node::Binary(op, left.boxed(), right.boxed()), pos);
+                continue;
+            }
+            if self.is_keyword("is") {
+                self.next();
+                let negated = self.is_keyword("not");
+                if negated {
+                    self.next();
+                }
+                if !self.is_keyword("null") {
+                    return Err(IfError::new("expected NULL after IS", self.peek().pos));
+                }
+                self.next();
+                left = Ast::new(Node::IsNull(left.boxed(), negated), pos);
+                continue;
+            }
+            let negated = self.is_keyword("not")
+                && ["between", "in", "like", "ilike"].iter().any(|k| self.is_keyword_at(1, k));
+            if negated {
+                self.next();
+            }
+            if self.is_keyword("between") {
+                self.next();
+                let low = self.additive()?;
+                if !self.is_keyword("and") {
+                    return Err(IfError::new("expected AND in BETWEEN", self.peek().pos));
+                }
+                self.next();
+                let high = self.additive()?;
+                left = Ast::new(Node::Between(left.boxed(), low.boxed(), high.boxed(), negated), pos);
+                continue;
+            }
+            if self.is_keyword("in") && self.peek_at(1).tok == Tok::LParen {
+                self.next();
+                let values = self.arguments()?;
+                left = Ast::new(Node::In(left.boxed(), values, negated), pos);
+                continue;
+            }
+            if self.is_keyword("like") || self.is_keyword("ilike") {
+                let case_insensitive = self.is_keyword("ilike");
+                self.next();
+                let Tok::Str(pattern) = self.peek().tok.clone() else {
+                    return Err(IfError::new("expected a quoted pattern after LIKE", self.peek().pos));
+                };
+                self.next();
+                left = Ast::new(Node::Like(left.boxed(), pattern, case_insensitive, negated), pos);
+                continue;
+            }
+            return Ok(left);
+        }
+    }
+
+    fn additive(&mut self) -> Result<Ast, IfError> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match &self.peek().tok {
+                Tok::Op("+") => BinaryOp::Add,
+                Tok::Op("-") => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            let pos = self.next().pos;
+            let right = self.multiplicative()?;
+            left = Ast::new(Node::Binary(op, left.boxed(), right.boxed()), pos);
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Ast, IfError> {
+        let mut left = self.unary()?;
+        loop {
+            let pos = self.peek().pos;
+            match &self.peek().tok {
+                Tok::Op("*") => {
+                    self.next();
+                    let right = self.unary()?;
+                    left = Ast::new(Node::Binary(BinaryOp::Mul, left.boxed(), right.boxed()), pos);
+                }
+                Tok::Op("/") => {
+                    self.next();
+                    let right = self.unary()?;
+                    left = Ast::new(Node::Binary(BinaryOp::Div, left.boxed(), right.boxed()), pos);
+                }
+                // SQL's a % b, as mod(a, b)
+                Tok::Op("%") => {
+                    self.next();
+                    let right = self.unary()?;
+                    left = Ast::new(Node::Call("mod".to_string(), vec![left, right]), pos);
+                }
+                _ => return Ok(left),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Ast, IfError> {
+        let pos = self.peek().pos;
+        if self.is_op("!") || self.is_op("~") {
+            self.next();
+            let operand = self.unary()?;
+            return Ok(Ast::new(Node::Not(operand.boxed()), pos));
+        }
+        if self.is_op("-") {
+            self.next();
+            let operand = self.unary()?;
+            return Ok(Ast::new(Node::Negate(operand.boxed()), pos));
+        }
+        if self.is_op("+") {
+            self.next();
+            return self.unary();
+        }
+        self.power()
+    }
+
+    /// ^ binds tighter than unary minus (-2^2 is -4) and is left
+    /// associative, as in Stata.
+    fn power(&mut self) -> Result<Ast, IfError> {
+        let mut left = self.primary()?;
+        while self.is_op("^") {
+            let pos = self.next().pos;
+            let right = if self.is_op("-") {
+                let neg_pos = self.next().pos;
+                Ast::new(Node::Negate(self.primary()?.boxed()), neg_pos)
+            } else {
+                self.primary()?
+            };
+            left = Ast::new(Node::Binary(BinaryOp::Pow, left.boxed(), right.boxed()), pos);
+        }
+        Ok(left)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Ast>, IfError> {
+        self.expect(Tok::LParen, "(")?;
+        let mut args = Vec::new();
+        if self.peek().tok == Tok::RParen {
+            self.next();
+            return Ok(args);
+        }
+        loop {
+            args.push(self.or()?);
+            match self.peek().tok {
+                Tok::Comma => {
+                    self.next();
+                }
+                Tok::RParen => {
+                    self.next();
+                    return Ok(args);
+                }
+                _ => {
+                    let found = self.unexpected();
+                    return Err(IfError::new(
+                        format!("expected , or ), {}", found.message.replace("unexpected ", "found ")),
+                        found.position,
+                    ));
+                }
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Ast, IfError> {
+        let token = self.peek().clone();
+        match token.tok {
+            Tok::Number(value) => {
+                self.next();
+                Ok(Ast::new(Node::Number(value), token.pos))
+            }
+            Tok::Missing(code) => {
+                self.next();
+                Ok(Ast::new(Node::Missing(code), token.pos))
+            }
+            Tok::Str(text) => {
+                self.next();
+                Ok(Ast::new(Node::Str(text), token.pos))
+            }
+            Tok::LParen => {
+                self.next();
+                let inner = self.or()?;
+                self.expect(Tok::RParen, ")")?;
+                Ok(inner)
+            }
+            Tok::Ident(name) => {
+                self.next();
+                if name.eq_ignore_ascii_case("date") {
+                    if let Tok::Str(text) = &self.peek().tok {
+                        let text = text.clone();
+                        let pos = self.next().pos;
+                        let days = days_from_iso_date(&text)
+                            .ok_or_else(|| IfError::new(format!("invalid date '{}', expected YYYY-MM-DD", text), pos))?;
+                        return Ok(Ast::new(Node::Date(days), token.pos));
+                    }
+                }
+                if name.eq_ignore_ascii_case("timestamp") {
+                    if let Tok::Str(text) = &self.peek().tok {
+                        let text = text.clone();
+                        let pos = self.next().pos;
+                        let ms = masked_value(&text, "YMDhms", true)
+                            .ok_or_else(|| {
+                                IfError::new(format!("invalid timestamp '{}', expected YYYY-MM-DD HH:MM:SS", text), pos)
+                            })?;
+                        let ms = ms as i64 - SEC_SHIFT_SAS_STATA * 1000;
+                        return Ok(Ast::new(Node::Timestamp(ms), token.pos));
+                    }
+                }
+                if name == "NULL" {
+                    return Ok(Ast::new(Node::Missing(None), token.pos));
+                }
+                if self.peek().tok != Tok::LParen {
+                    return Ok(Ast::new(Node::Column(name), token.pos));
+                }
+                if name.eq_ignore_ascii_case("cast") {
+                    self.next();
+                    let value = self.or()?;
+                    if !self.is_keyword("as") {
+                        return Err(IfError::new("expected AS in CAST", self.peek().pos));
+                    }
+                    self.next();
+                    let type_token = self.next();
+                    let Tok::Ident(type_name) = type_token.tok else {
+                        return Err(IfError::new("expected a type name in CAST", type_token.pos));
+                    };
+                    self.expect(Tok::RParen, ")")?;
+                    return Ok(Ast::new(Node::Cast(value.boxed(), type_name.to_ascii_lowercase()), token.pos));
+                }
+                let args = self.arguments()?;
+                // tC() counts leap seconds, so it is not tc()
+                let name = if name == "tC" { name } else { name.to_ascii_lowercase() };
+                Ok(Ast::new(Node::Call(name, args), token.pos))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+/// Days since 01jan1970 for a YYYY-MM-DD date.
+fn days_from_iso_date(text: &str) -> Option<i32> {
+    let mut parts = text.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    days_from_civil(year, month, day).map(|days| days as i32)
+}
+
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// The runs of digits and of letters in a date literal ("01jan2020 8:30"
+/// is 01, jan, 2020, 8, 30).
+fn literal_parts(text: &str) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    let mut previous: Option<bool> = None;
+    for c in text.chars() {
+        if !c.is_ascii_alphanumeric() {
+            previous = None;
+            continue;
+        }
+        let digit = c.is_ascii_digit();
+        match parts.last_mut() {
+            Some(last) if previous == Some(digit) => last.push(c),
+            _ => parts.push(c.to_string()),
+        }
+        previous = Some(digit);
+    }
+    parts
+}
+
+fn month_number(part: &str) -> Option<i64> {
+    if let Ok(month) = part.parse::<i64>() {
+        return Some(month);
+    }
+    let part = part.to_ascii_lowercase();
+    if part.len() < 3 {
+        return None;
+    }
+    MONTH_NAMES
+        .iter()
+        .position(|name| part.starts_with(name))
+        .map(|i| i as i64 + 1)
+}
+
+/// A date in the order of a Stata mask, as date(text, "DMY") or
+/// clock(text, "DMYhms"): days, or milliseconds, since 01jan1960. Time
+/// parts left off the end are 0.
+fn masked_value(text: &str, mask: &str, clock: bool) -> Option<f64> {
+    let parts = literal_parts(text);
+    let mask: Vec<char> = mask.chars().filter(|c| "DMYhms".contains(*c)).collect();
+    let (mut day, mut month, mut year) = (None, None, None);
+    let mut time = [0i64; 3];
+    let mut fraction = 0.0;
+    for (i, part) in parts.iter().enumerate() {
+        match mask.get(i) {
+            Some('D') => day = Some(part.parse().ok()?),
+            Some('M') => month = Some(month_number(part)?),
+            Some('Y') => year = Some(part.parse().ok()?),
+            Some('h') => time[0] = part.parse().ok()?,
+            Some('m') => time[1] = part.parse().ok()?,
+            Some('s') => time[2] = part.parse().ok()?,
+            None if clock && i == mask.len() && mask.last() == Some(&'s') => {
+                fraction = format!("0.{}", part).parse().ok()?;
+            }
+            _ => return None,
+        }
+    }
+    let days = days_from_civil(year?, month?, day?)? + DAY_SHIFT_SAS_STATA as i64;
+    if !clock {
+        return (parts.len() == mask.len()).then_some(days as f64);
+    }
+    if time[0] > 23 || time[1] > 59 || time[2] > 59 {
+        return None;
+    }
+    let seconds = ((days * 24 + time[0]) * 60 + time[1]) * 60 + time[2];
+    Some((seconds as f64 + fraction) * 1000.0)
+}
+
+/// The value of td(), tc(), tC(), tw(), tm(), tq(), th() or ty(): days,
+/// milliseconds (with leap seconds for tC()), weeks, months, quarters or
+/// half-years since 1960, or the year.
+fn date_literal(function: &str, text: &str) -> Option<f64> {
+    let parts = literal_parts(text);
+    let number = |i: usize| parts.get(i).and_then(|p| p.parse::<i64>().ok());
+    match function {
+        "td" => masked_value(text, "DMY", false),
+        "tc" => masked_value(text, "DMYhms", true),
+        "tC" => match masked_value(text, "DMYhms", true) {
+            Some(ms) => Some(clock_with_leap_seconds(ms)),
+            None => {
+                // 23:59:60, on the days a leap second was inserted
+                let ms = masked_value(&text.replacen(":60", ":59", 1), "DMYhms", true)?;
+                let leap = clock_with_leap_seconds(ms) + 1000.0;
+                (clock_without_leap_seconds(leap) == ms).then_some(leap)
+            }
+        },
+        "ty" if parts.len() == 1 => number(0).map(|y| y as f64),
+        "tw" | "tm" | "tq" | "th" if parts.len() == 3 => {
+            let per_year = match function {
+                "tw" => 52,
+                "tm" => 12,
+                "tq" => 4,
+                _ => 2,
+            };
+            let unit = &function[1..];
+            let period = number(2)?;
+            if !parts[1].eq_ignore_ascii_case(unit) || !(1..=per_year).contains(&period) {
+                return None;
+            }
+            Some(((number(0)? - 1960) * per_year + period - 1) as f64)
+        }
+        _ => None,
+    }
+}
+
+/// Parses an if() expression.
+pub fn parse(input: &str) -> Result<Ast, IfError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        at: 0,
+    };
+    if parser.peek().tok == Tok::End {
+        return Err(IfError::new("empty expression", 0));
+    }
+    let ast = parser.or()?;
+    if parser.peek().tok != Tok::End {
+        return Err(parser.unexpected());
+    }
+    Ok(ast)
+}
+
+/// The variables the expression refers to, in order of first use.
+pub fn columns(ast: &Ast) -> Vec<String> {
+    fn walk(ast: &Ast, out: &mut Vec<String>) {
+        match &ast.node {
+            Node::Column(name) => {
+                if !out.contains(name) {
+                    out.push(name.clone());
+                }
+            }
+            Node::Number(_) | Node::Missing(_) | Node::Str(_) | Node::Date(_) | Node::Timestamp(_) => {}
+            Node::Not(a) | Node::Negate(a) | Node::Cast(a, _) | Node::IsNull(a, _) | Node::Like(a, ..) => walk(a, out),
+            Node::Binary(_, a, b) => {
+                walk(a, out);
+                walk(b, out);
+            }
+            Node::Between(a, b, c, _) => {
+                walk(a, out);
+                walk(b, out);
+                walk(c, out);
+            }
+            Node::Call(_, args) => args.iter().for_each(|a| walk(a, out)),
+            Node::In(a, values, _) => {
+                walk(a, out);
+                values.iter().for_each(|v| walk(v, out));
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk(ast, &mut out);
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    String,
+    /// The result of a comparison or logical operator; 1/0 as a number.
+    Logical,
+    Date,
+    Datetime,
+    Other,
+}
+
+impl Kind {
+    fn of(dtype: &DataType) -> Self {
+        match dtype {
+            DataType::String | DataType::Categorical(_, _) | DataType::Enum(_, _) => Kind::String,
+            DataType::Boolean => Kind::Logical,
+            DataType::Date => Kind::Date,
+            DataType::Datetime(_, _) => Kind::Datetime,
+            dtype if dtype.is_primitive_numeric() => Kind::Number,
+            _ => Kind::Other,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Kind::String => "string",
+            Kind::Other => "of an unsupported type",
+            _ => "numeric",
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Kind::Number | Kind::Logical | Kind::Date | Kind::Datetime)
+    }
+}
+
+/// A compiled subexpression. `missing_literal` marks the literal `.` or
+/// `.a`-`.z`, with `missing_code` the letter. `calendar` is what a literal
+/// stands for next to a date or datetime column, when that differs from
+/// its number: tC() counts leap seconds, which datetimes do not, and a
+/// tm() (or other period) is a first day next to a date.
+struct Typed {
+    expr: Expr,
+    kind: Kind,
+    missing_literal: bool,
+    missing_code: Option<char>,
+    calendar: Option<Box<Typed>>,
+}
+
+impl Typed {
+    fn new(expr: Expr, kind: Kind) -> Self {
+        Typed {
+            expr,
+            kind,
+            missing_literal: false,
+            missing_code: None,
+            calendar: None,
+        }
+    }
+}
+
+struct Compiler<'a> {
+    schema: &'a Schema,
+    strict: bool,
+    /// Date columns saved from %tw/%tm/%tq/%th/%ty variables, compared as
+    /// the period counts Stata holds.
+    periods: &'a HashMap<String, StataPeriod>,
+}
+
+fn type_mismatch(pos: usize) -> IfError {
+    IfError::new("type mismatch", pos)
+}
+
+impl Compiler<'_> {
+    fn compile(&self, ast: &Ast) -> Result<Typed, IfError> {
+        match &ast.node {
+            Node::Column(name) => {
+                let dtype = self
+                    .schema
+                    .get(name.as_str())
+                    .ok_or_else(|| IfError::new(format!("variable {} not found", name), ast.pos))?;
+                if let (DataType::Date, Some(period)) = (dtype, self.periods.get(name)) {
+                    return Ok(Typed::new(period_count(*period, col(name.as_str())), Kind::Number));
+                }
+                let kind = Kind::of(dtype);
+                let expr = match dtype {
+                    DataType::Categorical(_, _) | DataType::Enum(_, _) => col(name.as_str()).cast(DataType::String),
+                    _ => col(name.as_str()),
+                };
+                Ok(Typed::new(expr, kind))
+            }
+            Node::Number(value) => Ok(Typed::new(lit(*value), Kind::Number)),
+            Node::Missing(code) => Ok(Typed {
+                missing_literal: true,
+                missing_code: *code,
+                ..Typed::new(lit(NULL).cast(DataType::Float64), Kind::Number)
+            }),
+            Node::Str(text) => Ok(Typed::new(lit(text.clone()), Kind::String)),
+            Node::Date(days) => Ok(Typed::new(lit(*days).cast(DataType::Date), Kind::Date)),
+            Node::Timestamp(ms) => Ok(Typed::new(
+                lit(*ms).cast(DataType::Datetime(TimeUnit::Milliseconds, None)),
+                Kind::Datetime,
+            )),
+            Node::Not(inner) => {
+                let inner = self.logical(inner)?;
+                Ok(Typed::new(inner.not(), Kind::Logical))
+            }
+            Node::Negate(inner) => {
+                let value = self.number(inner)?;
+                Ok(Typed::new(lit(0.0) - value, Kind::Number))
+            }
+            Node::Binary(op, left, right) => self.binary(*op, left, right, ast.pos),
+            Node::IsNull(inner, negated) => {
+                let missing = self.missing(inner)?;
+                Ok(Typed::new(if *negated { missing.not() } else { missing }, Kind::Logical))
+            }
+            Node::Between(value, low, high, negated) => {
+                let within = self
+                    .comparison(BinaryOp::Ge, value, low, ast.pos)?
+                    .and(self.comparison(BinaryOp::Le, value, high, ast.pos)?);
+                Ok(Typed::new(if *negated { within.not() } else { within }, Kind::Logical))
+            }
+            Node::In(value, values, negated) => {
+                let any = self.any_equal(value, values, ast.pos)?;
+                Ok(Typed::new(if *negated { any.not() } else { any }, Kind::Logical))
+            }
+            Node::Like(value, pattern, case_insensitive, negated) => {
+                let matched = self
+                    .string(value)?
+                    .str()
+                    .contains(lit(like_regex(pattern, *case_insensitive)), true);
+                Ok(Typed::new(if *negated { matched.not() } else { matched }, Kind::Logical))
+            }
+            Node::Cast(value, type_name) => self.cast(value, type_name, ast.pos),
+            Node::Call(name, args) => self.call(name, args, ast.pos),
+        }
+    }
+
+    /// The expression as a number: dates are days, and datetimes
+    /// milliseconds, since 01jan1960, as Stata holds them.
+    fn number(&self, ast: &Ast) -> Result<Expr, IfError> {
+        let typed = self.compile(ast)?;
+        self.as_number(typed, ast.pos)
+    }
+
+    fn as_number(&self, typed: Typed, pos: usize) -> Result<Expr, IfError> {
+        match typed.kind {
+            Kind::Number => Ok(typed.expr),
+            Kind::Logical => Ok(typed.expr.cast(DataType::Float64)),
+            Kind::Date => Ok(typed.expr.cast(DataType::Int32).cast(DataType::Float64) + lit(DAY_SHIFT_SAS_STATA as f64)),
+            Kind::Datetime => Ok(typed
+                .expr
+                .cast(DataType::Datetime(TimeUnit::Milliseconds, None))
+                .cast(DataType::Int64)
+                .cast(DataType::Float64)
+                + lit((SEC_SHIFT_SAS_STATA * 1000) as f64)),
+            Kind::String | Kind::Other => Err(type_mismatch(pos)),
+        }
+    }
+
+    fn string(&self, ast: &Ast) -> Result<Expr, IfError> {
+        let typed = self.compile(ast)?;
+        match typed.kind {
+            Kind::String => Ok(typed.expr),
+            _ => Err(type_mismatch(ast.pos)),
+        }
+    }
+
+    /// The expression as true/false: a number is true when it is not 0,
+    /// and a missing number is true in strict mode, as in Stata.
+    fn logical(&self, ast: &Ast) -> Result<Expr, IfError> {
+        let typed = self.compile(ast)?;
+        match typed.kind {
+            Kind::Logical => Ok(typed.expr),
+            Kind::Number => {
+                let truth = typed.expr.neq(lit(0));
+                Ok(if self.strict { truth.fill_null(lit(true)) } else { truth })
+            }
+            _ => Err(type_mismatch(ast.pos)),
+        }
+    }
+
+    fn missing(&self, ast: &Ast) -> Result<Expr, IfError> {
+        let typed = self.compile(ast)?;
+        Ok(match typed.kind {
+            Kind::String if self.strict => typed.expr.clone().is_null().or(typed.expr.eq(lit(""))),
+            _ => typed.expr.is_null(),
+        })
+    }
+
+    fn binary(&self, op: BinaryOp, left: &Ast, right: &Ast, pos: usize) -> Result<Typed, IfError> {
+        match op {
+            BinaryOp::Or => Ok(Typed::new(self.logical(left)?.or(self.logical(right)?), Kind::Logical)),
+            BinaryOp::And => Ok(Typed::new(self.logical(left)?.and(self.logical(right)?), Kind::Logical)),
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+                Ok(Typed::new(self.comparison(op, left, right, pos)?, Kind::Logical))
+            }
+            BinaryOp::Add => {
+                let (l, r) = (self.compile(left)?, self.compile(right)?);
+                if l.kind == Kind::String && r.kind == Kind::String {
+                    return Ok(Typed::new(concat_str([l.expr, r.expr], "", false), Kind::String));
+                }
+                Ok(Typed::new(self.as_number(l, left.pos)? + self.as_number(r, right.pos)?, Kind::Number))
+            }
+            BinaryOp::Sub => Ok(Typed::new(self.number(left)? - self.number(right)?, Kind::Number)),
+            BinaryOp::Mul => Ok(Typed::new(self.number(left)? * self.number(right)?, Kind::Number)),
+            BinaryOp::Div => {
+                // x/0 is missing, as in Stata
+                let denominator = self.number(right)?;
+                let quotient = when(denominator.clone().eq(lit(0.0)))
+                    .then(lit(NULL).cast(DataType::Float64))
+                    .otherwise(self.number(left)? / denominator);
+                Ok(Typed::new(quotient, Kind::Number))
+            }
+            BinaryOp::Pow => Ok(Typed::new(self.number(left)?.pow(self.number(right)?), Kind::Number)),
+        }
+    }
+
+    fn comparison(&self, op: BinaryOp, left: &Ast, right: &Ast, pos: usize) -> Result<Expr, IfError> {
+        let (mut l, mut r) = (self.compile(left)?, self.compile(right)?);
+
+        // x < . and friends: the literal missing value sorts above every
+        // number, and . < .a < ... < .z
+        if l.missing_literal || r.missing_literal {
+            let (value, value_ast, literal, op) =
+                if r.missing_literal { (l, left, r, op) } else { (r, right, l, mirrored(op)) };
+            if value.kind == Kind::String {
+                return Err(type_mismatch(pos));
+            }
+            let code = |code: Option<char>| lit(code.map(String::from).unwrap_or_default());
+            // Without a <name>_stata_missing column from extended_missing,
+            // .a-.z are stored as null, so every missing value matches them
+            let among_missing = match (value.missing_literal, self.extended_missing_column(value_ast)) {
+                (true, _) => compare(op, code(value.missing_code), code(literal.missing_code)),
+                (false, Some(codes)) => compare(op, col(codes).fill_null(lit("")), code(literal.missing_code)),
+                (false, None) => lit(matches!(op, BinaryOp::Eq | BinaryOp::Ge | BinaryOp::Le)),
+            };
+            let number = lit(matches!(op, BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le));
+            return Ok(when(value.expr.is_null()).then(among_missing).otherwise(number));
+        }
+
+        // A date literal next to a date or datetime column
+        if matches!(l.kind, Kind::Date | Kind::Datetime) {
+            if let Some(calendar) = r.calendar.take() {
+                r = *calendar;
+            }
+        }
+        if matches!(r.kind, Kind::Date | Kind::Datetime) {
+            if let Some(calendar) = l.calendar.take() {
+                l = *calendar;
+            }
+        }
+
+        let (l, r) = match (l.kind, r.kind) {
+            (Kind::String, Kind::String) if self.strict => {
+                return Ok(compare(op, l.expr.fill_null(lit("")), r.expr.fill_null(lit(""))));
+            }
+            (Kind::String, Kind::String) => (l.expr, r.expr),
+            (Kind::Date, Kind::Date) => (l.expr, r.expr),
+            (a, b) if a.is_numeric() && b.is_numeric() => (self.as_number(l, left.pos)?, self.as_number(r, right.pos)?),
+            (a, b) => {
+                let message = format!(
+                    "type mismatch: {} is {} and {} is {}",
+                    describe_ast(left),
+                    a.describe(),
+                    describe_ast(right),
+                    b.describe()
+                );
+                return Err(IfError::new(message, pos));
+            }
+        };
+        if !self.strict {
+            return Ok(compare(op, l, r));
+        }
+
+        // Missing numbers are greater than every number and equal to each other
+        let (l_missing, r_missing) = (l.clone().is_null(), r.clone().is_null());
+        let known = compare(if op == BinaryOp::Ne { BinaryOp::Eq } else { op }, l, r).fill_null(lit(false));
+        Ok(match op {
+            BinaryOp::Eq => l_missing.and(r_missing).or(known),
+            BinaryOp::Ne => l_missing.and(r_missing).or(known).not(),
+            BinaryOp::Gt => l_missing.and(r_missing.not()).or(known),
+            BinaryOp::Ge => l_missing.or(known),
+            BinaryOp::Lt => l_missing.not().and(r_missing).or(known),
+            _ => r_missing.or(known),
+        })
+    }
+
+    /// The column of .a-.z codes `pq save, extended_missing` wrote for a
+    /// variable, if the expression is such a variable.
+    fn extended_missing_column(&self, ast: &Ast) -> Option<String> {
+        let Node::Column(name) = &ast.node else {
+            return None;
+        };
+        let codes = format!("{}_stata_missing", name);
+        matches!(self.schema.get(codes.as_str()), Some(DataType::String)).then_some(codes)
+    }
+
+    fn any_equal(&self, value: &Ast, values: &[Ast], pos: usize) -> Result<Expr, IfError> {
+        let mut any: Option<Expr> = None;
+        for candidate in values {
+            let equal = self.comparison(BinaryOp::Eq, value, candidate, pos)?;
+            any = Some(match any {
+                Some(e) => e.or(equal),
+                None => equal,
+            });
+        }
+        Ok(any.unwrap_or(lit(false)))
+    }
+
+    fn cast(&self, value: &Ast, type_name: &str, pos: usize) -> Result<Typed, IfError> {
+        let typed = self.compile(value)?;
+        let (dtype, kind) = match type_name {
+            "int" | "integer" | "bigint" | "smallint" | "tinyint" => (DataType::Int64, Kind::Number),
+            "real" | "double" | "float" | "numeric" | "decimal" => (DataType::Float64, Kind::Number),
+            "varchar" | "text" | "string" | "char" => (DataType::String, Kind::String),
+            "date" => (DataType::Date, Kind::Date),
+            "timestamp" | "datetime" => (DataType::Datetime(TimeUnit::Milliseconds, None), Kind::Datetime),
+            _ => return Err(IfError::new(format!("unsupported type {} in CAST", type_name), pos)),
+        };
+        Ok(Typed::new(typed.expr.cast(dtype), kind))
+    }
+
+    fn expect_args(&self, name: &str, args: &[Ast], range: std::ops::RangeInclusive<usize>, pos: usize) -> Result<(), IfError> {
+        if range.contains(&args.len()) {
+            Ok(())
+        } else {
+            Err(IfError::new(format!("wrong number of arguments to {}()", name), pos))
+        }
+    }
+
+    fn call(&self, name: &str, args: &[Ast], pos: usize) -> Result<Typed, IfError> {
+        let number = |expr: Expr| Ok(Typed::new(expr, Kind::Number));
+        match name {
+            "missing" => {
+                self.expect_args(name, args, 1..=usize::MAX, pos)?;
+                let mut any = self.missing(&args[0])?;
+                for arg in &args[1..] {
+                    any = any.or(self.missing(arg)?);
+                }
+                Ok(Typed::new(any, Kind::Logical))
+            }
+            "inrange" => {
+                self.expect_args(name, args, 3..=3, pos)?;
+                let within = self
+                    .comparison(BinaryOp::Ge, &args[0], &args[1], pos)?
+                    .and(self.comparison(BinaryOp::Le, &args[0], &args[2], pos)?);
+                Ok(Typed::new(within, Kind::Logical))
+            }
+            "inlist" => {
+                self.expect_args(name, args, 2..=usize::MAX, pos)?;
+                Ok(Typed::new(self.any_equal(&args[0], &args[1..], pos)?, Kind::Logical))
+            }
+            "mod" => {
+                self.expect_args(name, args, 2..=2, pos)?;
+                let (x, y) = (self.number(&args[0])?, self.number(&args[1])?);
+                number(x.clone() - y.clone() * (x / y).floor())
+            }
+            "ceil" | "ceiling" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                number(self.number(&args[0])?.ceil())
+            }
+            "floor" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                number(self.number(&args[0])?.floor())
+            }
+            "round" => {
+                // round(x, y) is y * floor(x/y + 1/2); y defaults to 1
+                self.expect_args(name, args, 1..=2, pos)?;
+                let x = self.number(&args[0])?;
+                let unit = match args.get(1) {
+                    Some(arg) => self.number(arg)?,
+                    None => lit(1.0),
+                };
+                number((x / unit.clone() + lit(0.5)).floor() * unit)
+            }
+            "real" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                number(self.string(&args[0])?.cast(DataType::Float64))
+            }
+            "string" | "strofreal" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                let value = self.number(&args[0])?.cast(DataType::Float64);
+                let formatted = value.map(
+                    |c| {
+                        // string(.) is "."
+                        let values = c.f64()?;
+                        let text: StringChunked = values
+                            .iter()
+                            .map(|v| Some(v.map_or_else(|| ".".to_string(), format_9_0g)))
+                            .collect();
+                        Ok(text.with_name(values.name().clone()).into_column())
+                    },
+                    |_, field| Ok(Field::new(field.name().clone(), DataType::String)),
+                );
+                Ok(Typed::new(formatted, Kind::String))
+            }
+            "abs" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                number(self.number(&args[0])?.abs())
+            }
+            "exp" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                number(self.number(&args[0])?.exp())
+            }
+            "ln" | "log" | "log10" => {
+                // Missing outside the domain, rather than NaN or -inf
+                self.expect_args(name, args, 1..=1, pos)?;
+                let x = self.number(&args[0])?;
+                let base = if name == "log10" { 10.0 } else { std::f64::consts::E };
+                number(when(x.clone().gt(lit(0.0))).then(x.log(lit(base))).otherwise(missing_number()))
+            }
+            "sqrt" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                let x = self.number(&args[0])?;
+                number(when(x.clone().gt_eq(lit(0.0))).then(x.sqrt()).otherwise(missing_number()))
+            }
+            "int" | "trunc" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                let x = self.number(&args[0])?;
+                number(when(x.clone().lt(lit(0.0))).then(x.clone().ceil()).otherwise(x.floor()))
+            }
+            "sign" => {
+                self.expect_args(name, args, 1..=1, pos)?;
+                let x = self.number(&args[0])?;
+                number(x.clone().gt(lit(0.0)).cast(DataType::Float64) - x.lt(lit(0.0)).cast(DataType::Float64))
+            }
+            "min" | "max" => {
+                // Missing values are ignored unless every argument is missing
+                self.expect_args(name, args, 1..=usize::MAX, pos)?;
+                let values = args.iter().map(|a| self.number(a)).collect::<Result<Vec<_>, _>>()?;
+                let combined = if name == "min" { dsl::min_horizontal(values) } else { dsl::max_horizontal(values) };
+                number(combined.map_err(|e| IfError::new(e.to_string(), pos))?)
+            }
+            "cond" => {
+                self.expect_args(name, args, 3..=4, pos)?;
+                self.cond(args, pos)
+            }
+            _ => self.date_function(name, args, pos).or_else(|e| match e {
+                None => self.string_function(name, args, pos).map_err(|e| e.unwrap_or_else(|| unknown_function(name, pos))),
+                Some(e) => Err(e),
+            }),
+        }
+    }
+
+    /// cond(c, a, b[, m]): a where c is true (a nonzero number, or a
+    /// missing one unless m is given), m where c is missing, else b.
+    fn cond(&self, args: &[Ast], pos: usize) -> Result<Typed, IfError> {
+        let (a, b) = (self.compile(&args[1])?, self.compile(&args[2])?);
+        let kind = match (a.kind, b.kind) {
+            (Kind::String, Kind::String) => Kind::String,
+            (x, y) if x.is_numeric() && y.is_numeric() => Kind::Number,
+            _ => return Err(type_mismatch(pos)),
+        };
+        let branch = |typed: Typed, ast: &Ast| match kind {
+            Kind::String => Ok(typed.expr),
+            _ => self.as_number(typed, ast.pos),
+        };
+        let (a, b) = (branch(a, &args[1])?, branch(b, &args[2])?);
+        let condition = self.compile(&args[0])?;
+        let expr = match condition.kind {
+            Kind::Logical => when(condition.expr).then(a).otherwise(b),
+            Kind::Number => {
+                let if_missing = match args.get(3) {
+                    Some(arg) => branch(self.compile(arg)?, arg)?,
+                    None => a.clone(),
+                };
+                when(condition.expr.clone().is_null())
+                    .then(if_missing)
+                    .when(condition.expr.neq(lit(0.0)))
+                    .then(a)
+                    .otherwise(b)
+            }
+            _ => return Err(type_mismatch(args[0].pos)),
+        };
+        Ok(Typed::new(expr, kind))
+    }
+
+    /// A Stata date (days since 01jan1960) as a Polars Date; date
+    /// variables are used as they are.
+    fn date(&self, ast: &Ast) -> Result<Expr, IfError> {
+        let typed = self.compile(ast)?;
+        match typed.kind {
+            Kind::Date => Ok(typed.expr),
+            _ => Ok(to_date(self.as_number(typed, ast.pos)?)),
+        }
+    }
+
+    /// The date functions; Err(None) if `name` is not one of them.
+    fn date_function(&self, name: &str, args: &[Ast], pos: usize) -> Result<Typed, Option<IfError>> {
+        let number = |expr: Expr| Ok(Typed::new(expr, Kind::Number));
+        let arity = |range: std::ops::RangeInclusive<usize>| self.expect_args(name, args, range, pos).map_err(Some);
+        let part = |f: fn(dt::DateLikeNameSpace) -> Expr| -> Result<Expr, Option<IfError>> {
+            Ok(f(self.date(&args[0])?.dt()).cast(DataType::Float64))
+        };
+        match name {
+            "td" | "tc" | "tC" | "tw" | "tm" | "tq" | "th" | "ty" => {
+                let text = match args {
+                    [Ast { node: Node::Str(text), .. }] => text,
+                    _ => return Err(Some(IfError::new(format!("{}() expects a date, as in td(01jan2020)", name), pos))),
+                };
+                match date_literal(name, text) {
+                    // Datetimes have no leap seconds: next to one, tC() is cofC(tC())
+                    Some(value) if name == "tC" => Ok(Typed {
+                        calendar: Some(Box::new(Typed::new(lit(clock_without_leap_seconds(value)), Kind::Number))),
+                        ..Typed::new(lit(value), Kind::Number)
+                    }),
+                    // A Date column saved from a period variable holds the
+                    // first day of each period: next to one, so does tm() & co.
+                    Some(value) => {
+                        let first_day = StataPeriod::of_format(&format!("%{}", name))
+                            .and_then(|period| period.first_day(value as i64));
+                        Ok(Typed {
+                            calendar: first_day
+                                .map(|days| Box::new(Typed::new(lit(days as i32).cast(DataType::Date), Kind::Date))),
+                            ..Typed::new(lit(value), Kind::Number)
+                        })
+                    }
+                    None => Err(Some(IfError::new(format!("invalid date {}({})", name, text), pos))),
+                }
+            }
+            "date" | "clock" => {
+                // A Stata mask ("DMY", "YMDhms") for a quoted date, or a
+                // strftime format ("%d%b%Y") for any string, as in SQL
+                arity(2..=2)?;
+                let Node::Str(mask) = &args[1].node else {
+                    return Err(Some(IfError::new(format!("{}() expects a quoted mask", name), args[1].pos)));
+                };
+                if mask.contains('%') {
+                    let options = StrptimeOptions {
+                        format: Some(mask.as_str().into()),
+                        strict: false,
+                        ..Default::default()
+                    };
+                    let text = self.string(&args[0])?;
+                    return Ok(if name == "date" {
+                        Typed::new(text.str().to_date(options), Kind::Date)
+                    } else {
+                        let parsed = text.str().to_datetime(Some(TimeUnit::Milliseconds), None, options, lit("raise"));
+                        Typed::new(parsed, Kind::Datetime)
+                    });
+                }
+                let Node::Str(text) = &args[0].node else {
+                    let message = format!("{}() with a Stata mask expects a quoted date; use a % format for variables", name);
+                    return Err(Some(IfError::new(message, args[0].pos)));
+                };
+                match masked_value(text, mask, name == "clock") {
+                    Some(value) => number(lit(value)),
+                    None => Err(Some(IfError::new(format!("invalid date \"{}\" for mask \"{}\"", text, mask), args[0].pos))),
+                }
+            }
+            "mdy" => {
+                arity(3..=3)?;
+                let (m, d, y) = (self.number(&args[0])?, self.number(&args[1])?, self.number(&args[2])?);
+                let days = civil_days(y.clone(), m.clone(), d.clone());
+                // Missing unless the day exists: 31feb2020 does not round-trip
+                let valid = y
+                    .clone()
+                    .eq(y.floor())
+                    .and(m.clone().eq(to_date(days.clone()).dt().month().cast(DataType::Float64)))
+                    .and(d.clone().eq(to_date(days.clone()).dt().day().cast(DataType::Float64)))
+                    .and(m.clone().eq(m.floor()))
+                    .and(d.clone().eq(d.floor()));
+                number(when(valid).then(days).otherwise(missing_number()))
+            }
+            "mdyhms" => {
+                arity(6..=6)?;
+                let days = self.date_function("mdy", &args[..3], pos)?.expr;
+                let (h, mi, s) = (self.number(&args[3])?, self.number(&args[4])?, self.number(&args[5])?);
+                number(days * lit(MS_PER_DAY) + ((h * lit(60.0) + mi) * lit(60.0) + s) * lit(1000.0))
+            }
+            "year" | "yofd" => {
+                arity(1..=1)?;
+                number(part(|d| d.year())?)
+            }
+            "month" => {
+                arity(1..=1)?;
+                number(part(|d| d.month())?)
+            }
+            "day" => {
+                arity(1..=1)?;
+                number(part(|d| d.day())?)
+            }
+            "quarter" => {
+                arity(1..=1)?;
+                number(part(|d| d.quarter())?)
+            }
+            "halfyear" => {
+                arity(1..=1)?;
+                number(((part(|d| d.month())? - lit(1.0)) / lit(6.0)).floor() + lit(1.0))
+            }
+            "doy" => {
+                arity(1..=1)?;
+                number(part(|d| d.ordinal_day())?)
+            }
+            "dow" => {
+                // Polars counts Monday as 1 and Sunday as 7; Stata Sunday as 0
+                arity(1..=1)?;
+                let weekday = part(|d| d.weekday())?;
+                number(weekday.clone() - (weekday / lit(7.0)).floor() * lit(7.0))
+            }
+            "week" => {
+                // Week 52 runs to the end of the year
+                arity(1..=1)?;
+                let week = ((part(|d| d.ordinal_day())? - lit(1.0)) / lit(7.0)).floor() + lit(1.0);
+                number(when(week.clone().gt(lit(52.0))).then(lit(52.0)).otherwise(week))
+            }
+            "mofd" | "qofd" | "hofd" | "wofd" => {
+                arity(1..=1)?;
+                let period = match name {
+                    "mofd" => StataPeriod::Month,
+                    "qofd" => StataPeriod::Quarter,
+                    "hofd" => StataPeriod::HalfYear,
+                    _ => StataPeriod::Week,
+                };
+                number(period_count(period, self.date(&args[0])?))
+            }
+            "dofm" | "dofq" | "dofh" | "dofy" | "dofw" => {
+                arity(1..=1)?;
+                let period = self.number(&args[0])?.floor();
+                let per_year = match name {
+                    "dofm" => 12.0,
+                    "dofq" => 4.0,
+                    "dofh" => 2.0,
+                    "dofw" => 52.0,
+                    _ => 1.0,
+                };
+                if name == "dofy" {
+                    return number(civil_days(period, lit(1.0), lit(1.0)));
+                }
+                let year = (period.clone() / lit(per_year)).floor() + lit(1960.0);
+                let within = period.clone() - (period / lit(per_year)).floor() * lit(per_year);
+                if name == "dofw" {
+                    return number(civil_days(year, lit(1.0), lit(1.0)) + within * lit(7.0));
+                }
+                let month = within * lit(12.0 / per_year) + lit(1.0);
+                number(civil_days(year, month, lit(1.0)))
+            }
+            "dofc" => {
+                arity(1..=1)?;
+                number((self.number(&args[0])? / lit(MS_PER_DAY)).floor())
+            }
+            "cofd" => {
+                arity(1..=1)?;
+                number(self.number(&args[0])? * lit(MS_PER_DAY))
+            }
+            "hh" | "mm" | "ss" => {
+                arity(1..=1)?;
+                let ms = self.number(&args[0])?;
+                let (unit, span) = match name {
+                    "hh" => (3_600_000.0, MS_PER_DAY),
+                    "mm" => (60_000.0, 3_600_000.0),
+                    _ => (1_000.0, 60_000.0),
+                };
+                let within = ms.clone() - (ms / lit(span)).floor() * lit(span);
+                let value = within / lit(unit);
+                number(if name == "ss" { value } else { value.floor() })
+            }
+            _ => Err(None),
+        }
+    }
+
+    /// A string argument; Stata strings are never missing, only "".
+    fn text(&self, ast: &Ast) -> Result<Expr, IfError> {
+        Ok(self.string(ast)?.fill_null(lit("")))
+    }
+
+    /// The string functions; Err(None) if `name` is not one of them.
+    fn string_function(&self, name: &str, args: &[Ast], pos: usize) -> Result<Typed, Option<IfError>> {
+        let arity = |range: std::ops::RangeInclusive<usize>| self.expect_args(name, args, range, pos).map_err(Some);
+        let string = |expr: Expr| Ok(Typed::new(expr, Kind::String));
+        match name {
+            "substr" | "usubstr" => {
+                // 1-based; a negative start counts from the end and a missing
+                // length runs to the end
+                arity(3..=3)?;
+                let (s, start, length) = (self.text(&args[0])?, self.number(&args[1])?, self.number(&args[2])?);
+                let offset = when(start.clone().gt(lit(0.0))).then(start.clone() - lit(1.0)).otherwise(start.clone());
+                let length = when(length.clone().lt(lit(0.0))).then(lit(0.0)).otherwise(length);
+                let sliced = s.str().slice(offset.cast(DataType::Int64), length.cast(DataType::UInt64));
+                let empty = start.clone().eq(lit(0.0)).or(start.is_null());
+                string(when(empty).then(lit("")).otherwise(sliced))
+            }
+            "strpos" | "ustrpos" => {
+                arity(2..=2)?;
+                let found = self.text(&args[0])?.str().find_literal(self.text(&args[1])?);
+                Ok(Typed::new(found.cast(DataType::Float64).fill_null(lit(-1.0)) + lit(1.0), Kind::Number))
+            }
+            "strlen" | "length" => {
+                arity(1..=1)?;
+                Ok(Typed::new(self.text(&args[0])?.str().len_bytes().cast(DataType::Float64), Kind::Number))
+            }
+            "ustrlen" => {
+                arity(1..=1)?;
+                Ok(Typed::new(self.text(&args[0])?.str().len_chars().cast(DataType::Float64), Kind::Number))
+            }
+            "lower" | "strlower" | "ustrlower" => {
+                arity(1..=1)?;
+                string(self.text(&args[0])?.str().to_lowercase())
+            }
+            "upper" | "strupper" | "ustrupper" => {
+                arity(1..=1)?;
+                string(self.text(&args[0])?.str().to_uppercase())
+            }
+            "trim" | "strtrim" | "ustrtrim" => {
+                arity(1..=1)?;
+                string(self.text(&args[0])?.str().strip_chars(lit(" ")))
+            }
+            "ltrim" | "strltrim" | "ustrltrim" => {
+                arity(1..=1)?;
+                string(self.text(&args[0])?.str().strip_chars_start(lit(" ")))
+            }
+            "rtrim" | "strrtrim" | "ustrrtrim" => {
+                arity(1..=1)?;
+                string(self.text(&args[0])?.str().strip_chars_end(lit(" ")))
+            }
+            "itrim" | "stritrim" => {
+                arity(1..=1)?;
+                string(self.text(&args[0])?.str().replace_all(lit("  +"), lit(" "), false))
+            }
+            "regexm" | "ustrregexm" => {
+                arity(2..=2)?;
+                let matched = self.text(&args[0])?.str().contains(self.text(&args[1])?, true);
+                Ok(Typed::new(matched, Kind::Logical))
+            }
+            "strmatch" => {
+                arity(2..=2)?;
+                let Node::Str(pattern) = &args[1].node else {
+                    return Err(Some(IfError::new("strmatch() expects a quoted pattern", args[1].pos)));
+                };
+                let matched = self.text(&args[0])?.str().contains(lit(glob_regex(pattern)), true);
+                Ok(Typed::new(matched, Kind::Logical))
+            }
+            "subinstr" | "usubinstr" => {
+                // subinstr(s, from, to, n): the first n occurrences, or all with .
+                arity(4..=4)?;
+                let (s, from, to) = (self.text(&args[0])?, self.text(&args[1])?, self.text(&args[2])?);
+                let replaced = match &args[3].node {
+                    Node::Missing(None) => s.str().replace_all(from, to, true),
+                    Node::Number(n) if *n >= 1.0 && n.fract() == 0.0 => s.str().replace_n(from, to, true, *n as i64),
+                    _ => return Err(Some(IfError::new("subinstr() expects a count or . as its fourth argument", args[3].pos))),
+                };
+                string(replaced)
+            }
+            _ => Err(None),
+        }
+    }
+}
+
+const MS_PER_DAY: f64 = 86_400_000.0;
+
+fn unknown_function(name: &str, pos: usize) -> IfError {
+    IfError::new(format!("unknown function {}()", name), pos)
+}
+
+fn missing_number() -> Expr {
+    lit(NULL).cast(DataType::Float64)
+}
+
+/// A Stata date (days since 01jan1960) as a Polars Date.
+fn to_date(days: Expr) -> Expr {
+    (days.floor() - lit(DAY_SHIFT_SAS_STATA as f64))
+        .cast(DataType::Int32)
+        .cast(DataType::Date)
+}
+
+/// Days since 01jan1960 of a year, month and day, counting past the end
+/// of the month (mdy() checks the day exists).
+fn civil_days(year: Expr, month: Expr, day: Expr) -> Expr {
+    let before_march = month.clone().lt_eq(lit(2.0));
+    let y = year - when(before_march.clone()).then(lit(1.0)).otherwise(lit(0.0));
+    let era = (y.clone() / lit(400.0)).floor();
+    let yoe = y - era.clone() * lit(400.0);
+    let month_from_march = when(before_march).then(month.clone() + lit(9.0)).otherwise(month - lit(3.0));
+    let doy = ((lit(153.0) * month_from_march + lit(2.0)) / lit(5.0)).floor() + day - lit(1.0);
+    let doe = yoe.clone() * lit(365.0) + (yoe.clone() / lit(4.0)).floor() - (yoe / lit(100.0)).floor() + doy;
+    era * lit(146097.0) + doe - lit(719468.0) + lit(DAY_SHIFT_SAS_STATA as f64)
+}
+
+/// A strmatch() pattern (* and ? wildcards) as an anchored regex.
+fn glob_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?s)^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The period a date falls in, as a %tw/%tm/%tq/%th/%ty value, as
+/// wofd()/mofd()/qofd()/hofd()/yofd() give it.
+fn period_count(period: StataPeriod, date: Expr) -> Expr {
+    let part = |f: fn(dt::DateLikeNameSpace) -> Expr| f(date.clone().dt()).cast(DataType::Float64);
+    let (per_year, within) = match period {
+        StataPeriod::Year => return part(|d| d.year()),
+        StataPeriod::Month => (12.0, part(|d| d.month()) - lit(1.0)),
+        StataPeriod::Quarter => (4.0, part(|d| d.quarter()) - lit(1.0)),
+        StataPeriod::HalfYear => (2.0, ((part(|d| d.month()) - lit(1.0)) / lit(6.0)).floor()),
+        StataPeriod::Week => {
+            // Week 52 runs to the end of the year
+            let week = ((part(|d| d.ordinal_day()) - lit(1.0)) / lit(7.0)).floor();
+            (52.0, when(week.clone().gt(lit(51.0))).then(lit(51.0)).otherwise(week))
+        }
+    };
+    (part(|d| d.year()) - lit(1960.0)) * lit(per_year) + within
+}
+
+/// A number as Stata's %9.0g shows it, as string() does: at most nine
+/// characters, fixed while they hold the number and exponential beyond.
+fn format_9_0g(x: f64) -> String {
+    const WIDTH: usize = 9;
+    if x == 0.0 {
+        return "0".to_string();
+    }
+    let sign = usize::from(x < 0.0);
+    let exponent = x.abs().log10().floor() as i32;
+    if (-4..WIDTH as i32).contains(&exponent) {
+        // .5 has no 0 before the point
+        let integer_digits = if exponent < 0 { 0 } else { exponent as usize + 1 };
+        let decimals = WIDTH.saturating_sub(sign + integer_digits + 1);
+        let mut text = format!("{:.*}", decimals, x);
+        if text.contains('.') {
+            text = text.trim_end_matches('0').trim_end_matches('.').to_string();
+        }
+        // .5 and -.5, as Stata writes them
+        text = text.replacen("0.", ".", usize::from(text.trim_start_matches('-').starts_with("0.")));
+        if text.len() <= WIDTH {
+            return text;
+        }
+    }
+    // d.ddde+XX
+    let decimals = WIDTH.saturating_sub(sign + 2 + 4).max(1);
+    let text = format!("{:.*e}", decimals, x);
+    let (mantissa, power) = text.split_once('e').unwrap_or((&text, "0"));
+    let power: i32 = power.parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if power < 0 { '-' } else { '+' }, power.abs())
+}
+
+/// SQL LIKE pattern (% any run, _ one character) as an anchored regex.
+fn like_regex(pattern: &str, case_insensitive: bool) -> String {
+    let mut regex = String::from(if case_insensitive { "(?si)^" } else { "(?s)^" });
+    for c in pattern.chars() {
+        match c {
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn mirrored(op: BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::Lt => BinaryOp::Gt,
+        BinaryOp::Le => BinaryOp::Ge,
+        BinaryOp::Gt => BinaryOp::Lt,
+        BinaryOp::Ge => BinaryOp::Le,
+        other => other,
+    }
+}
+
+fn compare(op: BinaryOp, l: Expr, r: Expr) -> Expr {
+    match op {
+        BinaryOp::Eq => l.eq(r),
+        BinaryOp::Ne => l.neq(r),
+        BinaryOp::Lt => l.lt(r),
+        BinaryOp::Le => l.lt_eq(r),
+        BinaryOp::Gt => l.gt(r),
+        _ => l.gt_eq(r),
+    }
+}
+
+fn describe_ast(ast: &Ast) -> String {
+    match &ast.node {
+        Node::Column(name) => name.clone(),
+        Node::Str(text) => format!("\"{}\"", text),
+        Node::Number(value) => value.to_string(),
+        Node::Call(name, _) => format!("{}()", name),
+        _ => "the expression".to_string(),
+    }
+}
+
+/// Compiles a parsed expression against the schema of the data it filters.
+pub fn compile(ast: &Ast, schema: &Schema, strict: bool) -> Result<Expr, IfError> {
+    compile_with_periods(ast, schema, strict, &HashMap::new())
+}
+
+/// As [`compile`], with the Date columns that hold %tw/%tm/%tq/%th/%ty
+/// variables, which then compare as period counts.
+pub fn compile_with_periods(
+    ast: &Ast,
+    schema: &Schema,
+    strict: bool,
+    periods: &HashMap<String, StataPeriod>,
+) -> Result<Expr, IfError> {
+    let compiler = Compiler { schema, strict, periods };
+    compiler.logical(ast)
+}
+
+/// SQL words the Stata parser has no use for; a parse error at one of them
+/// means the filter was written as SQL rather than mistyped.
+const SQL_ONLY_KEYWORDS: [&str; 11] = [
+    "case", "when", "then", "else", "end", "similar", "regexp", "rlike", "distinct", "exists", "escape",
+];
+
+/// Whether the parse error sits on a SQL-only keyword.
+fn is_sql_only(input: &str, error: &IfError) -> bool {
+    let word: String = input
+        .chars()
+        .skip(error.position)
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    SQL_ONLY_KEYWORDS.contains(&word.to_ascii_lowercase().as_str())
+}
+
+/// What the "if" subfunction stores in `sql_if`, once the expression parses.
+/// Without stata_missing, an expression that stops parsing as Stata at a
+/// SQL-only keyword (CASE, SIMILAR TO, ...) and is a valid SQL predicate is
+/// stored as is and filtered with Polars SQL; other parse errors are reported.
+pub fn encode(input: &str, strict: bool) -> Result<String, IfError> {
+    match parse(input) {
+        Ok(_) => {
+            let prefix = if strict { STATA_IF_STRICT_PREFIX } else { STATA_IF_PREFIX };
+            Ok(format!("{}{}", prefix, input))
+        }
+        Err(e) if !strict && is_sql_only(input, &e) && crate::read::extract_sql_if_columns(input).is_ok() => {
+            Ok(input.to_string())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A Stata filter's expression and whether it is strict; None for SQL.
+fn decode(filter: &str) -> Option<(&str, bool)> {
+    if let Some(text) = filter.strip_prefix(STATA_IF_STRICT_PREFIX) {
+        Some((text, true))
+    } else {
+        filter.strip_prefix(STATA_IF_PREFIX).map(|text| (text, false))
+    }
+}
+
+/// The columns a filter refers to: from the parsed expression, or from
+/// the SQL for an SQL filter.
+pub fn filter_columns(filter: &str) -> Result<Vec<String>, String> {
+    match decode(filter) {
+        Some((text, _)) => parse(text).map(|ast| columns(&ast)).map_err(|e| e.render(text)),
+        None => crate::read::extract_sql_if_columns(filter),
+    }
+}
+
+/// Applies an if() filter (as stored in `sql_if`) to a scan.
+pub fn filter_lazy(lf: LazyFrame, filter: &str) -> PolarsResult<LazyFrame> {
+    filter_lazy_with_periods(lf, filter, &HashMap::new())
+}
+
+/// As [`filter_lazy`], where the Date columns in `periods` hold the first
+/// day of a Stata period (from the metadata envelope or staged formats).
+pub fn filter_lazy_with_periods(
+    mut lf: LazyFrame,
+    filter: &str,
+    periods: &HashMap<String, StataPeriod>,
+) -> PolarsResult<LazyFrame> {
+    if filter.trim().is_empty() {
+        return Ok(lf);
+    }
+    match decode(filter) {
+        Some((text, strict)) => {
+            let schema = lf.collect_schema()?;
+            let expr = parse(text)
+                .and_then(|ast| compile_with_periods(&ast, &schema, strict, periods))
+                .map_err(|e| polars_err!(ComputeError: "{}", e.render(text)))?;
+            Ok(lf.filter(expr))
+        }
+        None => {
+            let mut ctx = SQLContext::new();
+            ctx.register("df", lf);
+            ctx.execute(&format!("select * from df where {}", filter))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> DataFrame {
+        df!(
+            "age" => [Some(17i64), Some(30), Some(70), None, Some(45)],
+            "income" => [Some(10.0), None, Some(50.0), Some(20.0), Some(0.0)],
+            "country" => [Some("USA"), Some("Canada"), Some("a, b"), Some("John & Jane"), None],
+            "status" => [1i32, 2, 3, 1, 2]
+        )
+        .unwrap()
+    }
+
+    /// Row numbers (0-based) kept by the filter.
+    fn rows(input: &str, strict: bool) -> Vec<u32> {
+        rows_in(data(), input, strict)
+    }
+
+    fn rows_in(data: DataFrame, input: &str, strict: bool) -> Vec<u32> {
+        let filter = encode(input, strict).unwrap_or_else(|e| panic!("{}", e.render(input)));
+        let df = filter_lazy(data.lazy().with_row_index("row", None), &filter)
+            .and_then(|lf| lf.collect())
+            .unwrap_or_else(|e| panic!("{}: {}", input, e));
+        df.column("row").unwrap().idx().unwrap().into_no_null_iter().collect()
+    }
+
+    fn error(input: &str) -> IfError {
+        let schema = data().schema().as_ref().clone();
+        match parse(input).and_then(|ast| compile(&ast, &schema, false)) {
+            Ok(_) => panic!("{} compiled", input),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn stata_functions() {
+        assert_eq!(rows("missing(age)", false), [3]);
+        assert_eq!(rows("!missing(age)", false), [0, 1, 2, 4]);
+        assert_eq!(rows("inrange(age, 18, 65)", false), [1, 4]);
+        assert_eq!(rows("inlist(country, \"USA\", \"Canada\")", false), [0, 1]);
+        assert_eq!(rows("ceil(income / 20) == 1", false), [0, 3]);
+        assert_eq!(rows("mod(status, 2) == 1", false), [0, 2, 3]);
+        assert_eq!(rows("missing(age, country)", false), [3, 4]);
+        assert_eq!(rows("round(income, 20) == 20", false), [0, 3]);
+        assert_eq!(rows("real(string(status)) == 2", false), [1, 4]);
+    }
+
+    #[test]
+    fn stata_operators() {
+        assert_eq!(rows("age > 30 & country == \"USA\"", false), Vec::<u32>::new());
+        assert_eq!(rows("status == 1 | status == 2", false), [0, 1, 3, 4]);
+        assert_eq!(rows("status == 1 | 1", false).len(), 5);
+    }
+
+    #[test]
+    fn sql_spellings() {
+        assert_eq!(rows("age > 30 AND country = 'Canada'", false), Vec::<u32>::new());
+        assert_eq!(rows("status = 1 OR status = 2", false), [0, 1, 3, 4]);
+        assert_eq!(rows("status BETWEEN 2 AND 3", false), [1, 2, 4]);
+        assert_eq!(rows("country IN ('USA', 'Canada')", false), [0, 1]);
+        assert_eq!(rows("country NOT IN ('USA')", false), [1, 2, 3]);
+        assert_eq!(rows("age IS NULL", false), [3]);
+        assert_eq!(rows("age IS NOT NULL AND NOT status = 1", false), [1, 2, 4]);
+        assert_eq!(rows("CEILING(income) = 10 OR FLOOR(income) = 50", false), [0, 2]);
+        assert_eq!(rows("CAST(income AS INTEGER) = 20", false), [3]);
+        assert_eq!(rows("status % 2 = 0", false), [1, 4]);
+        assert_eq!(rows("country LIKE 'U%'", false), [0]);
+        assert_eq!(rows("country NOT LIKE '%a%' AND status = 1", false), [0]);
+        assert_eq!(rows("country ILIKE 'c_nada' | country like 'a, _'", false), [1, 2]);
+    }
+
+    #[test]
+    fn other_sql_falls_back_to_polars_sql() {
+        let input = "CASE WHEN status = 1 THEN age > 20 ELSE income > 10 END";
+        assert_eq!(encode(input, false).unwrap(), input);
+        assert_eq!(rows(input, false), [2]);
+        // stata_missing needs Stata syntax, and broken input is reported as Stata.
+        assert_eq!(encode(input, true).unwrap_err().message, "unexpected WHEN");
+        assert_eq!(encode("age > 30 &", false).unwrap_err().position, 10);
+        // A typo that happens to be valid SQL is still a Stata parse error.
+        assert!(crate::read::extract_sql_if_columns("age >> 30").is_ok());
+        assert_eq!(encode("age >> 30", false).unwrap_err().position, 5);
+    }
+
+    #[test]
+    fn quoted_text_is_not_parsed() {
+        assert_eq!(rows("country == 'John & Jane'", false), [3]);
+        assert_eq!(rows("country == \"a, b\" | country == \"x == y\"", false), [2]);
+        assert_eq!(rows("inlist(country, \"a, b\", \"missing(data)\")", false), [2]);
+        assert_eq!(rows("country == `\"John & Jane\"'", false), [3]);
+        assert_eq!(rows("country == “USA”", false), [0]);
+    }
+
+    #[test]
+    fn nesting_and_precedence() {
+        assert_eq!(rows("status==1&(age<20|age>60)", false), [0]);
+        assert_eq!(rows("inlist(mod(status, 2), (1), 5) & !(age < 20)", false), [2]);
+        assert_eq!(rows("-2^2 == -4 & 2^3^2 == 64 & status == 3", false), [2]);
+        assert_eq!(rows("!missing(income) & income / 0 == .", false), [0, 2, 3, 4]);
+    }
+
+    #[test]
+    fn missing_ordering() {
+        // SQL semantics by default: comparisons with missing are not true
+        assert_eq!(rows("age > 40", false), [2, 4]);
+        assert_eq!(rows("age != 30", false), [0, 2, 4]);
+        assert_eq!(rows("!(age < 40)", false), [2, 4]);
+        // Stata: missing is greater than every number
+        assert_eq!(rows("age > 40", true), [2, 3, 4]);
+        assert_eq!(rows("age != 30", true), [0, 2, 3, 4]);
+        assert_eq!(rows("!(age < 40)", true), [2, 3, 4]);
+        assert_eq!(rows("age <= 30", true), [0, 1]);
+        assert_eq!(rows("country == \"\"", true), [4]);
+        assert_eq!(rows("missing(country)", true), [4]);
+        // The literal . is always Stata's missing
+        assert_eq!(rows("age < .", false), [0, 1, 2, 4]);
+        assert_eq!(rows("age == .", false), [3]);
+        assert_eq!(rows("age >= . | . < status", false), [3]);
+    }
+
+    #[test]
+    fn date_literals_and_stata_dates() {
+        let df = df!("day" => [0i32, 18266]).unwrap().lazy().with_column(col("day").cast(DataType::Date));
+        let count = |input: &str| {
+            filter_lazy(df.clone(), &encode(input, false).unwrap())
+                .unwrap()
+                .collect()
+                .unwrap()
+                .height()
+        };
+        assert_eq!(count("day >= DATE '2020-01-05'"), 1);
+        // A date compared with a number is a Stata date: days since 01jan1960
+        assert_eq!(count("day == 3653"), 1);
+        assert_eq!(count("day > 21918"), 1);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        let e = error("age > 30 & foo(age)");
+        assert_eq!(e.message, "unknown function foo()");
+        assert_eq!(e.position, 11);
+        assert_eq!(e.render("age > 30 & foo(age)").lines().last().unwrap(), "               ^");
+
+        let e = error("age > 30 &");
+        assert_eq!((e.message.as_str(), e.position), ("unexpected end of expression", 10));
+        let e = error("(age > 30");
+        assert_eq!((e.message.as_str(), e.position), ("expected ), found end of expression", 9));
+        let e = error("country > 5");
+        assert_eq!(e.message, "type mismatch: country is string and 5 is numeric");
+        let e = error("height > 5");
+        assert_eq!((e.message.as_str(), e.position), ("variable height not found", 0));
+        let e = error("age $ 5");
+        assert_eq!((e.message.as_str(), e.position), ("unexpected character $", 4));
+        assert_eq!(error("country == \"USA").message, "unmatched quote");
+    }
+
+    #[test]
+    fn columns_and_sql_passthrough() {
+        assert_eq!(
+            filter_columns(&encode("inlist(b, 1, 2) & a > c | a == 1", false).unwrap()).unwrap(),
+            ["b", "a", "c"]
+        );
+        // Without the prefix the filter is SQL
+        let df = filter_lazy(data().lazy(), "age > 30 AND country = 'a, b'").unwrap().collect().unwrap();
+        assert_eq!(df.height(), 1);
+    }
+
+    /// 01jan2020, 29feb2020 and 31dec2019, with times on the first two.
+    fn dates() -> DataFrame {
+        df!(
+            "d" => [Some(18262i32), Some(18321), Some(18261), None],
+            "ts" => [Some(1577867415250i64), Some(1582934400000), None, None],
+            "name" => [Some("  Ann  Lee "), Some("bob"), Some("CY"), None],
+            "x" => [Some(-2.5), Some(4.0), Some(0.0), None]
+        )
+        .unwrap()
+        .lazy()
+        .with_columns([
+            col("d").cast(DataType::Date),
+            col("ts").cast(DataType::Datetime(TimeUnit::Milliseconds, None)),
+        ])
+        .collect()
+        .unwrap()
+    }
+
+    #[test]
+    fn date_functions() {
+        let rows = |input: &str| rows_in(dates(), input, false);
+        assert_eq!(rows("d == td(01jan2020)"), [0]);
+        assert_eq!(rows("d >= td(1 Jan 2020) & year(d) == 2020"), [0, 1]);
+        assert_eq!(rows("month(d) == 2 & day(d) == 29 & doy(d) == 60"), [1]);
+        assert_eq!(rows("mdy(2, 29, year(d)) == d"), [1]);
+        assert_eq!(rows("mdy(2, 30, 2020) == ."), [0, 1, 2, 3]);
+        assert_eq!(rows("dow(d) == 3"), [0]);
+        assert_eq!(rows("week(d) == 52 & halfyear(d) == 2"), [2]);
+        assert_eq!(rows("mofd(d) == tm(2020m2) | yofd(d) == ty(2019)"), [1, 2]);
+        assert_eq!(rows("qofd(d) == tq(2020q1) & hofd(d) == th(2020h1)"), [0, 1]);
+        assert_eq!(rows("dofm(mofd(d)) == td(01feb2020) | dofq(qofd(d)) == td(01oct2019)"), [1, 2]);
+        assert_eq!(rows("dofw(wofd(d)) <= d & dofw(wofd(d)) > d - 9 & dofy(yofd(d)) <= d"), [0, 1, 2]);
+        assert_eq!(rows("ts == tc(01jan2020 08:30:15.250)"), [0]);
+        // tC() counts the 27 leap seconds to 2020, which datetimes leave out
+        assert_eq!(rows("ts == tC(01jan2020 08:30:15.250) & ts < tC(29feb2020 00:00:00)"), [0]);
+        assert_eq!(rows("tC(01jan2020 08:30:15.250) - tc(01jan2020 08:30:15.250) == 27000").len(), 4);
+        assert_eq!(rows("tC(31dec2016 23:59:60) - tc(31dec2016 23:59:59) == 27000").len(), 4);
+        assert_eq!(rows("hh(ts) == 8 & mm(ts) == 30 & ss(ts) == 15.25"), [0]);
+        assert_eq!(rows("dofc(ts) == d & cofd(d) <= ts"), [0, 1]);
+        assert_eq!(rows("ts == mdyhms(2, 29, 2020, 0, 0, 0)"), [1]);
+        assert_eq!(rows("d == date(\"2020/02/29\", \"YMD\") | ts == clock(\"1 Jan 2020 8:30:15.25\", \"DMYhms\")"), [0, 1]);
+        // The SQL forms: strftime formats and TIMESTAMP literals
+        assert_eq!(rows("d >= date('05jan2020', '%d%b%Y')"), [1]);
+        assert_eq!(rows("ts >= TIMESTAMP '2020-01-01 08:30:15.250'"), [0, 1]);
+    }
+
+    #[test]
+    fn period_literals_against_dates() {
+        // Without the format, a period literal next to a date is its first day
+        let rows = |input: &str| rows_in(dates(), input, false);
+        assert_eq!(rows("d >= tm(2020m1)"), [0, 1]);
+        assert_eq!(rows("d == tq(2020q1) | d == ty(2019)"), [0]);
+        assert_eq!(rows("inrange(d, tm(2019m12), tm(2020m1))"), [0, 2]);
+        assert_eq!(rows("tm(2020m1) == 720").len(), 4);
+
+        // A %tm variable saved as a date compares as the month it holds
+        let periods = HashMap::from([("d".to_string(), StataPeriod::Month)]);
+        let rows = |input: &str| {
+            let filter = encode(input, false).unwrap();
+            let df = filter_lazy_with_periods(dates().lazy().with_row_index("row", None), &filter, &periods)
+                .and_then(|lf| lf.collect())
+                .unwrap_or_else(|e| panic!("{}: {}", input, e));
+            df.column("row").unwrap().idx().unwrap().into_no_null_iter().collect::<Vec<u32>>()
+        };
+        assert_eq!(rows("d >= tm(2020m1)"), [0, 1]);
+        assert_eq!(rows("d == tm(2020m2) | d + 1 == tm(2020m1)"), [1, 2]);
+        assert_eq!(rows("d == 720"), [0]);
+        assert_eq!(rows("dofm(d) == td(01feb2020)"), [1]);
+    }
+
+    #[test]
+    fn string_functions() {
+        let rows = |input: &str| rows_in(dates(), input, false);
+        assert_eq!(rows("strtrim(name) == \"Ann  Lee\" & stritrim(strtrim(name)) == \"Ann Lee\""), [0]);
+        assert_eq!(rows("lower(name) == \"cy\" | upper(substr(name, 1, 2)) == \"BO\""), [1, 2]);
+        assert_eq!(rows("substr(name, -1, 1) == \"Y\" | substr(name, 2, .) == \"ob\""), [1, 2]);
+        assert_eq!(rows("strpos(name, \"o\") == 2"), [1]);
+        assert_eq!(rows("strlen(name) == 0"), [3]);
+        assert_eq!(rows("regexm(name, \"^[A-Z]+$\")"), [2]);
+        assert_eq!(rows("strmatch(name, \"b?b*\")"), [1]);
+        assert_eq!(rows("subinstr(name, \"b\", \"c\", .) == \"coc\" & subinstr(name, \"b\", \"c\", 1) == \"cob\""), [1]);
+        assert_eq!(rows("ltrim(name) == \"Ann  Lee \" & rtrim(name) == \"  Ann  Lee\""), [0]);
+        assert_eq!(rows("string(x) == \"-2.5\" | string(x) == \"4\" | string(x) == \".\""), [0, 1, 3]);
+    }
+
+    #[test]
+    fn string_formats_as_9_0g() {
+        let cases = [
+            (1.0, "1"),
+            (-2.5, "-2.5"),
+            (0.5, ".5"),
+            (-0.5, "-.5"),
+            (1.0 / 3.0, ".33333333"),
+            (100.0 / 3.0, "33.333333"),
+            (0.1 + 0.2, ".3"),
+            (0.0001234, ".0001234"),
+            (123456789.0, "123456789"),
+            (1234567890.0, "1.235e+09"),
+            (-1234567890.0, "-1.23e+09"),
+            (0.00001, "1.000e-05"),
+        ];
+        for (value, text) in cases {
+            assert_eq!(format_9_0g(value), text, "{}", value);
+        }
+    }
+
+    #[test]
+    fn extended_missing_literals() {
+        // x_stata_missing is the companion column pq save, extended_missing writes
+        let coded = df!(
+            "x" => [Some(1.0), None, None, None],
+            "x_stata_missing" => [None, None, Some("a"), Some("b")]
+        )
+        .unwrap();
+        let rows = |input: &str| rows_in(coded.clone(), input, false);
+        assert_eq!(rows("x == .a"), [2]);
+        assert_eq!(rows("x == ."), [1]);
+        assert_eq!(rows("x != .a"), [0, 1, 3]);
+        assert_eq!(rows("x > ."), [2, 3]);
+        assert_eq!(rows("x < .b"), [0, 1, 2]);
+        assert_eq!(rows("inlist(x, 1, .b)"), [0, 3]);
+        assert_eq!(rows("missing(x)"), [1, 2, 3]);
+        // Without the companion, .a-.z were saved as null like .
+        assert_eq!(rows_in(data(), "age == .a", false), [3]);
+        assert_eq!(rows_in(data(), "age < .z", false), [0, 1, 2, 4]);
+        assert_eq!(error("age == .ab").message, "unexpected ab");
+    }
+
+    #[test]
+    fn math_functions() {
+        let rows = |input: &str| rows_in(dates(), input, false);
+        assert_eq!(rows("abs(x) == 2.5 & int(x) == -2 & sign(x) == -1"), [0]);
+        assert_eq!(rows("sqrt(x) == 2 & log10(x * 25) == 2"), [1]);
+        assert_eq!(rows("ln(x) == ."), [0, 2, 3]);
+        assert_eq!(rows("abs(exp(ln(x)) - x) < 1e-9"), [1]);
+        assert_eq!(rows("max(x, 1) == 1"), [0, 2, 3]);
+        assert_eq!(rows("min(x, 5, .) == 4"), [1]);
+        assert_eq!(rows("cond(x > 0, 1, 2) == 2"), [0, 2, 3]);
+        assert_eq!(rows("cond(x, 1, 2) == 1"), [0, 1, 3]);
+        assert_eq!(rows("cond(x, 1, 2, 3) == 3"), [3]);
+        assert_eq!(rows("cond(x > 0, \"pos\", name) == \"pos\""), [1]);
+    }
+
+    #[test]
+    fn function_errors_and_batches() {
+        let schema = dates().schema().as_ref().clone();
+        let error = |input: &str| match parse(input).and_then(|ast| compile(&ast, &schema, false)) {
+            Ok(_) => panic!("{} compiled", input),
+            Err(e) => e,
+        };
+        assert_eq!(error("d == td(31feb2020)").message, "invalid date td(31feb2020)");
+        assert_eq!(error("d == TD(x)").message, "td() expects a date, as in td(01jan2020)");
+        assert_eq!(error("ts == tC(30dec2016 23:59:60)").message, "invalid date tC(30dec2016 23:59:60)");
+        assert_eq!(error("strmatch(name, name)").position, 15);
+        assert_eq!(error("cond(x, name, 1) == 1").message, "type mismatch");
+        assert_eq!(error("mdy(1, 2)").message, "wrong number of arguments to mdy()");
+
+        // The readstat readers filter each batch the same way
+        let filter = encode("year(d) == 2020 & strlen(name) > 2", false).unwrap();
+        let filtered = crate::read::apply_sql_filter_to_batch(dates(), Some(&filter)).unwrap();
+        assert_eq!(filtered.height(), 2);
+    }
+}
@@ -10,8 +10,19 @@ use stata_sys;
 
 
 
+/// False outside a plugin call (the command-line tool, and unit tests),
+/// where messages go to stdout and macros and scalars are not set.
+#[inline]
+pub fn in_stata() -> bool {
+    unsafe { !_stata_.is_null() }
+}
+
 #[inline]
 pub fn display(msg: &str) -> i32 {
+    if !in_stata() {
+        println!("{}", msg);
+        return 0;
+    }
     stata_sys::display(&msg)    
 }
 
@@ -21,6 +32,9 @@ pub fn set_macro(
     value:&str,
     global:bool
 ) -> i32 {
+    if !in_stata() {
+        return 0;
+    }
     stata_sys::set_macro(macro_name, value, global)
 }
 
@@ -30,6 +44,9 @@ pub fn get_macro(
     global:bool,
     buffer_size: Option<usize>
 ) -> String {
+    if !in_stata() {
+        return String::new();
+    }
     stata_sys::get_macro(macro_name, global, buffer_size).unwrap_or_default()
 }
 
@@ -39,6 +56,9 @@ pub fn set_scalar(
     value:&f64,
     //  global:bool
 ) -> i32 {
+    if !in_stata() {
+        return 0;
+    }
     stata_sys::set_scalar(scalar_name, value)
 }
 
@@ -2,14 +2,16 @@ use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::path::Path;
 
-use polars::prelude::{KeyValueMetadata, PlSmallStr};
+use polars::prelude::{IpcReader, KeyValueMetadata, PlSmallStr, SerReader};
 use serde::{Deserialize, Serialize};
 
 use crate::mapping::{resolve_stata_type, StataColumnInfo};
 use crate::stata_interface::{display, get_macro, set_macro};
+use crate::utilities::{is_leap_clock_format, parse_duration_record, DurationUnit, StataPeriod};
 
 /// Resolves a `pq use` path (file, directory, or glob) to every Parquet
-/// file it covers, in glob order.
+/// file it covers, in glob order. A glob is taken as given, so this also
+/// serves Arrow IPC globs.
 pub fn resolve_all_parquet_files(path: &str) -> Vec<String> {
     let path_obj = Path::new(path);
     if path_obj.is_file() {
@@ -37,7 +39,7 @@ pub fn first_parquet_file(path: &str) -> Option<String> {
 }
 
 pub const STATA_METADATA_KEY: &str = "org.stata.pq.labels.v1";
-const STATA_METADATA_VERSION: u32 = 1;
+pub(crate) const STATA_METADATA_VERSION: u32 = 1;
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct VariableMetadata {
@@ -60,6 +62,17 @@ pub struct VariableMetadata {
     // binary columns; footer stats can't verify string length the same way.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub stata_type: Option<String>,
+    // Companion string column written by `pq save, extended_missing`: it
+    // holds the code letter ("a".."z") on rows where this variable was .a-.z
+    // and null elsewhere, so the exact code can be restored on read and
+    // non-Stata readers can still see it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extended_missing: Option<String>,
+    // Unit ("ms", "s" or "days") of a variable pq use loaded from a Duration
+    // column and pq save wrote back as one; pq use loads the column in it
+    // again unless duration() says otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -80,9 +93,11 @@ pub struct StataMetadataEnvelope {
 
 /// Gathers Stata metadata that pq.ado staged in indexed macros (pq_meta_*)
 /// before the plugin call. Mirrors write::column_info_from_macros, which
-/// reads the same kind of indexed macros for plain column info. Returns
-/// None when the caller didn't request statametadata (pq_meta_count unset
-/// or zero), so ordinary saves pay no extra cost here.
+/// reads the same kind of indexed macros for plain column info. When the
+/// caller didn't request statametadata (pq_meta_count unset or zero), only
+/// the formats of %tw/%tm/%tq/%th/%ty and %tC columns and the units of
+/// Duration ones are recorded (see temporal_records), and ordinary saves
+/// return None.
 ///
 /// `column_info` (already resolved, post-rename - the same list used to
 /// build the write schema) supplies the exact Stata storage type per
@@ -93,7 +108,7 @@ pub fn metadata_from_macros(
 ) -> Option<StataMetadataEnvelope> {
     let n_vars: usize = get_macro("pq_meta_count", false, None).parse().unwrap_or(0);
     if n_vars == 0 {
-        return None;
+        return temporal_records(column_info);
     }
 
     let column_info_by_name: HashMap<&str, &StataColumnInfo> = column_info
@@ -139,12 +154,17 @@ pub fn metadata_from_macros(
         let value_label = get_macro(&format!("pq_meta_vallabel_{i}"), false, None);
         let notes = read_indexed_list(&format!("pq_meta_note_{i}"));
         let var_format = get_macro(&format!("pq_meta_format_{i}"), false, None);
+        let extended_missing = get_macro(&format!("pq_meta_extmiss_{i}"), false, None);
         let stata_type = column_info_by_name
             .get(parquet_name.as_str())
             .map(|c| resolve_stata_type(&c.dtype, &c.format).to_string().to_string());
+        let duration = column_info_by_name
+            .get(parquet_name.as_str())
+            .and_then(|c| duration_unit(c));
 
         if label.is_empty() && value_label.is_empty() && notes.is_empty()
-            && var_format.is_empty() && stata_type.is_none() {
+            && var_format.is_empty() && stata_type.is_none() && extended_missing.is_empty()
+            && duration.is_none() {
             continue;
         }
 
@@ -164,6 +184,8 @@ pub fn metadata_from_macros(
                 notes,
                 format: if var_format.is_empty() { None } else { Some(var_format) },
                 stata_type,
+                extended_missing: if extended_missing.is_empty() { None } else { Some(extended_missing) },
+                duration,
             },
         );
     }
@@ -175,6 +197,64 @@ pub fn metadata_from_macros(
     Some(envelope)
 }
 
+/// The envelope for a save without statametadata: just the display format
+/// of each %tw/%tm/%tq/%th/%ty or %tC column and the unit of each Duration
+/// one. Those are written as dates (first day of the period), UTC datetimes
+/// or Arrow durations, and pq use needs these to turn them back into the
+/// values Stata had.
+fn temporal_records(column_info: &[StataColumnInfo]) -> Option<StataMetadataEnvelope> {
+    let variables: BTreeMap<String, VariableMetadata> = column_info
+        .iter()
+        .filter_map(|c| {
+            let format = (StataPeriod::of_format(&c.format).is_some() || is_leap_clock_format(&c.format))
+                .then(|| c.format.clone());
+            let duration = duration_unit(c);
+            if format.is_none() && duration.is_none() {
+                return None;
+            }
+            Some((c.name.clone(), VariableMetadata { format, duration, ..Default::default() }))
+        })
+        .collect();
+    (!variables.is_empty()).then(|| StataMetadataEnvelope {
+        version: STATA_METADATA_VERSION,
+        variables,
+        ..Default::default()
+    })
+}
+
+fn duration_unit(column: &StataColumnInfo) -> Option<String> {
+    parse_duration_record(&column.duration).map(|(unit, _)| unit.name().to_string())
+}
+
+/// Units pq save recorded for Duration columns, by Parquet column name, from
+/// the pq_meta_* macros describe_stata_metadata pushed (empty when the
+/// metadata isn't being applied).
+pub fn recorded_duration_units() -> HashMap<String, DurationUnit> {
+    if get_macro("pq_meta_present", false, None) != "1" {
+        return HashMap::new();
+    }
+    let n_vars: usize = get_macro("pq_meta_count", false, None).parse().unwrap_or(0);
+    (1..=n_vars)
+        .filter_map(|i| {
+            let unit = get_macro(&format!("pq_meta_duration_{i}"), false, None);
+            if unit.is_empty() {
+                return None;
+            }
+            Some((get_macro(&format!("pq_meta_name_{i}"), false, None), DurationUnit::parse(&unit)?))
+        })
+        .collect()
+}
+
+/// Columns saved from %tw/%tm/%tq/%th/%ty variables, by Parquet column
+/// name, from the formats the envelope records.
+pub fn period_columns(envelope: &StataMetadataEnvelope) -> HashMap<String, StataPeriod> {
+    envelope
+        .variables
+        .iter()
+        .filter_map(|(name, variable)| Some((name.clone(), StataPeriod::of_format(variable.format.as_deref()?)?)))
+        .collect()
+}
+
 fn read_indexed_list(prefix: &str) -> Vec<String> {
     let count: usize = get_macro(&format!("{prefix}_count"), false, None)
         .parse()
@@ -207,14 +287,27 @@ pub fn build_key_value_metadata(envelope: &StataMetadataEnvelope) -> Option<KeyV
     )]))
 }
 
-/// Reads the Stata metadata footer entry from a single Parquet file, as
-/// the raw JSON string still attached (no parse) - used for byte-exact
+/// The same envelope as Arrow IPC schema metadata, for `pq save` to
+/// .arrow/.feather files.
+pub fn build_ipc_schema_metadata(envelope: &StataMetadataEnvelope) -> Option<BTreeMap<PlSmallStr, PlSmallStr>> {
+    let json = serde_json::to_string(envelope).ok()?;
+    Some(BTreeMap::from([(
+        PlSmallStr::from_static(STATA_METADATA_KEY),
+        PlSmallStr::from(json),
+    )]))
+}
+
+/// Reads the Stata metadata footer entry from a single Parquet file (or,
+/// failing that, the schema metadata of an Arrow IPC file), as the raw
+/// JSON string still attached (no parse) - used for byte-exact
 /// cross-file comparison, since two envelopes that are semantically
 /// identical but serialized differently would otherwise compare unequal
 /// (or, worse, equal by accident after a lossy parse).
 fn read_raw_metadata_json(path: &str) -> Option<String> {
     let mut file = File::open(path).ok()?;
-    let metadata = polars_parquet::read::read_metadata(&mut file).ok()?;
+    let Ok(metadata) = polars_parquet::read::read_metadata(&mut file) else {
+        return read_raw_ipc_metadata_json(file);
+    };
     metadata
         .key_value_metadata()
         .as_ref()?
@@ -224,6 +317,13 @@ fn read_raw_metadata_json(path: &str) -> Option<String> {
         .clone()
 }
 
+fn read_raw_ipc_metadata_json(file: File) -> Option<String> {
+    let custom = IpcReader::new(file).custom_metadata().ok()??;
+    custom
+        .get(STATA_METADATA_KEY)
+        .map(|value| value.to_string())
+}
+
 /// Reads the Stata metadata envelope from a single Parquet file's footer.
 /// For directories/globs, callers pass the first resolved file - hive
 /// files are expected to carry identical metadata. Prefer
@@ -333,6 +433,82 @@ fn print_metadata_table(envelope: &StataMetadataEnvelope) {
             display(&format!("  {name}: {}", pairs.join(", ")));
         }
     }
+
+    let extended_missing: Vec<String> = envelope
+        .variables
+        .iter()
+        .filter_map(|(name, var)| var.extended_missing.as_ref().map(|column| format!("  {name}: {column}")))
+        .collect();
+    if !extended_missing.is_empty() {
+        display("");
+        display("Extended missing values (.a-.z) stored in:");
+        for line in &extended_missing {
+            display(line);
+        }
+    }
+
+    let durations: Vec<String> = envelope
+        .variables
+        .iter()
+        .filter_map(|(name, var)| var.duration.as_ref().map(|unit| format!("  {name}: {unit}")))
+        .collect();
+    if !durations.is_empty() {
+        display("");
+        display("Durations loaded as a count of:");
+        for line in &durations {
+            display(line);
+        }
+    }
+}
+
+/// Categories of one Categorical/Enum column loaded as codes with `encode`:
+/// code i+1 is `categories[i]`. describe builds these once and passes them
+/// to read (and the overflow .dta writer) as JSON in pq_cat_labels, so every
+/// pass assigns the same codes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CategoricalDictionary {
+    pub column: String,
+    // Stata value-label name (the column's Stata variable name).
+    pub label_name: String,
+    pub categories: Vec<String>,
+}
+
+pub fn dictionaries_from_json(json: &str) -> Vec<CategoricalDictionary> {
+    if json.trim().is_empty() {
+        return Vec::new();
+    }
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+/// Adds a value label per encoded column to the footer envelope (starting
+/// an empty one if there is none), replacing whatever label the footer
+/// recorded for that column, since its codes no longer apply.
+pub fn with_categorical_value_labels(
+    envelope: Option<StataMetadataEnvelope>,
+    dictionaries: &[CategoricalDictionary],
+) -> Option<StataMetadataEnvelope> {
+    if dictionaries.is_empty() {
+        return envelope;
+    }
+    let mut envelope = envelope.unwrap_or_else(|| StataMetadataEnvelope {
+        version: STATA_METADATA_VERSION,
+        ..Default::default()
+    });
+    for dict in dictionaries {
+        let defn: BTreeMap<String, String> = dict
+            .categories
+            .iter()
+            .enumerate()
+            .map(|(i, text)| ((i + 1).to_string(), text.clone()))
+            .collect();
+        envelope.value_labels.insert(dict.label_name.clone(), defn);
+        envelope
+            .variables
+            .entry(dict.column.clone())
+            .or_default()
+            .value_label = Some(dict.label_name.clone());
+    }
+    Some(envelope)
 }
 
 /// Pushes a read envelope back to Stata as indexed macros, mirroring
@@ -366,6 +542,16 @@ pub fn push_metadata_to_macros(envelope: &StataMetadataEnvelope) {
             var.format.as_deref().unwrap_or(""),
             false,
         );
+        set_macro(
+            &format!("pq_meta_extmiss_{idx}"),
+            var.extended_missing.as_deref().unwrap_or(""),
+            false,
+        );
+        set_macro(
+            &format!("pq_meta_duration_{idx}"),
+            var.duration.as_deref().unwrap_or(""),
+            false,
+        );
         write_indexed_list(&format!("pq_meta_note_{idx}"), &var.notes);
     }
 
@@ -395,3 +581,66 @@ fn write_indexed_list(prefix: &str, values: &[String]) {
         set_macro(&format!("{prefix}_{}", i + 1), value, false);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::prelude::{df, IpcWriter, SerWriter};
+    use std::sync::Arc;
+
+    #[test]
+    fn ipc_schema_metadata_round_trips() {
+        let mut envelope = StataMetadataEnvelope {
+            version: STATA_METADATA_VERSION,
+            dataset_label: Some("Wave 1".to_string()),
+            ..Default::default()
+        };
+        envelope.variables.insert(
+            "x".to_string(),
+            VariableMetadata {
+                label: Some("An x".to_string()),
+                format: Some("%9.2f".to_string()),
+                extended_missing: Some("x_stata_missing".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let path = std::env::temp_dir().join(format!("pq_ipc_meta_{}.arrow", std::process::id()));
+        let mut df = df!("x" => [1.0f64, 2.0]).unwrap();
+        let mut file = File::create(&path).unwrap();
+        let mut writer = IpcWriter::new(&mut file);
+        writer.set_custom_schema_metadata(Arc::new(build_ipc_schema_metadata(&envelope).unwrap()));
+        writer.finish(&mut df).unwrap();
+
+        let read_back = read_metadata_validated(path.to_str().unwrap()).unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read_back.dataset_label.as_deref(), Some("Wave 1"));
+        assert_eq!(read_back.variables["x"].label.as_deref(), Some("An x"));
+        assert_eq!(read_back.variables["x"].format.as_deref(), Some("%9.2f"));
+        assert_eq!(read_back.variables["x"].extended_missing.as_deref(), Some("x_stata_missing"));
+    }
+
+    #[test]
+    fn temporal_records_cover_periods_and_durations() {
+        let column = |name: &str, format: &str, duration: &str| StataColumnInfo {
+            name: name.to_string(),
+            dtype: "double".to_string(),
+            format: format.to_string(),
+            str_length: 0,
+            stata_col: 0,
+            duration: duration.to_string(),
+        };
+        let envelope = temporal_records(&[
+            column("month", "%tm", ""),
+            column("wait", "%10.0g", "s Duration(Microseconds)"),
+            column("x", "%9.2f", ""),
+        ])
+        .unwrap();
+        assert_eq!(envelope.variables.len(), 2);
+        assert_eq!(envelope.variables["month"].format.as_deref(), Some("%tm"));
+        assert_eq!(envelope.variables["wait"].duration.as_deref(), Some("s"));
+        assert!(envelope.variables["wait"].format.is_none());
+
+        assert!(temporal_records(&[column("x", "%9.2f", "")]).is_none());
+    }
+}
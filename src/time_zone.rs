@@ -0,0 +1,116 @@
+//! `timezone()`: Stata datetimes have no time zone, so a zone is applied at
+//! the edges. On read, zone-aware Datetime columns are converted to the
+//! chosen zone's local clock; on save, %tc and %tC variables (without
+//! their leap seconds) are taken as that zone's local clock and written
+//! zone-aware, so other readers get the right instant.
+
+use polars::prelude::*;
+
+/// The zone named in `timezone()`, validated ("America/New_York", "UTC",
+/// "+05:30", ...); None when the option was not given.
+pub fn parse(zone: &str) -> Result<Option<TimeZone>, String> {
+    let zone = zone.trim();
+    if zone.is_empty() {
+        return Ok(None);
+    }
+    TimeZone::opt_try_new(Some(zone)).map_err(|_| format!("timezone(): unknown time zone \"{zone}\""))
+}
+
+/// The zone of a Datetime column, or "" for naive datetimes and other types.
+pub fn zone_of(dtype: &DataType) -> String {
+    match dtype {
+        DataType::Datetime(_, Some(zone)) => zone.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Converts every zone-aware Datetime column to `zone`'s local clock and
+/// drops the zone, which is how Stata shows it.
+pub fn to_local_clock(mut lf: LazyFrame, zone: &TimeZone) -> PolarsResult<LazyFrame> {
+    let schema = lf.collect_schema()?;
+    let localized: Vec<Expr> = schema
+        .iter()
+        .filter(|(_, dtype)| matches!(dtype, DataType::Datetime(_, Some(_))))
+        .map(|(name, _)| {
+            col(name.clone())
+                .dt()
+                .convert_time_zone(zone.clone())
+                .dt()
+                .replace_time_zone(None, lit("raise"), NonExistent::Raise)
+        })
+        .collect();
+    if localized.is_empty() {
+        return Ok(lf);
+    }
+    Ok(lf.with_columns(localized))
+}
+
+/// Marks `columns` (naive datetimes holding `zone`'s local clock) as being
+/// in `zone`. A clock time that occurs twice when clocks go back is taken
+/// as the earlier one; one skipped when clocks go forward is an error.
+pub fn annotate(lf: LazyFrame, columns: &[String], zone: &TimeZone) -> LazyFrame {
+    if columns.is_empty() {
+        return lf;
+    }
+    let annotated: Vec<Expr> = columns
+        .iter()
+        .map(|name| {
+            col(name.as_str())
+                .dt()
+                .replace_time_zone(Some(zone.clone()), lit("earliest"), NonExistent::Raise)
+        })
+        .collect();
+    lf.with_columns(annotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc_frame() -> LazyFrame {
+        // 2020-01-15 12:00 and 2020-07-15 12:00 UTC
+        df!("at" => [1_579_089_600_000i64, 1_594_814_400_000], "n" => [1i32, 2])
+            .unwrap()
+            .lazy()
+            .with_columns([col("at")
+                .cast(DataType::Datetime(TimeUnit::Milliseconds, None))
+                .dt()
+                .replace_time_zone(Some(TimeZone::UTC), lit("raise"), NonExistent::Raise)])
+    }
+
+    fn millis(lf: LazyFrame) -> Vec<Option<i64>> {
+        let df = lf.select([col("at").cast(DataType::Int64)]).collect().unwrap();
+        df.column("at").unwrap().i64().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn zones_are_validated() {
+        assert!(parse("").unwrap().is_none());
+        assert_eq!(parse(" America/New_York ").unwrap().unwrap().to_string(), "America/New_York");
+        assert!(parse("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn read_converts_to_the_local_clock() {
+        let mut lf = utc_frame();
+        assert_eq!(zone_of(lf.collect_schema().unwrap().get("at").unwrap()), "UTC");
+
+        let zone = parse("America/New_York").unwrap().unwrap();
+        let mut local = to_local_clock(utc_frame(), &zone).unwrap();
+        assert_eq!(zone_of(local.collect_schema().unwrap().get("at").unwrap()), "");
+        // EST is UTC-5 in January, EDT UTC-4 in July
+        assert_eq!(
+            millis(local),
+            vec![Some(1_579_089_600_000 - 5 * 3_600_000), Some(1_594_814_400_000 - 4 * 3_600_000)]
+        );
+    }
+
+    #[test]
+    fn save_annotates_the_local_clock() {
+        let zone = parse("America/New_York").unwrap().unwrap();
+        let naive = to_local_clock(utc_frame(), &zone).unwrap();
+        let mut annotated = annotate(naive, &["at".to_string()], &zone);
+        assert_eq!(zone_of(annotated.collect_schema().unwrap().get("at").unwrap()), "America/New_York");
+        assert_eq!(millis(annotated), millis(utc_frame()));
+    }
+}
@@ -3,6 +3,8 @@ use std::env;
 use std::sync::OnceLock;
 use std::time::Duration;
 
+use polars::prelude::TimeUnit;
+
 use crate::stata_interface::get_macro;
 
 pub const DAY_SHIFT_SAS_STATA: i32 = 3653;
@@ -12,6 +14,173 @@ pub const SEC_MILLISECOND: i64 = 1_000;
 pub const SEC_MICROSECOND: i64 = 1_000_000;
 pub const SEC_NANOSECOND: i64 = 1_000_000_000;
 
+/// Days since 01jan1970, or None if the day does not exist.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if !(1..=12).contains(&month) || day < 1 || day > month_days[(month - 1) as usize] {
+        return None;
+    }
+    // Days from civil date, counting years from March
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
+}
+
+/// The Stata time-series formats whose values count weeks, months,
+/// quarters, half-years or years since 1960 (years are calendar years).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StataPeriod {
+    Week,
+    Month,
+    Quarter,
+    HalfYear,
+    Year,
+}
+
+impl StataPeriod {
+    /// The period counted by a display format (%tm, %-tq, %tmCCYY!mNN, ...).
+    pub fn of_format(format: &str) -> Option<StataPeriod> {
+        let body = format.strip_prefix('%')?;
+        let body = body.strip_prefix('-').unwrap_or(body);
+        match body.get(..2)? {
+            "tw" => Some(StataPeriod::Week),
+            "tm" => Some(StataPeriod::Month),
+            "tq" => Some(StataPeriod::Quarter),
+            "th" => Some(StataPeriod::HalfYear),
+            "ty" => Some(StataPeriod::Year),
+            _ => None,
+        }
+    }
+
+    /// Days since 01jan1970 of the first day of period `value`, as Stata's
+    /// dofw()/dofm()/dofq()/dofh()/dofy() give it.
+    pub fn first_day(&self, value: i64) -> Option<i64> {
+        match self {
+            // Week 52 of a year runs to 31dec, so weeks start 7 days apart from 01jan
+            StataPeriod::Week => days_from_civil(1960 + value.div_euclid(52), 1, 1)
+                .map(|jan1| jan1 + 7 * value.rem_euclid(52)),
+            StataPeriod::Month => days_from_civil(1960 + value.div_euclid(12), value.rem_euclid(12) + 1, 1),
+            StataPeriod::Quarter => days_from_civil(1960 + value.div_euclid(4), 3 * value.rem_euclid(4) + 1, 1),
+            StataPeriod::HalfYear => days_from_civil(1960 + value.div_euclid(2), 6 * value.rem_euclid(2) + 1, 1),
+            StataPeriod::Year => days_from_civil(value, 1, 1),
+        }
+    }
+}
+
+/// Days (in %td) ending with an inserted leap second, 23:59:60 UTC, in order.
+const LEAP_SECOND_DAYS: [(i64, i64, i64); 27] = [
+    (1972, 6, 30), (1972, 12, 31), (1973, 12, 31), (1974, 12, 31), (1975, 12, 31),
+    (1976, 12, 31), (1977, 12, 31), (1978, 12, 31), (1979, 12, 31), (1981, 6, 30),
+    (1982, 6, 30), (1983, 6, 30), (1985, 6, 30), (1987, 12, 31), (1989, 12, 31),
+    (1990, 12, 31), (1992, 6, 30), (1993, 6, 30), (1994, 6, 30), (1995, 12, 31),
+    (1997, 6, 30), (1998, 12, 31), (2005, 12, 31), (2008, 12, 31), (2012, 6, 30),
+    (2015, 6, 30), (2016, 12, 31),
+];
+
+/// True for %tC, Stata's datetime format that counts leap seconds.
+pub fn is_leap_clock_format(format: &str) -> bool {
+    let body = format.strip_prefix('%').unwrap_or(format);
+    body.strip_prefix('-').unwrap_or(body).starts_with("tC")
+}
+
+/// A %tC value (milliseconds since 01jan1960, leap seconds included) as
+/// %tc, as Stata's cofC() does. A time inside an inserted leap second is
+/// read as 23:59:59.
+pub fn clock_without_leap_seconds(ms: f64) -> f64 {
+    let mut leap_ms = 0.0;
+    for (year, month, day) in LEAP_SECOND_DAYS {
+        // The leap second starts at the following midnight in %tc, pushed
+        // back by the leap seconds inserted before it
+        let midnight = (days_from_civil(year, month, day).unwrap_or(0) + 1 + DAY_SHIFT_SAS_STATA as i64) as f64
+            * 86_400_000.0;
+        let start = midnight + leap_ms;
+        if ms < start {
+            break;
+        }
+        if ms < start + 1000.0 {
+            return ms - leap_ms - 1000.0;
+        }
+        leap_ms += 1000.0;
+    }
+    ms - leap_ms
+}
+
+/// A %tc value as %tC, counting the leap seconds inserted before it, as
+/// Stata's Cofc() does.
+pub fn clock_with_leap_seconds(ms: f64) -> f64 {
+    let mut leap_ms = 0.0;
+    for (year, month, day) in LEAP_SECOND_DAYS {
+        let midnight = (days_from_civil(year, month, day).unwrap_or(0) + 1 + DAY_SHIFT_SAS_STATA as i64) as f64
+            * 86_400_000.0;
+        if ms < midnight {
+            break;
+        }
+        leap_ms += 1000.0;
+    }
+    ms + leap_ms
+}
+
+/// What a variable loaded from a Duration column counts, set by duration():
+/// milliseconds (the default, Stata's clock unit), seconds or days.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DurationUnit {
+    Milliseconds,
+    Seconds,
+    Days,
+}
+
+impl DurationUnit {
+    /// The unit named in duration() ("" is the default, ms).
+    pub fn parse(unit: &str) -> Option<DurationUnit> {
+        match unit.trim() {
+            "" | "ms" => Some(DurationUnit::Milliseconds),
+            "s" => Some(DurationUnit::Seconds),
+            "days" => Some(DurationUnit::Days),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DurationUnit::Milliseconds => "ms",
+            DurationUnit::Seconds => "s",
+            DurationUnit::Days => "days",
+        }
+    }
+
+    /// How many ticks of a Duration column in `time_unit` make one of this unit.
+    pub fn ticks(&self, time_unit: TimeUnit) -> f64 {
+        let per_millisecond = match time_unit {
+            TimeUnit::Milliseconds => 1.0,
+            TimeUnit::Microseconds => (SEC_MICROSECOND / SEC_MILLISECOND) as f64,
+            TimeUnit::Nanoseconds => (SEC_NANOSECOND / SEC_MILLISECOND) as f64,
+        };
+        per_millisecond * match self {
+            DurationUnit::Milliseconds => 1.0,
+            DurationUnit::Seconds => SEC_MILLISECOND as f64,
+            DurationUnit::Days => 86_400_000.0,
+        }
+    }
+}
+
+/// The _pq_duration characteristic pq use puts on a variable loaded from a
+/// Duration column, "<unit> <Polars type>" (e.g. "s Duration(Microseconds)"),
+/// so pq save can write it back as the same type.
+pub fn parse_duration_record(record: &str) -> Option<(DurationUnit, TimeUnit)> {
+    let (unit, dtype) = record.trim().split_once(' ')?;
+    let time_unit = match dtype.trim() {
+        "Duration(Milliseconds)" => TimeUnit::Milliseconds,
+        "Duration(Microseconds)" => TimeUnit::Microseconds,
+        "Duration(Nanoseconds)" => TimeUnit::Nanoseconds,
+        _ => return None,
+    };
+    Some((DurationUnit::parse(unit)?, time_unit))
+}
+
 static THREAD_POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
 
 pub fn get_thread_pool(n_threads: usize) -> &'static rayon::ThreadPool {
@@ -77,3 +246,59 @@ pub fn ms(d: Duration) -> f64 {
     d.as_secs_f64() * 1000.0
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stata_ms(year: i64, month: i64, day: i64) -> f64 {
+        (days_from_civil(year, month, day).unwrap() + DAY_SHIFT_SAS_STATA as i64) as f64 * 86_400_000.0
+    }
+
+    #[test]
+    fn periods_start_on_their_first_day() {
+        assert_eq!(StataPeriod::of_format("%tm"), Some(StataPeriod::Month));
+        assert_eq!(StataPeriod::of_format("%-tqCCYY!qq"), Some(StataPeriod::Quarter));
+        assert_eq!(StataPeriod::of_format("%td"), None);
+        assert_eq!(StataPeriod::of_format("%9.0g"), None);
+
+        assert_eq!(StataPeriod::Month.first_day(720), days_from_civil(2020, 1, 1));
+        assert_eq!(StataPeriod::Month.first_day(-1), days_from_civil(1959, 12, 1));
+        assert_eq!(StataPeriod::Quarter.first_day(242), days_from_civil(2020, 7, 1));
+        assert_eq!(StataPeriod::Quarter.first_day(-1), days_from_civil(1959, 10, 1));
+        assert_eq!(StataPeriod::HalfYear.first_day(121), days_from_civil(2020, 7, 1));
+        assert_eq!(StataPeriod::Week.first_day(60 * 52 + 51), days_from_civil(2020, 12, 23));
+        assert_eq!(StataPeriod::Week.first_day(-52), days_from_civil(1959, 1, 1));
+        assert_eq!(StataPeriod::Year.first_day(2020), days_from_civil(2020, 1, 1));
+    }
+
+    #[test]
+    fn leap_seconds_are_removed_from_tc_capital() {
+        assert!(is_leap_clock_format("%tC"));
+        assert!(is_leap_clock_format("%-tCDDmonCCYY"));
+        assert!(!is_leap_clock_format("%tc"));
+
+        let before = stata_ms(1970, 3, 1) + 1234.0;
+        assert_eq!(clock_without_leap_seconds(before), before);
+        let new_year = stata_ms(2017, 1, 1);
+        assert_eq!(clock_without_leap_seconds(new_year + 27_000.0), new_year);
+        assert_eq!(clock_without_leap_seconds(new_year + 26_500.0), new_year - 500.0);
+        assert_eq!(clock_without_leap_seconds(stata_ms(1973, 1, 1) + 2_000.0), stata_ms(1973, 1, 1));
+    }
+
+    #[test]
+    fn duration_units_and_records() {
+        assert_eq!(DurationUnit::parse(""), Some(DurationUnit::Milliseconds));
+        assert_eq!(DurationUnit::parse("hours"), None);
+        assert_eq!(DurationUnit::Seconds.ticks(TimeUnit::Microseconds), 1_000_000.0);
+        assert_eq!(DurationUnit::Days.ticks(TimeUnit::Milliseconds), 86_400_000.0);
+
+        assert_eq!(
+            parse_duration_record("s Duration(Microseconds)"),
+            Some((DurationUnit::Seconds, TimeUnit::Microseconds))
+        );
+        assert_eq!(parse_duration_record(""), None);
+        assert_eq!(parse_duration_record(" Duration(Nanoseconds)"), None);
+        assert_eq!(parse_duration_record("ms Datetime(Milliseconds, None)"), None);
+    }
+}
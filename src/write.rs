@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use std::fs::File;
 use polars::prelude::{NamedFrom, TimeUnit};
 use polars::prelude::*;
-use polars_sql::SQLContext;
+use crate::stata_if;
 use rayon::prelude::*;
 use std::error::Error;
 use std::collections::HashMap;
@@ -11,15 +11,21 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::path::Path;
 use polars_parquet::write::{BrotliLevel, GzipLevel, ZstdLevel};
 
-use crate::{downcast, stata_interface, stata_metadata};
+use crate::{downcast, sav, stata_interface, stata_metadata, time_zone, xpt};
 use crate::stata_interface::{
     display,
     get_macro
 };
-use crate::mapping::{self, StataColumnInfo};
+use crate::mapping::{self, StataColumnInfo, StataType};
+use crate::csv_dialect::CsvWriteOptions;
+use crate::decompress::Compression;
 use polars::prelude::KeyValueMetadata;
 
 use crate::utilities::{
+    clock_without_leap_seconds,
+    is_leap_clock_format,
+    parse_duration_record,
+    StataPeriod,
     DAY_SHIFT_SAS_STATA,
     SEC_SHIFT_SAS_STATA,
     //  SEC_MILLISECOND,
@@ -57,6 +63,9 @@ pub fn write_from_stata(
     quietly: bool,
     append_to_partition: bool,
     output_format: &str,
+    csv_options: &CsvWriteOptions,
+    xpt_version: u8,
+    spss_missing: &sav::ExtendedMissingCodes,
 ) -> Result<i32,Box<dyn Error>> {
     let variables_as_str = if variables_as_str == "" || variables_as_str == "from_macro" {
         &get_macro("varlist", false,  Some(1024 * 1024 * 10))
@@ -120,8 +129,29 @@ pub fn write_from_stata(
     //    println!("columns     = {:?}", all_columns);
     //    println!("column info = {:?}", column_info);
 
-    let key_value_metadata = stata_metadata::metadata_from_macros(&rename_list, &column_info)
-        .and_then(|envelope| stata_metadata::build_key_value_metadata(&envelope));
+    let metadata_envelope = stata_metadata::metadata_from_macros(&rename_list, &column_info);
+    let key_value_metadata = metadata_envelope
+        .as_ref()
+        .and_then(stata_metadata::build_key_value_metadata);
+
+    // timezone(): %tc columns hold that zone's local clock and are written
+    // zone-aware; %tC is already UTC
+    let time_zone = match time_zone::parse(&get_macro("pq_timezone", false, None)) {
+        Ok(zone) => zone,
+        Err(e) => {
+            display(&e);
+            return Ok(198);
+        }
+    };
+    let zoned_columns: Vec<String> = match time_zone {
+        Some(_) => column_info
+            .iter()
+            // %tC columns too, once the scan has taken their leap seconds out
+            .filter(|col| mapping::resolve_stata_type(&col.dtype, &col.format) == StataType::DateTime)
+            .map(|col| col.name.clone())
+            .collect(),
+        None => Vec::new(),
+    };
 
     // Convert Option<&str> to Option<String>
     let sql_if_owned = sql_if.map(|s| s.to_string());
@@ -143,12 +173,19 @@ pub fn write_from_stata(
         ScanArgsAnonymous::default()
     );
     
-    let lf_unwrapped = lf.unwrap();
+    let mut lf_unwrapped = lf.unwrap();
+    if let Some(zone) = &time_zone {
+        lf_unwrapped = time_zone::annotate(lf_unwrapped, &zoned_columns, zone);
+    }
 
 
     let output_format_normalized = output_format.to_ascii_lowercase();
 
     if output_format_normalized == "parquet" {
+        if let Err(e) = check_parquet_compression_level(compression, compression_level) {
+            display(&e);
+            return Ok(198);
+        }
         let delete_error = delete_existing_files(
             path,
             overwrite_partition,
@@ -161,13 +198,15 @@ pub fn write_from_stata(
             save_partitioned(
                 path,
                 lf_unwrapped,
-                compression,
-                compression_level,
-                &partition_by,
-                compress,
-                compress_string,
-                quietly,
-                append_to_partition,
+                &PartitionedWriteOptions {
+                    compression,
+                    compression_level,
+                    partition_by: &partition_by,
+                    compress,
+                    compress_string,
+                    quietly,
+                    append_to_partition,
+                },
                 key_value_metadata,
             )
         } else {
@@ -216,13 +255,71 @@ pub fn write_from_stata(
 
         match output_format_normalized.as_str() {
             "spss" => {
-                let writer = polars_readstat_rs::SpssWriter::new(path);
-                if let Err(e) = writer.write_df(&df) {
+                let spss_missing: sav::ExtendedMissingCodes = spss_missing
+                    .iter()
+                    .map(|(name, codes)| {
+                        let name = PlSmallStr::from(name.as_str());
+                        let renamed = rename_list.get(&name).cloned().unwrap_or(name);
+                        (renamed.to_string(), codes.clone())
+                    })
+                    .collect();
+                if let Err(e) = sav::write_sav(path, &df, metadata_envelope.as_ref(), &spss_missing) {
                     display(&format!("SPSS write error: {}", e));
                     return Ok(198);
                 }
             }
+            "xpt" => {
+                if !matches!(compression.to_ascii_lowercase().as_str(), "" | "uncompressed") {
+                    display("compression() is not available for xpt output");
+                    return Ok(198);
+                }
+                let adjustments = match xpt::write_xpt(path, df, metadata_envelope.as_ref(), xpt_version) {
+                    Ok(adjustments) => adjustments,
+                    Err(e) => {
+                        display(&format!("XPORT write error: {}", e));
+                        return Ok(198);
+                    }
+                };
+                display_xpt_adjustments(&adjustments, xpt_version);
+            }
+            "ipc" => {
+                let ipc_compression = match ipc_compression(compression) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        display(&e);
+                        return Ok(198);
+                    }
+                };
+                let mut file = match File::create(path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        display(&format!("IPC file create error: {}", e));
+                        return Ok(198);
+                    }
+                };
+
+                let mut writer = IpcWriter::new(&mut file).with_compression(ipc_compression);
+                if let Some(schema_metadata) = metadata_envelope
+                    .as_ref()
+                    .and_then(stata_metadata::build_ipc_schema_metadata)
+                {
+                    writer.set_custom_schema_metadata(Arc::new(schema_metadata));
+                }
+                if let Err(e) = writer.finish(&mut df) {
+                    display(&format!("IPC write error: {}", e));
+                    return Ok(198);
+                }
+            }
             "csv" => {
+                let csv_compression = match csv_output_compression(path, compression)
+                    .and_then(|c| check_csv_compression_level(c, compression_level).map(|_| c))
+                {
+                    Ok(c) => c,
+                    Err(e) => {
+                        display(&e);
+                        return Ok(198);
+                    }
+                };
                 let mut file = match File::create(path) {
                     Ok(f) => f,
                     Err(e) => {
@@ -231,7 +328,25 @@ pub fn write_from_stata(
                     }
                 };
 
-                if let Err(e) = CsvWriter::new(&mut file).include_header(true).finish(&mut df) {
+                // The encoders stream, and must be finished to write their trailers.
+                let write_result = match csv_compression {
+                    None => csv_options.apply(CsvWriter::new(&mut file)).finish(&mut df),
+                    Some(Compression::Gzip) => {
+                        let level = flate2::Compression::new(compression_level.unwrap_or(6) as u32);
+                        let mut encoder = flate2::write::GzEncoder::new(file, level);
+                        csv_options
+                            .apply(CsvWriter::new(&mut encoder))
+                            .finish(&mut df)
+                            .and_then(|_| encoder.finish().map(|_| ()).map_err(PolarsError::from))
+                    }
+                    Some(_) => zstd::stream::write::Encoder::new(file, compression_level.unwrap_or(3) as i32)
+                        .map_err(PolarsError::from)
+                        .and_then(|mut encoder| {
+                            csv_options.apply(CsvWriter::new(&mut encoder)).finish(&mut df)?;
+                            encoder.finish().map(|_| ()).map_err(PolarsError::from)
+                        }),
+                };
+                if let Err(e) = write_result {
                     display(&format!("CSV write error: {}", e));
                     return Ok(198);
                 }
@@ -249,7 +364,72 @@ pub fn write_from_stata(
     }
 }
 
-fn delete_existing_non_parquet(path: &str) -> i32 {
+/// Compression for CSV output: compression() if given, otherwise the file
+/// extension (out.csv.gz, out.csv.zst). Only gzip and zstd are written.
+pub fn ipc_compression(compression: &str) -> Result<Option<IpcCompression>, String> {
+    match compression.to_ascii_lowercase().as_str() {
+        "" | "uncompressed" => Ok(None),
+        "lz4" => Ok(Some(IpcCompression::LZ4)),
+        "zstd" => Ok(Some(IpcCompression::ZSTD(Default::default()))),
+        other => Err(format!(
+            "compression({}) is not available for ipc output; use lz4, zstd, or uncompressed",
+            other
+        )),
+    }
+}
+
+pub fn display_xpt_adjustments(adjustments: &xpt::XptAdjustments, xpt_version: u8) {
+    let renames = &adjustments.renames;
+    if !renames.is_empty() {
+        display(&format!(
+            "note: {} variable name(s) changed to fit XPORT v{} naming rules:",
+            renames.len(),
+            xpt_version
+        ));
+        for (from, to) in renames {
+            display(&format!("      {} -> {}", from, to));
+        }
+    }
+    let truncated = &adjustments.truncated_labels;
+    if !truncated.is_empty() {
+        display(&format!(
+            "note: {} variable label(s) truncated to the 40 bytes XPORT v{} allows (xpt_version(8) keeps them whole):",
+            truncated.len(),
+            xpt_version
+        ));
+        display(&format!("      {}", truncated.join(" ")));
+    }
+}
+
+pub fn csv_output_compression(path: &str, compression: &str) -> Result<Option<Compression>, String> {
+    let from_extension = Compression::from_path(path);
+    let requested = match compression.to_ascii_lowercase().as_str() {
+        "" => from_extension,
+        "uncompressed" => None,
+        "gzip" => Some(Compression::Gzip),
+        "zstd" => Some(Compression::Zstd),
+        other => {
+            return Err(format!(
+                "compression({}) is not available for csv output; use gzip, zstd, or uncompressed",
+                other
+            ))
+        }
+    };
+    if from_extension.is_some() && requested != from_extension {
+        return Err(format!(
+            "compression({}) does not match the file extension of {}",
+            compression, path
+        ));
+    }
+    match requested {
+        Some(Compression::Bzip2) | Some(Compression::Xz) => {
+            Err("csv output can be compressed with gzip (.gz) or zstd (.zst) only".to_string())
+        }
+        other => Ok(other),
+    }
+}
+
+pub fn delete_existing_non_parquet(path: &str) -> i32 {
     let path_obj = Path::new(path);
     if !path_obj.exists() {
         return 0;
@@ -274,13 +454,7 @@ fn delete_existing_non_parquet(path: &str) -> i32 {
 fn save_partitioned(
     path:&str,
     lf:LazyFrame,
-    compression:&str,
-    compression_level:Option<usize>,
-    partition_by:&Vec<PlSmallStr>,
-    compress:bool,
-    compress_string: bool,
-    quietly: bool,
-    append_to_partition: bool,
+    options:&PartitionedWriteOptions,
     key_value_metadata: Option<KeyValueMetadata>,
 )  -> Result<i32,Box<dyn Error>> {
     let mut df = match lf.collect() {
@@ -291,18 +465,18 @@ fn save_partitioned(
         Ok(df_collected) => df_collected,
     };
 
-    if compress | compress_string {
+    if options.compress | options.compress_string {
         let cols_to_downcast: Vec<String> = df.get_column_names().iter()
             .map(|&name| name.to_string())
             .collect();
 
-        let cols_not_boolean: Vec<String> = partition_by.iter()
+        let cols_not_boolean: Vec<String> = options.partition_by.iter()
             .map(|p| p.as_str().to_string())
             .collect();
 
         let mut down_config = downcast::DowncastConfig::default();
-        down_config.check_strings = compress_string;
-        down_config.prefer_int_over_float = compress;
+        down_config.check_strings = options.compress_string;
+        down_config.prefer_int_over_float = options.compress;
         df = match downcast::intelligent_downcast_df(
             df,
             Some(cols_to_downcast),
@@ -317,16 +491,16 @@ fn save_partitioned(
         }
     }
 
+    // Already downcast above.
+    let sequential_options = PartitionedWriteOptions {
+        compress: false,
+        compress_string: false,
+        ..*options
+    };
     save_partitioned_sequential(
         path,
         df.lazy(),
-        compression,
-        compression_level,
-        partition_by,
-        false,
-        false,
-        quietly,
-        append_to_partition,
+        &sequential_options,
         key_value_metadata,
     )
 
@@ -395,18 +569,33 @@ fn next_partition_file_index(partition_dir: &PathBuf) -> usize {
     max_idx.map_or(0, |m| m + 1)
 }
 
-fn save_partitioned_sequential(
+/// How partitioned parquet output is written.
+pub struct PartitionedWriteOptions<'a> {
+    pub compression: &'a str,
+    pub compression_level: Option<usize>,
+    pub partition_by: &'a [PlSmallStr],
+    pub compress: bool,
+    pub compress_string: bool,
+    pub quietly: bool,
+    /// Add new data_N.parquet files next to the existing ones in each partition.
+    pub append_to_partition: bool,
+}
+
+pub fn save_partitioned_sequential(
     path: &str,
     lf: LazyFrame,
-    compression: &str,
-    compression_level: Option<usize>,
-    partition_by: &Vec<PlSmallStr>,
-    compress: bool,
-    compress_string: bool,
-    quietly: bool,
-    append_to_partition: bool,
+    options: &PartitionedWriteOptions,
     key_value_metadata: Option<KeyValueMetadata>,
 ) -> Result<i32, Box<dyn Error>> {
+    let PartitionedWriteOptions {
+        compression,
+        compression_level,
+        partition_by,
+        compress,
+        compress_string,
+        quietly,
+        append_to_partition,
+    } = *options;
     let pqo = parquet_options(compression, compression_level, key_value_metadata);
     
     // First, get unique partition values by collecting only the partition columns
@@ -765,14 +954,79 @@ fn save_no_partition(
     }
 }
 
-fn parquet_options(
+/// The parquet codec a compression() value selects; blank and unknown values use zstd.
+fn parquet_codec(compression: &str) -> &'static str {
+    match compression {
+        "lz4" => "lz4",
+        "uncompressed" => "uncompressed",
+        "snappy" => "snappy",
+        "gzip" => "gzip",
+        "brotli" => "brotli",
+        _ => "zstd",
+    }
+}
+
+/// Reads the compression_level() argument; -1 (or blank) means the codec's default.
+pub fn parse_compression_level(arg: &str) -> Result<Option<usize>, String> {
+    let arg = arg.trim();
+    if arg.is_empty() || arg == "-1" {
+        return Ok(None);
+    }
+    match arg.parse::<i64>() {
+        Ok(level) if level >= 0 => Ok(Some(level as usize)),
+        _ => Err(format!("compression_level() must be a non-negative integer, passed \"{}\"", arg)),
+    }
+}
+
+/// Checks compression_level() against the range the codec accepts, so an
+/// out-of-range value is reported rather than wrapped or silently dropped.
+pub fn check_compression_level(codec: &str, compression_level: Option<usize>) -> Result<(), String> {
+    let Some(level) = compression_level else {
+        return Ok(());
+    };
+    let (min, max) = match codec {
+        "gzip" => (0, 9),
+        "brotli" => (0, 11),
+        "zstd" => (1, 22),
+        other => return Err(format!("compression_level() is not supported for compression = \"{}\"", other)),
+    };
+    if (min..=max).contains(&level) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Acceptable compression_level range for compression = \"{}\" [{}, {}], passed \"{}\"",
+            codec, min, max, level
+        ))
+    }
+}
+
+/// As check_compression_level, for parquet output.  Codecs without levels
+/// (lz4, snappy, uncompressed) ignore compression_level().
+pub fn check_parquet_compression_level(compression: &str, compression_level: Option<usize>) -> Result<(), String> {
+    match parquet_codec(compression) {
+        "lz4" | "snappy" | "uncompressed" => Ok(()),
+        codec => check_compression_level(codec, compression_level),
+    }
+}
+
+/// As check_compression_level, for csv output (None is uncompressed).
+pub fn check_csv_compression_level(compression: Option<Compression>, compression_level: Option<usize>) -> Result<(), String> {
+    match compression {
+        Some(Compression::Gzip) => check_compression_level("gzip", compression_level),
+        Some(_) => check_compression_level("zstd", compression_level),
+        None if compression_level.is_some() => Err("compression_level() requires gzip or zstd csv output".to_string()),
+        None => Ok(()),
+    }
+}
+
+pub fn parquet_options(
     compression:&str,
     compression_level:Option<usize>,
     key_value_metadata: Option<KeyValueMetadata>,
 ) -> ParquetWriteOptions {
     let mut pqo = ParquetWriteOptions::default();
     pqo.key_value_metadata = key_value_metadata;
-    pqo.compression = match compression {
+    pqo.compression = match parquet_codec(compression) {
         "lz4" => ParquetCompression::Lz4Raw,
         "uncompressed" => ParquetCompression::Uncompressed,
         "snappy" => ParquetCompression::Snappy,
@@ -784,7 +1038,6 @@ fn parquet_options(
 
             ParquetCompression::Gzip(gzip_level)
         },
-        "lzo" => ParquetCompression::Zstd(None),
         "brotli" => {
             let brotli_level = match compression_level {
                 None => None,
@@ -840,7 +1093,7 @@ fn get_rename_list() -> HashMap<PlSmallStr,PlSmallStr> {
 }
 
 
-fn column_info_from_macros(
+pub fn column_info_from_macros(
     n_vars: usize,
     rename_list: HashMap<PlSmallStr,PlSmallStr>,
 ) -> Vec<StataColumnInfo> {
@@ -868,6 +1121,7 @@ fn column_info_from_macros(
         
         let stata_col_str = get_macro(&format!("col_{}", i+1), false, None);
         let stata_col = stata_col_str.parse::<usize>().unwrap_or(0);
+        let duration = get_macro(&format!("duration_{}", i+1), false, None);
 
         column_infos.push(StataColumnInfo {
             name,
@@ -875,6 +1129,7 @@ fn column_info_from_macros(
             format,
             str_length,
             stata_col,
+            duration,
         });
     }
     
@@ -939,7 +1194,9 @@ struct TimeProcess(i64);
 
 impl FromStataValue<TimeProcess> for TimeProcess {
     fn from_stata_value(value: f64) -> TimeProcess {
-        TimeProcess((value as i64) * SEC_MICROSECOND)
+        // Milliseconds into the day (a full %tc value keeps its clock time),
+        // as nanoseconds
+        TimeProcess((value.round() as i64).rem_euclid(86_400_000) * SEC_MICROSECOND)
     }
 }
 
@@ -1212,9 +1469,17 @@ fn process_column(
             Series::new(col_name.clone(), values)
         }
         DataType::Datetime(TimeUnit::Milliseconds, _) => {
-            let values = process_numeric_data::<DatetimeProcess>(col_idx, n_rows_to_read, offset, parallelize_rows);
-            // Convert the DatetimeProcess wrapper to i64 values
-            let i64_values: Vec<Option<i64>> = values.into_iter().map(|opt| opt.map(|dm| dm.0)).collect();
+            let i64_values: Vec<Option<i64>> = if is_leap_clock_format(&mapping::find_format_by_name(column_info, col_name)) {
+                // %tC counts leap seconds; drop them so the column is plain UTC
+                process_numeric_data::<f64>(col_idx, n_rows_to_read, offset, parallelize_rows)
+                    .into_iter()
+                    .map(|opt| opt.map(|v| DatetimeProcess::from_stata_value(clock_without_leap_seconds(v)).0))
+                    .collect()
+            } else {
+                let values = process_numeric_data::<DatetimeProcess>(col_idx, n_rows_to_read, offset, parallelize_rows);
+                // Convert the DatetimeProcess wrapper to i64 values
+                values.into_iter().map(|opt| opt.map(|dm| dm.0)).collect()
+            };
             Series::new(col_name.clone(), i64_values).cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?
         }
         DataType::Time => {
@@ -1223,10 +1488,28 @@ fn process_column(
             let i64_values: Vec<Option<i64>> = values.into_iter().map(|opt| opt.map(|tm| tm.0)).collect();
             Series::new(col_name.clone(), i64_values).cast(&DataType::Time)?
         }
+        DataType::Duration(time_unit) => {
+            let ticks = parse_duration_record(&mapping::find_duration_by_name(column_info, col_name))
+                .map_or(1.0, |(unit, _)| unit.ticks(time_unit));
+            let i64_values: Vec<Option<i64>> = process_numeric_data::<f64>(col_idx, n_rows_to_read, offset, parallelize_rows)
+                .into_iter()
+                .map(|opt| opt.map(|v| (v * ticks).round() as i64))
+                .collect();
+            Series::new(col_name.clone(), i64_values).cast(&DataType::Duration(time_unit))?
+        }
         DataType::Date => {
-            let values = process_numeric_data::<DateProcess>(col_idx, n_rows_to_read, offset, parallelize_rows);
-            // Convert the DateProcess wrapper to i32 values
-            let i32_values: Vec<Option<i32>> = values.into_iter().map(|opt| opt.map(|dv| dv.0)).collect();
+            let i32_values: Vec<Option<i32>> = match StataPeriod::of_format(&mapping::find_format_by_name(column_info, col_name)) {
+                // %tw/%tm/%tq/%th/%ty count periods, written as the first day of each
+                Some(period) => process_numeric_data::<f64>(col_idx, n_rows_to_read, offset, parallelize_rows)
+                    .into_iter()
+                    .map(|opt| opt.and_then(|v| period.first_day(v.floor() as i64)).map(|days| days as i32))
+                    .collect(),
+                None => {
+                    let values = process_numeric_data::<DateProcess>(col_idx, n_rows_to_read, offset, parallelize_rows);
+                    // Convert the DateProcess wrapper to i32 values
+                    values.into_iter().map(|opt| opt.map(|dv| dv.0)).collect()
+                }
+            };
             Series::new(col_name.clone(), i32_values).cast(&DataType::Date)?
         }
         // Add more data types as needed
@@ -1276,10 +1559,13 @@ fn read_single_batch(
 
     if let Some(sql_if) = &sds.sql_if {
         if !sql_if.is_empty() {
-            let mut ctx = SQLContext::new();
-            ctx.register("df", df);
-
-            df = ctx.execute(&format!("select * from df where {}", sql_if))
+            // %tm & co. were just written as the first day of each period
+            let period_columns: HashMap<String, StataPeriod> = sds
+                .column_info
+                .iter()
+                .filter_map(|column| Some((column.name.clone(), StataPeriod::of_format(&column.format)?)))
+                .collect();
+            df = stata_if::filter_lazy_with_periods(df, sql_if, &period_columns)
                 .map_err(|e| {
                     display(&format!("Error in SQL if statement: {}", e));
                     e
@@ -1289,3 +1575,38 @@ fn read_single_batch(
     
     Ok(Some(df.collect()?))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_compression_follows_option_then_extension() {
+        assert_eq!(csv_output_compression("out.csv", ""), Ok(None));
+        assert_eq!(csv_output_compression("out.csv.gz", ""), Ok(Some(Compression::Gzip)));
+        assert_eq!(csv_output_compression("out.csv.zst", "zstd"), Ok(Some(Compression::Zstd)));
+        assert_eq!(csv_output_compression("out.csv", "gzip"), Ok(Some(Compression::Gzip)));
+        assert!(csv_output_compression("out.csv.gz", "zstd").is_err());
+        assert!(csv_output_compression("out.csv.xz", "").is_err());
+        assert!(csv_output_compression("out.csv", "snappy").is_err());
+    }
+
+    #[test]
+    fn compression_level_is_checked_per_codec() {
+        assert_eq!(parse_compression_level("-1"), Ok(None));
+        assert_eq!(parse_compression_level("7"), Ok(Some(7)));
+        assert!(parse_compression_level("-5").is_err());
+
+        assert!(check_parquet_compression_level("", Some(22)).is_ok());
+        assert!(check_parquet_compression_level("zstd", Some(0)).is_err());
+        assert!(check_parquet_compression_level("gzip", Some(10)).is_err());
+        assert!(check_parquet_compression_level("brotli", Some(11)).is_ok());
+        assert!(check_parquet_compression_level("lz4", Some(100)).is_ok());
+
+        assert!(check_csv_compression_level(Some(Compression::Gzip), Some(9)).is_ok());
+        assert!(check_csv_compression_level(Some(Compression::Gzip), Some(300)).is_err());
+        assert!(check_csv_compression_level(Some(Compression::Zstd), Some(23)).is_err());
+        assert!(check_csv_compression_level(None, Some(3)).is_err());
+        assert!(check_csv_compression_level(None, None).is_ok());
+    }
+}
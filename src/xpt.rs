@@ -0,0 +1,299 @@
+//! SAS transport (XPORT v5/v8) output for pq save.
+//!
+//! Dates and datetimes arrive here as Polars Date/Datetime columns (the
+//! Stata scan already removed DAY_SHIFT_SAS_STATA / SEC_SHIFT_SAS_STATA),
+//! and XptWriter puts them back on the 1960 epoch SAS shares with Stata.
+//! This module maps names, labels and display formats onto XPORT's limits.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use polars::prelude::*;
+use polars_readstat_rs::XptWriter;
+use regex::Regex;
+
+use crate::stata_metadata::StataMetadataEnvelope;
+
+/// v5 allows 200-byte character variables; v8 has no practical limit.
+const XPT_V5_MAX_STRING: usize = 200;
+
+/// v5 variable labels are 40 bytes; v8 stores longer ones in a LABELV8 record.
+const XPT_V5_MAX_LABEL: usize = 40;
+
+/// What write_xpt changed to fit XPORT's limits, for the caller to report.
+#[derive(Debug, Default, PartialEq)]
+pub struct XptAdjustments {
+    /// Renamed variables as (original, xpt) pairs.
+    pub renames: Vec<(String, String)>,
+    /// Variables (original names) whose labels were cut to 40 bytes.
+    pub truncated_labels: Vec<String>,
+}
+
+fn max_name_length(version: u8) -> usize {
+    if version >= 8 { 32 } else { 8 }
+}
+
+/// XPORT-valid names for `names`, in order: ASCII letters, digits and
+/// underscores, not starting with a digit, within the version's length limit
+/// and unique ignoring case (SAS names are case-insensitive).
+pub fn xpt_names(names: &[String], version: u8) -> Vec<String> {
+    let max_len = max_name_length(version);
+    let mut seen: HashSet<String> = HashSet::new();
+    names
+        .iter()
+        .map(|name| {
+            let mut base: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+                .collect();
+            if base.is_empty() || base.starts_with(|c: char| c.is_ascii_digit()) {
+                base.insert(0, '_');
+            }
+            base.truncate(max_len);
+
+            let mut candidate = base.clone();
+            let mut counter = 1;
+            while !seen.insert(candidate.to_ascii_uppercase()) {
+                counter += 1;
+                let suffix = counter.to_string();
+                let keep = base.len().min(max_len - suffix.len());
+                candidate = format!("{}{}", &base[..keep], suffix);
+            }
+            candidate
+        })
+        .collect()
+}
+
+/// SAS format for a Stata display format, where one exists.
+pub fn stata_format_to_sas(format: &str) -> Option<String> {
+    if format.starts_with("%td") || format.starts_with("%d") {
+        return Some("DATE9.".to_string());
+    }
+    if format.starts_with("%tc") || format.starts_with("%tC") {
+        return Some("DATETIME20.".to_string());
+    }
+
+    let numeric = Regex::new(r"^%-?0?(\d+)\.(\d+)(f|fc|g|gc|e)$").ok()?;
+    if let Some(caps) = numeric.captures(format) {
+        let width: usize = caps[1].parse().ok()?;
+        let decimals: usize = caps[2].parse().ok()?;
+        let width = width.clamp(1, 32);
+        return match &caps[3] {
+            "f" => Some(format!("{}.{}", width, decimals.min(width.saturating_sub(1)))),
+            "fc" => Some(format!("COMMA{}.{}", width, decimals.min(width.saturating_sub(1)))),
+            "e" => Some(format!("E{}.", width.max(7))),
+            _ => Some(format!("BEST{}.", width)),
+        };
+    }
+
+    let string = Regex::new(r"^%-?(\d+)s$").ok()?;
+    string
+        .captures(format)
+        .and_then(|caps| caps[1].parse::<usize>().ok())
+        .map(|width| format!("${}.", width.clamp(1, 32767)))
+}
+
+/// Member name: the file stem, made XPORT-valid, upper case.
+fn table_name(path: &str, version: u8) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    xpt_names(&[stem], version)
+        .pop()
+        .unwrap_or_default()
+        .to_ascii_uppercase()
+}
+
+/// Label cut to at most `max_len` bytes on a character boundary.
+fn truncate_label(label: &str, max_len: usize) -> &str {
+    if label.len() <= max_len {
+        return label;
+    }
+    let mut end = max_len;
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    &label[..end]
+}
+
+/// Writes `df` as an XPORT file, returning the renamed variables and cut
+/// labels for the caller to report.
+pub fn write_xpt(
+    path: &str,
+    mut df: DataFrame,
+    envelope: Option<&StataMetadataEnvelope>,
+    version: u8,
+) -> Result<XptAdjustments, String> {
+    if version != 5 && version != 8 {
+        return Err(format!("xpt_version({}) must be 5 or 8", version));
+    }
+
+    let original: Vec<String> = df.get_column_names().iter().map(|n| n.to_string()).collect();
+    let renamed = xpt_names(&original, version);
+    let renames: Vec<(String, String)> = original
+        .iter()
+        .zip(renamed.iter())
+        .filter(|(from, to)| from != to)
+        .map(|(from, to)| (from.clone(), to.clone()))
+        .collect();
+    if !renames.is_empty() {
+        df.set_column_names(&renamed)
+            .map_err(|e| e.to_string())?;
+    }
+
+    if version == 5 {
+        for column in df.columns() {
+            if let Ok(ca) = column.str() {
+                let longest = ca.iter().flatten().map(str::len).max().unwrap_or(0);
+                if longest > XPT_V5_MAX_STRING {
+                    return Err(format!(
+                        "{} has values of {} bytes; XPORT v5 allows {} (use xpt_version(8))",
+                        column.name(),
+                        longest,
+                        XPT_V5_MAX_STRING
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut truncated_labels: Vec<String> = Vec::new();
+    let mut labels: HashMap<String, String> = HashMap::new();
+    let mut formats: HashMap<String, String> = HashMap::new();
+    if let Some(envelope) = envelope {
+        for (from, to) in original.iter().zip(renamed.iter()) {
+            let Some(meta) = envelope.variables.get(from) else {
+                continue;
+            };
+            if let Some(full) = meta.label.as_deref().filter(|l| !l.is_empty()) {
+                let label = if version == 5 { truncate_label(full, XPT_V5_MAX_LABEL) } else { full };
+                if label.len() < full.len() {
+                    truncated_labels.push(from.clone());
+                }
+                labels.insert(to.clone(), label.to_string());
+            }
+            if let Some(format) = meta.format.as_deref().and_then(stata_format_to_sas) {
+                formats.insert(to.clone(), format);
+            }
+        }
+    }
+
+    let mut writer = XptWriter::new(path)
+        .with_version(version)
+        .with_table_name(table_name(path, version))
+        .with_variable_labels(labels)
+        .with_variable_formats(formats);
+    if let Some(label) = envelope.and_then(|e| e.dataset_label.as_ref()) {
+        writer = writer.with_file_label(label.clone());
+    }
+    writer.write_df(&df).map_err(|e| e.to_string())?;
+    Ok(XptAdjustments {
+        renames,
+        truncated_labels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stata_metadata::VariableMetadata;
+    use polars_readstat_rs::{read_xpt_metadata, readstat_scan, ReadStatFormat};
+
+    #[test]
+    fn names_fit_the_version_limits() {
+        let names: Vec<String> = ["household_income", "household_id", "HOUSEHOLD_INCOME", "2nd", "x"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            xpt_names(&names, 5),
+            ["househol", "househo2", "HOUSEHO3", "_2nd", "x"]
+        );
+        assert_eq!(
+            xpt_names(&names, 8),
+            ["household_income", "household_id", "HOUSEHOLD_INCOME2", "_2nd", "x"]
+        );
+    }
+
+    #[test]
+    fn labels_are_cut_on_a_character_boundary() {
+        assert_eq!(truncate_label("Income", XPT_V5_MAX_LABEL), "Income");
+        let label = format!("{}é", "a".repeat(39));
+        assert_eq!(truncate_label(&label, XPT_V5_MAX_LABEL), "a".repeat(39));
+    }
+
+    #[test]
+    fn stata_formats_map_to_sas() {
+        assert_eq!(stata_format_to_sas("%td").as_deref(), Some("DATE9."));
+        assert_eq!(stata_format_to_sas("%tcDDmonCCYY_HH:MM").as_deref(), Some("DATETIME20."));
+        assert_eq!(stata_format_to_sas("%9.2f").as_deref(), Some("9.2"));
+        assert_eq!(stata_format_to_sas("%12.0fc").as_deref(), Some("COMMA12.0"));
+        assert_eq!(stata_format_to_sas("%9.0g").as_deref(), Some("BEST9."));
+        assert_eq!(stata_format_to_sas("%-20s").as_deref(), Some("$20."));
+        assert_eq!(stata_format_to_sas("%tm"), None);
+    }
+
+    #[test]
+    fn xpt_round_trip_with_labels_and_dates() {
+        let path = std::env::temp_dir().join(format!("pq_xpt_test_{}.xpt", std::process::id()));
+        let path_str = path.to_string_lossy().to_string();
+
+        let df = df!(
+            "household_income" => [Some(1.5f64), None],
+            "visit" => [19783i32, 20088],
+            "site" => ["a", "bb"],
+        )
+        .unwrap()
+        .lazy()
+        .with_column(col("visit").cast(DataType::Date))
+        .collect()
+        .unwrap();
+
+        let mut envelope = StataMetadataEnvelope {
+            version: 1,
+            dataset_label: Some("Trial data".to_string()),
+            ..Default::default()
+        };
+        envelope.variables.insert(
+            "household_income".to_string(),
+            VariableMetadata {
+                label: Some("Income".to_string()),
+                format: Some("%9.2f".to_string()),
+                ..Default::default()
+            },
+        );
+
+        envelope.variables.insert(
+            "site".to_string(),
+            VariableMetadata {
+                label: Some("Clinical site where the participant was first seen".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let adjustments = write_xpt(&path_str, df, Some(&envelope), 5).unwrap();
+        assert_eq!(adjustments.renames, [("household_income".to_string(), "househol".to_string())]);
+        assert_eq!(adjustments.truncated_labels, ["site"]);
+
+        let meta = read_xpt_metadata(&path).unwrap();
+        assert_eq!(meta.version, 5);
+        assert_eq!(meta.file_label.trim(), "Trial data");
+        let income = &meta.columns[0];
+        assert_eq!(income.name.trim(), "househol");
+        assert_eq!(income.label.trim(), "Income");
+        assert_eq!(meta.columns[2].label.trim(), "Clinical site where the participant was");
+
+        let back = readstat_scan(&path, None, Some(ReadStatFormat::SasXpt))
+            .unwrap()
+            .collect()
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(back.height(), 2);
+        assert_eq!(back.column("visit").unwrap().dtype(), &DataType::Date);
+        assert_eq!(
+            back.column("visit").unwrap().cast(&DataType::Int32).unwrap().i32().unwrap().get(1),
+            Some(20088)
+        );
+    }
+}